@@ -8,6 +8,7 @@ import Server from './Server';
 import { IconCircleX } from '@tabler/icons-react';
 import React, { useEffect } from 'react';
 import Commits from './Commits';
+import Footer from './Footer';
 
 export function App() {
   const { state, dispatch, localDispatch } = useEtes();
@@ -36,7 +37,7 @@ export function App() {
         <Flex gap="md" wrap="wrap">
           <Commits state={state} dispatch={dispatch} />
           <Releases state={state} dispatch={dispatch} />
-          <Server state={state} />
+          <Server state={state} dispatch={dispatch} />
         </Flex>
         <Box>
           {state.error && (
@@ -54,6 +55,7 @@ export function App() {
           <PullTable state={state} dispatch={dispatch} />
           <ServiceTable state={state} dispatch={dispatch} />
         </Box>
+        <Footer state={state} />
       </Box>
     </MantineProvider >
   )
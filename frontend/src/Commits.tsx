@@ -59,6 +59,7 @@ export default function Commits({ state, dispatch }: CommitsProps) {
                   words={state.words}
                   services={state.services}
                   executables={state.executables}
+                  hostPlatform={state.hostPlatform}
                   dispatch={dispatch}
                 />
               )}
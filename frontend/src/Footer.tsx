@@ -0,0 +1,23 @@
+import React from 'react';
+import { Text } from '@mantine/core';
+import { State } from './types';
+import { formatDuration } from './util';
+import DateTime from './DateTime';
+
+export default function Footer({ state }: { state: State }) {
+  const version = state.version;
+
+  if (!version) {
+    return null;
+  }
+
+  const uptime = (Date.now() - new Date(version.startedAt).getTime()) / 1000;
+
+  return (
+    <Text component="footer" size="xs" c="dimmed" ta="center" mt="lg">
+      etes {version.version} ({version.commit.slice(0, 7)})
+      {version.buildTime && <>, built <DateTime date={version.buildTime} /></>}
+      , up for {formatDuration(uptime)}
+    </Text>
+  );
+}
@@ -2,7 +2,6 @@ import React from 'react';
 import { State } from "./types";
 import { Button } from '@mantine/core';
 import { IconExternalLink, } from "@tabler/icons-react";
-import { getServiceUrl } from "./util";
 
 interface OpenButtonProps {
   state: State
@@ -10,7 +9,7 @@ interface OpenButtonProps {
 }
 
 export function OpenButton({ state, commitHash }: OpenButtonProps) {
-  const service = state.services.find((s) => s.executable.triggerHash === commitHash && s.state === 'running');
+  const service = state.services.find((s) => s.executable?.triggerHash === commitHash && s.state === 'running');
 
   if (!service) {
     return null;
@@ -23,7 +22,7 @@ export function OpenButton({ state, commitHash }: OpenButtonProps) {
       rightSection={<IconExternalLink size={14} />}
       color="darkblue"
       variant="light"
-      href={getServiceUrl(service.name)}
+      href={service.url}
     >
       Open
     </Button>
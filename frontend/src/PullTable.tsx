@@ -1,13 +1,13 @@
-import { Anchor, Avatar, Badge, Button, Card, Flex, Loader, Text, Table, Title, Tooltip, Box } from '@mantine/core';
+import { ActionIcon, Anchor, Avatar, Badge, Button, Card, Flex, Loader, Text, Table, Title, Tooltip, Box } from '@mantine/core';
 import { Action, Pull, State, WorkflowStatus } from "./types";
 import { RunButton } from './RunButton';
 import React, { Dispatch } from 'react';
-import { IconAlertTriangle, IconCheck, IconRefresh } from '@tabler/icons-react';
+import { IconAlertTriangle, IconCheck, IconDownload, IconLock, IconLockOpen, IconRefresh, IconTrash } from '@tabler/icons-react';
 import DateTime from './DateTime';
 import CommitHash from './CommitHash';
 import { OpenButton } from './OpenButton';
 import { PullRequest } from './PullRequest';
-import { statusColor } from './util';
+import { formatFileSize, statusColor } from './util';
 import { useMediaQuery } from '@mantine/hooks';
 
 interface PullTableProps {
@@ -40,14 +40,28 @@ export function PullTable({ state, dispatch }: PullTableProps) {
           </Title>
           <Badge size="xl" variant="light">{state.github.pulls.length}</Badge>
         </Flex>
-        <Button
-          leftSection={<IconRefresh size={14} />}
-          onClick={() => dispatch({ type: 'github_refresh' })}
-          loading={state.githubLoading}
-          variant="outline"
-        >
-          Sync pull requests
-        </Button>
+        <Flex gap="md" align="center">
+          {state.githubRefresh?.succeededAt && (
+            <Text size="sm" c="dimmed">
+              GitHub data updated <DateTime date={state.githubRefresh.succeededAt} />
+            </Text>
+          )}
+          {state.githubRefresh?.error && (
+            <Tooltip label={state.githubRefresh.error}>
+              <Badge color="orange" variant="light" leftSection={<IconAlertTriangle size={14} />}>
+                Last refresh failed
+              </Badge>
+            </Tooltip>
+          )}
+          <Button
+            leftSection={<IconRefresh size={14} />}
+            onClick={() => dispatch({ type: 'github_refresh' })}
+            loading={state.githubLoading}
+            variant="outline"
+          >
+            Sync pull requests
+          </Button>
+        </Flex>
       </Flex>
       <Table verticalSpacing="xs" horizontalSpacing="xs" striped withTableBorder>
         <Table.Thead>
@@ -86,7 +100,49 @@ export function PullTable({ state, dispatch }: PullTableProps) {
               </Table.Td>
               <Table.Td>
                 {state.executables.filter((e) => e.triggerHash === pull.commit.hash).map((e) => (
-                  <CommitHash key={e.hash} baseUrl={state.baseUrl} commitHash={e.hash} />
+                  <Tooltip
+                    key={`${e.hash}-${e.platform ?? ''}`}
+                    label={`Uploaded ${e.uploadedAt ? new Date(e.uploadedAt).toLocaleString() : 'at an unknown time'}${e.sizeBytes != null ? `, ${formatFileSize(e.sizeBytes)}` : ''}`}
+                    disabled={!e.uploadedAt && e.sizeBytes == null}
+                  >
+                    <Flex component="span" gap={4} align="center">
+                      <CommitHash baseUrl={state.baseUrl} commitHash={e.hash} />
+                      {e.platform && <Text size="xs" c="dimmed">{e.platform}</Text>}
+                      {state.isAdmin ? (
+                        <ActionIcon
+                          size="xs"
+                          variant="subtle"
+                          color={e.protected ? 'blue' : 'gray'}
+                          aria-label={e.protected ? 'Unprotect executable' : 'Protect executable'}
+                          onClick={() => dispatch({ type: 'protect_executable', hash: e.hash, protected: !e.protected })}
+                        >
+                          {e.protected ? <IconLock size={12} /> : <IconLockOpen size={12} />}
+                        </ActionIcon>
+                      ) : e.protected && <IconLock size={12} aria-label="Protected" />}
+                      {state.isAdmin && (
+                        <ActionIcon
+                          component="a"
+                          size="xs"
+                          variant="subtle"
+                          aria-label="Download executable"
+                          href={`/etes/api/v1/executable/${e.triggerHash}/${e.hash}${e.platform ? `?platform=${e.platform}` : ''}`}
+                        >
+                          <IconDownload size={12} />
+                        </ActionIcon>
+                      )}
+                      {state.isAdmin && !e.protected && !state.services.some((s) => s.executable?.hash === e.hash) && (
+                        <ActionIcon
+                          size="xs"
+                          color="red"
+                          variant="subtle"
+                          aria-label="Delete executable"
+                          onClick={() => dispatch({ type: 'delete_executable', hash: e.hash, trigger_hash: e.triggerHash, platform: e.platform })}
+                        >
+                          <IconTrash size={12} />
+                        </ActionIcon>
+                      )}
+                    </Flex>
+                  </Tooltip>
                 ))}
               </Table.Td>
               <Table.Td>
@@ -116,6 +172,7 @@ export function PullTable({ state, dispatch }: PullTableProps) {
                       words={state.words}
                       services={state.services}
                       executables={state.executables}
+                      hostPlatform={state.hostPlatform}
                       dispatch={dispatch}
                     />
                   )}
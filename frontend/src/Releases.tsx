@@ -58,6 +58,7 @@ export default function Releases({ state, dispatch }: ReleasesProps) {
                   words={state.words}
                   services={state.services}
                   executables={state.executables}
+                  hostPlatform={state.hostPlatform}
                   dispatch={dispatch}
                 />
               )}
@@ -2,33 +2,47 @@ import { Action, Commit, Executable, Service } from "./types";
 import { Button } from '@mantine/core';
 import { IconRocket } from "@tabler/icons-react";
 import React, { Dispatch, useEffect, useState } from 'react';
-import { generateName, getServiceUrl } from "./util";
+import { generateName } from "./util";
 
 interface RunButtonProps {
   commit: Commit;
   words: string[];
   services: Service[];
   executables: Executable[];
+  // executables for other platforms can't be started
+  hostPlatform: string | null;
   dispatch: Dispatch<Action>;
 }
 
-export function RunButton({ commit, words, services, executables, dispatch }: RunButtonProps) {
+export function RunButton({ commit, words, services, executables, hostPlatform, dispatch }: RunButtonProps) {
   const [name, setName] = useState(generateName(words));
   const [loading, setLoading] = useState(false);
 
   useEffect(() => {
     if (loading) {
-      const service = services.find((s) => s.executable.triggerHash === commit.hash && s.name === name);
+      const service = services.find((s) => s.executable?.triggerHash === commit.hash && s.name === name);
 
       if (service && service.state === 'running') {
-        window.open(getServiceUrl(service.name), '_blank');
+        window.open(service.url, '_blank');
         setLoading(false);
         setName(generateName(words));
       }
     }
   }, [loading, services]);
 
-  const executable = executables.find((e: Executable) => e.triggerHash === commit.hash);
+  const builds = executables.filter((e: Executable) => e.triggerHash === commit.hash);
+  const executable = builds.find((e) => !e.platform || !hostPlatform || e.platform === hostPlatform);
+
+  if (!executable && builds.length > 0) {
+    return (
+      <Button
+        color="gray"
+        disabled
+      >
+        Built for {builds.map((e) => e.platform).join(', ')}
+      </Button>
+    );
+  }
 
   if (!executable) {
     return (
@@ -41,6 +55,17 @@ export function RunButton({ commit, words, services, executables, dispatch }: Ru
     );
   }
 
+  if (executable.corrupt) {
+    return (
+      <Button
+        color="red"
+        disabled
+      >
+        Executable corrupt
+      </Button>
+    );
+  }
+
   const onclick = () => {
     setLoading(true);
     dispatch({
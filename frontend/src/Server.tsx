@@ -1,15 +1,17 @@
-import React from 'react';
+import React, { Dispatch } from 'react';
 import { Card, Title, Table, Flex, Button, Avatar, Tooltip, Text } from '@mantine/core';
-import { State } from './types';
-import { formatFileSize, isGitHubUser } from './util';
+import { Action, State } from './types';
+import { formatDuration, formatFileSize, isGitHubUser } from './util';
 import { ConnectionState } from './ConnectionState';
-import { IconBrandGithub, IconLogout } from '@tabler/icons-react';
+import { Sparkline } from './Sparkline';
+import { IconBrandGithub, IconLogout, IconTrash } from '@tabler/icons-react';
 
 interface ServerProps {
   state: State;
+  dispatch: Dispatch<Action>;
 }
 
-export default function Server({ state }: ServerProps) {
+export default function Server({ state, dispatch }: ServerProps) {
   return (
     <Card withBorder key="server">
       <Flex direction="column" gap="sm">
@@ -25,14 +27,99 @@ export default function Server({ state }: ServerProps) {
             <Table.Tr>
               <Table.Th scope="row">Running services</Table.Th>
               <Table.Td>
-                {state.services.length}
+                {state.memory?.services ? (
+                  `${state.memory.services.running} running, ${state.memory.services.error} error, ${state.memory.services.pending} pending`
+                ) : state.services.length}
               </Table.Td>
             </Table.Tr>
+            {state.presence && (
+              <Table.Tr>
+                <Table.Th scope="row">Online</Table.Th>
+                <Table.Td>
+                  {state.presence.connected} ({state.presence.authenticated} logged in, {state.presence.anonymous} anonymous)
+                </Table.Td>
+              </Table.Tr>
+            )}
             {state.memory && (
               <Table.Tr>
                 <Table.Th scope="row">Memory usage</Table.Th>
                 <Table.Td>
                   {formatFileSize(state.memory.used)} / {formatFileSize(state.memory.total)}
+                  {state.memory.memory_source && state.memory.memory_source !== 'host' && ' (container limit)'}
+                  <Sparkline values={state.history.map((sample) => sample.used)} max={state.memory.total} />
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.memory?.swap_total ? (
+              <Table.Tr>
+                <Table.Th scope="row">Swap usage</Table.Th>
+                <Table.Td>
+                  {formatFileSize(state.memory.swap_used ?? 0)} / {formatFileSize(state.memory.swap_total)}
+                </Table.Td>
+              </Table.Tr>
+            ) : null}
+            {state.memory && (
+              <Table.Tr>
+                <Table.Th scope="row">CPU usage</Table.Th>
+                <Table.Td>
+                  {Math.round(state.memory.cpu)}% of {state.memory.cores} cores
+                  <Sparkline values={state.history.map((sample) => sample.cpu)} max={100} />
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.memory && (
+              <Table.Tr>
+                <Table.Th scope="row">Binaries</Table.Th>
+                <Table.Td>
+                  {formatFileSize(state.memory.bin_size)}
+                  {state.memory.disk_free != null && `, ${formatFileSize(state.memory.disk_free)} free`}
+                  {state.isAdmin && (
+                    <Button
+                      size="compact-xs"
+                      ml="xs"
+                      leftSection={<IconTrash size={12} />}
+                      color="darkblue"
+                      variant="outline"
+                      onClick={() => dispatch({ type: 'cleanup_executables' })}
+                    >
+                      Clean up
+                    </Button>
+                  )}
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.memory?.load_average && (
+              <Table.Tr>
+                <Table.Th scope="row">Load average</Table.Th>
+                <Table.Td>
+                  {state.memory.load_average.map((load) => load.toFixed(2)).join(' / ')}
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.memory?.fds != null && (
+              <Table.Tr>
+                <Table.Th scope="row">Open files</Table.Th>
+                <Table.Td>
+                  {state.memory.fds}{state.memory.fd_limit != null && ` / ${state.memory.fd_limit}`}
+                  {state.memory.processes != null && `, ${state.memory.processes} service processes`}
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.workers.some((worker) => !worker.healthy) && (
+              <Table.Tr>
+                <Table.Th scope="row">Stalled workers</Table.Th>
+                <Table.Td>
+                  <Text c="red" size="sm">
+                    {state.workers.filter((worker) => !worker.healthy).map((worker) => worker.worker).join(', ')}
+                  </Text>
+                </Table.Td>
+              </Table.Tr>
+            )}
+            {state.memory?.uptime != null && (
+              <Table.Tr>
+                <Table.Th scope="row">Uptime</Table.Th>
+                <Table.Td>
+                  {formatDuration(state.memory.uptime)}
                 </Table.Td>
               </Table.Tr>
             )}
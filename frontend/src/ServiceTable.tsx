@@ -1,8 +1,8 @@
 import { Anchor, Avatar, Badge, Button, Card, Flex, Table, Title, Text, Tooltip } from '@mantine/core';
 import { Action, Service, State } from './types';
-import React, { Dispatch } from 'react';
-import { getServiceUrl, isGitHubUser } from './util';
-import { IconExternalLink, IconHandStop } from '@tabler/icons-react';
+import React, { Dispatch, useState } from 'react';
+import { formatFileSize, isGitHubUser } from './util';
+import { IconArrowDown, IconExternalLink, IconHandStop } from '@tabler/icons-react';
 import DateTime from './DateTime';
 import CommitHash from './CommitHash';
 import { PullRequest } from './PullRequest';
@@ -30,10 +30,16 @@ function renderState(service: Service) {
 }
 
 export function ServiceTable({ state, dispatch }: PullTableProps) {
+  const [sortByMemory, setSortByMemory] = useState(false);
+
   if (state.services.length === 0) {
     return null;
   }
 
+  const services = sortByMemory
+    ? [...state.services].sort((a, b) => (b.memory ?? 0) - (a.memory ?? 0))
+    : state.services;
+
   return (
     <Card withBorder mt="lg">
       <Flex gap="md">
@@ -50,16 +56,32 @@ export function ServiceTable({ state, dispatch }: PullTableProps) {
             <Table.Th>Commit</Table.Th>
             <Table.Th>Created</Table.Th>
             <Table.Th>State</Table.Th>
+            <Table.Th>
+              <Button
+                size="compact-sm"
+                variant="subtle"
+                color="dark"
+                rightSection={sortByMemory && <IconArrowDown size={14} />}
+                onClick={() => setSortByMemory(!sortByMemory)}
+              >
+                Memory
+              </Button>
+            </Table.Th>
             <Table.Th></Table.Th>
           </Table.Tr>
         </Table.Thead>
         <Table.Tbody>
-          {state.services.map((service: Service) => (
+          {services.map((service: Service) => (
             <Table.Tr key={service.name}>
               <Table.Td>
-                <Anchor href={getServiceUrl(service.name)} target="_blank">
-                  {getServiceUrl(service.name)}
+                <Anchor href={service.url} target="_blank">
+                  {service.url}
                 </Anchor>
+                {service.aliasUrls.map((url) => (
+                  <Anchor href={url} target="_blank" key={url} display="block" size="xs">
+                    {url}
+                  </Anchor>
+                ))}
               </Table.Td>
               <Table.Td>
                 {isGitHubUser(service.creator) ? (
@@ -72,9 +94,13 @@ export function ServiceTable({ state, dispatch }: PullTableProps) {
               </Table.Td>
               <Table.Td>
                 <Flex gap="sm">
-                  <CommitHash baseUrl={state.baseUrl} commitHash={service.executable.hash} />
+                  {service.executable ? (
+                    <CommitHash baseUrl={state.baseUrl} commitHash={service.executable.hash} />
+                  ) : (
+                    <Text size="xs" c="dimmed">{service.upstream.host}</Text>
+                  )}
                   {state.github.pulls
-                    .filter((pull) => pull.commit.hash === service.executable.triggerHash)
+                    .filter((pull) => pull.commit.hash === service.executable?.triggerHash)
                     .map((pull) => (
                       <PullRequest
                         baseUrl={state.baseUrl}
@@ -88,6 +114,13 @@ export function ServiceTable({ state, dispatch }: PullTableProps) {
                 <DateTime date={service.createdAt} />
               </Table.Td>
               <Table.Td>{renderState(service)}</Table.Td>
+              <Table.Td>
+                {service.memory != null && (
+                  <Tooltip label={`${Math.round(service.cpu ?? 0)}% CPU`}>
+                    <Text size="sm">{formatFileSize(service.memory)}</Text>
+                  </Tooltip>
+                )}
+              </Table.Td>
               <Table.Td>
                 <Flex gap="xs" justify="end" wrap="wrap">
                   {(service.creator === state.user || state.isAdmin) && (
@@ -110,7 +143,7 @@ export function ServiceTable({ state, dispatch }: PullTableProps) {
                       rightSection={<IconExternalLink size={14} />}
                       color="darkblue"
                       variant="light"
-                      href={getServiceUrl(service.name)}
+                      href={service.url}
                       loading={service.state !== 'running'}
                     >
                       Open
@@ -0,0 +1,32 @@
+import React from 'react';
+
+interface SparklineProps {
+  // values from oldest to newest
+  values: number[];
+  // upper bound of the values, the largest value if not set
+  max?: number;
+  width?: number;
+  height?: number;
+}
+
+export function Sparkline({ values, max, width = 120, height = 24 }: SparklineProps) {
+  if (values.length < 2) {
+    return null;
+  }
+
+  const top = max ?? Math.max(...values, 1);
+  const points = values
+    .map((value, index) => {
+      const x = (index / (values.length - 1)) * width;
+      const y = height - (Math.min(value, top) / top) * height;
+
+      return `${x.toFixed(1)},${y.toFixed(1)}`;
+    })
+    .join(' ');
+
+  return (
+    <svg width={width} height={height} viewBox={`0 0 ${width} ${height}`} style={{ display: 'block' }}>
+      <polyline points={points} fill="none" stroke="currentColor" strokeWidth={1.5} />
+    </svg>
+  );
+}
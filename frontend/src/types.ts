@@ -13,6 +13,16 @@ export interface Commit {
 export interface Executable {
   hash: string;
   triggerHash: string;
+  uploadedAt?: string;
+  sizeBytes?: number;
+  // SHA-256, unknown for executables uploaded before it was recorded
+  checksum?: string;
+  // the contents changed since the upload, services can't be started from it
+  corrupt?: boolean;
+  // builds for several platforms are listed separately
+  platform?: string;
+  // never removed by the cleanup or deletes
+  protected?: boolean;
 }
 
 export interface Release {
@@ -46,6 +56,15 @@ export interface GitHubState {
   pulls: Pull[];
 }
 
+export interface GitHubRefresh {
+  succeededAt: string | null;
+  // time and error of the last fetch, if it failed
+  failedAt: string | null;
+  error: string | null;
+}
+
+export type RoutingMode = 'subdomain';
+
 export interface State {
   isAdmin: boolean,
   user: User,
@@ -53,25 +72,190 @@ export interface State {
   words: string[];
   github: GitHubState;
   githubLoading: boolean;
+  githubRefresh: GitHubRefresh | null;
+  // how the public URLs of services are built, as configured on the server
+  previewBaseDomain: string | null;
+  urlScheme: string;
+  routingMode: RoutingMode;
+  // services can only be started from executables built for it
+  hostPlatform: string | null;
+  // last scan of the executables on the server
+  lastExecutableScan: string | null;
   baseUrl: string;
   websocket: WebSocket | null;
   services: Service[];
   executables: Executable[];
   error: string | null;
-  memory: null | {
-    used: number;
-    total: number;
-  };
+  memory: SystemState | null;
+  // recent samples of the monitor, oldest first
+  history: MonitorSample[];
+  // health of the background workers, as of the initial fetch
+  workers: WorkerStatus[];
+  presence: Presence | null;
+  // details of the last requested service
+  serviceDetail: ServiceDetail | null;
+  // only sent to admins
+  bus: BusMetrics | null;
+  version: VersionInfo | null;
+  // incremented when the server configuration changed, to fetch the initial state again
+  configVersion: number;
+  // state was received over the websocket, which takes precedence over the initial fetch
+  synced: boolean;
+}
+
+export interface MonitorSample {
+  time: string;
+  used: number;
+  total: number;
+  cpu: number;
+  services: ServiceCounts;
+}
+
+export type AlertMetric =
+  'memory' | 'disk_free' | 'fds' | 'error_services' | 'github_worker' | 'services_worker' | 'monitor_worker';
+
+export interface WorkerStatus {
+  worker: 'github' | 'services' | 'monitor';
+  lastHeartbeat: string | null;
+  timeoutSeconds: number;
+  healthy: boolean;
+}
+
+export interface VersionInfo {
+  version: string;
+  commit: string;
+  buildTime: string | null;
+  // start of the server process
+  startedAt: string;
+}
+
+export type MemorySource = 'host' | 'cgroup_v2' | 'cgroup_v1';
+
+export interface SystemState {
+  used: number;
+  total: number;
+  // the limit of the container etes runs in, if it is lower than the memory of the host
+  memory_source?: MemorySource;
+  swap_used?: number;
+  swap_total?: number;
+  // utilization of all cores together, as a percentage
+  cpu: number;
+  cores: number;
+  // size of the uploaded binaries, and the free space on their disk
+  bin_size: number;
+  disk_free?: number | null;
+  load_average?: [number, number, number] | null;
+  // seconds since the host booted
+  uptime?: number;
+  services?: ServiceCounts;
+  // open file descriptors of etes and their limit, only on Linux
+  fds?: number | null;
+  fd_limit?: number | null;
+  // processes of the services, including their child processes
+  processes?: number;
+}
+
+export interface ServiceCounts {
+  pending: number;
+  running: number;
+  error: number;
+}
+
+export interface Presence {
+  connected: number;
+  authenticated: number;
+  anonymous: number;
+}
+
+export interface RateLimit {
+  rate: number;
+  burst: number;
+}
+
+export type ErrorCode =
+  | 'not_owner'
+  | 'not_admin'
+  | 'name_taken'
+  | 'invalid_name'
+  | 'invalid_request'
+  | 'executable_not_found'
+  | 'executable_corrupt'
+  | 'executable_in_use'
+  | 'executable_protected'
+  | 'wrong_platform'
+  | 'service_not_found'
+  | 'no_free_port'
+  | 'memory_exhausted'
+  | 'start_failed'
+  | 'service_unreachable'
+  | 'github_fetch_failed'
+  | 'cleanup_failed';
+
+export type Topic = 'github' | 'services' | 'executables' | 'memory' | 'presence';
+
+export interface BusMetrics {
+  capacity: number;
+  receivers: number;
+  sent: number;
+  sendErrors: number;
+  lagged: number;
+  highWaterMark: number;
+  events: Record<string, number>;
+  sinkErrors: Record<string, number>;
+}
+
+export interface Metrics {
+  requests: number;
+  errors: number;
+  bytesIn: number;
+  bytesOut: number;
+  averageLatencyMs?: number | null;
+  latency: number[];
+}
+
+export interface Upstream {
+  scheme: string;
+  host: string;
+  port: number;
 }
 
 export interface Service {
   name: string;
+  // public URL of the service and its aliases
+  url: string;
+  aliasUrls: string[];
   port?: number;
-  executable: Executable,
+  upstream: Upstream;
+  executable?: Executable | null,
   creator: User;
   createdAt: string,
   state: ServiceState;
   error?: string | null;
+  metrics: Metrics;
+  aliases: string[];
+  exitStatus?: string | null;
+  restartedBy?: User | null;
+  startupDurationMs?: number | null;
+  rateLimit?: RateLimit | null;
+  throttled: boolean;
+  throttledRequests: number;
+  // memory in bytes and CPU percentage of the process and its children
+  memory?: number | null;
+  cpu?: number | null;
+}
+
+export interface StateChange {
+  state: ServiceState;
+  error: string | null;
+  at: string;
+}
+
+export interface ServiceDetail extends Service {
+  restarts: number;
+  // state changes, oldest first
+  history: StateChange[];
+  // most recent output of the process, oldest first, only for the owner and admins
+  logs?: string[];
 }
 
 export interface GitHubUser {
@@ -90,25 +274,87 @@ export type Action = {
   executables: Executable[],
   baseUrl: string,
   words: string[],
-  memory: {
-    used: number;
-    total: number;
-  };
+  memory: SystemState;
+  // missing on older servers
+  history?: MonitorSample[],
+  workers?: WorkerStatus[],
+  presence: Presence,
+  // only sent to admins
+  bus?: BusMetrics,
+  // missing on older servers
+  version?: VersionInfo,
   github: GitHubState,
+  // missing on older servers
+  previewBaseDomain?: string,
+  urlScheme?: string,
+  routingMode?: RoutingMode,
+  hostPlatform?: string,
+  githubRefresh?: GitHubRefresh,
+  lastExecutableScan?: string | null,
   services: Service[],
 } | {
   type: 'service_state',
   services: Service[],
+} | {
+  type: 'service_added',
+  service: Service,
+} | {
+  type: 'service_updated',
+  service: Service,
+} | {
+  type: 'service_removed',
+  name: string,
 } | {
   type: 'executables_state',
   executables: Executable[],
+  scannedAt?: string | null,
 } | {
   type: 'github_state',
-  payload: GitHubState
+  payload: GitHubState,
+  refresh?: GitHubRefresh,
 } | {
   type: 'memory_state',
   used: number,
   total: number,
+  memory_source?: MemorySource,
+  swap_used?: number,
+  swap_total?: number,
+  cpu: number,
+  cores: number,
+  bin_size: number,
+  disk_free?: number | null,
+  // 1, 5 and 15 minute load averages, null on Windows
+  load_average?: [number, number, number] | null,
+  uptime?: number,
+  services?: ServiceCounts,
+  fds?: number | null,
+  fd_limit?: number | null,
+  processes?: number,
+} | {
+  type: 'service_resources',
+  // name, memory and CPU usage of every running service
+  usage: [string, number, number][],
+} | {
+  type: 'presence',
+  connected: number,
+  authenticated: number,
+  anonymous: number,
+} | {
+  type: 'bus_metrics',
+  metrics: BusMetrics,
+} | {
+  // only sent to admins
+  type: 'warning',
+  message: string,
+} | {
+  // only sent to admins
+  type: 'alert',
+  severity: 'warning' | 'cleared',
+  metric: AlertMetric,
+  value: number,
+  threshold: number,
+} | {
+  type: 'config_reloaded',
 } | {
   type: 'github_refresh',
   // added to the event on websocket forward
@@ -117,11 +363,78 @@ export type Action = {
   type: 'start_service',
   executable: Executable,
   name: string,
+  request_id?: string,
+  // start even when the host is low on memory, only for admins
+  force?: boolean,
   // added to the event on websocket forward
   user?: User,
 } | {
   type: 'stop_service',
   name: string,
+  request_id?: string,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'restart_service',
+  name: string,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'set_rate_limit',
+  name: string,
+  rate_limit: RateLimit | null,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'subscribe',
+  topics: Topic[],
+} | {
+  type: 'subscribe_logs',
+  services: string[],
+} | {
+  type: 'service_log',
+  name: string,
+  line: string,
+} | {
+  type: 'add_external_service',
+  name: string,
+  upstream: Upstream,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'get_monitor_history',
+} | {
+  type: 'monitor_history',
+  samples: MonitorSample[],
+} | {
+  type: 'get_service',
+  name: string,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'service_detail',
+  service: ServiceDetail,
+} | {
+  type: 'set_alias',
+  name: string,
+  alias: string,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'delete_executable',
+  hash: string,
+  trigger_hash: string,
+  platform?: string,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'protect_executable',
+  hash: string,
+  protected: boolean,
+  // added to the event on websocket forward
+  user?: User,
+} | {
+  type: 'cleanup_executables',
   // added to the event on websocket forward
   user?: User,
 } | {
@@ -130,7 +443,13 @@ export type Action = {
 } | {
   type: 'error',
   message: string,
+  // unknown codes can be added by newer servers
+  code?: ErrorCode | string,
   caller: string,
+  request_id?: string,
+} | {
+  type: 'done',
+  request_id: string,
 } | {
   type: 'clear_error',
 };
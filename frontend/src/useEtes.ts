@@ -1,6 +1,6 @@
 import { useEffect, useReducer } from 'react';
 import { State, Action, Service } from './types';
-import { randomString } from './util';
+import { formatAlert, randomString } from './util';
 
 function reducer(state: State, action: Action) {
   if (action.type === 'stop_service') {
@@ -28,30 +28,117 @@ function reducer(state: State, action: Action) {
     return {
       ...state,
       executables: action.executables,
+      lastExecutableScan: action.scannedAt ?? state.lastExecutableScan,
     };
   } else if (action.type === 'initial_state') {
-    return {
-      ...state,
+    const fetched = {
       isAdmin: action.isAdmin,
       user: action.user,
       title: action.title,
       words: action.words,
-      memory: action.memory,
       baseUrl: action.baseUrl,
+      previewBaseDomain: action.previewBaseDomain ?? null,
+      urlScheme: action.urlScheme ?? 'https',
+      routingMode: action.routingMode ?? 'subdomain',
+      hostPlatform: action.hostPlatform ?? null,
+      workers: action.workers ?? [],
+      version: action.version ?? null,
+    };
+
+    if (state.synced) {
+      return {
+        ...state,
+        ...fetched,
+      };
+    }
+
+    return {
+      ...state,
+      ...fetched,
+      memory: action.memory,
+      history: action.history ?? [],
+      presence: action.presence,
+      bus: action.bus ?? null,
       executables: action.executables,
       githubLoading: false,
       github: action.github,
+      githubRefresh: action.githubRefresh ?? null,
+      lastExecutableScan: action.lastExecutableScan ?? null,
       services: action.services,
     };
+  } else if (action.type === 'service_detail') {
+    return {
+      ...state,
+      serviceDetail: action.service,
+    };
+  } else if (action.type === 'monitor_history') {
+    return {
+      ...state,
+      history: action.samples,
+    };
+  } else if (action.type === 'service_log') {
+    if (state.serviceDetail?.name !== action.name || !state.serviceDetail.logs) {
+      return state;
+    }
+
+    return {
+      ...state,
+      serviceDetail: {
+        ...state.serviceDetail,
+        logs: [...state.serviceDetail.logs, action.line].slice(-100),
+      },
+    };
+  } else if (action.type === 'config_reloaded') {
+    return {
+      ...state,
+      configVersion: state.configVersion + 1,
+    };
+  } else if (action.type === 'bus_metrics') {
+    return {
+      ...state,
+      bus: action.metrics,
+    };
+  } else if (action.type === 'service_added') {
+    return {
+      ...state,
+      services: [
+        action.service,
+        ...state.services.filter((service) => service.name !== action.service.name),
+      ],
+    };
+  } else if (action.type === 'service_updated') {
+    return {
+      ...state,
+      services: state.services.map((service) =>
+        service.name === action.service.name ? action.service : service
+      ),
+    };
+  } else if (action.type === 'service_resources') {
+    const usage = new Map(action.usage.map(([name, memory, cpu]) => [name, { memory, cpu }]));
+
+    return {
+      ...state,
+      services: state.services.map((service) => ({
+        ...service,
+        ...(usage.get(service.name) ?? { memory: null, cpu: null }),
+      })),
+    };
+  } else if (action.type === 'service_removed') {
+    return {
+      ...state,
+      services: state.services.filter((service) => service.name !== action.name),
+    };
   } else if (action.type === 'github_state') {
     return {
       ...state,
       githubLoading: false,
       github: action.payload,
+      githubRefresh: action.refresh ?? state.githubRefresh,
     };
   } else if (action.type === 'service_state') {
     return {
       ...state,
+      synced: true,
       services: action.services,
     };
   } else if (action.type === 'websocket') {
@@ -64,17 +151,63 @@ function reducer(state: State, action: Action) {
       ...state,
       error: action.message,
     };
+  } else if (action.type === 'warning') {
+    return {
+      ...state,
+      error: action.message,
+    };
+  } else if (action.type === 'alert') {
+    const workers = state.workers.map((worker) =>
+      `${worker.worker}_worker` === action.metric
+        ? { ...worker, healthy: action.severity === 'cleared' }
+        : worker
+    );
+
+    if (action.severity === 'cleared') {
+      return {
+        ...state,
+        workers,
+      };
+    }
+
+    return {
+      ...state,
+      workers,
+      error: formatAlert(action.metric, action.value, action.threshold),
+    };
   } else if (action.type === 'clear_error') {
     return {
       ...state,
       error: null,
     };
+  } else if (action.type === 'presence') {
+    return {
+      ...state,
+      presence: {
+        connected: action.connected,
+        authenticated: action.authenticated,
+        anonymous: action.anonymous,
+      },
+    };
   } else if (action.type === 'memory_state') {
     return {
       ...state,
       memory: {
         used: action.used,
         total: action.total,
+        memory_source: action.memory_source,
+        swap_used: action.swap_used,
+        swap_total: action.swap_total,
+        cpu: action.cpu,
+        cores: action.cores,
+        bin_size: action.bin_size,
+        disk_free: action.disk_free,
+        load_average: action.load_average,
+        uptime: action.uptime,
+        services: action.services,
+        fds: action.fds,
+        fd_limit: action.fd_limit,
+        processes: action.processes,
       },
     };
   }
@@ -86,21 +219,56 @@ function reducer(state: State, action: Action) {
 export const caller = window.localStorage.getItem('caller_id') || randomString(24);
 window.localStorage.setItem('caller_id', caller);
 
+// Milliseconds between refreshes of the monitor history
+const HISTORY_REFRESH_INTERVAL = 60 * 1000;
+
+// Sequence number of the last received event, missed events are replayed on reconnect
+let lastSeq: number | null = null;
+
+// Token of the last connection, to keep the same identity and missed events after a reconnect
+let resumeToken: string | null = null;
+
 /**
  * Connect to the websocket server
  * @param localDispatch Local dispatch function
  * @returns void
  */
 function connectWebsocket(localDispatch: (action: Action) => void) {
-  const websocket = new WebSocket(`${window.location.protocol === 'http:' ? 'ws' : 'wss'}://${window.location.host}/etes/api/v1/ws/${caller}`);
+  const params = new URLSearchParams();
+
+  if (lastSeq !== null) {
+    params.set('since', String(lastSeq));
+  }
+
+  if (resumeToken !== null) {
+    params.set('resume', resumeToken);
+  }
+
+  const query = params.size > 0 ? `?${params}` : '';
+  const websocket = new WebSocket(`${window.location.protocol === 'http:' ? 'ws' : 'wss'}://${window.location.host}/etes/api/v1/ws/${caller}${query}`);
 
   websocket.addEventListener("open", () => {
+    // memory updates are opt-in, the server widget shows them
+    websocket.send(JSON.stringify({
+      type: 'subscribe',
+      topics: ['github', 'services', 'executables', 'presence', 'memory'],
+    }));
     localDispatch({ type: 'websocket', websocket });
   });
 
   websocket.addEventListener("message", (event) => {
     try {
       const action = JSON.parse(event.data);
+
+      if (action.type === 'resume_token') {
+        resumeToken = action.token;
+        return;
+      }
+
+      if (typeof action.seq === 'number') {
+        lastSeq = action.seq;
+      }
+
       localDispatch(action);
     } catch (e) {
       console.error('Failed to parse message', e);
@@ -129,6 +297,12 @@ export function useEtes() {
     words: [],
     title: document.title,
     githubLoading: false,
+    githubRefresh: null,
+    lastExecutableScan: null,
+    previewBaseDomain: null,
+    urlScheme: 'https',
+    routingMode: 'subdomain',
+    hostPlatform: null,
     baseUrl: '',
     github: {
       commits: [],
@@ -140,6 +314,14 @@ export function useEtes() {
     websocket: null,
     error: null,
     memory: null,
+    history: [],
+    workers: [],
+    presence: null,
+    serviceDetail: null,
+    bus: null,
+    version: null,
+    configVersion: 0,
+    synced: false,
   });
 
   // Fetch initial (github) state
@@ -155,7 +337,7 @@ export function useEtes() {
     fetchState();
 
     return () => controller.abort();
-  }, []);
+  }, [state.configVersion]);
 
   const reconnect = () => connectWebsocket(localDispatch);
 
@@ -166,6 +348,19 @@ export function useEtes() {
     return () => state.websocket?.close()
   }, []);
 
+  // Refresh the history of the monitor for the sparklines
+  useEffect(() => {
+    const websocket = state.websocket;
+
+    const interval = setInterval(() => {
+      if (websocket?.readyState === WebSocket.OPEN) {
+        websocket.send(JSON.stringify({ type: 'get_monitor_history' }));
+      }
+    }, HISTORY_REFRESH_INTERVAL);
+
+    return () => clearInterval(interval);
+  }, [state.websocket]);
+
   // Dispatch event to the server and the local reducer
   const dispatch = (action: Action) => {
     if (state.websocket?.readyState === WebSocket.OPEN) {
@@ -1,4 +1,4 @@
-import { GitHubUser, WorkflowStatus } from "./types";
+import { AlertMetric, GitHubUser, WorkflowStatus } from "./types";
 
 export function formatFileSize(size: number) {
   if (size < 1024) {
@@ -16,8 +16,37 @@ export function formatFileSize(size: number) {
   return `${(size / 1024 ** 3).toFixed(.1)} GB`;
 }
 
-export function getServiceUrl(name: string) {
-  return `${window.location.protocol}//${name}.${window.location.host}`;
+export function formatDuration(seconds: number) {
+  const days = Math.floor(seconds / 86400);
+  const hours = Math.floor((seconds % 86400) / 3600);
+  const minutes = Math.floor((seconds % 3600) / 60);
+
+  if (days > 0) {
+    return `${days}d ${hours}h`;
+  }
+
+  if (hours > 0) {
+    return `${hours}h ${minutes}m`;
+  }
+
+  return `${minutes}m`;
+}
+
+export function formatAlert(metric: AlertMetric, value: number, threshold: number) {
+  switch (metric) {
+    case 'memory':
+      return `Memory usage is ${value}%, the alert threshold is ${threshold}%`;
+    case 'disk_free':
+      return `Only ${value} MB of disk space is free, the alert threshold is ${threshold} MB`;
+    case 'fds':
+      return `File descriptor usage is ${value}%, the alert threshold is ${threshold}%`;
+    case 'error_services':
+      return `${value} services crashed, the alert threshold is ${threshold}`;
+    case 'github_worker':
+    case 'services_worker':
+    case 'monitor_worker':
+      return `The ${metric.replace('_worker', '')} worker has not responded for ${value} seconds`;
+  }
 }
 
 const characters = 'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789';
@@ -0,0 +1,318 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{error, info, warn};
+
+use crate::{
+    events::ServerEvent,
+    health::{Worker, WorkerStatus},
+    monitor::{MB, SystemState},
+};
+
+// Share of the threshold a metric has to recover by before its alert is cleared,
+// so a metric hovering around the threshold doesn't flap
+const HYSTERESIS: f64 = 0.1;
+
+/// Thresholds of the alerts sent to the admins, alerts without a threshold are disabled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertThresholds {
+    // Percentage of the memory in use
+    pub memory_percent: Option<f64>,
+    // Free space on the filesystem of bin_dir in MB, alerts when there is less
+    pub disk_free_mb: Option<u64>,
+    // Percentage of the file descriptor limit in use
+    pub fd_percent: Option<f64>,
+    // Number of services in the error state
+    pub error_services: Option<usize>,
+}
+
+impl Default for AlertThresholds {
+    fn default() -> Self {
+        Self {
+            memory_percent: None,
+            disk_free_mb: None,
+            fd_percent: Some(80.0),
+            error_services: None,
+        }
+    }
+}
+
+/// Metric of the monitor that crossed its threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    Memory,
+    DiskFree,
+    Fds,
+    ErrorServices,
+    // Seconds since the last heartbeat of a worker
+    GithubWorker,
+    ServicesWorker,
+    MonitorWorker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSeverity {
+    Warning,
+    // The metric recovered
+    Cleared,
+}
+
+impl AlertMetric {
+    // Metrics of the system state, the workers are checked separately
+    const SYSTEM: [AlertMetric; 4] = [
+        AlertMetric::Memory,
+        AlertMetric::DiskFree,
+        AlertMetric::Fds,
+        AlertMetric::ErrorServices,
+    ];
+
+    // Current value and threshold, None when the metric is not measured or has no threshold
+    fn measure(self, system: &SystemState, thresholds: &AlertThresholds) -> Option<(f64, f64)> {
+        match self {
+            AlertMetric::Memory => Some((
+                (system.used * 100).checked_div(system.total)? as f64,
+                thresholds.memory_percent?,
+            )),
+            AlertMetric::DiskFree => Some((
+                (system.disk_free? / MB) as f64,
+                thresholds.disk_free_mb? as f64,
+            )),
+            AlertMetric::Fds => Some((
+                (system.fds? * 100).checked_div(system.fd_limit?)? as f64,
+                thresholds.fd_percent?,
+            )),
+            AlertMetric::ErrorServices => Some((
+                system.services.error as f64,
+                thresholds.error_services? as f64,
+            )),
+            AlertMetric::GithubWorker
+            | AlertMetric::ServicesWorker
+            | AlertMetric::MonitorWorker => None,
+        }
+    }
+
+    // Free disk space alerts when it drops below the threshold, the others when they exceed it
+    fn alerts_below(self) -> bool {
+        self == AlertMetric::DiskFree
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AlertMetric::Memory => "memory usage",
+            AlertMetric::DiskFree => "free disk space",
+            AlertMetric::Fds => "file descriptor usage",
+            AlertMetric::ErrorServices => "number of crashed services",
+            AlertMetric::GithubWorker => "time since the GitHub worker was last seen",
+            AlertMetric::ServicesWorker => "time since the service worker was last seen",
+            AlertMetric::MonitorWorker => "time since the monitor was last seen",
+        }
+    }
+
+    fn format(self, value: f64) -> String {
+        match self {
+            AlertMetric::Memory | AlertMetric::Fds => format!("{value}%"),
+            AlertMetric::DiskFree => format!("{value} MB"),
+            AlertMetric::ErrorServices => format!("{value}"),
+            AlertMetric::GithubWorker
+            | AlertMetric::ServicesWorker
+            | AlertMetric::MonitorWorker => {
+                format!("{value} seconds")
+            }
+        }
+    }
+}
+
+impl From<Worker> for AlertMetric {
+    fn from(worker: Worker) -> Self {
+        match worker {
+            Worker::Github => AlertMetric::GithubWorker,
+            Worker::Services => AlertMetric::ServicesWorker,
+            Worker::Monitor => AlertMetric::MonitorWorker,
+        }
+    }
+}
+
+// Description of an alert for the log and the notification webhooks
+pub fn alert_message(
+    severity: AlertSeverity,
+    metric: AlertMetric,
+    value: f64,
+    threshold: f64,
+) -> String {
+    let name = metric.name();
+    let value = metric.format(value);
+    let threshold = metric.format(threshold);
+
+    match severity {
+        AlertSeverity::Warning => format!("Alert: {name} is {value}, the threshold is {threshold}"),
+        AlertSeverity::Cleared => {
+            format!("Cleared: {name} is {value}, the threshold is {threshold}")
+        }
+    }
+}
+
+/// Metrics of the monitor that are past their thresholds
+#[derive(Default)]
+pub struct Alerts {
+    active: HashSet<AlertMetric>,
+}
+
+impl Alerts {
+    // Alerts for the metrics that crossed their threshold, and for those that recovered,
+    // an alert is only cleared when the metric is back past the threshold with some margin
+    pub fn check(
+        &mut self,
+        system: &SystemState,
+        thresholds: &AlertThresholds,
+    ) -> Vec<ServerEvent> {
+        let mut events = Vec::new();
+
+        for metric in AlertMetric::SYSTEM {
+            let Some((value, threshold)) = metric.measure(system, thresholds) else {
+                // disabled by a reload, or no longer measured
+                self.active.remove(&metric);
+                continue;
+            };
+
+            let (crossed, recovered) = if metric.alerts_below() {
+                (value < threshold, value >= threshold * (1.0 + HYSTERESIS))
+            } else {
+                (value > threshold, value <= threshold * (1.0 - HYSTERESIS))
+            };
+
+            let severity = if crossed && self.active.insert(metric) {
+                AlertSeverity::Warning
+            } else if recovered && self.active.remove(&metric) {
+                AlertSeverity::Cleared
+            } else {
+                continue;
+            };
+
+            let message = alert_message(severity, metric, value, threshold);
+
+            match severity {
+                AlertSeverity::Warning => warn!("{message}"),
+                AlertSeverity::Cleared => info!("{message}"),
+            }
+
+            events.push(ServerEvent::Alert {
+                severity,
+                metric,
+                value,
+                threshold,
+            });
+        }
+
+        events
+    }
+
+    // Alerts for the workers that stopped beating, and for those that are back
+    pub fn check_workers(&mut self, workers: &[WorkerStatus]) -> Vec<ServerEvent> {
+        let now = Utc::now();
+        let mut events = Vec::new();
+
+        for status in workers {
+            let metric = AlertMetric::from(status.worker);
+
+            // a worker that never started is not silent
+            let Some(last_heartbeat) = status.last_heartbeat else {
+                continue;
+            };
+
+            let severity = if !status.healthy && self.active.insert(metric) {
+                AlertSeverity::Warning
+            } else if status.healthy && self.active.remove(&metric) {
+                AlertSeverity::Cleared
+            } else {
+                continue;
+            };
+
+            let value = (now - last_heartbeat).num_seconds() as f64;
+            let threshold = status.timeout_seconds as f64;
+            let message = alert_message(severity, metric, value, threshold);
+
+            match severity {
+                AlertSeverity::Warning => error!("{message}"),
+                AlertSeverity::Cleared => info!("{message}"),
+            }
+
+            events.push(ServerEvent::Alert {
+                severity,
+                metric,
+                value,
+                threshold,
+            });
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AlertMetric, AlertSeverity, AlertThresholds, Alerts};
+    use crate::{
+        events::ServerEvent,
+        monitor::{MB, SystemState},
+    };
+
+    #[test]
+    fn test_alerts() {
+        let mut alerts = Alerts::default();
+        let thresholds = AlertThresholds {
+            memory_percent: Some(90.0),
+            disk_free_mb: Some(100),
+            ..Default::default()
+        };
+
+        let mut system = SystemState {
+            used: 95,
+            total: 100,
+            disk_free: Some(500 * MB),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            alerts.check(&system, &thresholds),
+            vec![ServerEvent::Alert {
+                severity: AlertSeverity::Warning,
+                metric: AlertMetric::Memory,
+                value: 95.0,
+                threshold: 90.0,
+            }]
+        );
+
+        // still above the threshold, and not far enough below it
+        assert!(alerts.check(&system, &thresholds).is_empty());
+        system.used = 85;
+        assert!(alerts.check(&system, &thresholds).is_empty());
+
+        system.used = 80;
+        system.disk_free = Some(50 * MB);
+        let events = alerts.check(&system, &thresholds);
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            ServerEvent::Alert {
+                severity: AlertSeverity::Cleared,
+                metric: AlertMetric::Memory,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[1],
+            ServerEvent::Alert {
+                severity: AlertSeverity::Warning,
+                metric: AlertMetric::DiskFree,
+                ..
+            }
+        ));
+
+        // the file descriptors are not measured
+        assert_eq!(alerts.active.len(), 1);
+    }
+}
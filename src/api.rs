@@ -0,0 +1,286 @@
+use anyhow::anyhow;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{
+    AppState,
+    auth::check_api_key,
+    data::{ServiceDetail, ServiceParams},
+    error::{AppError, ErrorResponse},
+    events::ErrorCode,
+    service::ServiceData,
+    services::ServiceError,
+    user::{GitHubUser, User},
+    util::sha256,
+};
+
+/// Request to start a service over the REST API
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct StartServiceRequest {
+    name: String,
+    // commit hash of the trigger or the build, or the tag of a release
+    commit: String,
+    // start even when the host is low on memory, only for admins
+    #[serde(default)]
+    force: bool,
+}
+
+// Caller of the REST API, a logged in GitHub user or a client with the API key,
+// the services started with the API key belong to every client with the key
+fn api_user(
+    state: &AppState,
+    headers: &HeaderMap,
+    github_user: Option<GitHubUser>,
+) -> Result<User, AppError> {
+    if let Some(user) = github_user {
+        return Ok(User::GitHub(user));
+    }
+
+    let api_key = &state.config().api_key;
+
+    check_api_key(headers, api_key)
+        .map_err(|_| AppError::Unauthorized(anyhow!("Log in or use the API key")))?;
+
+    Ok(User::Anonymous(sha256(api_key)))
+}
+
+// Start a service, responds when the service is up
+#[utoipa::path(
+    post,
+    path = "/etes/api/v1/services",
+    request_body = StartServiceRequest,
+    responses(
+        (status = 201, description = "Service started", body = ServiceData),
+        (status = 400, description = "Invalid name or request", body = ErrorResponse),
+        (status = 401, description = "Not logged in and no valid API key", body = ErrorResponse),
+        (status = 403, description = "Only admins can force a start", body = ErrorResponse),
+        (status = 404, description = "No executable for the commit", body = ErrorResponse),
+        (status = 409, description = "Name already in use", body = ErrorResponse),
+        (status = 503, description = "No free port or not enough memory", body = ErrorResponse),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub async fn start_service_handler(
+    State(state): State<AppState>,
+    github_user: Option<GitHubUser>,
+    headers: HeaderMap,
+    Json(request): Json<StartServiceRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = api_user(&state, &headers, github_user)?;
+
+    info!(
+        "Starting service {} over the API, user {user}",
+        request.name
+    );
+
+    let commit = state
+        .github
+        .release_commit(&request.commit)
+        .unwrap_or(request.commit);
+
+    let service = state
+        .services
+        .try_start_service(&request.name, &commit, &user, request.force, &state)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json::<ServiceData>(service)))
+}
+
+// List the services, "me" as creator selects the services of the caller
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/services",
+    params(ServiceParams),
+    responses(
+        (status = 200, description = "The selected services", body = Vec<ServiceData>),
+        (status = 400, description = "Invalid state or sort order", body = ErrorResponse),
+        (status = 401, description = "Not logged in and no valid API key", body = ErrorResponse),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub async fn list_services_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ServiceParams>,
+    github_user: Option<GitHubUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ServiceData>>, AppError> {
+    let user = api_user(&state, &headers, github_user)?;
+
+    Ok(Json(
+        state
+            .services
+            .get_state(&state.config(), &params.to_query(&user)),
+    ))
+}
+
+// Get a service by its name or an alias, with the details the service list leaves out
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/services/{name}",
+    params(("name" = String, Path, description = "Name or alias of the service")),
+    responses(
+        (status = 200, description = "The service, the logs only for its owner and admins", body = ServiceDetail),
+        (status = 401, description = "Not logged in and no valid API key", body = ErrorResponse),
+        (status = 404, description = "No service with the name", body = ErrorResponse),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub async fn get_service_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    github_user: Option<GitHubUser>,
+    headers: HeaderMap,
+) -> Result<Json<ServiceDetail>, AppError> {
+    let user = api_user(&state, &headers, github_user)?;
+
+    let service = state
+        .services
+        .get_service_detail(&name, &user, &state.config())
+        .ok_or_else(|| {
+            ServiceError::new(
+                ErrorCode::ServiceNotFound,
+                format!("Service {name} does not exist"),
+            )
+        })?;
+
+    Ok(Json(service))
+}
+
+// Stop a service, only its owner or an admin can
+#[utoipa::path(
+    delete,
+    path = "/etes/api/v1/services/{name}",
+    params(("name" = String, Path, description = "Name of the service")),
+    responses(
+        (status = 204, description = "Service stopped"),
+        (status = 401, description = "Not logged in and no valid API key", body = ErrorResponse),
+        (status = 403, description = "Not the owner", body = ErrorResponse),
+        (status = 404, description = "No service with the name", body = ErrorResponse),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub async fn stop_service_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    github_user: Option<GitHubUser>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let user = api_user(&state, &headers, github_user)?;
+
+    info!("Stopping service {name} over the API, user {user}");
+
+    state.services.try_stop_service(&name, &user, &state)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use hyper::Method;
+    use tower::ServiceExt;
+
+    use crate::{app, config::ConfigArgs};
+
+    #[tokio::test]
+    async fn test_service_api() {
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
+        let authorization = format!("Bearer {}", state.config().api_key);
+        let body = r#"{"name": "foobar", "commit": "ffffffffffffffffffffffffffffffffffffffff"}"#;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/etes/api/v1/services")
+                    .header("Content-Type", "application/json")
+                    .body(Body::new(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 401);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/etes/api/v1/services")
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", &authorization)
+                    .body(Body::new(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"]["code"], "executable_not_found");
+        assert_eq!(error["error"]["message"], "Executable not found");
+        assert_eq!(error["error"]["correlation_id"].as_str().unwrap().len(), 24);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/etes/api/v1/services?creator=me&state=running&sort=name")
+                    .header("Authorization", &authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"[]");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/etes/api/v1/services/foobar")
+                    .header("Authorization", &authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"]["code"], "service_not_found");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::DELETE)
+                    .uri("/etes/api/v1/services/foobar")
+                    .header("Authorization", &authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+    }
+}
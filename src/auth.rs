@@ -1,14 +1,15 @@
 /// This module contains the request handlers for the GitHub OAuth flow.
 /// It includes functions for login, logout, and authorization.
-use anyhow::{Context, anyhow};
+use anyhow::{Context, anyhow, bail};
 use axum::{
     extract::{FromRef, Query, State},
-    http::HeaderValue,
+    http::{HeaderMap, HeaderValue},
     response::{IntoResponse, Redirect, Response},
 };
 use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use constant_time_eq::constant_time_eq;
 use cookie::{Key, SameSite};
-use hyper::header::{ACCEPT, USER_AGENT};
+use hyper::header::{ACCEPT, AUTHORIZATION, LOCATION, USER_AGENT};
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet,
     RedirectUrl, Scope, TokenResponse, TokenUrl, basic::BasicClient, reqwest as oauth2_reqwest,
@@ -16,10 +17,15 @@ use oauth2::{
 use serde::Deserialize;
 use std::fmt::Debug;
 
-use crate::{config::Config, error::AppError, user::GitHubUser, util::sha512};
+use crate::{
+    check::CHECK_TIMEOUT, config::Config, error::AppError, user::GitHubUser, util::sha512,
+};
 
-pub static COOKIE_NAME: &str = "SESSION";
+static COOKIE_NAME: &str = "SESSION";
 static CSRF_COOKIE_NAME: &str = "CSRF";
+static VISITOR_COOKIE_NAME: &str = "VISITOR";
+// Cookies with this prefix are only sent to the host that set them, see RFC 6265bis
+static HOST_PREFIX: &str = "__Host-";
 static USER_AGENT_VALUE: &str = "etes";
 
 static GITHUB_AUTH_URL: &str = "https://github.com/login/oauth/authorize";
@@ -35,6 +41,9 @@ pub struct GithubOauthService {
     oauth_client: GithubClient,
     http_client: oauth2_reqwest::Client,
     session_key: Key,
+    cookie_domain: Option<String>,
+    session_max_age: cookie::time::Duration,
+    cookie_prefix: &'static str,
 }
 
 impl FromRef<GithubOauthService> for Key {
@@ -64,6 +73,13 @@ impl GithubOauthService {
             oauth_client,
             http_client,
             session_key,
+            cookie_domain: config.cookie_domain(),
+            session_max_age: cookie::time::Duration::days(config.session_max_age_days as i64),
+            cookie_prefix: if config.cookie_host_prefix {
+                HOST_PREFIX
+            } else {
+                ""
+            },
         })
     }
 
@@ -71,6 +87,110 @@ impl GithubOauthService {
     pub fn session_key(&self) -> Key {
         self.session_key.clone()
     }
+
+    /// Returns the name of the session cookie, including the configured prefix.
+    pub fn session_cookie_name(&self) -> String {
+        format!("{}{COOKIE_NAME}", self.cookie_prefix)
+    }
+
+    pub fn csrf_cookie_name(&self) -> String {
+        format!("{}{CSRF_COOKIE_NAME}", self.cookie_prefix)
+    }
+
+    pub fn visitor_cookie_name(&self) -> String {
+        format!("{}{VISITOR_COOKIE_NAME}", self.cookie_prefix)
+    }
+
+    // Cookie with the id of an anonymous visitor of the services, it lasts as long as a login
+    pub fn visitor_cookie(&self, id: String) -> Cookie<'static> {
+        let mut cookie = self.cookie(self.visitor_cookie_name(), id);
+        cookie.set_max_age(self.session_max_age);
+
+        cookie
+    }
+
+    // Cookie with the attributes shared by the session and CSRF cookies,
+    // a cookie is only removed by the browser when these match
+    fn cookie(&self, name: String, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(name, value);
+        cookie.set_http_only(true);
+        cookie.set_secure(true);
+        cookie.set_same_site(SameSite::Lax);
+        cookie.set_path("/");
+
+        if let Some(domain) = &self.cookie_domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        cookie
+    }
+}
+
+// Check the bearer token of a request from CI or another API client against the API key
+pub fn check_api_key(headers: &HeaderMap, api_key: &str) -> Result<(), AppError> {
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .ok_or_else(|| AppError::Unauthorized(anyhow!("No authorization header found")))?
+        .to_str()
+        .map_err(|_| AppError::Unauthorized(anyhow!("Invalid authorization header value")))?
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| {
+            AppError::Unauthorized(anyhow!("Missing 'Bearer' in authorization header value"))
+        })?;
+
+    // secure string compare
+    if !constant_time_eq(authorization.as_bytes(), api_key.as_bytes()) {
+        return Err(AppError::Unauthorized(anyhow!("Invalid API key")));
+    }
+
+    Ok(())
+}
+
+/// Checks the OAuth app by requesting its authorization page.
+/// GitHub does not know an unknown client ID, and redirects with an error
+/// when the callback URL does not belong to the app.
+pub async fn check_oauth(config: &Config) -> anyhow::Result<String> {
+    let service = GithubOauthService::new(config)?;
+    let (auth_url, _) = service
+        .oauth_client
+        .authorize_url(CsrfToken::new_random)
+        .url();
+
+    let response = service
+        .http_client
+        .get(auth_url.as_str())
+        .header(USER_AGENT, HeaderValue::from_static(USER_AGENT_VALUE))
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await
+        // the URL only repeats the configuration, with a random state
+        .map_err(|e| e.without_url())
+        .context("Failed to reach GitHub")?;
+
+    if response.status() == oauth2_reqwest::StatusCode::NOT_FOUND {
+        bail!(
+            "github_client_id {} is not a GitHub OAuth app",
+            config.github_client_id
+        );
+    }
+
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .unwrap_or_default();
+
+    if location.contains("error=redirect_uri_mismatch") {
+        bail!(
+            "authorize_url {} is not the callback URL of the OAuth app",
+            config.authorize_url
+        );
+    }
+
+    Ok(format!(
+        "OAuth app {} accepts {}",
+        config.github_client_id, config.authorize_url
+    ))
 }
 
 /// Handles the login request.
@@ -106,14 +226,8 @@ pub(super) async fn login(
     let csrf_cookie_value = serde_json::to_string(&csrf_token)?;
 
     // Create a new CSRF token cookie
-    let mut csrf_cookie = Cookie::new(CSRF_COOKIE_NAME, csrf_cookie_value);
-
-    // Set cookie attributes
-    csrf_cookie.set_http_only(true);
-    csrf_cookie.set_secure(true);
-    csrf_cookie.set_same_site(SameSite::Lax);
+    let mut csrf_cookie = service.cookie(service.csrf_cookie_name(), csrf_cookie_value);
     csrf_cookie.set_max_age(cookie::time::Duration::minutes(60));
-    csrf_cookie.set_path("/");
 
     // Add the CSRF token cookie to the cookie jar
     let updated_jar = jar.add(csrf_cookie);
@@ -128,19 +242,20 @@ pub(super) async fn login(
 ///
 /// # Parameters
 ///
+/// - `service`: The `GithubOauthService` instance.
 /// - `jar`: The private cookie jar containing the session cookie.
 ///
 /// # Returns
 ///
 /// Returns a tuple containing the updated cookie jar and a simple logout message.
-pub(super) async fn logout(mut jar: PrivateCookieJar) -> impl IntoResponse {
+pub(super) async fn logout(
+    State(service): State<GithubOauthService>,
+    mut jar: PrivateCookieJar,
+) -> impl IntoResponse {
     // Remove the session cookie from the cookie jar
-    if let Some(mut cookie) = jar.get(COOKIE_NAME) {
-        cookie.set_same_site(SameSite::Lax);
-        cookie.set_http_only(true);
-        cookie.set_secure(true);
-        cookie.set_path("/");
-        jar = jar.remove(cookie);
+    let name = service.session_cookie_name();
+    if jar.get(&name).is_some() {
+        jar = jar.remove(service.cookie(name, String::new()));
     }
 
     // Return the updated cookie jar and a logout message
@@ -188,23 +303,17 @@ pub(super) async fn authorize(
         .context("Invalid token provided")?;
 
     // Get the CSRF token cookie from the cookie jar
-    let mut csrf_cookie = jar
-        .get(CSRF_COOKIE_NAME)
+    let csrf_cookie = jar
+        .get(&service.csrf_cookie_name())
         .context("Missing CSRF cookie")
         .map_err(AppError::Client)?;
 
-    // Set cookie attributes
-    csrf_cookie.set_same_site(SameSite::Lax);
-    csrf_cookie.set_http_only(true);
-    csrf_cookie.set_secure(true);
-    csrf_cookie.set_path("/");
-
     // Deserialize the CSRF token from the cookie value
     let csrf_token: CsrfToken = serde_json::from_str(csrf_cookie.value())?;
 
     // Validate the CSRF token
     if query.state != *csrf_token.secret() {
-        return Err(AppError::Client(anyhow!("Invalid CSRF token")));
+        return Err(AppError::Forbidden(anyhow!("Invalid CSRF token")));
     }
 
     // Fetch user data from the GitHub API
@@ -229,15 +338,13 @@ pub(super) async fn authorize(
     let session_cookie_value = serde_json::to_string(&user)?;
 
     // Create a new session cookie
-    let mut session_cookie = Cookie::new(COOKIE_NAME, session_cookie_value);
-    session_cookie.set_http_only(true);
-    session_cookie.set_secure(true);
-    session_cookie.set_same_site(cookie::SameSite::Lax);
-    session_cookie.set_max_age(cookie::time::Duration::days(30));
-    session_cookie.set_path("/");
+    let mut session_cookie = service.cookie(service.session_cookie_name(), session_cookie_value);
+    session_cookie.set_max_age(service.session_max_age);
 
     // Remove the CSRF token cookie and add the session cookie to the cookie jar
-    let updated_jar = jar.remove(csrf_cookie).add(session_cookie);
+    let updated_jar = jar
+        .remove(service.cookie(service.csrf_cookie_name(), String::new()))
+        .add(session_cookie);
 
     // Return the updated cookie jar and a redirect response to the home page
     Ok((updated_jar, Redirect::to("/")).into_response())
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use utoipa::ToSchema;
+
+// Where the control groups are mounted, v1 has a directory per controller
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Where the memory figures of the monitor come from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MemorySource {
+    #[default]
+    Host,
+    CgroupV2,
+    CgroupV1,
+}
+
+/// Memory limit and usage of the control group etes runs in
+#[derive(Debug, PartialEq, Eq)]
+pub struct CgroupMemory {
+    pub source: MemorySource,
+    pub used: u64,
+    pub limit: u64,
+}
+
+// Memory of the control group of etes, None when it is not limited to less than the host memory,
+// which is the case outside containers
+pub fn memory(host_total: u64) -> Option<CgroupMemory> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let root = Path::new(CGROUP_ROOT);
+
+    let v1 = cgroup_path(&cgroups, "memory")
+        .and_then(|path| candidates(&root.join("memory"), path).find_map(|dir| read_v1(&dir)));
+    let memory =
+        v1.or_else(|| candidates(root, cgroup_path(&cgroups, "")?).find_map(|dir| read_v2(&dir)))?;
+
+    (memory.limit < host_total).then_some(memory)
+}
+
+// Path of the control group of a controller in /proc/self/cgroup, the v2 hierarchy has no controllers
+fn cgroup_path<'a>(cgroups: &'a str, controller: &str) -> Option<&'a str> {
+    cgroups.lines().find_map(|line| {
+        let mut parts = line.splitn(3, ':');
+        let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+
+        if controller.is_empty() {
+            controllers.is_empty().then_some(path)
+        } else {
+            controllers
+                .split(',')
+                .any(|name| name == controller)
+                .then_some(path)
+        }
+    })
+}
+
+// Directories that can hold the files of the control group, a container with its own
+// cgroup namespace has its control group mounted at the root
+fn candidates(mount: &Path, path: &str) -> impl Iterator<Item = PathBuf> {
+    [
+        mount.join(path.trim_start_matches('/')),
+        mount.to_path_buf(),
+    ]
+    .into_iter()
+}
+
+// Read the limit and usage of a cgroup v2 control group
+fn read_v2(dir: &Path) -> Option<CgroupMemory> {
+    let limit = parse_limit(&std::fs::read_to_string(dir.join("memory.max")).ok()?)?;
+    let current = parse_limit(&std::fs::read_to_string(dir.join("memory.current")).ok()?)?;
+    let stat = std::fs::read_to_string(dir.join("memory.stat")).unwrap_or_default();
+
+    Some(CgroupMemory {
+        source: MemorySource::CgroupV2,
+        used: current.saturating_sub(stat_value(&stat, "inactive_file").unwrap_or(0)),
+        limit,
+    })
+}
+
+// Read the limit and usage of a cgroup v1 memory control group
+fn read_v1(dir: &Path) -> Option<CgroupMemory> {
+    let limit = parse_limit(&std::fs::read_to_string(dir.join("memory.limit_in_bytes")).ok()?)?;
+    let usage = parse_limit(&std::fs::read_to_string(dir.join("memory.usage_in_bytes")).ok()?)?;
+    let stat = std::fs::read_to_string(dir.join("memory.stat")).unwrap_or_default();
+
+    Some(CgroupMemory {
+        source: MemorySource::CgroupV1,
+        used: usage.saturating_sub(stat_value(&stat, "total_inactive_file").unwrap_or(0)),
+        limit,
+    })
+}
+
+// A number of bytes, "max" means there is no limit
+fn parse_limit(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+// Value of a key in memory.stat, the page cache that can be reclaimed doesn't count as used
+fn stat_value(stat: &str, key: &str) -> Option<u64> {
+    stat.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+
+        (name == key).then(|| value.trim().parse().ok()).flatten()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CgroupMemory, MemorySource, cgroup_path, read_v1, read_v2};
+
+    #[test]
+    fn test_cgroup_memory() {
+        let v1 = "5:cpuacct,cpu:/\n4:memory:/docker/abc\n0::/\n";
+        assert_eq!(cgroup_path(v1, "memory"), Some("/docker/abc"));
+        assert_eq!(cgroup_path(v1, ""), Some("/"));
+        assert_eq!(cgroup_path("0::/system.slice/etes.service", "memory"), None);
+
+        let dir = std::env::temp_dir().join(format!("etes-cgroup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("memory.max"), "4294967296\n").unwrap();
+        std::fs::write(dir.join("memory.current"), "1073741824\n").unwrap();
+        std::fs::write(
+            dir.join("memory.stat"),
+            "anon 536870912\nfile 536870912\ninactive_file 268435456\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_v2(&dir),
+            Some(CgroupMemory {
+                source: MemorySource::CgroupV2,
+                used: 805306368,
+                limit: 4294967296,
+            })
+        );
+
+        // not limited
+        std::fs::write(dir.join("memory.max"), "max\n").unwrap();
+        assert_eq!(read_v2(&dir), None);
+
+        std::fs::write(dir.join("memory.limit_in_bytes"), "9223372036854771712\n").unwrap();
+        std::fs::write(dir.join("memory.usage_in_bytes"), "5223305216\n").unwrap();
+        std::fs::write(
+            dir.join("memory.stat"),
+            "cache 1024\ntotal_inactive_file 1024\n",
+        )
+        .unwrap();
+
+        let v1 = read_v1(&dir).unwrap();
+        assert_eq!(v1.source, MemorySource::CgroupV1);
+        assert_eq!(v1.used, 5223304192);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result, bail};
+use std::{path::Path, time::Duration};
+use tracing::{info, warn};
+
+use crate::{
+    auth,
+    config::{Config, ConfigArgs},
+    executable, github, listener,
+};
+
+// Time to wait for GitHub during a check
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of one of the deployment checks, with a short description when it passed
+pub struct Check {
+    name: &'static str,
+    result: Result<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String>) -> Self {
+        Self { name, result }
+    }
+}
+
+// Check what would otherwise only fail at the first fetch, upload or login,
+// binding the listeners is skipped when etes itself is about to bind them
+async fn run_checks(config: &Config, bind_listeners: bool) -> Vec<Check> {
+    let (bin_dir, github, oauth) = tokio::join!(
+        check_bin_dir(&config.bin_dir),
+        github::check_access(config),
+        auth::check_oauth(config)
+    );
+
+    let mut checks = vec![
+        Check::new("bin_dir", bin_dir),
+        Check::new("github", github),
+        Check::new("oauth", oauth),
+    ];
+
+    if bind_listeners {
+        checks.push(Check::new("listeners", check_listeners(config)));
+    }
+
+    checks
+}
+
+// Check that uploads can be written to the bin_dir, without creating it like etes does at startup
+async fn check_bin_dir(bin_dir: &Path) -> Result<String> {
+    if bin_dir.is_dir() {
+        executable::check_bin_dir(bin_dir).await?;
+
+        return Ok(format!("{} is writable", bin_dir.display()));
+    }
+
+    let parent = bin_dir
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    executable::check_bin_dir(parent)
+        .await
+        .with_context(|| format!("{} does not exist and can't be created", bin_dir.display()))?;
+
+    Ok(format!(
+        "{} does not exist yet and can be created",
+        bin_dir.display()
+    ))
+}
+
+// Bind the addresses of both servers at the same time, so they can't overlap either
+fn check_listeners(config: &Config) -> Result<String> {
+    let server_addrs = config.server_addrs()?;
+    let proxy_addrs = config.proxy_addrs()?;
+
+    let _server = listener::try_bind(&server_addrs)?;
+    let _proxy = listener::try_bind(&proxy_addrs)?;
+
+    let addrs: Vec<String> = server_addrs
+        .iter()
+        .chain(&proxy_addrs)
+        .map(ToString::to_string)
+        .collect();
+
+    Ok(format!("can listen on {}", addrs.join(", ")))
+}
+
+// Run all checks, print the results as a table, and fail when any check failed
+pub async fn check(config_args: &ConfigArgs) -> Result<()> {
+    let checks = match Config::from_env(config_args) {
+        Ok(config) => {
+            let mut checks = vec![Check::new("configuration", Ok("valid".to_string()))];
+            checks.extend(run_checks(&config, true).await);
+
+            // mismatches are only reported, the running etes marks the executables as corrupt
+            let executables = executable::verify_executables(&config.bin_dir, false).await;
+            checks.push(Check::new("executables", executables));
+
+            checks
+        }
+        Err(e) => vec![Check::new("configuration", Err(e))],
+    };
+
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut failed = 0;
+
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("PASS  {:width$}  {detail}", check.name),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {:width$}  {e:#}", check.name);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+
+    Ok(())
+}
+
+// Run the checks while etes starts, failures are only logged
+pub async fn log_checks(config: &Config) {
+    for check in run_checks(config, false).await {
+        log_check(check);
+    }
+
+    // hashing every executable takes a while, so it is done after the other checks are logged
+    let executables = executable::verify_executables(&config.bin_dir, true).await;
+    log_check(Check::new("executables", executables));
+}
+
+fn log_check(check: Check) {
+    match check.result {
+        Ok(detail) => info!("Startup check {} passed: {detail}", check.name),
+        Err(e) => warn!("Startup check {} failed: {e:#}", check.name),
+    }
+}
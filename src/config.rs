@@ -1,9 +1,222 @@
-use std::{collections::HashMap, env};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use config::{ConfigBuilder, ConfigError, File, Map, Source, Value, builder::DefaultState};
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{debug, warn};
+use url::Url;
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    alerts::AlertThresholds,
+    github::CommitHash,
+    notify::NotifyKind,
+    sinks::EventWebhook,
+    util::{default_words, is_valid_hash, name_combinations, render_template},
+};
+
+// Warn when there are fewer possible random names than this many times max_services
+const NAME_SPACE_FACTOR: u64 = 100;
+
+// Bounds of the event bus capacity, every receiver can buffer this many events
+const MIN_CHANNEL_CAPACITY: usize = 16;
+const MAX_CHANNEL_CAPACITY: usize = 65536;
+
+// Minimum length of the session key and api key
+const MIN_SECRET_LENGTH: usize = 16;
+
+// Shortest interval between measurements of the system usage, in seconds
+const MIN_MONITOR_INTERVAL: f64 = 1.0;
+
+// Replaces secrets when printing the configuration
+const REDACTED: &str = "<redacted>";
+
+// Settings that can also be read from a file, using the setting name with a `_file` suffix
+const SECRETS: [&str; 4] = [
+    "github_token",
+    "github_client_secret",
+    "session_key",
+    "api_key",
+];
+
+/// How the proxy finds the service of a request, sent to the dashboard to build links
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingMode {
+    // by the subdomain of the base domain, the only mode of the proxy
+    Subdomain,
+}
+
+// How the Host header is passed to upstream services
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum HostHeader {
+    // Forward the original public host
+    Preserve,
+    // Replace the host with the local service address
+    Rewrite,
+    // Replace the host with a fixed value
+    Override(String),
+}
+
+impl From<String> for HostHeader {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "preserve" => HostHeader::Preserve,
+            "rewrite" => HostHeader::Rewrite,
+            _ => HostHeader::Override(value),
+        }
+    }
+}
+
+impl From<HostHeader> for String {
+    fn from(value: HostHeader) -> Self {
+        match value {
+            HostHeader::Preserve => "preserve".to_string(),
+            HostHeader::Rewrite => "rewrite".to_string(),
+            HostHeader::Override(host) => host,
+        }
+    }
+}
+
+/// Command line flags, these take precedence over the configuration file and environment
+#[derive(Debug, Clone, Default, Args, Serialize)]
+pub struct ConfigArgs {
+    /// Configuration file [default: config.toml]
+    #[arg(long = "config", env = "ETES_CONFIG_FILE", global = true)]
+    #[serde(skip)]
+    pub config_file: Option<String>,
+    /// Page title and header
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Base domain services are hosted under
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_domain: Option<String>,
+    /// Socket address for the main HTTP server, repeat to listen on several addresses
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listen_addr: Vec<String>,
+    /// Socket address for the proxy server, repeat to listen on several addresses
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub proxy_listen_addr: Vec<String>,
+    /// Port for the main HTTP server
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_port: Option<u16>,
+    /// Port for the proxy server
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_port: Option<u16>,
+    /// Maximum number of concurrent services
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_services: Option<u64>,
+    /// Directory uploaded binaries are stored in
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bin_dir: Option<String>,
+    /// Start a service when a commit subdomain without a service is visited
+    #[arg(long, global = true, value_name = "BOOL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_autostart: Option<bool>,
+    /// Log every request forwarded by the proxy
+    #[arg(long, global = true, value_name = "BOOL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_access_log: Option<bool>,
+}
+
+/// Icon of the dashboard
+#[derive(Debug, Clone, PartialEq)]
+pub enum Favicon {
+    // Emoji or letter, drawn in an SVG
+    Text(String),
+    // PNG, SVG or ICO image served by etes
+    File(PathBuf),
+    Url(Url),
+}
+
+impl Favicon {
+    fn parse(value: &str) -> Self {
+        if let Ok(url) = Url::parse(value)
+            && matches!(url.scheme(), "http" | "https")
+        {
+            return Favicon::Url(url);
+        }
+
+        // an emoji or letter never looks like a path
+        if value.contains(['/', '.']) {
+            return Favicon::File(PathBuf::from(value));
+        }
+
+        Favicon::Text(value.to_string())
+    }
+
+    pub fn content_type(path: &Path) -> Option<&'static str> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "png" => Some("image/png"),
+            "svg" => Some("image/svg+xml"),
+            "ico" => Some("image/x-icon"),
+            _ => None,
+        }
+    }
+
+    // Link element for the HTML template
+    pub fn link(&self) -> String {
+        match self {
+            Favicon::Text(_) => r#"<link rel="icon" href="/favicon.svg" />"#.to_string(),
+            Favicon::File(path) => format!(
+                r#"<link rel="icon" href="/etes/favicon.ico" type="{}" />"#,
+                Favicon::content_type(path).unwrap_or("image/x-icon")
+            ),
+            Favicon::Url(url) => format!(r#"<link rel="icon" href="{url}" />"#),
+        }
+    }
+}
+
+/// How to run the binary of a service
+#[derive(Debug, PartialEq)]
+pub struct ServiceCommand {
+    pub workdir: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+}
+
+// Address a listener binds to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    // Unix domain socket, configured as unix:/path/to.sock
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Request rate limit for a service, a token bucket refilled at `rate` per second
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     // Page title and header
     pub title: String,
@@ -18,18 +231,72 @@ pub struct Config {
     pub github_client_secret: String,
     // OAuth callback URL
     pub authorize_url: String,
+    // Base domain services are hosted under, e.g. example.com
+    pub base_domain: String,
+    // Scheme of the public URLs, http only for local setups without TLS
+    pub url_scheme: String,
     // Session key for cookies
     pub session_key: String,
+    // Domain of the session cookie, e.g. example.com to send it to the services on subdomains too,
+    // only the host of etes receives it when not set
+    pub cookie_domain: Option<String>,
+    // Days a login lasts
+    pub session_max_age_days: u64,
+    // Prefix the cookie names with __Host-, so they can't be set or overwritten by subdomains
+    pub cookie_host_prefix: bool,
     // API key for binary uploads
     pub api_key: String,
-    // Arguments passed to the binary, use {port} to interpolate the port number
+    // Arguments passed to the binary, use {port}, {name}, {hash}, {trigger_hash}, {url} and {workdir}
+    // to interpolate values of the service
     pub command_args: Vec<String>,
-    // Environment variables passed to the binary
+    // Environment variables passed to the binary, with the same placeholders as command_args
     pub command_env: HashMap<String, String>,
-    // Emoji favicon or letter
+    // Working directory of a service, created when it starts, e.g. ./work/{name}
+    pub command_workdir: Option<String>,
+    // Directory uploaded binaries are stored in
+    pub bin_dir: PathBuf,
+    // Check the GitHub token and OAuth app at startup like `etes check`, failures are logged
+    pub startup_checks: bool,
+    // Seconds between measurements of the memory, CPU and disk usage, at least 1
+    pub monitor_interval_seconds: f64,
+    // Free memory below which new services are refused, in MB, unlimited if not set
+    pub min_free_memory_mb: Option<u64>,
+    // Used swap above which new services are refused as well, in MB, unlimited if not set
+    pub max_swap_used_mb: Option<u64>,
+    // Free space on the filesystem of bin_dir below which a warning is logged, in MB
+    pub min_free_disk_mb: u64,
+    // Thresholds of the alerts of the monitor, sent to the admins and the notification webhooks
+    #[serde(default)]
+    pub alerts: AlertThresholds,
+    // Only report ready on /etes/readyz when the last GitHub fetch succeeded
+    pub ready_requires_github: bool,
+    // Days to keep executables that are not of a release, None ("forever") keeps them forever
+    #[serde(default, deserialize_with = "days_or_forever")]
+    pub retention_days: Option<u64>,
+    // Days to keep executables of a release, None ("forever") keeps them forever
+    #[serde(default, deserialize_with = "days_or_forever")]
+    pub release_retention_days: Option<u64>,
+    // Size budget of the bin_dir in bytes, the oldest unused executables are removed above it
+    pub bin_dir_max_bytes: Option<u64>,
+    // Newest builds to keep of a trigger hash, older ones are removed unless a service uses them
+    pub keep_builds_per_trigger: Option<usize>,
+    // Commit hashes of builds that are never removed, of a build or of its trigger
+    pub protected_hashes: Vec<CommitHash>,
+    // Hours between runs of the executable cleanup
+    pub cleanup_interval_hours: u64,
+    // Reject uploads without the SHA-256 of the executable in the X-Etes-Sha256 header
+    pub require_upload_checksum: bool,
+    // Emoji favicon or letter, or the path or URL of an image
     pub favicon: String,
-    // List of words to combine into a unique service name
+    // List of words to combine into a unique service name, the built-in list is used when empty
     pub words: Vec<String>,
+    // Add the configured words to the built-in list instead of replacing it
+    pub extend_default_words: bool,
+    // Most recent commits and pull requests sent to the dashboard
+    pub github_max_commits: usize,
+    pub github_max_pulls: usize,
+    // Characters of the commit messages sent to the dashboard, longer messages are truncated
+    pub github_message_length: usize,
     // Github user handles of admins
     pub admins: Vec<String>,
     // Maximum number of concurrent services
@@ -38,25 +305,1118 @@ pub struct Config {
     pub server_port: u16,
     // Port for the proxy server
     pub proxy_port: u16,
+    // Socket addresses for the main HTTP server, e.g. 0.0.0.0:80, [::]:80 or unix:/run/etes.sock,
+    // a single address or a list, addresses without a port use server_port
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub listen_addr: Vec<String>,
+    // Socket addresses for the proxy server, addresses without a port use proxy_port
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub proxy_listen_addr: Vec<String>,
+    // Octal permissions of unix domain sockets the listeners bind to
+    pub unix_socket_mode: String,
+    // PEM certificate chain and private key, both listeners use TLS when set
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    // PEM certificate chain and private key for the proxy only, e.g. a wildcard certificate
+    pub proxy_tls_cert_path: Option<PathBuf>,
+    pub proxy_tls_key_path: Option<PathBuf>,
+    // Log every request forwarded by the proxy
+    pub proxy_access_log: bool,
+    // IP addresses of reverse proxies whose X-Forwarded-For header is used for the client IP
+    pub trusted_proxies: Vec<String>,
+    // Start a service when a commit subdomain without a service is visited
+    pub proxy_autostart: bool,
+    // Host header sent to services: preserve, rewrite or a literal host
+    pub proxy_host_header: HostHeader,
+    // Seconds to hold proxied requests while a service is starting
+    pub startup_grace_period: u64,
+    // Pass the logged in GitHub user to services in X-Etes-User-* headers
+    pub inject_user_headers: bool,
+    // Pass the hashed anonymous id to services for visitors that are not logged in
+    pub inject_anonymous_user_headers: bool,
+    // Maximum size of request bodies forwarded by the proxy
+    pub proxy_max_body_bytes: u64,
+    // Maximum size of response bodies returned by services, unlimited if not set
+    pub proxy_max_response_bytes: Option<u64>,
+    // Requests per second allowed per service, unlimited if not set
+    pub proxy_rate_limit: Option<f64>,
+    // Number of requests allowed in a burst above the rate limit
+    pub proxy_rate_burst: u32,
+    // Seconds between pings sent to websocket clients
+    pub ws_ping_interval: u64,
+    // Seconds to wait for a pong before closing a websocket connection
+    pub ws_pong_timeout: u64,
+    // Maximum size of messages sent by websocket clients
+    pub ws_max_message_bytes: usize,
+    // Origins besides the base domain allowed to open websockets, like development servers
+    pub ws_allowed_origins: Vec<String>,
+    // Allow websockets without an Origin header, as opened by non-browser clients
+    pub ws_allow_missing_origin: bool,
+    // Origins of other sites allowed to call the API and open websockets, with the session cookie
+    pub cors_allowed_origins: Vec<String>,
+    // Seconds a resume token stays valid, tokens are refreshed with every ping
+    pub ws_resume_token_ttl: u64,
+    // Number of events a websocket or worker can fall behind before it misses events
+    pub event_channel_capacity: usize,
+    // Broadcast the full service list after every change, besides the granular service events
+    pub service_state_snapshots: bool,
+    // Append every event to this file as JSON lines
+    pub event_log_file: Option<String>,
+    // Post every event as JSON to these webhooks
+    pub event_webhooks: Vec<EventWebhook>,
+    // Webhooks, like Slack incoming webhooks, notified of important events
+    pub notify_webhooks: Vec<EventWebhook>,
+    // Kinds of events sent to the notification webhooks
+    pub notify_events: Vec<NotifyKind>,
+    // Seconds during which identical notifications are sent only once
+    pub notify_repeat_interval: u64,
 }
 
 impl Config {
-    pub fn from_env() -> Result<&'static Config> {
-        let config_file = env::var("ETES_CONFIG_FILE").unwrap_or("config.toml".to_string());
+    pub fn from_env(args: &ConfigArgs) -> Result<Config> {
+        let config_file = args.config_file.as_deref().unwrap_or("config.toml");
+        let override_file = env::var("ETES_CONFIG_OVERRIDE_FILE").ok();
+        let profile = env::var("ETES_PROFILE").ok();
+
+        let mut layers = file_layers(config_file, override_file.as_deref(), profile.as_deref())?;
+        layers.push(Layer::collect(
+            "environment",
+            config::Environment::with_prefix("etes")
+                .try_parsing(true)
+                .list_separator(" "),
+        )?);
+        layers.push(Layer::collect(
+            "command line",
+            config::Config::try_from(args)?,
+        )?);
+
+        log_layers(&layers);
+
+        let sources = layers
+            .into_iter()
+            .fold(Self::defaults()?, |builder, layer| {
+                builder.add_source(layer)
+            })
+            .build()?;
+
+        let mut config: Config = read_secret_files(sources)?.try_deserialize()?;
+
+        config.apply_default_words();
+        config.validate()?;
+
+        Ok(config)
+    }
 
-        let config: Config = config::Config::builder()
+    fn defaults() -> Result<ConfigBuilder<DefaultState>> {
+        Ok(config::Config::builder()
             .set_default("max_services", 1000)?
+            .set_default("bin_dir", "./bin")?
+            .set_default("startup_checks", false)?
+            .set_default("monitor_interval_seconds", 10)?
+            .set_default("ready_requires_github", false)?
+            .set_default("min_free_disk_mb", 1024)?
+            .set_default("words", Vec::<String>::new())?
+            .set_default("extend_default_words", false)?
+            .set_default("github_max_commits", 30)?
+            .set_default("github_max_pulls", 50)?
+            .set_default("github_message_length", 100)?
+            .set_default("retention_days", 30)?
+            .set_default("cleanup_interval_hours", 24)?
+            .set_default("protected_hashes", Vec::<String>::new())?
+            .set_default("require_upload_checksum", false)?
+            .set_default("session_max_age_days", 30)?
+            .set_default("cookie_host_prefix", false)?
             .set_default("server_port", 3000)?
             .set_default("proxy_port", 3001)?
-            .add_source(config::File::with_name(&config_file))
-            .add_source(
-                config::Environment::with_prefix("etes")
-                    .try_parsing(true)
-                    .list_separator(" "),
+            .set_default("unix_socket_mode", "660")?
+            .set_default("proxy_access_log", true)?
+            .set_default("trusted_proxies", vec!["127.0.0.1", "::1"])?
+            .set_default("proxy_autostart", true)?
+            .set_default("proxy_host_header", "preserve")?
+            .set_default("url_scheme", "https")?
+            .set_default("startup_grace_period", 15)?
+            .set_default("inject_user_headers", false)?
+            .set_default("inject_anonymous_user_headers", false)?
+            .set_default("proxy_max_body_bytes", 64 * 1024 * 1024)?
+            .set_default("proxy_rate_burst", 20)?
+            .set_default("ws_ping_interval", 20)?
+            .set_default("ws_pong_timeout", 10)?
+            .set_default("ws_max_message_bytes", 8 * 1024)?
+            .set_default("ws_allowed_origins", Vec::<String>::new())?
+            .set_default("ws_allow_missing_origin", false)?
+            .set_default("cors_allowed_origins", Vec::<String>::new())?
+            .set_default("ws_resume_token_ttl", 300)?
+            .set_default("event_channel_capacity", 512)?
+            .set_default("service_state_snapshots", true)?
+            .set_default("event_webhooks", Vec::<String>::new())?
+            .set_default("notify_webhooks", Vec::<String>::new())?
+            .set_default(
+                "notify_events",
+                vec![
+                    "service_started",
+                    "service_crashed",
+                    "service_stopped",
+                    "upload_completed",
+                    "github_refresh_failed",
+                    "alert",
+                ],
+            )?
+            .set_default("notify_repeat_interval", 600)?)
+    }
+
+    // Use the built-in words when none are configured, or when they should be extended
+    fn apply_default_words(&mut self) {
+        if !self.words.is_empty() && !self.extend_default_words {
+            return;
+        }
+
+        let mut words = default_words();
+        for word in self.words.drain(..) {
+            if !words.contains(&word) {
+                words.push(word);
+            }
+        }
+
+        self.words = words;
+    }
+
+    // Reject values etes can't run with, all problems are reported at once
+    fn validate(&self) -> Result<()> {
+        let combinations = name_combinations(&self.words);
+        if combinations < self.max_services as u64 * NAME_SPACE_FACTOR {
+            warn!(
+                "words only combine into {combinations} names for up to {} services, random names will collide often",
+                self.max_services
+            );
+        }
+
+        if !self.command_args.iter().any(|arg| arg.contains("{port}")) {
+            warn!(
+                "command_args does not contain {{port}}, services will not know which port to listen on"
+            );
+        }
+
+        if !self.event_channel_capacity.is_power_of_two() {
+            warn!(
+                "event_channel_capacity {} is rounded up to {}",
+                self.event_channel_capacity,
+                self.event_channel_capacity.next_power_of_two()
+            );
+        }
+
+        for origin in self.cross_site_cors_origins() {
+            warn!(
+                "cors_allowed_origins contains {origin}, which is not on the site of the session cookie, its requests are anonymous"
+            );
+        }
+
+        if self.monitor_interval_seconds < MIN_MONITOR_INTERVAL {
+            warn!(
+                "monitor_interval_seconds {} is raised to {MIN_MONITOR_INTERVAL}",
+                self.monitor_interval_seconds
+            );
+        }
+
+        let problems = self.problems();
+
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n- {}", problems.join("\n- "));
+        }
+
+        Ok(())
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // services are routed by their subdomain of the base domain
+        if self.base_domain.is_empty() {
+            problems.push("base_domain must not be empty".to_string());
+        } else if self.base_domain.contains(['/', '@', ' ']) {
+            problems.push(format!(
+                "base_domain {:?} must be a domain name like example.com",
+                self.base_domain
+            ));
+        }
+
+        if !matches!(self.url_scheme.as_str(), "http" | "https") {
+            problems.push(format!(
+                "url_scheme must be http or https, found {:?}",
+                self.url_scheme
+            ));
+        }
+
+        for addr in [self.server_addrs(), self.proxy_addrs()] {
+            if let Err(e) = addr {
+                problems.push(e.to_string());
+            }
+        }
+
+        if let Err(e) = self.service_command("name", 1, "hash", "hash") {
+            problems.push(format!("{e:#}"));
+        }
+
+        if let Favicon::File(path) = self.favicon() {
+            if !path.is_file() {
+                problems.push(format!("favicon file {} does not exist", path.display()));
+            } else if Favicon::content_type(&path).is_none() {
+                problems.push(format!(
+                    "favicon file {} must be a PNG, SVG or ICO image",
+                    path.display()
+                ));
+            }
+        }
+
+        if let Err(e) = self.unix_socket_mode() {
+            problems.push(e.to_string());
+        }
+
+        for (name, percent) in [
+            ("alerts.memory_percent", self.alerts.memory_percent),
+            ("alerts.fd_percent", self.alerts.fd_percent),
+        ] {
+            if percent.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+                problems.push(format!("{name} must be between 0 and 100"));
+            }
+        }
+
+        if self.max_services == 0 {
+            problems.push("max_services must be at least 1".to_string());
+        }
+
+        if !(MIN_CHANNEL_CAPACITY..=MAX_CHANNEL_CAPACITY).contains(&self.event_channel_capacity) {
+            problems.push(format!(
+                "event_channel_capacity must be between {MIN_CHANNEL_CAPACITY} and {MAX_CHANNEL_CAPACITY}"
+            ));
+        }
+
+        for proxy in &self.trusted_proxies {
+            if proxy.parse::<IpAddr>().is_err() {
+                problems.push(format!(
+                    "trusted_proxies entry {proxy:?} must be an IP address"
+                ));
+            }
+        }
+
+        for origin in &self.cors_allowed_origins {
+            // credentials are allowed, so every origin has to be listed explicitly
+            if origin == "*" {
+                problems.push(
+                    "cors_allowed_origins must list origins, \"*\" is not allowed".to_string(),
+                );
+            } else if Url::parse(origin)
+                .map(|url| url.origin().ascii_serialization() != *origin)
+                .unwrap_or(true)
+            {
+                problems.push(format!(
+                    "cors_allowed_origins entry {origin:?} must be an origin like https://portal.example.com"
+                ));
+            }
+        }
+
+        if self.cleanup_interval_hours == 0 {
+            problems.push("cleanup_interval_hours must be at least 1".to_string());
+        }
+
+        if self.keep_builds_per_trigger == Some(0) {
+            problems.push("keep_builds_per_trigger must be at least 1".to_string());
+        }
+
+        for hash in &self.protected_hashes {
+            if !is_valid_hash(hash) {
+                problems.push(format!(
+                    "protected_hashes entry {hash:?} must be a full commit hash of 40 hex characters"
+                ));
+            }
+        }
+
+        if self.ws_ping_interval == 0 {
+            problems.push("ws_ping_interval must be at least 1 second".to_string());
+        }
+
+        if self.proxy_rate_limit.is_some_and(|rate| rate <= 0.0) {
+            problems.push("proxy_rate_limit must be positive".to_string());
+        }
+
+        let unique_words: HashSet<&String> = self.words.iter().collect();
+        if unique_words.len() < 3 {
+            problems.push(format!(
+                "words must contain at least 3 unique words to generate names, found {}",
+                unique_words.len()
+            ));
+        }
+
+        match Url::parse(&self.authorize_url) {
+            Ok(url) => {
+                // the browser ignores cookies for a domain the host is not part of
+                if let Some(domain) = self.cookie_domain() {
+                    let host = url.host_str().unwrap_or_default();
+
+                    if host != domain && !host.ends_with(&format!(".{domain}")) {
+                        problems.push(format!(
+                            "cookie_domain {domain:?} must contain the host of authorize_url {host:?}"
+                        ));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!(
+                "authorize_url {:?} is not a valid URL: {e}",
+                self.authorize_url
+            )),
+        }
+
+        if self.cookie_host_prefix && self.cookie_domain().is_some() {
+            problems.push(
+                "cookie_host_prefix can't be combined with cookie_domain, __Host- cookies have no domain"
+                    .to_string(),
+            );
+        }
+
+        if self.session_max_age_days == 0 {
+            problems.push("session_max_age_days must be at least 1".to_string());
+        }
+
+        for (name, value) in [
+            ("session_key", &self.session_key),
+            ("api_key", &self.api_key),
+        ] {
+            if value.len() < MIN_SECRET_LENGTH {
+                problems.push(format!(
+                    "{name} must be at least {MIN_SECRET_LENGTH} characters long"
+                ));
+            }
+        }
+
+        for (cert, key, cert_name, key_name) in [
+            (
+                &self.tls_cert_path,
+                &self.tls_key_path,
+                "tls_cert_path",
+                "tls_key_path",
+            ),
+            (
+                &self.proxy_tls_cert_path,
+                &self.proxy_tls_key_path,
+                "proxy_tls_cert_path",
+                "proxy_tls_key_path",
+            ),
+        ] {
+            if cert.is_some() != key.is_some() {
+                problems.push(format!("{cert_name} and {key_name} must be set together"));
+            }
+        }
+
+        if self.github_token.is_empty() {
+            problems.push("github_token must not be empty".to_string());
+        }
+
+        if self.admins.iter().any(|admin| admin.trim().is_empty()) {
+            problems.push("admins must not contain empty handles".to_string());
+        }
+
+        problems
+    }
+
+    // Addresses for the main HTTP server, localhost on server_port unless listen_addr is set
+    pub fn server_addrs(&self) -> Result<Vec<ListenAddr>> {
+        parse_listen_addrs("listen_addr", &self.listen_addr, self.server_port)
+    }
+
+    // Addresses for the proxy server, localhost on proxy_port unless proxy_listen_addr is set
+    pub fn proxy_addrs(&self) -> Result<Vec<ListenAddr>> {
+        parse_listen_addrs(
+            "proxy_listen_addr",
+            &self.proxy_listen_addr,
+            self.proxy_port,
+        )
+    }
+
+    // Working directory, arguments and environment of a service, with the placeholders replaced
+    pub fn service_command(
+        &self,
+        name: &str,
+        port: u16,
+        hash: &str,
+        trigger_hash: &str,
+    ) -> Result<ServiceCommand> {
+        let port = port.to_string();
+        let url = self.service_url(name);
+        let mut values = vec![
+            ("name", name),
+            ("port", port.as_str()),
+            ("hash", hash),
+            ("trigger_hash", trigger_hash),
+            ("url", url.as_str()),
+        ];
+
+        let workdir = match &self.command_workdir {
+            Some(workdir) => {
+                Some(render_template(workdir, &values).context("Invalid command_workdir")?)
+            }
+            None => None,
+        };
+
+        if let Some(workdir) = &workdir {
+            values.push(("workdir", workdir));
+        }
+
+        let args = self
+            .command_args
+            .iter()
+            .map(|arg| render_template(arg, &values))
+            .collect::<Result<_>>()
+            .context("Invalid command_args")?;
+
+        let env = self
+            .command_env
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), render_template(value, &values)?)))
+            .collect::<Result<_>>()
+            .context("Invalid command_env")?;
+
+        Ok(ServiceCommand {
+            workdir: workdir.map(PathBuf::from),
+            args,
+            env,
+        })
+    }
+
+    // The configuration as TOML, with secrets and webhook URLs redacted
+    pub fn redacted(&self) -> Result<String> {
+        let mut table = toml::Table::try_from(self)?;
+
+        for name in SECRETS {
+            if let Some(value) = table.get_mut(name) {
+                *value = REDACTED.into();
+            }
+        }
+
+        // webhook URLs, like those of Slack, often contain a secret
+        for name in ["event_webhooks", "notify_webhooks"] {
+            let Some(toml::Value::Array(webhooks)) = table.get_mut(name) else {
+                continue;
+            };
+
+            for webhook in webhooks.iter_mut().filter_map(toml::Value::as_table_mut) {
+                if let Some(url) = webhook.get_mut("url") {
+                    let origin = url
+                        .as_str()
+                        .and_then(|url| Url::parse(url).ok())
+                        .map(|url| url.origin().ascii_serialization())
+                        .unwrap_or_default();
+
+                    *url = format!("{origin}/{REDACTED}").into();
+                }
+
+                if let Some(token) = webhook.get_mut("token") {
+                    *token = REDACTED.into();
+                }
+            }
+        }
+
+        Ok(toml::to_string_pretty(&table)?)
+    }
+
+    // Domain of the cookies without a leading dot, browsers ignore it anyway
+    pub fn cookie_domain(&self) -> Option<String> {
+        self.cookie_domain
+            .as_deref()
+            .map(|domain| domain.trim_start_matches('.'))
+            .filter(|domain| !domain.is_empty())
+            .map(str::to_string)
+    }
+
+    // Check if a connection comes from a reverse proxy that may report the client IP
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .filter_map(|proxy| proxy.parse::<IpAddr>().ok())
+            .any(|proxy| proxy.to_canonical() == ip.to_canonical())
+    }
+
+    // Allowed CORS origins the session cookie is not sent from, it is SameSite=Lax
+    pub fn cross_site_cors_origins(&self) -> Vec<&str> {
+        let site = self
+            .cookie_domain()
+            .unwrap_or_else(|| self.base_domain.clone());
+
+        self.cors_allowed_origins
+            .iter()
+            .filter(|origin| {
+                let host = Url::parse(origin)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+
+                !host.is_some_and(|host| host == site || host.ends_with(&format!(".{site}")))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    // Public URL of a service or alias
+    pub fn service_url(&self, name: &str) -> String {
+        format!("{}://{name}.{}", self.url_scheme, self.base_domain)
+    }
+
+    // Public URL of the dashboard, on the base domain
+    pub fn dashboard_url(&self) -> String {
+        format!("{}://{}", self.url_scheme, self.base_domain)
+    }
+
+    pub fn routing_mode(&self) -> RoutingMode {
+        RoutingMode::Subdomain
+    }
+
+    pub fn monitor_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.monitor_interval_seconds.max(MIN_MONITOR_INTERVAL))
+    }
+
+    pub fn favicon(&self) -> Favicon {
+        Favicon::parse(&self.favicon)
+    }
+
+    pub fn unix_socket_mode(&self) -> Result<u32> {
+        u32::from_str_radix(&self.unix_socket_mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .with_context(|| {
+                format!(
+                    "Invalid unix_socket_mode {:?}, expected octal permissions like 660",
+                    self.unix_socket_mode
+                )
+            })
+    }
+
+    // Settings that differ from the new configuration, but are only used at startup
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("server_port", self.server_port != new.server_port),
+            ("proxy_port", self.proxy_port != new.proxy_port),
+            ("listen_addr", self.listen_addr != new.listen_addr),
+            (
+                "unix_socket_mode",
+                self.unix_socket_mode != new.unix_socket_mode,
+            ),
+            (
+                "proxy_listen_addr",
+                self.proxy_listen_addr != new.proxy_listen_addr,
+            ),
+            ("session_key", self.session_key != new.session_key),
+            ("cookie_domain", self.cookie_domain != new.cookie_domain),
+            (
+                "session_max_age_days",
+                self.session_max_age_days != new.session_max_age_days,
+            ),
+            (
+                "cookie_host_prefix",
+                self.cookie_host_prefix != new.cookie_host_prefix,
+            ),
+            (
+                "github_client_id",
+                self.github_client_id != new.github_client_id,
+            ),
+            (
+                "github_client_secret",
+                self.github_client_secret != new.github_client_secret,
+            ),
+            ("authorize_url", self.authorize_url != new.authorize_url),
+            ("favicon", self.favicon != new.favicon),
+            ("bin_dir", self.bin_dir != new.bin_dir),
+            ("startup_checks", self.startup_checks != new.startup_checks),
+            ("tls_cert_path", self.tls_cert_path != new.tls_cert_path),
+            ("tls_key_path", self.tls_key_path != new.tls_key_path),
+            (
+                "proxy_tls_cert_path",
+                self.proxy_tls_cert_path != new.proxy_tls_cert_path,
+            ),
+            (
+                "proxy_tls_key_path",
+                self.proxy_tls_key_path != new.proxy_tls_key_path,
+            ),
+            (
+                "event_channel_capacity",
+                self.event_channel_capacity != new.event_channel_capacity,
+            ),
+            ("event_log_file", self.event_log_file != new.event_log_file),
+            ("event_webhooks", self.event_webhooks != new.event_webhooks),
+            (
+                "cors_allowed_origins",
+                self.cors_allowed_origins != new.cors_allowed_origins,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    // Default rate limit for services
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.proxy_rate_limit.map(|rate| RateLimit {
+            rate,
+            burst: self.proxy_rate_burst,
+        })
+    }
+}
+
+/// Values of one source of the configuration, with a name to tell where a value came from
+#[derive(Debug, Clone)]
+struct Layer {
+    name: String,
+    values: Map<String, Value>,
+}
+
+impl Layer {
+    fn collect(name: impl Into<String>, source: impl Source) -> Result<Self> {
+        Ok(Self {
+            name: name.into(),
+            values: source.collect()?,
+        })
+    }
+}
+
+impl Source for Layer {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        Ok(self.values.clone())
+    }
+}
+
+// The configuration file, an optional override file and an optional profile from
+// the `profiles` table of these files, later layers override earlier ones per key
+fn file_layers(
+    config_file: &str,
+    override_file: Option<&str>,
+    profile: Option<&str>,
+) -> Result<Vec<Layer>> {
+    let mut layers = vec![Layer::collect(config_file, File::with_name(config_file))?];
+
+    if let Some(override_file) = override_file {
+        layers.push(Layer::collect(
+            override_file,
+            File::with_name(override_file),
+        )?);
+    }
+
+    if let Some(profile) = profile {
+        let files = layers
+            .iter()
+            .fold(config::Config::builder(), |builder, layer| {
+                builder.add_source(layer.clone())
+            })
+            .build()?;
+
+        let values = files
+            .get_table(&format!("profiles.{profile}"))
+            .with_context(|| format!("Profile {profile:?} not found in the profiles table"))?;
+
+        layers.push(Layer {
+            name: format!("profile {profile}"),
+            values,
+        });
+    }
+
+    Ok(layers)
+}
+
+// Log which layer each setting came from, settings that aren't listed use their default
+fn log_layers(layers: &[Layer]) {
+    let mut origins = BTreeMap::new();
+
+    for layer in layers {
+        flatten_keys("", &layer.values, &mut |key| {
+            origins.insert(key, layer.name.clone());
+        });
+    }
+
+    for (key, origin) in origins {
+        let secret = SECRETS.iter().any(|secret| key == *secret);
+
+        if !secret && !key.starts_with("profiles.") {
+            debug!("Configuration {key} from {origin}");
+        }
+    }
+}
+
+fn flatten_keys(prefix: &str, values: &Map<String, Value>, found: &mut impl FnMut(String)) {
+    for (key, value) in values {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        match value.clone().into_table() {
+            Ok(table) => flatten_keys(&key, &table, found),
+            Err(_) => found(key),
+        }
+    }
+}
+
+// Replace secrets configured as `<name>_file` with the contents of that file,
+// so secrets can be mounted as files instead of being put in the configuration
+fn read_secret_files(sources: config::Config) -> Result<config::Config> {
+    let mut builder = config::Config::builder().add_source(sources.clone());
+
+    for name in SECRETS {
+        let file_key = format!("{name}_file");
+
+        let Ok(path) = sources.get_string(&file_key) else {
+            continue;
+        };
+
+        if sources.get_string(name).is_ok() {
+            bail!("Both {name} and {file_key} are set, use only one of them");
+        }
+
+        let secret = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {file_key} {path:?}"))?;
+
+        builder = builder.set_override(name, secret.trim_end())?;
+    }
+
+    Ok(builder.build()?)
+}
+
+// Accept a single value or a list, like listen_addr = "[::]:80" or listen_addr = ["[::]:80", "0.0.0.0:80"]
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+// Accept a number of days or "forever", TOML has no null to unset a default
+fn days_or_forever<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Days {
+        Days(u64),
+        Text(String),
+    }
+
+    match Option::<Days>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Days::Days(days)) => Ok(Some(days)),
+        Some(Days::Text(text)) if text.eq_ignore_ascii_case("forever") => Ok(None),
+        // environment variables are strings
+        Some(Days::Text(text)) => text.parse().map(Some).map_err(|_| {
+            serde::de::Error::custom(format!(
+                "expected a number of days or \"forever\", got {text:?}"
+            ))
+        }),
+    }
+}
+
+fn parse_listen_addrs(name: &str, addrs: &[String], port: u16) -> Result<Vec<ListenAddr>> {
+    if addrs.is_empty() {
+        return Ok(vec![ListenAddr::Tcp(SocketAddr::from((
+            [127, 0, 0, 1],
+            port,
+        )))]);
+    }
+
+    addrs
+        .iter()
+        .map(|addr| parse_listen_addr(name, addr, port))
+        .collect()
+}
+
+fn parse_listen_addr(name: &str, addr: &str, port: u16) -> Result<ListenAddr> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        if path.is_empty() {
+            bail!("Invalid {name} {addr:?}, expected a socket path like unix:/run/etes.sock");
+        }
+
+        return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
+
+    if let Ok(addr) = addr.parse() {
+        return Ok(ListenAddr::Tcp(addr));
+    }
+
+    // an IP address without port, IPv6 addresses may be in brackets like [::]
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(addr);
+
+    let ip: IpAddr = ip.parse().with_context(|| {
+        format!(
+            "Invalid {name} {addr:?}, expected an address like 127.0.0.1:3000, [::]:3000 or unix:/run/etes.sock"
+        )
+    })?;
+
+    Ok(ListenAddr::Tcp(SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod test {
+    use config::{File, FileFormat};
+
+    use super::{Config, ListenAddr, file_layers, read_secret_files};
+
+    const VALID: &str = include_str!("../config.toml");
+
+    fn parse(overrides: &str) -> Config {
+        Config::defaults()
+            .unwrap()
+            .add_source(File::from_str(VALID, FileFormat::Toml))
+            .add_source(File::from_str(overrides, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn secret_sources(config: &str) -> anyhow::Result<config::Config> {
+        read_secret_files(
+            config::Config::builder()
+                .add_source(File::from_str(config, FileFormat::Toml))
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_file_layers() {
+        let dir = std::env::temp_dir().join(format!("etes-layers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let base = dir.join("base.toml");
+        std::fs::write(
+            &base,
+            r#"
+            title = "Base"
+            max_services = 10
+            command_env = { FOO = "base", BAR = "base" }
+
+            [profiles.staging]
+            title = "Staging"
+            "#,
+        )
+        .unwrap();
+
+        let overrides = dir.join("override.toml");
+        std::fs::write(
+            &overrides,
+            r#"
+            max_services = 20
+            command_env = { BAR = "override" }
+            "#,
+        )
+        .unwrap();
+
+        let merge = |profile| {
+            file_layers(
+                base.to_str().unwrap(),
+                Some(overrides.to_str().unwrap()),
+                profile,
             )
-            .build()?
-            .try_deserialize()?;
+            .unwrap()
+            .into_iter()
+            .fold(config::Config::builder(), |builder, layer| {
+                builder.add_source(layer)
+            })
+            .build()
+            .unwrap()
+        };
+
+        // later layers win per key, also inside nested tables
+        let config = merge(None);
+        assert_eq!(config.get_string("title").unwrap(), "Base");
+        assert_eq!(config.get_int("max_services").unwrap(), 20);
+        assert_eq!(config.get_string("command_env.FOO").unwrap(), "base");
+        assert_eq!(config.get_string("command_env.BAR").unwrap(), "override");
+
+        let config = merge(Some("staging"));
+        assert_eq!(config.get_string("title").unwrap(), "Staging");
+        assert_eq!(config.get_int("max_services").unwrap(), 20);
+
+        assert!(file_layers(base.to_str().unwrap(), None, Some("production")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secret_files() {
+        let path = std::env::temp_dir().join(format!("etes-secret-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+
+        let literal = secret_sources(r#"api_key = "literal""#).unwrap();
+        assert_eq!(literal.get_string("api_key").unwrap(), "literal");
+
+        // trailing whitespace, like the newline most editors add, is trimmed
+        let file = secret_sources(&format!("api_key_file = {path:?}")).unwrap();
+        assert_eq!(file.get_string("api_key").unwrap(), "from-file");
+
+        let both = secret_sources(&format!("api_key = \"literal\"\napi_key_file = {path:?}"));
+        assert!(
+            both.unwrap_err()
+                .to_string()
+                .contains("Both api_key and api_key_file")
+        );
+
+        let missing = secret_sources(r#"session_key_file = "/nonexistent/etes-secret""#);
+        assert!(
+            missing
+                .unwrap_err()
+                .to_string()
+                .contains("session_key_file")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_default_words() {
+        let mut config = parse("words = []");
+        config.apply_default_words();
+        assert!(config.words.len() > 100);
+        assert!(config.validate().is_ok());
+
+        let mut config = parse(r#"words = ["foo", "oak"]"#);
+        config.extend_default_words = true;
+        config.apply_default_words();
+        assert_eq!(config.words.iter().filter(|word| *word == "oak").count(), 1);
+        assert_eq!(config.words.last().unwrap(), "foo");
+
+        // the configured words are used as is, unless extending is enabled
+        let mut config = parse("");
+        config.apply_default_words();
+        assert_eq!(config.words, vec!["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        let config = parse(
+            r#"
+            listen_addr = ["[::]", "0.0.0.0:80"]
+            proxy_listen_addr = "[::1]:0"
+            "#,
+        );
+
+        assert_eq!(
+            config.server_addrs().unwrap(),
+            vec![
+                ListenAddr::Tcp("[::]:3000".parse().unwrap()),
+                ListenAddr::Tcp("0.0.0.0:80".parse().unwrap()),
+            ]
+        );
+        assert_eq!(
+            config.proxy_addrs().unwrap(),
+            vec![ListenAddr::Tcp("[::1]:0".parse().unwrap())]
+        );
+
+        let config = parse(r#"listen_addr = "::1""#);
+        assert_eq!(
+            config.server_addrs().unwrap(),
+            vec![ListenAddr::Tcp("[::1]:3000".parse().unwrap())]
+        );
+
+        let error = parse(r#"listen_addr = "[::1:80""#)
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Invalid listen_addr"));
+    }
+
+    #[test]
+    fn test_retention_days() {
+        let config = parse("");
+        assert_eq!(config.retention_days, Some(30));
+        assert_eq!(config.release_retention_days, None);
+
+        let config = parse(
+            r#"
+            retention_days = "forever"
+            release_retention_days = 365
+            "#,
+        );
+        assert_eq!(config.retention_days, None);
+        assert_eq!(config.release_retention_days, Some(365));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(parse("").validate().is_ok());
+
+        let config = parse(
+            r#"
+            words = ["foo", "foo", "bar"]
+            authorize_url = "example.com/authorize"
+            max_services = 0
+            session_key = "short"
+            admins = ["example", ""]
+            "#,
+        );
+
+        let problems = config.problems();
+        assert_eq!(problems.len(), 5, "{problems:?}");
+
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("words must contain at least 3 unique words"));
+        assert!(error.contains("authorize_url"));
+        assert!(error.contains("max_services"));
+        assert!(error.contains("session_key"));
+        assert!(error.contains("admins"));
+
+        let error = parse(r#"favicon = "./missing.png""#)
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("favicon file ./missing.png does not exist"));
+
+        let error = parse(r#"command_env = { DATA = "{workdir}/data" }"#)
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Invalid command_env: unknown placeholder {workdir}"));
+
+        let config = parse(r#"url_scheme = "http""#);
+        assert!(config.problems().is_empty());
+        assert_eq!(config.service_url("foo"), "http://foo.example.com");
+
+        let problems = parse(r#"url_scheme = "ftp""#).problems();
+        assert!(problems[0].contains("url_scheme must be http or https"));
+
+        let problems = parse(r#"base_domain = "https://example.com""#).problems();
+        assert!(problems[0].contains("must be a domain name"));
+
+        let problems =
+            parse(r#"cors_allowed_origins = ["*", "https://portal.example.com/"]"#).problems();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("\"*\" is not allowed"));
+
+        let problems = parse(r#"protected_hashes = ["abc"]"#).problems();
+        assert!(problems[0].contains("protected_hashes entry \"abc\""));
+
+        // config.toml authorizes on example.com
+        assert!(
+            parse(r#"cookie_domain = ".example.com""#)
+                .validate()
+                .is_ok()
+        );
 
-        Ok(Box::leak(Box::new(config)))
+        let problems = parse(
+            r#"
+            cookie_domain = "example.org"
+            cookie_host_prefix = true
+            "#,
+        )
+        .problems();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("cookie_domain \"example.org\" must contain"));
+        assert!(problems[1].contains("cookie_host_prefix"));
     }
 }
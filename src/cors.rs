@@ -0,0 +1,144 @@
+use axum::http::{
+    HeaderName, HeaderValue, Method,
+    header::{AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE},
+};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::Config;
+
+// Browsers cache a preflight response for at most this long
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(3600);
+
+// CORS for the API routes, only when other origins are configured. The origins are
+// validated with the configuration, none of them is "*" as credentials are allowed.
+pub fn cors_layer(config: &Config) -> Option<CorsLayer> {
+    let origins: Vec<HeaderValue> = config
+        .cors_allowed_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+
+    if origins.is_empty() {
+        return None;
+    }
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true)
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE, IF_NONE_MATCH, RANGE])
+            .expose_headers([
+                ETAG,
+                CONTENT_RANGE,
+                HeaderName::from_static("x-correlation-id"),
+                HeaderName::from_static("x-etes-sha256"),
+            ])
+            .max_age(PREFLIGHT_MAX_AGE),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Method, Request},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    use crate::config::{Config, ConfigArgs};
+
+    use super::cors_layer;
+
+    const PORTAL: &str = "https://portal.example.com";
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+        assert!(cors_layer(&config).is_none());
+
+        config.cors_allowed_origins = vec![PORTAL.to_string()];
+        let app = Router::new()
+            .route("/etes/api/v1/version", get(|| async { "version" }))
+            .layer(cors_layer(&config).unwrap());
+
+        let preflight = |origin: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri("/etes/api/v1/version")
+                    .header("Origin", origin)
+                    .header("Access-Control-Request-Method", "GET")
+                    .header("Access-Control-Request-Headers", "if-none-match")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = preflight(PORTAL).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], PORTAL);
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert!(
+            headers["access-control-allow-headers"]
+                .to_str()
+                .unwrap()
+                .contains("if-none-match")
+        );
+
+        let response = preflight("https://evil.com").await.unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key("access-control-allow-origin")
+        );
+
+        // the request itself, with the session cookie of the user
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/etes/api/v1/version")
+                    .header("Origin", PORTAL)
+                    .header("Cookie", "SESSION=value")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["access-control-allow-origin"], PORTAL);
+        assert_eq!(
+            response.headers()["access-control-allow-credentials"],
+            "true"
+        );
+    }
+
+    #[test]
+    fn test_session_cookie_site() {
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+
+        // the SameSite=Lax session cookie is sent from the base domain, not from other sites
+        config.cors_allowed_origins = vec![
+            PORTAL.to_string(),
+            "https://example.com".to_string(),
+            "https://portal.example.org".to_string(),
+            "https://notexample.com".to_string(),
+        ];
+        assert_eq!(
+            config.cross_site_cors_origins(),
+            ["https://portal.example.org", "https://notexample.com"]
+        );
+
+        config.cookie_domain = Some(".example.org".to_string());
+        assert_eq!(
+            config.cross_site_cors_origins(),
+            [PORTAL, "https://example.com", "https://notexample.com"]
+        );
+    }
+}
@@ -1,58 +1,278 @@
 use anyhow::Result;
 use axum::{
-    Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    },
+    response::{IntoResponse, Response},
 };
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
     AppState, GITHUB_BASE_URL,
-    error::AppError,
-    executable::ExecutableData,
-    github::GitHubState,
-    monitor::MemoryState,
-    service::ServiceData,
+    config::RoutingMode,
+    error::{AppError, ErrorResponse},
+    events::ServiceState,
+    executable::{self, ExecutableData},
+    github::{GitHubLimits, GitHubRefresh, GitHubState},
+    health::WorkerStatus,
+    metrics::BusMetrics,
+    monitor::{HISTORY_POINTS, MonitorSample, SystemState},
+    presence::PresenceState,
+    service::{ServiceData, StateChange},
+    services::{ServiceQuery, ServiceSort},
     user::{GitHubUser, User},
+    util::sha256,
+    version::VersionInfo,
 };
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct InitialState {
     is_admin: bool,
     user: User,
     title: String,
     base_url: String,
+    // how the public URLs of services are built, services have their url as well
+    preview_base_domain: String,
+    url_scheme: String,
+    routing_mode: RoutingMode,
+    // services can only be started from executables built for this platform
+    host_platform: String,
     github: GitHubState,
-    memory: MemoryState,
+    // last fetches of the GitHub data, the uptime of etes is part of the version
+    github_refresh: GitHubRefresh,
+    last_executable_scan: Option<DateTime<Utc>>,
+    // memory and CPU usage, named after the former memory state
+    memory: SystemState,
+    // recent samples of the monitor, for sparklines
+    history: Vec<MonitorSample>,
+    presence: PresenceState,
+    // health of the background workers
+    workers: Vec<WorkerStatus>,
     executables: Vec<ExecutableData>,
     services: Vec<ServiceData>,
     words: Vec<String>,
+    version: VersionInfo,
+    // only for admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bus: Option<BusMetrics>,
 }
 
-// Initial data fetch
+/// Service with the details left out of the service list, for the HTTP API and the websocket
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceDetail {
+    #[serde(flatten)]
+    pub service: ServiceData,
+    pub restarts: u32,
+    // state changes, oldest first
+    pub history: Vec<StateChange>,
+    // most recent output of the process, oldest first, only for the owner and admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<String>>,
+}
+
+/// Number of commits and pull requests a client wants, at most the configured number is sent
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DataQuery {
+    commits: Option<usize>,
+    pulls: Option<usize>,
+}
+
+/// Selection and order of the services, for the service listing and the dashboard data
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ServiceParams {
+    // "me" for the services of the caller, or the login of a GitHub user
+    creator: Option<String>,
+    state: Option<ServiceState>,
+    sort: Option<ServiceSort>,
+}
+
+impl ServiceParams {
+    // Resolve "me" to the caller, the query hashes anonymous identities like the service data
+    pub fn to_query(&self, user: &User) -> ServiceQuery {
+        ServiceQuery {
+            creator: self.creator.as_deref().map(|creator| match creator {
+                "me" => user.clone(),
+                // GitHub users are compared by their login
+                login => User::GitHub(GitHubUser {
+                    login: login.to_string(),
+                    name: String::new(),
+                    avatar_url: String::new(),
+                }),
+            }),
+            state: self.state.clone(),
+            sort: self.sort.unwrap_or_default(),
+        }
+    }
+}
+
+// Initial data fetch, the websocket sends the same state on connect which takes precedence
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/data/{caller}",
+    params(
+        ("caller" = String, Path, description = "Caller id of an anonymous client, ignored when logged in"),
+        DataQuery,
+        ServiceParams,
+    ),
+    responses(
+        (status = 200, description = "State of the dashboard", body = InitialState),
+        (status = 304, description = "The state matches the ETag in If-None-Match"),
+        (status = 400, description = "Invalid caller name", body = ErrorResponse),
+    ),
+)]
 pub async fn data_handler(
     State(state): State<AppState>,
     Path(caller): Path<String>,
+    Query(query): Query<DataQuery>,
+    Query(service_params): Query<ServiceParams>,
+    headers: HeaderMap,
     github_user: Option<GitHubUser>,
-) -> Result<Json<InitialState>, AppError> {
+) -> Result<Response, AppError> {
     let user = User::from_request(caller, github_user)?;
 
-    let github = state.github.get_state();
-    let services = state.services.get_state();
-    let executables = state.services.get_executables();
+    let config = state.config();
+    let is_admin = user.is_admin(&config);
+    let mut limits = GitHubLimits::from_config(&config);
+    limits.commits = query
+        .commits
+        .map_or(limits.commits, |n| n.min(limits.commits));
+    limits.pulls = query.pulls.map_or(limits.pulls, |n| n.min(limits.pulls));
 
-    Ok(Json(InitialState {
-        is_admin: user.is_admin(state.config),
+    let github = state.github.get_shaped(limits);
+    let services = state
+        .services
+        .get_state(&config, &service_params.to_query(&user));
+    let executables = state.services.get_executables(&config);
+
+    let initial_state = InitialState {
+        is_admin,
         user: user.hash_anonymous(),
         base_url: format!(
             "{GITHUB_BASE_URL}/{}/{}",
-            state.config.github_owner, state.config.github_repo
+            config.github_owner, config.github_repo
         ),
-        title: state.config.title.clone(),
+        title: config.title.clone(),
+        preview_base_domain: config.base_domain.clone(),
+        url_scheme: config.url_scheme.clone(),
+        routing_mode: config.routing_mode(),
+        host_platform: executable::host_platform().to_string(),
         memory: state.monitor.get_state(),
+        history: state.monitor.get_history(HISTORY_POINTS),
+        presence: state.presence.get_state(),
+        workers: state.health.get_state(config.monitor_interval()),
         executables,
         github,
+        github_refresh: state.github.get_refresh(),
+        last_executable_scan: state.services.last_executable_scan(),
         services,
-        words: state.config.words.clone(),
-    }))
+        words: config.words.clone(),
+        version: state.version.clone(),
+        bus: is_admin.then(|| state.channel.get_metrics()),
+    };
+
+    // The dashboard refetches the state on focus, mostly to get the same payload again
+    let body = serde_json::to_string(&initial_state)?;
+    let etag = etag(&initial_state)?;
+
+    let cache_headers = [
+        (ETAG, HeaderValue::from_str(&etag)?),
+        // browsers revalidate instead of showing a stale state
+        (CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+    ];
+
+    if is_not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    Ok((
+        cache_headers,
+        [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        body,
+    )
+        .into_response())
+}
+
+// Parts of the state that change with every sample or heartbeat, these would make every ETag
+// unique. Clients get them over the websocket, which sends a full snapshot when it connects.
+const VOLATILE_FIELDS: [&str; 5] = ["memory", "history", "presence", "workers", "bus"];
+
+// Weak ETag of the state, a matching tag can carry outdated volatile fields
+fn etag(initial_state: &InitialState) -> Result<String> {
+    let mut value = serde_json::to_value(initial_state)?;
+
+    if let Some(fields) = value.as_object_mut() {
+        for field in VOLATILE_FIELDS {
+            fields.remove(field);
+        }
+    }
+
+    Ok(format!("W/\"{}\"", &sha256(&value.to_string())[..32]))
+}
+
+// Check an If-None-Match header against the ETag, with the weak comparison of RFC 9110
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use crate::{app, config::ConfigArgs, events::Topic, presence::PresenceGuard, user::User};
+
+    #[tokio::test]
+    async fn test_etag() {
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
+
+        let request = |etag: Option<&str>| {
+            let mut request = Request::builder().uri("/etes/api/v1/data/caller");
+
+            if let Some(etag) = etag {
+                request = request.header("If-None-Match", etag);
+            }
+
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = request(None).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""));
+
+        let response = request(Some(&format!("\"other\", {etag}"))).await.unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()["etag"], etag.as_str());
+
+        // another client connecting does not change the tag
+        let _presence = PresenceGuard::new(
+            state.clone(),
+            User::Anonymous("other".to_string()),
+            &Topic::defaults(),
+            "192.0.2.1".to_string(),
+        );
+
+        let response = request(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), 304);
+
+        let response = request(Some("\"other\"")).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
 }
@@ -0,0 +1,322 @@
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        HeaderMap, HeaderValue,
+        header::{
+            ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+        },
+    },
+    response::{IntoResponse, Response},
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use utoipa::IntoParams;
+
+use crate::{
+    AppState,
+    auth::check_api_key,
+    error::{AppError, ErrorResponse},
+    executable::host_platform,
+    upload::CHECKSUM,
+    user::{GitHubUser, User},
+    util::{is_valid_hash, is_valid_platform},
+};
+
+/// Build to download when a commit was built for several platforms
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DownloadParams {
+    // platform of the build, the build for the host of etes by default
+    platform: Option<String>,
+}
+
+// Part of the executable requested with a Range header
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    // first and last byte, inclusive like the header
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only single ranges are served, the whole file is sent for several ranges or a header
+// that can't be parsed, which the Range header allows
+fn byte_range(range: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+
+    match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), _) if start >= size => ByteRange::Unsatisfiable,
+        (Ok(start), Ok(end)) if start <= end => ByteRange::Partial(start, end.min(size - 1)),
+        (Ok(start), Err(_)) if end.trim().is_empty() => ByteRange::Partial(start, size - 1),
+        // the last bytes of the file
+        (Err(_), Ok(suffix)) if start.trim().is_empty() => match suffix.min(size) {
+            0 => ByteRange::Unsatisfiable,
+            suffix => ByteRange::Partial(size - suffix, size - 1),
+        },
+        _ => ByteRange::Full,
+    }
+}
+
+// Download the executable of a build, for example to debug a crash of a service locally
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/executable/{trigger_hash}/{build_hash}",
+    params(
+        ("trigger_hash" = String, Path, description = "Commit hash that triggered the build"),
+        ("build_hash" = String, Path, description = "Commit hash that was built"),
+        DownloadParams,
+        ("Range" = Option<String>, Header, description = "Single byte range of the executable, like bytes=0-1023"),
+    ),
+    responses(
+        (status = 200, description = "The executable, with its SHA-256 in the X-Etes-Sha256 header when it is known", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range of the executable", content_type = "application/octet-stream"),
+        (status = 400, description = "Invalid commit hash or platform", body = ErrorResponse),
+        (status = 401, description = "Not logged in and no valid API key", body = ErrorResponse),
+        (status = 403, description = "Only admins can download executables", body = ErrorResponse),
+        (status = 404, description = "No executable for the commits", body = ErrorResponse),
+        (status = 416, description = "Range outside the executable"),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub async fn download_handler(
+    State(state): State<AppState>,
+    Path((trigger_hash, build_hash)): Path<(String, String)>,
+    Query(params): Query<DownloadParams>,
+    github_user: Option<GitHubUser>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !is_valid_hash(&trigger_hash) || !is_valid_hash(&build_hash) {
+        return Err(AppError::Client(anyhow!("Invalid commit hash")));
+    }
+
+    if params
+        .platform
+        .as_deref()
+        .is_some_and(|platform| !is_valid_platform(platform))
+    {
+        return Err(AppError::Client(anyhow!(
+            "platform must be a platform like x86_64 or aarch64"
+        )));
+    }
+
+    // the API key of the uploads, or the session of an admin
+    let user = match (
+        check_api_key(&headers, &state.config().api_key),
+        github_user,
+    ) {
+        (Ok(()), _) => "the API key".to_string(),
+        (Err(_), Some(user)) if User::GitHub(user.clone()).is_admin(&state.config()) => user.login,
+        (Err(_), Some(_)) => {
+            return Err(AppError::Forbidden(anyhow!(
+                "Only admins can download executables"
+            )));
+        }
+        (Err(_), None) => {
+            return Err(AppError::Unauthorized(anyhow!(
+                "Log in as an admin or use the API key"
+            )));
+        }
+    };
+
+    let executable = match &params.platform {
+        Some(platform) => state
+            .services
+            .get_executable(&build_hash, &trigger_hash, Some(platform)),
+        None => state
+            .services
+            .get_executable(&build_hash, &trigger_hash, None)
+            .or_else(|| {
+                state
+                    .services
+                    .get_executable(&build_hash, &trigger_hash, Some(host_platform()))
+            }),
+    }
+    .ok_or_else(|| {
+        AppError::NotFound(anyhow!("No executable for {trigger_hash} and {build_hash}"))
+    })?;
+
+    let mut file = tokio::fs::File::open(executable.path()).await?;
+    let size = file.metadata().await?.len();
+
+    let range = headers.get(RANGE).and_then(|value| value.to_str().ok());
+
+    let (status, start, end) = match byte_range(range, size) {
+        ByteRange::Full => (StatusCode::OK, 0, size),
+        ByteRange::Partial(first, last) => (StatusCode::PARTIAL_CONTENT, first, last + 1),
+        ByteRange::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response());
+        }
+    };
+
+    info!(
+        "Download of {:?} by {user}, bytes {start} to {end} of {size}",
+        executable.path()
+    );
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = Body::from_stream(ReaderStream::new(file.take(end - start)));
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+
+    let file_name = executable
+        .path()
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response_headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{file_name}\""))?,
+    );
+
+    if status == StatusCode::PARTIAL_CONTENT {
+        response_headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {start}-{}/{size}", end - 1))?,
+        );
+    }
+
+    // of the whole executable, also for a range
+    if let Some(checksum) = executable
+        .metadata()
+        .and_then(|metadata| metadata.checksum.as_deref())
+    {
+        response_headers.insert(CHECKSUM, HeaderValue::from_str(checksum)?);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use hyper::Method;
+    use tower::ServiceExt;
+
+    use crate::{app, config::ConfigArgs, util::sha256};
+
+    use super::{ByteRange, byte_range};
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range(None, 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=2-5"), 10), ByteRange::Partial(2, 5));
+        assert_eq!(byte_range(Some("bytes=2-"), 10), ByteRange::Partial(2, 9));
+        assert_eq!(byte_range(Some("bytes=2-50"), 10), ByteRange::Partial(2, 9));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), ByteRange::Partial(7, 9));
+        assert_eq!(byte_range(Some("bytes=-30"), 10), ByteRange::Partial(0, 9));
+        assert_eq!(byte_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=5-2"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("lines=1-2"), 10), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn test_download_handler() {
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
+
+        let hash1 = "3333333333333333333333333333333333333333";
+        let hash2 = "4444444444444444444444444444444444444444";
+        let uri = format!("/etes/api/v1/executable/{hash1}/{hash2}");
+        let authorization = format!("Bearer {}", state.config().api_key);
+
+        let request = |method: Method, range: Option<&str>, body: Body| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(&uri)
+                .header("Authorization", &authorization);
+
+            if let Some(range) = range {
+                request = request.header("Range", range);
+            }
+
+            app.clone().oneshot(request.body(body).unwrap())
+        };
+
+        let response = request(Method::GET, None, Body::empty()).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let response = request(Method::PUT, None, Body::new("executable".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+
+        let response = request(Method::GET, None, Body::empty()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], "10");
+        assert_eq!(response.headers()["x-etes-sha256"], sha256("executable"));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"executable");
+
+        let response = request(Method::GET, Some("bytes=2-5"), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 2-5/10");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"ecut");
+
+        let response = request(Method::GET, Some("bytes=20-"), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 416);
+
+        // without the API key or a login
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/etes/api/v1/executable/{hash1}/abc"))
+                    .header("Authorization", &authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        state
+            .services
+            .get_executable_by_commit(&hash2.into())
+            .unwrap()
+            .remove()
+            .await
+            .unwrap();
+    }
+}
@@ -1,27 +1,105 @@
-use axum::response::{IntoResponse, Response};
+use axum::{
+    Json,
+    extract::Request,
+    http::{
+        HeaderName, HeaderValue,
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE},
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
 use hyper::StatusCode;
+use serde::Serialize;
 use tracing::error;
+use utoipa::ToSchema;
+
+use crate::{events::ErrorCode, services::ServiceError, util::random_string};
+
+// Header with the id of an error in the log
+const CORRELATION_ID: HeaderName = HeaderName::from_static("x-correlation-id");
 
 pub enum AppError {
     Client(anyhow::Error),
+    Unauthorized(anyhow::Error),
+    Forbidden(anyhow::Error),
+    NotFound(anyhow::Error),
+    Conflict(anyhow::Error),
+    Unavailable(anyhow::Error),
     Server(anyhow::Error),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        match &self {
-            AppError::Client(e) => error!("Client error: {e:?}"),
-            AppError::Server(e) => error!("Server error: {e:?}"),
-        }
+/// Error of a request, with the id it was logged with
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ErrorBody {
+    code: String,
+    message: String,
+    correlation_id: String,
+}
+
+/// Error response of the API routes and clients that accept JSON
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: ErrorBody,
+}
 
+impl AppError {
+    fn parts(&self) -> (StatusCode, &'static str, &'static str, &anyhow::Error) {
         match self {
-            AppError::Client(e) => (StatusCode::BAD_REQUEST, format!("Client error: {e}")),
+            AppError::Client(e) => (StatusCode::BAD_REQUEST, "bad_request", "Client error", e),
+            AppError::Unauthorized(e) => {
+                (StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized", e)
+            }
+            AppError::Forbidden(e) => (StatusCode::FORBIDDEN, "forbidden", "Forbidden", e),
+            AppError::NotFound(e) => (StatusCode::NOT_FOUND, "not_found", "Not found", e),
+            AppError::Conflict(e) => (StatusCode::CONFLICT, "conflict", "Conflict", e),
+            AppError::Unavailable(e) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Unavailable",
+                e,
+            ),
             AppError::Server(e) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Server error: {e}"),
+                "server_error",
+                "Server error",
+                e,
             ),
         }
-        .into_response()
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, code, label, e) = self.parts();
+        let correlation_id = random_string();
+
+        error!("{label} ({correlation_id}): {e:?}");
+
+        // failed service operations keep their specific code
+        let code = match e.downcast_ref::<ServiceError>() {
+            Some(service_error) => serde_json::to_value(service_error.code)
+                .ok()
+                .and_then(|code| code.as_str().map(str::to_string))
+                .unwrap_or_else(|| code.to_string()),
+            None => code.to_string(),
+        };
+
+        let body = ErrorBody {
+            code,
+            message: e.to_string(),
+            correlation_id: correlation_id.clone(),
+        };
+
+        let mut response = (status, format!("{label}: {e}")).into_response();
+
+        if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+            response.headers_mut().insert(CORRELATION_ID, value);
+        }
+
+        // rendered for the client by `negotiate_errors`
+        response.extensions_mut().insert(body);
+
+        response
     }
 }
 
@@ -33,3 +111,139 @@ where
         Self::Server(err.into())
     }
 }
+
+impl From<ServiceError> for AppError {
+    fn from(err: ServiceError) -> Self {
+        let code = err.code;
+        let err = anyhow::Error::msg(err);
+
+        match code {
+            ErrorCode::NotOwner | ErrorCode::NotAdmin => AppError::Forbidden(err),
+            ErrorCode::NameTaken
+            | ErrorCode::ExecutableCorrupt
+            | ErrorCode::ExecutableInUse
+            | ErrorCode::ExecutableProtected
+            | ErrorCode::WrongPlatform => AppError::Conflict(err),
+            ErrorCode::InvalidName | ErrorCode::InvalidRequest => AppError::Client(err),
+            ErrorCode::ExecutableNotFound | ErrorCode::ServiceNotFound => AppError::NotFound(err),
+            ErrorCode::NoFreePort | ErrorCode::MemoryExhausted => AppError::Unavailable(err),
+            ErrorCode::StartFailed
+            | ErrorCode::ServiceUnreachable
+            | ErrorCode::GithubFetchFailed
+            | ErrorCode::CleanupFailed => AppError::Server(err),
+        }
+    }
+}
+
+// Render error responses as JSON for the API routes and clients that accept JSON,
+// and as a page for browsers, other clients get plain text
+pub async fn negotiate_errors(request: Request, next: Next) -> Response {
+    let accept = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let json =
+        request.uri().path().starts_with("/etes/api/") || accept.contains("application/json");
+    let html = accept.contains("text/html");
+
+    let response = next.run(request).await;
+
+    let Some(error) = response.extensions().get::<ErrorBody>().cloned() else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+
+    let rendered = if json {
+        Json(ErrorResponse { error }).into_response()
+    } else if html {
+        Html(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>{status}</title></head>
+<body>
+<h1>{status}</h1>
+<p>{message}</p>
+<p>Error id: {id}</p>
+</body>
+</html>
+"#,
+            status = parts.status,
+            message = escape_html(&error.message),
+            id = error.correlation_id,
+        ))
+        .into_response()
+    } else {
+        return Response::from_parts(parts, body);
+    };
+
+    let (rendered_parts, rendered_body) = rendered.into_parts();
+
+    if let Some(content_type) = rendered_parts.headers.get(CONTENT_TYPE) {
+        parts.headers.insert(CONTENT_TYPE, content_type.clone());
+    }
+
+    Response::from_parts(parts, rendered_body)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use axum::{Router, body::Body, http::Request, middleware, routing::get};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::{AppError, negotiate_errors};
+
+    #[tokio::test]
+    async fn test_negotiate_errors() {
+        let handler = || async { Err::<(), _>(AppError::NotFound(anyhow!("No <such> thing"))) };
+        let app = Router::new()
+            .route("/etes/api/v1/thing", get(handler))
+            .route("/etes/thing", get(handler))
+            .layer(middleware::from_fn(negotiate_errors));
+
+        let body = |accept: &str, uri: &str| {
+            let request = Request::builder()
+                .uri(uri)
+                .header("Accept", accept)
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), 404);
+                assert!(response.headers().contains_key("x-correlation-id"));
+
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let json = body("*/*", "/etes/api/v1/thing").await;
+        let error: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(error["error"]["code"], "not_found");
+
+        let json = body("application/json", "/etes/thing").await;
+        assert!(json.starts_with(r#"{"error":{"code":"not_found""#));
+
+        let html = body("text/html,*/*", "/etes/thing").await;
+        assert!(html.contains("<p>No &lt;such&gt; thing</p>"));
+
+        assert_eq!(
+            body("*/*", "/etes/thing").await,
+            "Not found: No <such> thing"
+        );
+    }
+}
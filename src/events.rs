@@ -1,10 +1,29 @@
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
 use tokio::sync::broadcast;
-use tracing::error;
+use tracing::{error, info};
+use utoipa::ToSchema;
 
-use crate::{executable::ExecutableData, github::GitHubState, service::ServiceData, user::User};
+use crate::{
+    alerts::{AlertMetric, AlertSeverity},
+    cgroup::MemorySource,
+    config::RateLimit,
+    data::ServiceDetail,
+    executable::ExecutableData,
+    github::{CommitHash, GitHubRefresh, GitHubState},
+    metrics::BusMetrics,
+    service::{ServiceData, Upstream},
+    sinks::EventSink,
+    user::{Role, User},
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ServiceState {
     Pending,
@@ -12,10 +31,82 @@ pub enum ServiceState {
     Error,
 }
 
+/// Number of services in each state, for the summary on the dashboard
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct ServiceCounts {
+    pub pending: usize,
+    pub running: usize,
+    pub error: usize,
+}
+
+impl ServiceCounts {
+    pub fn count(services: &[ServiceData]) -> Self {
+        let mut counts = Self::default();
+
+        for service in services {
+            match service.state {
+                ServiceState::Pending => counts.pending += 1,
+                ServiceState::Running => counts.running += 1,
+                ServiceState::Error => counts.error += 1,
+            }
+        }
+
+        counts
+    }
+}
+
+// Groups of broadcast events a websocket client can subscribe to
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Github,
+    Services,
+    Executables,
+    Memory,
+    Presence,
+}
+
+impl Topic {
+    // Topics of a connection that did not subscribe, memory updates are opt-in
+    pub fn defaults() -> HashSet<Topic> {
+        HashSet::from([
+            Topic::Github,
+            Topic::Services,
+            Topic::Executables,
+            Topic::Presence,
+        ])
+    }
+}
+
+// Machine readable reason of an error, new codes may be added at any time
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotOwner,
+    NotAdmin,
+    NameTaken,
+    InvalidName,
+    InvalidRequest,
+    ExecutableNotFound,
+    ExecutableCorrupt,
+    ExecutableInUse,
+    ExecutableProtected,
+    WrongPlatform,
+    ServiceNotFound,
+    NoFreePort,
+    MemoryExhausted,
+    StartFailed,
+    ServiceUnreachable,
+    GithubFetchFailed,
+    CleanupFailed,
+}
+
+/// Requests sent by clients over the websocket
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum Event {
-    // Client requests
+pub enum ClientEvent {
     GithubRefresh {
         user: User,
     },
@@ -23,111 +114,629 @@ pub enum Event {
         executable: ExecutableData,
         name: String,
         user: User,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        // Start even when the host is low on memory, only for admins
+        #[serde(default)]
+        force: bool,
     },
     StopService {
         name: String,
         user: User,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    RestartService {
+        name: String,
+        user: User,
+    },
+    SetAlias {
+        name: String,
+        alias: String,
+        user: User,
+    },
+    SetRateLimit {
+        name: String,
+        rate_limit: Option<RateLimit>,
+        user: User,
+    },
+    AddExternalService {
+        name: String,
+        upstream: Upstream,
+        user: User,
+    },
+    GetService {
+        name: String,
+        user: User,
+    },
+    // Run the executable cleanup now, only for admins
+    CleanupExecutables {
+        user: User,
+    },
+    // Remove an executable that no service uses, only for admins
+    DeleteExecutable {
+        hash: CommitHash,
+        trigger_hash: CommitHash,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        platform: Option<String>,
+        user: User,
+    },
+    // Exempt the builds of a commit hash from the cleanup, or lift that, only for admins
+    ProtectExecutable {
+        hash: CommitHash,
+        protected: bool,
+        user: User,
+    },
+    // Handled per connection, never broadcast
+    Subscribe {
+        topics: HashSet<Topic>,
     },
-    // Server responses
+    // Services to follow the output of, handled per connection
+    SubscribeLogs {
+        services: HashSet<String>,
+    },
+    // Recent samples of the monitor, answered per connection
+    GetMonitorHistory,
+}
+
+/// Responses and updates sent to clients over the websocket
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
     Error {
         message: String,
+        code: ErrorCode,
+        user: User,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    // Sent to the caller when a request with an id has been handled
+    Done {
+        request_id: String,
+        user: User,
+    },
+    // Answer to GetService, sent to the caller only
+    ServiceDetail {
+        service: Box<ServiceDetail>,
         user: User,
     },
     GithubState {
         payload: GitHubState,
+        // missing in event logs of older versions
+        #[serde(default)]
+        refresh: GitHubRefresh,
     },
     ServiceState {
         services: Vec<ServiceData>,
     },
+    // Changes to a single service, followed by a ServiceState unless `service_state_snapshots` is off
+    ServiceAdded {
+        service: Box<ServiceData>,
+    },
+    ServiceUpdated {
+        service: Box<ServiceData>,
+    },
+    ServiceRemoved {
+        name: String,
+    },
     ExecutablesState {
         executables: Vec<ExecutableData>,
+        // last scan of the bin directory
+        #[serde(default)]
+        scanned_at: Option<DateTime<Utc>>,
     },
+    // Memory, CPU and disk usage of the machine, see SystemState
     MemoryState {
         used: u64,
         total: u64,
+        memory_source: MemorySource,
+        swap_used: u64,
+        swap_total: u64,
+        cpu: f32,
+        cores: usize,
+        bin_size: u64,
+        disk_free: Option<u64>,
+        load_average: Option<[f64; 3]>,
+        uptime: u64,
+        services: ServiceCounts,
+        fds: Option<u64>,
+        fd_limit: Option<u64>,
+        processes: usize,
+    },
+    // Memory in bytes and CPU percentage of the running services, with their child processes
+    ServiceResources {
+        usage: Vec<(String, u64, f32)>,
+    },
+    Presence {
+        connected: usize,
+        authenticated: usize,
+        anonymous: usize,
+    },
+    BusMetrics {
+        metrics: BusMetrics,
     },
+    // Capacity problems worth noticing early, sent to admins only
+    Warning {
+        message: String,
+    },
+    // A metric of the monitor crossed its threshold or recovered, sent to admins only
+    Alert {
+        severity: AlertSeverity,
+        metric: AlertMetric,
+        value: f64,
+        threshold: f64,
+    },
+    // Clients should fetch their initial state again, like the title and admin status
+    ConfigReloaded,
+}
+
+// Who a server event may be sent to
+#[derive(Debug, PartialEq)]
+pub enum Visibility<'a> {
+    Public,
+    // only the connections of this user
+    Targeted(&'a User),
+    // only admin connections, regardless of the subscribed topics
+    Admin,
+}
+
+/// Events on the internal bus
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Event {
+    Client(ClientEvent),
+    Server(ServerEvent),
+}
+
+impl From<ClientEvent> for Event {
+    fn from(event: ClientEvent) -> Self {
+        Event::Client(event)
+    }
+}
+
+impl From<ServerEvent> for Event {
+    fn from(event: ServerEvent) -> Self {
+        Event::Server(event)
+    }
 }
 
 impl Event {
     pub fn caller(&self) -> Option<&User> {
         match self {
-            Event::GithubRefresh { user, .. } => Some(user),
-            Event::StartService { user, .. } => Some(user),
-            Event::StopService { user, .. } => Some(user),
-            Event::Error { user, .. } => Some(user),
-            _ => None,
+            Event::Client(event) => event.caller(),
+            Event::Server(
+                ServerEvent::Error { user, .. }
+                | ServerEvent::Done { user, .. }
+                | ServerEvent::ServiceDetail { user, .. },
+            ) => Some(user),
+            Event::Server(_) => None,
         }
     }
 
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         match self {
-            Event::ExecutablesState { .. } => "executables_state",
-            Event::GithubRefresh { .. } => "github_refresh",
-            Event::GithubState { .. } => "github_state",
-            Event::StartService { .. } => "run",
-            Event::ServiceState { .. } => "service_state",
-            Event::StopService { .. } => "stop_service",
-            Event::Error { .. } => "error",
-            Event::MemoryState { .. } => "memory_state",
+            Event::Client(event) => event.name(),
+            Event::Server(event) => event.name(),
         }
     }
 
-    pub fn is_memory_state(&self) -> bool {
-        matches!(self, Event::MemoryState { .. })
+    // Frequent measurements, which are not logged or sent to the sinks
+    pub fn is_usage_update(&self) -> bool {
+        matches!(
+            self,
+            Event::Server(ServerEvent::MemoryState { .. } | ServerEvent::ServiceResources { .. })
+        )
     }
 
-    pub fn should_forward(&self, user: &User) -> bool {
+    // Copy of the event with hashed anonymous ids, which must not leave etes
+    pub fn anonymized(&self) -> Event {
         match self {
-            Event::Error {
-                user: event_user, ..
-            } => user == event_user,
-            e if e.is_client_event() => false,
-            _ => true,
+            Event::Client(event) => match event.caller() {
+                Some(user) => Event::Client(event.clone().update_user(user.hash_anonymous())),
+                None => self.clone(),
+            },
+            Event::Server(event) => {
+                let mut event = event.clone();
+
+                if let ServerEvent::Error { user, .. }
+                | ServerEvent::Done { user, .. }
+                | ServerEvent::ServiceDetail { user, .. } = &mut event
+                {
+                    *user = user.hash_anonymous();
+                }
+
+                Event::Server(event)
+            }
         }
     }
+}
 
-    pub fn is_client_event(&self) -> bool {
-        matches!(
-            self,
-            Event::GithubRefresh { .. } | Event::StartService { .. } | Event::StopService { .. }
-        )
+impl ClientEvent {
+    pub fn caller(&self) -> Option<&User> {
+        match self {
+            ClientEvent::GithubRefresh { user, .. } => Some(user),
+            ClientEvent::StartService { user, .. } => Some(user),
+            ClientEvent::StopService { user, .. } => Some(user),
+            ClientEvent::RestartService { user, .. } => Some(user),
+            ClientEvent::SetAlias { user, .. } => Some(user),
+            ClientEvent::SetRateLimit { user, .. } => Some(user),
+            ClientEvent::AddExternalService { user, .. } => Some(user),
+            ClientEvent::GetService { user, .. } => Some(user),
+            ClientEvent::CleanupExecutables { user } => Some(user),
+            ClientEvent::DeleteExecutable { user, .. } => Some(user),
+            ClientEvent::ProtectExecutable { user, .. } => Some(user),
+            ClientEvent::Subscribe { .. }
+            | ClientEvent::SubscribeLogs { .. }
+            | ClientEvent::GetMonitorHistory => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEvent::GithubRefresh { .. } => "github_refresh",
+            ClientEvent::StartService { .. } => "run",
+            ClientEvent::StopService { .. } => "stop_service",
+            ClientEvent::RestartService { .. } => "restart_service",
+            ClientEvent::SetAlias { .. } => "set_alias",
+            ClientEvent::SetRateLimit { .. } => "set_rate_limit",
+            ClientEvent::AddExternalService { .. } => "add_external_service",
+            ClientEvent::GetService { .. } => "get_service",
+            ClientEvent::CleanupExecutables { .. } => "cleanup_executables",
+            ClientEvent::DeleteExecutable { .. } => "delete_executable",
+            ClientEvent::ProtectExecutable { .. } => "protect_executable",
+            ClientEvent::Subscribe { .. } => "subscribe",
+            ClientEvent::SubscribeLogs { .. } => "subscribe_logs",
+            ClientEvent::GetMonitorHistory => "get_monitor_history",
+        }
     }
 
+    // Replace the user sent by the client with the authenticated user of the connection
     pub fn update_user(self, user: User) -> Self {
         match self {
-            Event::GithubRefresh { .. } => Event::GithubRefresh { user },
-            Event::StartService {
-                executable, name, ..
-            } => Event::StartService {
+            ClientEvent::GithubRefresh { .. } => ClientEvent::GithubRefresh { user },
+            ClientEvent::StartService {
                 executable,
+                name,
+                request_id,
+                force,
+                ..
+            } => ClientEvent::StartService {
+                executable,
+                name,
+                user,
+                request_id,
+                force,
+            },
+            ClientEvent::StopService {
+                name, request_id, ..
+            } => ClientEvent::StopService {
                 name,
                 user,
+                request_id,
             },
-            Event::StopService { name, .. } => Event::StopService { name, user },
-            Event::Error { message, .. } => Event::Error { message, user },
-            event => event,
+            ClientEvent::RestartService { name, .. } => ClientEvent::RestartService { name, user },
+            ClientEvent::SetAlias { name, alias, .. } => {
+                ClientEvent::SetAlias { name, alias, user }
+            }
+            ClientEvent::SetRateLimit {
+                name, rate_limit, ..
+            } => ClientEvent::SetRateLimit {
+                name,
+                rate_limit,
+                user,
+            },
+            ClientEvent::AddExternalService { name, upstream, .. } => {
+                ClientEvent::AddExternalService {
+                    name,
+                    upstream,
+                    user,
+                }
+            }
+            ClientEvent::GetService { name, .. } => ClientEvent::GetService { name, user },
+            ClientEvent::CleanupExecutables { .. } => ClientEvent::CleanupExecutables { user },
+            ClientEvent::DeleteExecutable {
+                hash,
+                trigger_hash,
+                platform,
+                ..
+            } => ClientEvent::DeleteExecutable {
+                hash,
+                trigger_hash,
+                platform,
+                user,
+            },
+            ClientEvent::ProtectExecutable {
+                hash, protected, ..
+            } => ClientEvent::ProtectExecutable {
+                hash,
+                protected,
+                user,
+            },
+            event @ (ClientEvent::Subscribe { .. }
+            | ClientEvent::SubscribeLogs { .. }
+            | ClientEvent::GetMonitorHistory) => event,
         }
     }
 }
 
+impl ServerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::Error { .. } => "error",
+            ServerEvent::Done { .. } => "done",
+            ServerEvent::ServiceDetail { .. } => "service_detail",
+            ServerEvent::GithubState { .. } => "github_state",
+            ServerEvent::ServiceState { .. } => "service_state",
+            ServerEvent::ServiceAdded { .. } => "service_added",
+            ServerEvent::ServiceUpdated { .. } => "service_updated",
+            ServerEvent::ServiceRemoved { .. } => "service_removed",
+            ServerEvent::ExecutablesState { .. } => "executables_state",
+            ServerEvent::MemoryState { .. } => "memory_state",
+            ServerEvent::ServiceResources { .. } => "service_resources",
+            ServerEvent::Presence { .. } => "presence",
+            ServerEvent::BusMetrics { .. } => "bus_metrics",
+            ServerEvent::Warning { .. } => "warning",
+            ServerEvent::Alert { .. } => "alert",
+            ServerEvent::ConfigReloaded => "config_reloaded",
+        }
+    }
+
+    // Topic of a broadcast event, targeted events have none
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            ServerEvent::GithubState { .. } => Some(Topic::Github),
+            ServerEvent::ServiceState { .. }
+            | ServerEvent::ServiceAdded { .. }
+            | ServerEvent::ServiceUpdated { .. }
+            | ServerEvent::ServiceRemoved { .. }
+            | ServerEvent::ServiceResources { .. } => Some(Topic::Services),
+            ServerEvent::ExecutablesState { .. } => Some(Topic::Executables),
+            ServerEvent::MemoryState { .. } => Some(Topic::Memory),
+            ServerEvent::Presence { .. } => Some(Topic::Presence),
+            ServerEvent::Error { .. }
+            | ServerEvent::Done { .. }
+            | ServerEvent::ServiceDetail { .. }
+            | ServerEvent::BusMetrics { .. }
+            | ServerEvent::Warning { .. }
+            | ServerEvent::Alert { .. }
+            | ServerEvent::ConfigReloaded => None,
+        }
+    }
+
+    // Events worth replaying to reconnecting clients, frequent status updates and
+    // answers to one-off requests are not
+    fn is_replayable(&self) -> bool {
+        !matches!(
+            self,
+            ServerEvent::MemoryState { .. }
+                | ServerEvent::ServiceResources { .. }
+                | ServerEvent::Presence { .. }
+                | ServerEvent::ServiceDetail { .. }
+                | ServerEvent::BusMetrics { .. }
+        )
+    }
+
+    pub fn visibility(&self) -> Visibility<'_> {
+        match self {
+            ServerEvent::Error { user, .. }
+            | ServerEvent::Done { user, .. }
+            | ServerEvent::ServiceDetail { user, .. } => Visibility::Targeted(user),
+            ServerEvent::BusMetrics { .. }
+            | ServerEvent::Warning { .. }
+            | ServerEvent::Alert { .. } => Visibility::Admin,
+            _ => Visibility::Public,
+        }
+    }
+
+    // Check if the event may be sent to a connection of the user with the given role
+    pub fn should_forward(&self, user: &User, role: Role) -> bool {
+        match self.visibility() {
+            Visibility::Public => true,
+            Visibility::Targeted(target) => user == target,
+            Visibility::Admin => role == Role::Admin,
+        }
+    }
+}
+
+// Number of recent events kept to replay to reconnecting clients
+const HISTORY_SIZE: usize = 256;
+
+// Part of etes an event was sent from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    Ws,
+    Proxy,
+    Upload,
+    Worker,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Ws => write!(f, "ws"),
+            Origin::Proxy => write!(f, "proxy"),
+            Origin::Upload => write!(f, "upload"),
+            Origin::Worker => write!(f, "worker"),
+        }
+    }
+}
+
+/// Event on the bus with its sequence number, time and origin
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub emitted_at: DateTime<Utc>,
+    pub origin: Origin,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// What a (re)connecting client needs before receiving live events
+pub enum Replay {
+    // Events the client missed since its last seen sequence number
+    Events(Vec<SequencedEvent>),
+    // The client is too far behind, a full snapshot is needed at this sequence number
+    Snapshot(u64),
+}
+
+struct History {
+    last: u64,
+    // highest sequence number no longer in the history
+    evicted: u64,
+    events: VecDeque<SequencedEvent>,
+    // health of the bus, only updated while sending
+    send_errors: u64,
+    high_water_mark: usize,
+    counts: BTreeMap<&'static str, u64>,
+}
+
 pub struct EventManager {
-    sender: broadcast::Sender<Event>,
+    sender: broadcast::Sender<SequencedEvent>,
+    // number of events a receiver can fall behind before it lags
+    capacity: usize,
+    history: Mutex<History>,
+    lagged: AtomicU64,
+    sinks: RwLock<Vec<Box<dyn EventSink>>>,
 }
 
 impl EventManager {
-    pub fn new() -> Self {
-        let (sender, _) = broadcast::channel(512);
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self {
+            sender,
+            capacity,
+            history: Mutex::new(History {
+                last: 0,
+                evicted: 0,
+                events: VecDeque::with_capacity(HISTORY_SIZE),
+                send_errors: 0,
+                high_water_mark: 0,
+                counts: BTreeMap::new(),
+            }),
+            lagged: AtomicU64::new(0),
+            sinks: RwLock::new(Vec::new()),
+        }
+    }
 
-        Self { sender }
+    // Also send all events, except memory updates, to the sink
+    pub fn add_sink(&self, sink: impl EventSink + 'static) {
+        info!("Sending events to {}", sink.name());
+        self.sinks.write().push(Box::new(sink));
     }
 
-    pub fn send(&self, event: Event) {
+    // Send an event from one of the background workers
+    pub fn send(&self, event: impl Into<Event>) {
+        self.send_from(Origin::Worker, event);
+    }
+
+    pub fn send_from(&self, origin: Origin, event: impl Into<Event>) {
+        let mut history = self.history.lock();
+        history.last += 1;
+
+        let event = SequencedEvent {
+            seq: history.last,
+            emitted_at: Utc::now(),
+            origin,
+            event: event.into(),
+        };
+
+        if let Event::Server(server_event) = &event.event
+            && server_event.is_replayable()
+        {
+            if history.events.len() == HISTORY_SIZE
+                && let Some(evicted) = history.events.pop_front()
+            {
+                history.evicted = evicted.seq;
+            }
+
+            history.events.push_back(event.clone());
+        }
+
+        *history.counts.entry(event.event.name()).or_default() += 1;
+
+        let sinks = self.sinks.read();
+
+        if !sinks.is_empty() && !event.event.is_usage_update() {
+            let anonymized = SequencedEvent {
+                event: event.event.anonymized(),
+                ..event.clone()
+            };
+
+            for sink in sinks.iter() {
+                sink.send(&anonymized);
+            }
+        }
+
+        // send while holding the lock, so receivers see events in sequence order
         if let Err(e) = self.sender.send(event) {
+            history.send_errors += 1;
             error!("Failed to send event: {e:?}");
         }
+
+        history.high_water_mark = history.high_water_mark.max(self.sender.len());
+    }
+
+    // Count events a receiver missed because it fell behind
+    pub fn record_lag(&self, skipped: u64) {
+        self.lagged.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn get_metrics(&self) -> BusMetrics {
+        let history = self.history.lock();
+
+        BusMetrics {
+            capacity: self.capacity,
+            receivers: self.sender.receiver_count(),
+            sent: history.last,
+            send_errors: history.send_errors,
+            lagged: self.lagged.load(Ordering::Relaxed),
+            high_water_mark: history.high_water_mark,
+            events: history
+                .counts
+                .iter()
+                .map(|(name, count)| (name.to_string(), *count))
+                .collect(),
+            sink_errors: self
+                .sinks
+                .read()
+                .iter()
+                .map(|sink| (sink.name(), sink.errors()))
+                .collect(),
+        }
     }
 
-    pub fn get_receiver(&self) -> broadcast::Receiver<Event> {
+    pub fn get_receiver(&self) -> broadcast::Receiver<SequencedEvent> {
         self.sender.subscribe()
     }
+
+    // Sequence number of the last sent event
+    pub fn last_sequence(&self) -> u64 {
+        self.history.lock().last
+    }
+
+    // Subscribe to live events and get the events missed since a sequence number
+    pub fn subscribe_since(
+        &self,
+        since: Option<u64>,
+    ) -> (broadcast::Receiver<SequencedEvent>, Replay) {
+        let history = self.history.lock();
+        let receiver = self.sender.subscribe();
+
+        let replay = match since {
+            Some(since) if since >= history.evicted && since <= history.last => Replay::Events(
+                history
+                    .events
+                    .iter()
+                    .filter(|event| event.seq > since)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => Replay::Snapshot(history.last),
+        };
+
+        (receiver, replay)
+    }
 }
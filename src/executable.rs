@@ -1,51 +1,122 @@
+use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
-use tracing::info;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Once,
+    time::{Duration, SystemTime},
+};
+use tokio::{io::AsyncReadExt, task::JoinHandle};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
 
-use crate::{AppState, github::CommitHash, util::is_valid_hash};
+use crate::{
+    AppState,
+    events::{ErrorCode, ServerEvent},
+    github::{CommitHash, GitHubState},
+    user::User,
+    util::{hex, is_valid_hash, is_valid_platform, random_string},
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ExecutableData {
     hash: CommitHash,
     trigger_hash: CommitHash,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uploaded_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<u64>,
+    // SHA-256 of the executable, unknown for executables uploaded before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    // builds for several platforms are listed separately, None for uploads without a platform
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    platform: Option<String>,
+    // the contents no longer match the checksum, services are not started from it
+    #[serde(default)]
+    corrupt: bool,
+    // exempt from the cleanup and deletes, by the configuration or an admin
+    #[serde(default)]
+    protected: bool,
 }
 
 impl ExecutableData {
     pub fn hash(&self) -> &CommitHash {
         &self.hash
     }
+
+    pub fn with_protected(self, protected: bool) -> Self {
+        Self { protected, ..self }
+    }
 }
 
 impl From<&Executable> for ExecutableData {
     fn from(executable: &Executable) -> Self {
+        let metadata = executable.metadata.as_ref();
+
         Self {
             hash: executable.hash.clone(),
             trigger_hash: executable.trigger_hash.clone(),
+            uploaded_at: metadata.map(|metadata| metadata.uploaded_at),
+            size_bytes: metadata.map(|metadata| metadata.size_bytes),
+            checksum: metadata.and_then(|metadata| metadata.checksum.clone()),
+            corrupt: executable.is_corrupt(),
+            platform: executable.platform.clone(),
+            protected: false,
         }
     }
 }
 
+/// Upload of an executable, stored next to it in a JSON file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutableMetadata {
+    pub uploaded_at: DateTime<Utc>,
+    pub size_bytes: u64,
+    pub checksum: Option<String>,
+    // set by the verification when the contents differ from the checksum
+    #[serde(default)]
+    pub corrupt: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Executable {
     path: PathBuf,
     hash: CommitHash,
     trigger_hash: CommitHash,
+    // platform the executable was built for, None runs on every host
+    #[serde(default)]
+    platform: Option<String>,
+    // read from the metadata file, or the file system for executables uploaded without one
+    #[serde(default)]
+    metadata: Option<ExecutableMetadata>,
 }
 
 impl Executable {
-    pub fn from_commit(commit_hash: CommitHash, trigger_hash: CommitHash) -> Self {
-        let path = if commit_hash == trigger_hash {
-            format!("./bin/{commit_hash}.bin")
-        } else {
-            format!("./bin/{trigger_hash}_{commit_hash}.bin")
+    // Executable of a build, stored as {trigger_hash}/{commit_hash}.{platform}.bin in the bin_dir
+    pub fn from_commit(
+        bin_dir: &Path,
+        commit_hash: CommitHash,
+        trigger_hash: CommitHash,
+        platform: Option<String>,
+    ) -> Self {
+        let file_name = match &platform {
+            Some(platform) => format!("{commit_hash}.{platform}.bin"),
+            None => format!("{commit_hash}.bin"),
         };
 
         Self {
-            path: PathBuf::from(path),
+            path: bin_dir.join(&trigger_hash).join(file_name),
             hash: commit_hash,
             trigger_hash,
+            platform,
+            metadata: None,
         }
     }
 
@@ -60,96 +131,1052 @@ impl Executable {
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
+
+    pub fn platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    // Executables without a platform are assumed to be built for the host
+    pub fn runs_on_host(&self) -> bool {
+        self.platform
+            .as_deref()
+            .is_none_or(|platform| platform == host_platform())
+    }
+
+    pub fn metadata(&self) -> Option<&ExecutableMetadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.corrupt)
+    }
+
+    // Protecting the trigger hash protects all of its builds
+    pub fn is_protected(&self, protected: &HashSet<CommitHash>) -> bool {
+        protected.contains(&self.hash) || protected.contains(&self.trigger_hash)
+    }
+
+    fn metadata_path(&self) -> PathBuf {
+        self.path.with_extension("json")
+    }
+
+    // Store the metadata of an upload next to the executable
+    pub async fn write_metadata(&mut self, metadata: ExecutableMetadata) -> anyhow::Result<()> {
+        tokio::fs::write(self.metadata_path(), serde_json::to_vec(&metadata)?).await?;
+        self.metadata = Some(metadata);
+
+        Ok(())
+    }
+
+    // Read the metadata of the upload, executables from before the metadata files get the
+    // modification time and size of the file system
+    async fn read_metadata(&mut self) {
+        if let Ok(contents) = tokio::fs::read(self.metadata_path()).await {
+            match serde_json::from_slice(&contents) {
+                Ok(metadata) => {
+                    self.metadata = Some(metadata);
+                    return;
+                }
+                Err(e) => warn!("Invalid metadata of executable {:?}: {e}", self.path),
+            }
+        }
+
+        self.metadata = tokio::fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|metadata| {
+                Some(ExecutableMetadata {
+                    uploaded_at: written_at(&metadata)?.into(),
+                    size_bytes: metadata.len(),
+                    checksum: None,
+                    corrupt: false,
+                })
+            });
+    }
+
+    // Create the directory of the trigger hash to store the executable in
+    pub async fn create_dir(&self) -> std::io::Result<()> {
+        match self.path.parent() {
+            Some(dir) => tokio::fs::create_dir_all(dir).await,
+            None => Ok(()),
+        }
+    }
+
+    // Remove the executable with its metadata, and the directory of the trigger hash once it is empty
+    pub async fn remove(&self) -> std::io::Result<()> {
+        tokio::fs::remove_file(&self.path).await?;
+
+        if let Err(e) = tokio::fs::remove_file(self.metadata_path()).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(e);
+        }
+
+        if let Some(dir) = self.path.parent() {
+            // fails while other builds of the trigger hash are left
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+
+        Ok(())
+    }
+}
+
+// Platform of the host, services can only be started from executables built for it
+pub fn host_platform() -> &'static str {
+    std::env::consts::ARCH
+}
+
+// Commit hash and platform in the name of an executable, {commit_hash}.{platform}.bin
+fn parse_file_name(file_name: &str) -> Option<(&str, Option<&str>)> {
+    let name = file_name.strip_suffix(".bin")?;
+
+    match name.split_once('.') {
+        Some((hash, platform)) => is_valid_platform(platform).then_some((hash, Some(platform))),
+        None => Some((name, None)),
+    }
 }
 
-// Loop over all files in the bin directory and create a new Executable for each file with valid git commit hash name
-pub async fn get_executables() -> Vec<Executable> {
+// Loop over the directories of the trigger hashes in the bin directory and create a new
+// Executable for each file with a valid git commit hash name
+pub async fn get_executables(bin_dir: &Path) -> Vec<Executable> {
     let mut executables = Vec::new();
 
-    if let Ok(mut dir) = tokio::fs::read_dir("./bin").await {
-        while let Ok(Some(file)) = dir.next_entry().await {
-            let path = &file.path();
+    let Ok(mut triggers) = tokio::fs::read_dir(bin_dir).await else {
+        return executables;
+    };
+
+    while let Ok(Some(trigger)) = triggers.next_entry().await {
+        let trigger_hash = trigger.file_name().to_string_lossy().to_string();
+
+        if !is_valid_hash(&trigger_hash) {
+            continue;
+        }
+
+        let Ok(mut builds) = tokio::fs::read_dir(trigger.path()).await else {
+            continue;
+        };
+
+        while let Ok(Some(build)) = builds.next_entry().await {
+            let file_name = build.file_name().to_string_lossy().to_string();
 
-            let Some(file_name) = path.file_name() else {
+            let Some((hash, platform)) = parse_file_name(&file_name) else {
                 continue;
             };
 
-            if let Some(hash_or_hashes) = file_name.to_string_lossy().strip_suffix(".bin") {
-                let executable = match hash_or_hashes.split_once('_') {
-                    Some((trigger_hash, hash)) => {
-                        // Check for valid git commit hashes
-                        if !is_valid_hash(hash) || !is_valid_hash(trigger_hash) {
-                            continue;
-                        }
-
-                        Executable {
-                            path: path.clone(),
-                            hash: hash.into(),
-                            trigger_hash: trigger_hash.into(),
-                        }
-                    }
-                    None => {
-                        // Check for valid git commit hash
-                        if !is_valid_hash(hash_or_hashes) {
-                            continue;
-                        }
-
-                        Executable {
-                            path: path.clone(),
-                            hash: hash_or_hashes.into(),
-                            trigger_hash: hash_or_hashes.into(),
-                        }
-                    }
-                };
-
-                executables.push(executable);
+            // Check for valid git commit hash
+            if !is_valid_hash(hash) {
+                continue;
             }
+
+            let mut executable = Executable {
+                path: build.path(),
+                hash: hash.into(),
+                trigger_hash: trigger_hash.clone(),
+                platform: platform.map(str::to_string),
+                metadata: None,
+            };
+
+            executable.read_metadata().await;
+            executables.push(executable);
         }
     }
 
+    // newest uploads first, the hashes decide between equal or unknown upload times
+    executables.sort_by(|a, b| {
+        let uploaded_at = |executable: &Executable| {
+            executable
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.uploaded_at)
+        };
+
+        uploaded_at(b)
+            .cmp(&uploaded_at(a))
+            .then_with(|| a.hash.cmp(&b.hash))
+            .then_with(|| a.trigger_hash.cmp(&b.trigger_hash))
+            .then_with(|| a.platform.cmp(&b.platform))
+    });
+
     executables
 }
 
+// Move the executables of the former flat layout, {trigger_hash}_{commit_hash}.bin in the
+// bin_dir, into the directory of their trigger hash. Moved executables are not found again.
+pub async fn migrate_bin_dir(bin_dir: &Path) -> anyhow::Result<()> {
+    let mut moved = 0;
+    let mut dir = tokio::fs::read_dir(bin_dir).await?;
+
+    while let Some(file) = dir.next_entry().await? {
+        let file_name = file.file_name().to_string_lossy().to_string();
+
+        let Some((hashes, platform)) = parse_file_name(&file_name) else {
+            continue;
+        };
+
+        let (trigger_hash, hash) = hashes.split_once('_').unwrap_or((hashes, hashes));
+
+        if !is_valid_hash(trigger_hash) || !is_valid_hash(hash) {
+            continue;
+        }
+
+        let executable = Executable::from_commit(
+            bin_dir,
+            hash.into(),
+            trigger_hash.into(),
+            platform.map(str::to_string),
+        );
+
+        executable.create_dir().await?;
+        tokio::fs::rename(file.path(), executable.path()).await?;
+
+        let metadata = file.path().with_extension("json");
+        if tokio::fs::try_exists(&metadata).await? {
+            tokio::fs::rename(metadata, executable.metadata_path()).await?;
+        }
+
+        moved += 1;
+    }
+
+    if moved > 0 {
+        info!("Moved {moved} executables into the directories of their trigger hash");
+    }
+
+    Ok(())
+}
+
+// Create the bin directory if it does not exist, and check that uploads can be written to it
+pub async fn prepare_bin_dir(bin_dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(bin_dir)
+        .await
+        .with_context(|| format!("Failed to create bin_dir {}", bin_dir.display()))?;
+
+    check_bin_dir(bin_dir).await
+}
+
+// Check that executables can be written to the bin_dir, the probe has a unique name
+// so concurrent readiness checks don't remove each other's probe
+pub async fn check_bin_dir(bin_dir: &Path) -> anyhow::Result<()> {
+    let probe = bin_dir.join(format!(".write-check-{}", random_string()));
+    tokio::fs::write(&probe, b"")
+        .await
+        .with_context(|| format!("bin_dir {} is not writable", bin_dir.display()))?;
+    tokio::fs::remove_file(&probe).await?;
+
+    Ok(())
+}
+
+// SHA-256 of the contents of a file, hex encoded
+pub async fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+// Hash the executables again and compare them with the checksum of their upload, executables
+// that changed on disk are marked as corrupt in their metadata when mark_corrupt is set
+pub async fn verify_executables(bin_dir: &Path, mark_corrupt: bool) -> anyhow::Result<String> {
+    let mut verified = 0;
+    let mut unknown = 0;
+    let mut corrupt = Vec::new();
+
+    for mut executable in get_executables(bin_dir).await {
+        let Some(metadata) = executable.metadata().cloned() else {
+            unknown += 1;
+            continue;
+        };
+
+        let Some(checksum) = &metadata.checksum else {
+            unknown += 1;
+            continue;
+        };
+
+        if file_sha256(executable.path()).await? == *checksum {
+            verified += 1;
+            continue;
+        }
+
+        if mark_corrupt && !metadata.corrupt {
+            error!(
+                "Executable {:?} does not match the checksum of its upload",
+                executable.path()
+            );
+
+            executable
+                .write_metadata(ExecutableMetadata {
+                    corrupt: true,
+                    ..metadata
+                })
+                .await?;
+        }
+
+        corrupt.push(executable.path().display().to_string());
+    }
+
+    if !corrupt.is_empty() {
+        bail!(
+            "{} executables do not match their checksum: {}",
+            corrupt.len(),
+            corrupt.join(", ")
+        );
+    }
+
+    Ok(format!(
+        "{verified} executables match their checksum, {unknown} were uploaded without one"
+    ))
+}
+
+// Time an executable was written, file systems without creation times (like XFS) use the modification time
+fn written_at(metadata: &Metadata) -> Option<SystemTime> {
+    static TIME_SOURCE: Once = Once::new();
+
+    match metadata.created() {
+        Ok(created) => {
+            TIME_SOURCE
+                .call_once(|| info!("Using the creation time of executables for the retention"));
+            Some(created)
+        }
+        Err(_) => {
+            TIME_SOURCE.call_once(|| {
+                info!("Creation time of executables is unavailable, using the modification time for the retention")
+            });
+            metadata.modified().ok()
+        }
+    }
+}
+
+// Age in whole days, a time in the future (clock skew) counts as just written
+fn age_days(written_at: SystemTime, now: SystemTime) -> u64 {
+    now.duration_since(written_at).unwrap_or_default().as_secs() / (24 * 60 * 60)
+}
+
+// Builds of a trigger hash and platform past the newest `keep`, without the builds services
+// use and the protected builds
+fn excess_builds<'a>(
+    executables: &'a [Executable],
+    in_use: &HashSet<CommitHash>,
+    protected: &HashSet<CommitHash>,
+    keep: usize,
+) -> Vec<&'a Executable> {
+    let mut builds: HashMap<(&str, Option<&str>), Vec<&Executable>> = HashMap::new();
+
+    for executable in executables {
+        builds
+            .entry((executable.trigger_hash(), executable.platform()))
+            .or_default()
+            .push(executable);
+    }
+
+    builds
+        .into_values()
+        .flat_map(|mut builds| {
+            // newest first, builds without an upload time count as the oldest
+            builds.sort_by_key(|build| {
+                Reverse(build.metadata().map(|metadata| metadata.uploaded_at))
+            });
+            builds.into_iter().skip(keep)
+        })
+        .filter(|build| !in_use.contains(build.hash()) && !build.is_protected(protected))
+        .collect()
+}
+
+// Remove the older builds of a trigger hash past keep_builds_per_trigger, returns the number
+// of removed executables and their size
+pub async fn remove_excess_builds(state: &AppState) -> anyhow::Result<(usize, u64)> {
+    let config = state.config();
+    let Some(keep) = config.keep_builds_per_trigger else {
+        return Ok((0, 0));
+    };
+
+    let executables = get_executables(state.services.bin_dir()).await;
+    let in_use = state.services.hashes_in_use();
+    let protected = state.services.protected_hashes(&config);
+
+    let mut removed = 0;
+    let mut reclaimed = 0;
+
+    for executable in excess_builds(&executables, &in_use, &protected, keep) {
+        executable.remove().await?;
+        info!(
+            "Removing executable {:?}, {keep} newer builds of {} are kept",
+            executable.path(),
+            executable.trigger_hash()
+        );
+
+        removed += 1;
+        reclaimed += executable
+            .metadata()
+            .map(|metadata| metadata.size_bytes)
+            .unwrap_or_default();
+    }
+
+    Ok((removed, reclaimed))
+}
+
+// Remove the older builds of a trigger hash, executables older than the retention period for
+// their kind of commit, then the oldest ones while the bin_dir is over its size budget.
+// Executables of services and protected ones are always kept, and releases are only removed by age.
 pub async fn remove_unused_executables(state: AppState) -> anyhow::Result<()> {
-    // create bin directory if it does not exist
-    tokio::fs::create_dir_all("./bin").await?;
+    let _cleanup = state.services.lock_cleanup().await;
+    let (mut removed, mut reclaimed) = remove_excess_builds(&state).await?;
+
+    let executables = get_executables(state.services.bin_dir()).await;
+    let github = state.github.get_state();
+    // GitHub no longer knows the commits of old or force pushed pull requests, their services can still run
+    let in_use = state.services.hashes_in_use();
+    let config = state.config();
+    let protected = state.services.protected_hashes(&config);
 
-    let executables = get_executables().await;
-    let commit_hashes = state.github.get_commit_hashes();
+    let mut kept = 0;
+
+    // executables that are left, with their size and upload time, and whether the budget may remove them
+    let mut remaining = Vec::new();
 
-    // check if any of the executables are not in the commit hashes
     for executable in executables {
-        if !commit_hashes
+        let is_commit = |check: fn(&GitHubState, &str) -> bool| {
+            check(&github, executable.hash()) || check(&github, executable.trigger_hash())
+        };
+
+        let is_release = is_commit(GitHubState::is_release_commit);
+        let (kind, retention_days) = if is_release {
+            ("release", config.release_retention_days)
+        } else if is_commit(GitHubState::is_pull_commit) {
+            ("pull request", config.retention_days)
+        } else {
+            ("unknown commit", config.retention_days)
+        };
+
+        let in_use = in_use.contains(executable.hash())
+            || in_use.contains(executable.trigger_hash())
+            || executable.is_protected(&protected);
+
+        // the upload time is the age, file system timestamps differ between file systems
+        let Some(metadata) = executable.metadata().cloned() else {
+            kept += 1;
+            continue;
+        };
+
+        let uploaded_at = SystemTime::from(metadata.uploaded_at);
+        let days = age_days(uploaded_at, SystemTime::now());
+        let expired =
+            retention_days.is_some_and(|retention_days| !in_use && days >= retention_days);
+
+        if expired {
+            executable.remove().await?;
+            info!(
+                "Removing executable of {kind}: {:?} {days} days",
+                executable.path()
+            );
+
+            removed += 1;
+            reclaimed += metadata.size_bytes;
+        } else {
+            remaining.push((
+                executable,
+                metadata.size_bytes,
+                uploaded_at,
+                !in_use && !is_release,
+            ));
+        }
+    }
+
+    kept += remaining.len();
+
+    if let Some(max_bytes) = config.bin_dir_max_bytes {
+        let mut total: u64 = remaining.iter().map(|(_, size, _, _)| size).sum();
+
+        // oldest uploads first
+        let mut candidates: Vec<_> = remaining
             .iter()
-            .any(|hash| hash == executable.hash() || hash == executable.trigger_hash())
-        {
-            // remove old executables (after 30 days)
-            if let Ok(metadata) = tokio::fs::metadata(executable.path()).await {
-                let Ok(created) = metadata.created() else {
-                    continue;
-                };
-
-                let Ok(duration) = created.elapsed() else {
-                    continue;
-                };
-
-                if duration.as_secs() > 30 * 24 * 60 * 60 {
-                    tokio::fs::remove_file(executable.path()).await?;
-                    info!("Removing old executable: {:?}", executable.path());
-                } else {
-                    info!(
-                        "Keeping recent executable: {:?} {} days",
-                        executable.path(),
-                        duration.as_secs() / (24 * 60 * 60)
-                    );
-                }
-            } else {
-                info!("Keeping used executable: {:?}", executable.path());
+            .filter(|(_, _, _, removable)| *removable)
+            .collect();
+        candidates.sort_by_key(|(_, _, uploaded_at, _)| *uploaded_at);
+
+        for (executable, size, _, _) in candidates {
+            if total <= max_bytes {
+                break;
             }
+
+            executable.remove().await?;
+            info!(
+                "Removing executable {:?} to stay within bin_dir_max_bytes, reclaimed {} MB",
+                executable.path(),
+                size / (1024 * 1024)
+            );
+
+            total -= size;
+            kept -= 1;
+            removed += 1;
+            reclaimed += size;
+        }
+
+        if total > max_bytes {
+            warn!(
+                "bin_dir holds {} MB after the cleanup, more than bin_dir_max_bytes, the other executables are in use or releases",
+                total / (1024 * 1024)
+            );
         }
     }
 
+    info!(
+        "Executable cleanup kept {kept}, removed {removed} and reclaimed {} MB",
+        reclaimed / (1024 * 1024)
+    );
+
     state.services.update_executables().await;
 
+    if removed > 0 {
+        state.channel.send(ServerEvent::ExecutablesState {
+            executables: state.services.get_executables(&state.config()),
+            scanned_at: state.services.last_executable_scan(),
+        });
+    }
+
     Ok(())
 }
+
+// Run the cleanup every cleanup_interval_hours, the first run is at startup
+pub async fn cleanup_periodically(state: AppState) {
+    loop {
+        let interval = Duration::from_secs(state.config().cleanup_interval_hours * 60 * 60);
+        tokio::time::sleep(interval).await;
+
+        if let Err(e) = remove_unused_executables(state.clone()).await {
+            error!("Failed to remove unused executables: {e:?}");
+        }
+    }
+}
+
+// Run the cleanup in the background, failures are logged
+pub fn spawn_cleanup(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = remove_unused_executables(state).await {
+            error!("Failed to remove unused executables: {e:?}");
+        }
+    })
+}
+
+// Run the cleanup on request of an admin, for when uploads fail because the disk is full
+pub async fn cleanup_executables(state: AppState, user: User) {
+    if !user.is_admin(&state.config()) {
+        state.channel.send(ServerEvent::Error {
+            message: "Only admins can clean up executables".to_owned(),
+            code: ErrorCode::NotAdmin,
+            user,
+            request_id: None,
+        });
+
+        return;
+    }
+
+    info!("Executable cleanup requested by {user}");
+
+    if let Err(e) = remove_unused_executables(state.clone()).await {
+        error!("Failed to remove unused executables: {e:?}");
+        state.channel.send(ServerEvent::Error {
+            message: format!("Failed to clean up executables: {e}"),
+            code: ErrorCode::CleanupFailed,
+            user,
+            request_id: None,
+        });
+    }
+}
+
+// Remove an executable on request of an admin, for example a bad artifact, unless a service uses it
+pub async fn delete_executable(
+    state: AppState,
+    hash: CommitHash,
+    trigger_hash: CommitHash,
+    platform: Option<String>,
+    user: User,
+) {
+    let error = |message: String, code: ErrorCode, user: User| {
+        state.channel.send(ServerEvent::Error {
+            message,
+            code,
+            user,
+            request_id: None,
+        });
+    };
+
+    if !user.is_admin(&state.config()) {
+        return error(
+            "Only admins can delete executables".to_owned(),
+            ErrorCode::NotAdmin,
+            user,
+        );
+    }
+
+    let Some(executable) = state
+        .services
+        .get_executable(&hash, &trigger_hash, platform.as_deref())
+    else {
+        return error(
+            format!("Executable of {hash} not found"),
+            ErrorCode::ExecutableNotFound,
+            user,
+        );
+    };
+
+    let _cleanup = state.services.lock_cleanup().await;
+
+    if state.services.hashes_in_use().contains(executable.hash()) {
+        return error(
+            format!("Executable of {hash} is used by a service, stop it first"),
+            ErrorCode::ExecutableInUse,
+            user,
+        );
+    }
+
+    if executable.is_protected(&state.services.protected_hashes(&state.config())) {
+        return error(
+            format!("Executable of {hash} is protected, unprotect it first"),
+            ErrorCode::ExecutableProtected,
+            user,
+        );
+    }
+
+    if let Err(e) = executable.remove().await {
+        error!("Failed to delete executable {:?}: {e:?}", executable.path());
+        return error(
+            format!("Failed to delete executable of {hash}: {e}"),
+            ErrorCode::CleanupFailed,
+            user,
+        );
+    }
+
+    info!("Executable {:?} deleted by {user}", executable.path());
+
+    state.services.update_executables().await;
+    state.channel.send(ServerEvent::ExecutablesState {
+        executables: state.services.get_executables(&state.config()),
+        scanned_at: state.services.last_executable_scan(),
+    });
+}
+
+// Protect the builds of a commit hash from the cleanup and deletes on request of an admin, or lift that
+pub async fn protect_executable(state: AppState, hash: CommitHash, protected: bool, user: User) {
+    let error = |message: String, code: ErrorCode, user: User| {
+        state.channel.send(ServerEvent::Error {
+            message,
+            code,
+            user,
+            request_id: None,
+        });
+    };
+
+    if !user.is_admin(&state.config()) {
+        return error(
+            "Only admins can protect executables".to_owned(),
+            ErrorCode::NotAdmin,
+            user,
+        );
+    }
+
+    if !is_valid_hash(&hash) {
+        return error(
+            format!("Invalid commit hash {hash}"),
+            ErrorCode::InvalidRequest,
+            user,
+        );
+    }
+
+    // a running cleanup finishes before the protection changes
+    let _cleanup = state.services.lock_cleanup().await;
+
+    if let Err(e) = state.services.set_protected(&hash, protected).await {
+        error!("Failed to store the protected executables: {e:?}");
+        return error(
+            format!("Failed to store the protection of {hash}: {e}"),
+            ErrorCode::CleanupFailed,
+            user,
+        );
+    }
+
+    if protected {
+        info!("Executables of {hash} protected by {user}");
+    } else {
+        info!("Executables of {hash} unprotected by {user}");
+    }
+
+    state.channel.send(ServerEvent::ExecutablesState {
+        executables: state.services.get_executables(&state.config()),
+        scanned_at: state.services.last_executable_scan(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeDelta, Utc};
+    use std::{
+        collections::HashSet,
+        path::Path,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        AppState, AppStateContainer,
+        config::{Config, ConfigArgs},
+        events::{ErrorCode, Event, ServerEvent},
+        services::ServiceManager,
+        user::{GitHubUser, User},
+    };
+
+    use super::{
+        Executable, ExecutableMetadata, age_days, delete_executable, excess_builds, file_sha256,
+        get_executables, host_platform, migrate_bin_dir, prepare_bin_dir, protect_executable,
+        verify_executables, written_at,
+    };
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_age_days() {
+        let now = SystemTime::now();
+
+        assert_eq!(age_days(now, now), 0);
+        assert_eq!(age_days(now - 3 * DAY, now), 3);
+        assert_eq!(age_days(now - 3 * DAY + Duration::from_secs(1), now), 2);
+        assert_eq!(age_days(now + DAY, now), 0);
+    }
+
+    #[test]
+    fn test_written_at() {
+        let path = std::env::temp_dir().join(format!("etes-written-at-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - 40 * DAY).unwrap();
+
+        let metadata = file.metadata().unwrap();
+        let written_at = written_at(&metadata).unwrap();
+
+        // the creation time where the file system has one, the modification time otherwise
+        match metadata.created() {
+            Ok(created) => assert_eq!(written_at, created),
+            Err(_) => assert_eq!(age_days(written_at, SystemTime::now()), 40),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_excess_builds() {
+        let trigger = "1".repeat(40);
+        let build = |hash: char, age_hours: i64| {
+            let mut executable = Executable::from_commit(
+                Path::new("bin"),
+                hash.to_string().repeat(40),
+                trigger.clone(),
+                None,
+            );
+            executable.metadata = Some(ExecutableMetadata {
+                uploaded_at: Utc::now() - TimeDelta::hours(age_hours),
+                size_bytes: 10,
+                checksum: None,
+                corrupt: false,
+            });
+
+            executable
+        };
+
+        let executables = [build('a', 3), build('b', 1), build('c', 2), build('d', 4)];
+        let hashes = |builds: Vec<&Executable>| {
+            let mut hashes: Vec<char> = builds
+                .iter()
+                .map(|build| build.hash().chars().next().unwrap())
+                .collect();
+            hashes.sort();
+            hashes
+        };
+
+        assert_eq!(
+            hashes(excess_builds(
+                &executables,
+                &HashSet::new(),
+                &HashSet::new(),
+                2
+            )),
+            ['a', 'd']
+        );
+        assert!(excess_builds(&executables, &HashSet::new(), &HashSet::new(), 4).is_empty());
+
+        // the build of a service is kept
+        let in_use = HashSet::from(["d".repeat(40)]);
+        assert_eq!(
+            hashes(excess_builds(&executables, &in_use, &HashSet::new(), 1)),
+            ['a', 'c']
+        );
+
+        // protected builds are kept, protecting the trigger hash keeps all of them
+        let protected = HashSet::from(["a".repeat(40)]);
+        assert_eq!(
+            hashes(excess_builds(&executables, &in_use, &protected, 1)),
+            ['c']
+        );
+        let protected = HashSet::from([trigger.clone()]);
+        assert!(excess_builds(&executables, &HashSet::new(), &protected, 1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verify_executables() {
+        let bin_dir = std::env::temp_dir().join(format!("etes-verify-{}", std::process::id()));
+        tokio::fs::create_dir_all(&bin_dir).await.unwrap();
+
+        let hash = "3".repeat(40);
+        let mut executable = Executable::from_commit(&bin_dir, hash.clone(), hash.clone(), None);
+        executable.create_dir().await.unwrap();
+        tokio::fs::write(executable.path(), "test").await.unwrap();
+        executable
+            .write_metadata(ExecutableMetadata {
+                uploaded_at: Utc::now(),
+                size_bytes: 4,
+                checksum: Some(file_sha256(executable.path()).await.unwrap()),
+                corrupt: false,
+            })
+            .await
+            .unwrap();
+
+        assert!(verify_executables(&bin_dir, true).await.is_ok());
+
+        tokio::fs::write(executable.path(), "tampered")
+            .await
+            .unwrap();
+
+        // etes check only reports the mismatch
+        assert!(verify_executables(&bin_dir, false).await.is_err());
+        assert!(!get_executables(&bin_dir).await[0].is_corrupt());
+
+        assert!(verify_executables(&bin_dir, true).await.is_err());
+
+        let executables = get_executables(&bin_dir).await;
+        assert!(executables[0].is_corrupt());
+
+        tokio::fs::remove_dir_all(&bin_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_executable() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+        config.admins = vec!["octocat".to_string()];
+        *state.config.write() = Arc::new(config);
+
+        let admin = User::GitHub(GitHubUser {
+            login: "octocat".to_string(),
+            name: "The Octocat".to_string(),
+            avatar_url: String::new(),
+        });
+
+        let hash = "4".repeat(40);
+        let executable =
+            Executable::from_commit(state.services.bin_dir(), hash.clone(), hash.clone(), None);
+        prepare_bin_dir(state.services.bin_dir()).await.unwrap();
+        executable.create_dir().await.unwrap();
+        tokio::fs::write(executable.path(), "test").await.unwrap();
+        state.services.update_executables().await;
+
+        let mut receiver = state.channel.get_receiver();
+
+        delete_executable(
+            state.clone(),
+            hash.clone(),
+            hash.clone(),
+            None,
+            User::Anonymous("frank".to_string()),
+        )
+        .await;
+
+        let event = receiver.recv().await.unwrap().event;
+        let Event::Server(ServerEvent::Error { code, .. }) = event else {
+            panic!("Expected Error event, got {event:?}");
+        };
+        assert_eq!(code, ErrorCode::NotAdmin);
+        assert!(executable.path().exists());
+
+        protect_executable(state.clone(), hash.clone(), true, admin.clone()).await;
+
+        let event = receiver.recv().await.unwrap().event;
+        let Event::Server(ServerEvent::ExecutablesState { executables, .. }) = event else {
+            panic!("Expected ExecutablesState event, got {event:?}");
+        };
+        assert!(executables[0].protected);
+
+        delete_executable(
+            state.clone(),
+            hash.clone(),
+            hash.clone(),
+            None,
+            admin.clone(),
+        )
+        .await;
+
+        let event = receiver.recv().await.unwrap().event;
+        let Event::Server(ServerEvent::Error { code, .. }) = event else {
+            panic!("Expected Error event, got {event:?}");
+        };
+        assert_eq!(code, ErrorCode::ExecutableProtected);
+        assert!(executable.path().exists());
+
+        // the protection survives a restart
+        let services = ServiceManager::new(state.services.bin_dir().to_path_buf());
+        services.load_protected().await.unwrap();
+        assert!(services.protected_hashes(&state.config()).contains(&hash));
+
+        protect_executable(state.clone(), hash.clone(), false, admin.clone()).await;
+        receiver.recv().await.unwrap();
+
+        delete_executable(state.clone(), hash.clone(), hash.clone(), None, admin).await;
+
+        let event = receiver.recv().await.unwrap().event;
+        let Event::Server(ServerEvent::ExecutablesState { executables, .. }) = event else {
+            panic!("Expected ExecutablesState event, got {event:?}");
+        };
+        assert!(
+            executables
+                .iter()
+                .all(|executable| *executable.hash() != hash)
+        );
+        assert!(!executable.path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_platforms() {
+        let bin_dir = std::env::temp_dir().join(format!("etes-platforms-{}", std::process::id()));
+        tokio::fs::create_dir_all(&bin_dir).await.unwrap();
+
+        let hash = "5".repeat(40);
+        for platform in [Some("other-arch"), Some(host_platform()), Some("not.valid")] {
+            let executable = Executable::from_commit(
+                &bin_dir,
+                hash.clone(),
+                hash.clone(),
+                platform.map(str::to_string),
+            );
+            executable.create_dir().await.unwrap();
+            tokio::fs::write(executable.path(), "test").await.unwrap();
+        }
+
+        let mut platforms: Vec<_> = get_executables(&bin_dir)
+            .await
+            .into_iter()
+            .map(|executable| executable.platform)
+            .collect();
+        platforms.sort();
+
+        let mut expected = vec![
+            Some(host_platform().to_string()),
+            Some("other-arch".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(platforms, expected);
+
+        // the build for the host is started
+        let services = ServiceManager::new(bin_dir.clone());
+        services.update_executables().await;
+        let executable = services.get_executable_by_commit(&hash).unwrap();
+        assert!(executable.runs_on_host());
+        assert_eq!(executable.platform(), Some(host_platform()));
+
+        tokio::fs::remove_dir_all(&bin_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_bin_dir() {
+        let bin_dir = std::env::temp_dir().join(format!("etes-migrate-{}", std::process::id()));
+        tokio::fs::create_dir_all(&bin_dir).await.unwrap();
+
+        let (trigger, build) = ("6".repeat(40), "7".repeat(40));
+        for file_name in [
+            format!("{trigger}_{build}.bin"),
+            format!("{trigger}_{build}.json"),
+            format!("{build}.aarch64.bin"),
+            "notes.txt".to_string(),
+        ] {
+            tokio::fs::write(bin_dir.join(file_name), "{}")
+                .await
+                .unwrap();
+        }
+
+        migrate_bin_dir(&bin_dir).await.unwrap();
+        // moved files are not moved again
+        migrate_bin_dir(&bin_dir).await.unwrap();
+
+        let mut paths: Vec<_> = get_executables(&bin_dir)
+            .await
+            .iter()
+            .map(|executable| {
+                executable
+                    .path()
+                    .strip_prefix(&bin_dir)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            [
+                Path::new(&trigger).join(format!("{build}.bin")),
+                Path::new(&build).join(format!("{build}.aarch64.bin")),
+            ]
+        );
+        assert!(
+            bin_dir
+                .join(&trigger)
+                .join(format!("{build}.json"))
+                .exists()
+        );
+        assert!(bin_dir.join("notes.txt").exists());
+
+        tokio::fs::remove_dir_all(&bin_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_executables_order() {
+        let bin_dir = std::env::temp_dir().join(format!("etes-order-{}", std::process::id()));
+        let now = Utc::now();
+
+        for (hash, age_hours) in [('8', 2), ('a', 1), ('9', 1), ('b', 3)] {
+            let hash = hash.to_string().repeat(40);
+            let mut executable = Executable::from_commit(&bin_dir, hash.clone(), hash, None);
+            executable.create_dir().await.unwrap();
+            tokio::fs::write(executable.path(), "test").await.unwrap();
+            executable
+                .write_metadata(ExecutableMetadata {
+                    uploaded_at: now - TimeDelta::hours(age_hours),
+                    size_bytes: 4,
+                    checksum: None,
+                    corrupt: false,
+                })
+                .await
+                .unwrap();
+        }
+
+        let hashes: Vec<char> = get_executables(&bin_dir)
+            .await
+            .iter()
+            .map(|executable| executable.hash().chars().next().unwrap())
+            .collect();
+
+        assert_eq!(hashes, ['9', 'a', '8', 'b']);
+
+        tokio::fs::remove_dir_all(&bin_dir).await.unwrap();
+    }
+}
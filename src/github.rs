@@ -1,15 +1,24 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use chrono::{DateTime, Utc};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
-
-use crate::{AppState, Config, events::Event};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    AppState, Config,
+    check::CHECK_TIMEOUT,
+    events::{ClientEvent, ErrorCode, Event, ServerEvent},
+    executable,
+    health::{self, Worker},
+};
 
 pub type CommitHash = String;
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum WorkflowStatus {
     #[default]
@@ -20,7 +29,7 @@ pub enum WorkflowStatus {
     Success,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Commit {
     date: DateTime<Utc>,
@@ -33,7 +42,7 @@ pub struct Commit {
     message: Option<String>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Release {
     name: String,
@@ -43,7 +52,7 @@ pub struct Release {
     commit: Commit,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Assignee {
     avatar_url: String,
@@ -51,7 +60,7 @@ pub struct Assignee {
     name: Option<String>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Pull {
     number: i64,
@@ -63,38 +72,133 @@ pub struct Pull {
     commit: Commit,
 }
 
+// Cut a message to at most `length` characters, the last one marks that it was cut
+fn truncate_message(message: &str, length: usize) -> String {
+    if message.chars().count() <= length {
+        return message.to_string();
+    }
+
+    let mut truncated: String = message.chars().take(length.saturating_sub(1)).collect();
+    truncated.push('…');
+
+    truncated
+}
+
+/// Outcome of the GitHub fetches, so the dashboard can tell how fresh its data is
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GitHubRefresh {
+    pub succeeded_at: Option<DateTime<Utc>>,
+    // time and error of the last fetch, None when it succeeded or nothing was fetched yet
+    pub failed_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
 pub struct GitHubStateManager {
     state: Arc<RwLock<GitHubState>>,
+    refresh: RwLock<GitHubRefresh>,
 }
 
 impl GitHubStateManager {
     pub fn new() -> Self {
         Self {
             state: Arc::new(RwLock::new(GitHubState::default())),
+            refresh: RwLock::new(GitHubRefresh::default()),
         }
     }
 
     pub async fn update(&self, config: &Config) -> Result<()> {
-        let state = GitHubState::fetch(config).await?;
+        let state = match GitHubState::fetch(config).await {
+            Ok(state) => state,
+            Err(e) => {
+                let mut refresh = self.refresh.write();
+                refresh.failed_at = Some(Utc::now());
+                refresh.error = Some(e.to_string());
+
+                return Err(e);
+            }
+        };
+
+        *self.refresh.write() = GitHubRefresh {
+            succeeded_at: Some(Utc::now()),
+            failed_at: None,
+            error: None,
+        };
         self.set_state(state.clone());
 
         Ok(())
     }
 
-    pub fn get_commit_hashes(&self) -> Vec<String> {
-        self.state.read().get_commit_hashes()
+    pub fn fetch_error(&self) -> Option<String> {
+        self.refresh.read().error.clone()
+    }
+
+    pub fn get_refresh(&self) -> GitHubRefresh {
+        self.refresh.read().clone()
+    }
+
+    // Get all known commit hashes starting with the given prefix
+    pub fn get_commit_hashes_by_prefix(&self, prefix: &str) -> Vec<String> {
+        self.state
+            .read()
+            .get_commit_hashes()
+            .into_iter()
+            .filter(|hash| hash.starts_with(prefix))
+            .collect()
+    }
+
+    // Get the head commit hash of a pull request
+    pub fn get_pull_commit_hash(&self, number: i64) -> Option<CommitHash> {
+        self.state.read().get_pull_commit_hash(number)
     }
 
     pub fn get_state(&self) -> GitHubState {
         self.state.read().clone()
     }
 
+    // State for the dashboard, limited to the configured size
+    pub fn get_dashboard_state(&self, config: &Config) -> GitHubState {
+        self.get_shaped(GitHubLimits::from_config(config))
+    }
+
+    pub fn get_shaped(&self, limits: GitHubLimits) -> GitHubState {
+        self.state.read().shape(limits)
+    }
+
+    // Commit of the release with the given tag
+    pub fn release_commit(&self, tag: &str) -> Option<CommitHash> {
+        self.state
+            .read()
+            .releases
+            .iter()
+            .find(|release| release.tag_name == tag)
+            .map(|release| release.commit.hash.clone())
+    }
+
     fn set_state(&self, state: GitHubState) {
         *self.state.write() = state;
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+/// Size of the GitHub state sent to the dashboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GitHubLimits {
+    pub commits: usize,
+    pub pulls: usize,
+    pub message_length: usize,
+}
+
+impl GitHubLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            commits: config.github_max_commits,
+            pulls: config.github_max_pulls,
+            message_length: config.github_message_length,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct GitHubState {
     commits: Vec<Commit>,
@@ -136,6 +240,55 @@ impl GitHubState {
             .collect()
     }
 
+    pub fn is_release_commit(&self, hash: &str) -> bool {
+        self.releases
+            .iter()
+            .any(|release| release.commit.hash == hash)
+    }
+
+    pub fn is_pull_commit(&self, hash: &str) -> bool {
+        self.pulls.iter().any(|pull| pull.commit.hash == hash)
+    }
+
+    // Get the head commit hash of a pull request by number
+    pub fn get_pull_commit_hash(&self, number: i64) -> Option<CommitHash> {
+        self.pulls
+            .iter()
+            .find(|pull| pull.number == number)
+            .map(|pull| pull.commit.hash.clone())
+    }
+
+    // The most recent commits and pull requests, with truncated commit messages,
+    // the full state is kept for looking up commits
+    pub fn shape(&self, limits: GitHubLimits) -> Self {
+        let truncate = |commit: &Commit| Commit {
+            message: commit
+                .message
+                .as_deref()
+                .map(|message| truncate_message(message, limits.message_length)),
+            ..commit.clone()
+        };
+
+        Self {
+            commits: self
+                .commits
+                .iter()
+                .take(limits.commits)
+                .map(truncate)
+                .collect(),
+            releases: self.releases.clone(),
+            pulls: self
+                .pulls
+                .iter()
+                .take(limits.pulls)
+                .map(|pull| Pull {
+                    commit: truncate(&pull.commit),
+                    ..pull.clone()
+                })
+                .collect(),
+        }
+    }
+
     // Convert data returned from graphql to GitHubState
     async fn from_graphql(root: GraphRoot) -> anyhow::Result<Self> {
         let mut pulls = Vec::new();
@@ -278,27 +431,71 @@ structstruck::strike! {
     }
 }
 
+// Minimal authenticated request, to check the token and its access to the repository
+pub async fn check_access(config: &Config) -> Result<String> {
+    let repository = format!("{}/{}", config.github_owner, config.github_repo);
+
+    let response = reqwest::Client::new()
+        .get(format!("https://api.github.com/repos/{repository}"))
+        .header("User-Agent", "etes")
+        .bearer_auth(&config.github_token)
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await?;
+
+    match response.status() {
+        status if status.is_success() => Ok(format!("github_token can read {repository}")),
+        reqwest::StatusCode::UNAUTHORIZED => bail!("github_token is invalid or expired"),
+        reqwest::StatusCode::NOT_FOUND => {
+            bail!("{repository} does not exist, or github_token has no access to it")
+        }
+        status => bail!("GitHub responded with status {status}"),
+    }
+}
+
 // Refresh GitHub data when requested
 pub async fn refresh_github_data(state: AppState) -> Result<()> {
     let mut receiver = state.channel.get_receiver();
 
-    while let Ok(event) = receiver.recv().await {
-        let Event::GithubRefresh { user } = event else {
+    loop {
+        let event = match health::next_event(&mut receiver, &state.health, Worker::Github).await {
+            Ok(received) => received,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("GitHub worker missed {skipped} events");
+                state.channel.record_lag(skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let Event::Client(ClientEvent::GithubRefresh { user }) = event.event else {
             continue;
         };
 
-        match state.github.update(state.config).await {
-            Ok(_) => {
-                state.channel.send(Event::GithubState {
-                    payload: state.github.get_state(),
-                });
-            }
-            Err(e) => {
-                state.channel.send(Event::Error {
-                    user,
-                    message: format!("Failed to fetch GitHub data: {e}"),
-                });
+        info!(
+            "Received GitHub refresh from {user} ({} at {})",
+            event.origin, event.emitted_at
+        );
+
+        let result = state.github.update(&state.config()).await;
+
+        // the data is sent after a failed fetch too, so every dashboard shows it is stale
+        state.channel.send(ServerEvent::GithubState {
+            payload: state.github.get_dashboard_state(&state.config()),
+            refresh: state.github.get_refresh(),
+        });
+
+        match result {
+            // commits of closed pull requests are no longer known, their executables may be removed
+            Ok(()) => {
+                executable::spawn_cleanup(state.clone());
             }
+            Err(e) => state.channel.send(ServerEvent::Error {
+                user,
+                message: format!("Failed to fetch GitHub data: {e}"),
+                code: ErrorCode::GithubFetchFailed,
+                request_id: None,
+            }),
         }
     }
 
@@ -312,10 +509,40 @@ mod tests {
     #[tokio::test]
     #[ignore = "do not call propduction API's in tests"]
     async fn test_get_state() {
-        let config = Config::from_env().unwrap();
-        let state = GitHubState::fetch(config).await.unwrap();
+        let config = Config::from_env(&crate::config::ConfigArgs::default()).unwrap();
+        let state = GitHubState::fetch(&config).await.unwrap();
 
         assert!(!state.releases.is_empty());
         assert!(!state.pulls.is_empty());
     }
+
+    #[test]
+    fn test_shape() {
+        let commit = |hash: &str, message: &str| Commit {
+            hash: hash.to_string(),
+            message: Some(message.to_string()),
+            ..Default::default()
+        };
+
+        let state = GitHubState {
+            commits: vec![
+                commit("a", "Fix the proxy"),
+                commit("b", "Add a very long message"),
+                commit("c", "Initial commit"),
+            ],
+            releases: Vec::new(),
+            pulls: vec![Pull::default(); 3],
+        };
+
+        let shaped = state.shape(GitHubLimits {
+            commits: 2,
+            pulls: 1,
+            message_length: 13,
+        });
+
+        assert_eq!(shaped.commits.len(), 2);
+        assert_eq!(shaped.pulls.len(), 1);
+        assert_eq!(shaped.commits[0].message.as_deref(), Some("Fix the proxy"));
+        assert_eq!(shaped.commits[1].message.as_deref(), Some("Add a very l…"));
+    }
 }
@@ -0,0 +1,264 @@
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use utoipa::ToSchema;
+
+use crate::{AppState, alerts::Alerts, events::SequencedEvent, executable::check_bin_dir};
+
+// Workers waiting for events beat at least this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// A worker that hasn't beaten for this long is stuck or gone, a GitHub refresh
+// of a large repository can keep the GitHub worker busy for a while
+const WORKER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Background workers that keep etes running, without them the dashboard stops responding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Worker {
+    Github,
+    Services,
+    Monitor,
+}
+
+impl Worker {
+    pub const ALL: [Worker; 3] = [Worker::Github, Worker::Services, Worker::Monitor];
+
+    fn name(self) -> &'static str {
+        match self {
+            Worker::Github => "github",
+            Worker::Services => "services",
+            Worker::Monitor => "monitor",
+        }
+    }
+}
+
+/// Health of a worker, for the dashboard
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerStatus {
+    pub worker: Worker,
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    // Seconds the worker may be silent
+    pub timeout_seconds: u64,
+    pub healthy: bool,
+}
+
+/// Last heartbeat of every worker, in milliseconds since the epoch
+#[derive(Default)]
+pub struct WorkerHealth {
+    heartbeats: [AtomicI64; Worker::ALL.len()],
+}
+
+impl WorkerHealth {
+    pub fn beat(&self, worker: Worker) {
+        self.heartbeats[worker as usize].store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    // Health of the workers, the monitor beats once per monitor interval so it may be silent longer
+    pub fn get_state(&self, monitor_interval: Duration) -> Vec<WorkerStatus> {
+        let now = Utc::now();
+
+        Worker::ALL
+            .into_iter()
+            .map(|worker| {
+                let last_heartbeat = DateTime::from_timestamp_millis(
+                    self.heartbeats[worker as usize].load(Ordering::Relaxed),
+                )
+                .filter(|time| time.timestamp_millis() > 0);
+
+                let timeout = match worker {
+                    Worker::Monitor => WORKER_TIMEOUT.max(monitor_interval * 3),
+                    Worker::Github | Worker::Services => WORKER_TIMEOUT,
+                };
+
+                let healthy = last_heartbeat
+                    .is_some_and(|time| (now - time).to_std().unwrap_or_default() <= timeout);
+
+                WorkerStatus {
+                    worker,
+                    last_heartbeat,
+                    timeout_seconds: timeout.as_secs(),
+                    healthy,
+                }
+            })
+            .collect()
+    }
+}
+
+// Wait for the next event on the bus, beating while the bus is quiet,
+// so only a worker that stopped or is stuck goes silent
+pub async fn next_event(
+    receiver: &mut Receiver<SequencedEvent>,
+    health: &WorkerHealth,
+    worker: Worker,
+) -> Result<SequencedEvent, RecvError> {
+    loop {
+        health.beat(worker);
+
+        if let Ok(result) = tokio::time::timeout(HEARTBEAT_INTERVAL, receiver.recv()).await {
+            return result;
+        }
+    }
+}
+
+// Alert when a worker stops beating, from a task of its own so every worker is watched,
+// including the monitor
+pub async fn watch_workers(state: AppState) {
+    let mut alerts = Alerts::default();
+
+    loop {
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+
+        let workers = state.health.get_state(state.config().monitor_interval());
+
+        for event in alerts.check_workers(&workers) {
+            state.channel.send(event);
+        }
+    }
+}
+
+/// Check of the readiness probe that failed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FailedCheck {
+    // github_worker, services_worker, monitor_worker, bin_dir or github
+    check: String,
+    message: String,
+}
+
+/// Result of the readiness probe, ready when no check failed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    failing: Vec<FailedCheck>,
+}
+
+// Liveness probe for load balancers, etes is alive as long as it responds
+#[utoipa::path(
+    get,
+    path = "/etes/healthz",
+    responses((status = 200, description = "The process is up")),
+)]
+pub async fn healthz_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+// Readiness probe for load balancers, checks that the workers are beating and uploads can be stored
+#[utoipa::path(
+    get,
+    path = "/etes/readyz",
+    responses(
+        (status = 200, description = "Ready to receive traffic", body = Readiness),
+        (status = 503, description = "A check failed", body = Readiness),
+    ),
+)]
+pub async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let config = state.config();
+    let mut failing = Vec::new();
+
+    for status in state.health.get_state(config.monitor_interval()) {
+        if !status.healthy {
+            let message = match status.last_heartbeat {
+                Some(time) => format!("No heartbeat since {time}"),
+                None => "Not started".to_string(),
+            };
+
+            failing.push(FailedCheck {
+                check: format!("{}_worker", status.worker.name()),
+                message,
+            });
+        }
+    }
+
+    if let Err(e) = check_bin_dir(state.services.bin_dir()).await {
+        failing.push(FailedCheck {
+            check: "bin_dir".to_string(),
+            message: format!("{e:#}"),
+        });
+    }
+
+    if config.ready_requires_github
+        && let Some(error) = state.github.fetch_error()
+    {
+        failing.push(FailedCheck {
+            check: "github".to_string(),
+            message: error,
+        });
+    }
+
+    let status = if failing.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Readiness {
+            ready: failing.is_empty(),
+            failing,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use std::{sync::atomic::Ordering, time::Duration};
+    use tower::ServiceExt;
+
+    use super::{Worker, WorkerHealth};
+    use crate::{app, config::ConfigArgs, executable::prepare_bin_dir};
+
+    #[test]
+    fn test_worker_health() {
+        let health = WorkerHealth::default();
+        health.beat(Worker::Services);
+
+        // the monitor beat long ago, but its interval is long as well
+        let hour_ago = chrono::Utc::now().timestamp_millis() - 60 * 60 * 1000;
+        health.heartbeats[Worker::Monitor as usize].store(hour_ago, Ordering::Relaxed);
+
+        let state = health.get_state(Duration::from_secs(10));
+        assert!(!state[0].healthy);
+        assert!(state[0].last_heartbeat.is_none());
+        assert!(state[1].healthy);
+        assert!(!state[2].healthy);
+
+        let state = health.get_state(Duration::from_secs(30 * 60));
+        assert!(state[2].healthy);
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
+        prepare_bin_dir(state.services.bin_dir()).await.unwrap();
+
+        let request = |uri| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(request("/etes/healthz")).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        // the workers are not spawned
+        let response = app.clone().oneshot(request("/etes/readyz")).await.unwrap();
+        assert_eq!(response.status(), 503);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(readiness["failing"][0]["check"], "github_worker");
+        assert_eq!(readiness["failing"].as_array().unwrap().len(), 3);
+
+        for worker in Worker::ALL {
+            state.health.beat(worker);
+        }
+
+        let response = app.oneshot(request("/etes/readyz")).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+}
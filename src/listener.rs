@@ -0,0 +1,280 @@
+use anyhow::{Context, Result, bail};
+use axum::{extract::Request, extract::connect_info::Connected};
+use axum_server::{Address, Handle, Server, service::MakeService};
+use hyper::body::Incoming;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::Permissions,
+    io,
+    net::SocketAddr,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::SocketAddr as UnixSocketAddr,
+    },
+    path::PathBuf,
+};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{config::ListenAddr, tls::Certificate};
+
+/// Bound socket of the server or the proxy
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+// Connections waiting to be accepted
+const BACKLOG: i32 = 1024;
+
+impl Listener {
+    // Bind to every address of a server, IPv6 sockets only accept IPv6 connections when there
+    // are more addresses, so [::]:80 and 0.0.0.0:80 can be combined
+    pub async fn bind_all(addrs: &[ListenAddr], socket_mode: u32) -> Result<Vec<Self>> {
+        let only_v6 = addrs.len() > 1;
+        let mut listeners = Vec::with_capacity(addrs.len());
+
+        for addr in addrs {
+            listeners.push(Self::bind(addr, socket_mode, only_v6).await?);
+        }
+
+        Ok(listeners)
+    }
+
+    // Bind to the address, a socket file left behind by a previous run is removed first
+    async fn bind(addr: &ListenAddr, socket_mode: u32, only_v6: bool) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = bind_tcp(*addr, only_v6)
+                    .with_context(|| format!("Failed to listen on {addr}"))?;
+
+                Ok(Listener::Tcp(listener))
+            }
+            ListenAddr::Unix(path) => {
+                if let Ok(metadata) = tokio::fs::symlink_metadata(path).await {
+                    if !metadata.file_type().is_socket() {
+                        bail!(
+                            "Failed to listen on {}, the file exists and is not a socket",
+                            path.display()
+                        );
+                    }
+
+                    info!("Removing stale socket {}", path.display());
+                    tokio::fs::remove_file(path).await?;
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen on {}", path.display()))?;
+
+                tokio::fs::set_permissions(path, Permissions::from_mode(socket_mode))
+                    .await
+                    .with_context(|| format!("Failed to set permissions of {}", path.display()))?;
+
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+}
+
+// Check that the addresses can be bound, the sockets are closed when the result is dropped.
+// Unix domain sockets may be in use by a running etes, only their directory is checked
+pub fn try_bind(addrs: &[ListenAddr]) -> Result<Vec<TcpListener>> {
+    let only_v6 = addrs.len() > 1;
+    let mut listeners = Vec::new();
+
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp(addr) => listeners.push(
+                bind_tcp(*addr, only_v6).with_context(|| format!("Failed to listen on {addr}"))?,
+            ),
+            ListenAddr::Unix(path) => {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+
+                if dir.is_some_and(|dir| !dir.is_dir()) {
+                    bail!(
+                        "Failed to listen on {}, the directory does not exist",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(listeners)
+}
+
+// Bind a TCP socket, IPv6 sockets also accept IPv4 connections as mapped addresses
+// unless only_v6 is set, regardless of the default of the OS
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "unknown address"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Address a connection came from, connections over a unix domain socket have no IP address
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub Option<SocketAddr>);
+
+impl Connected<SocketAddr> for ClientAddr {
+    fn connect_info(addr: SocketAddr) -> Self {
+        ClientAddr(Some(addr))
+    }
+}
+
+impl Connected<UnixSocketAddr> for ClientAddr {
+    fn connect_info(_: UnixSocketAddr) -> Self {
+        ClientAddr(None)
+    }
+}
+
+// Handle that stops accepting connections on shutdown, and lets in-flight requests complete
+fn shutdown_handle<A: Address + Send + Sync + 'static>(shutdown: CancellationToken) -> Handle<A> {
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    handle
+}
+
+// Serve on the listener until shutdown, over TLS when a certificate is given
+pub async fn serve<M>(
+    listener: Listener,
+    certificate: Option<Certificate>,
+    make_service: M,
+    shutdown: CancellationToken,
+) -> io::Result<()>
+where
+    M: MakeService<SocketAddr, Request<Incoming>> + MakeService<UnixSocketAddr, Request<Incoming>>,
+{
+    match listener {
+        Listener::Tcp(listener) => {
+            let server =
+                Server::<SocketAddr>::from_listener(listener).handle(shutdown_handle(shutdown));
+
+            match certificate {
+                Some(certificate) => {
+                    server
+                        .acceptor(certificate.acceptor())
+                        .serve(make_service)
+                        .await
+                }
+                None => server.serve(make_service).await,
+            }
+        }
+        Listener::Unix(listener, _) => {
+            let server =
+                Server::<UnixSocketAddr>::from_listener(listener).handle(shutdown_handle(shutdown));
+
+            match certificate {
+                Some(certificate) => {
+                    server
+                        .acceptor(certificate.acceptor())
+                        .serve(make_service)
+                        .await
+                }
+                None => server.serve(make_service).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Listener, try_bind};
+    use crate::config::ListenAddr;
+
+    #[tokio::test]
+    async fn test_unix_listener() {
+        let path = std::env::temp_dir().join(format!("etes-{}.sock", std::process::id()));
+        let addr = ListenAddr::Unix(path.clone());
+
+        let listener = Listener::bind(&addr, 0o600, false).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // the socket file is left behind, and removed when binding again
+        drop(listener);
+        assert!(path.exists());
+        let listener = Listener::bind(&addr, 0o660, false).await.unwrap();
+        assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+        drop(listener);
+
+        // other files are never removed
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(Listener::bind(&addr, 0o660, false).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_listener() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            eprintln!("IPv6 is not available, skipping");
+            return;
+        }
+
+        let addrs = [
+            ListenAddr::Tcp("[::1]:0".parse().unwrap()),
+            ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+        ];
+        let listeners = Listener::bind_all(&addrs, 0o660).await.unwrap();
+
+        let Listener::Tcp(listener) = &listeners[0] else {
+            panic!("expected a TCP listener");
+        };
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv6());
+        assert_eq!(listeners[0].to_string(), format!("[::1]:{}", addr.port()));
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        connected.unwrap();
+        assert!(accepted.unwrap().1.ip().is_loopback());
+
+        assert!(listeners[1].to_string().starts_with("127.0.0.1:"));
+    }
+
+    #[tokio::test]
+    async fn test_try_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken = ListenAddr::Tcp(listener.local_addr().unwrap());
+        let free = ListenAddr::Tcp("127.0.0.1:0".parse().unwrap());
+
+        assert_eq!(try_bind(std::slice::from_ref(&free)).unwrap().len(), 1);
+        assert!(try_bind(&[free, taken]).is_err());
+
+        // unix sockets are never touched, only their directory is checked
+        let missing = ListenAddr::Unix("/nonexistent/etes.sock".into());
+        assert!(try_bind(&[missing]).is_err());
+    }
+}
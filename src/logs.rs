@@ -0,0 +1,142 @@
+use parking_lot::{Mutex, RwLock};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::broadcast::{self, error::RecvError},
+};
+
+// Number of output lines of a service kept for the detail view
+const LOG_LINES: usize = 100;
+
+// Number of lines buffered for a slow subscriber before it misses lines
+const LOG_CHANNEL_CAPACITY: usize = 256;
+
+/// Output of a service, kept apart from the event bus so a chatty service
+/// can't crowd out other events
+#[derive(Debug, Default)]
+pub struct ServiceLogs {
+    recent: RwLock<VecDeque<String>>,
+    // live output for subscribed clients, only exists while someone listens
+    channel: Mutex<Option<broadcast::Sender<String>>>,
+}
+
+impl ServiceLogs {
+    pub fn recent(&self) -> Vec<String> {
+        self.recent.read().iter().cloned().collect()
+    }
+
+    fn push(&self, line: String) {
+        {
+            let mut recent = self.recent.write();
+
+            if recent.len() == LOG_LINES {
+                recent.pop_front();
+            }
+
+            recent.push_back(line.clone());
+        }
+
+        if let Some(sender) = self.channel.lock().as_ref() {
+            let _ = sender.send(line);
+        }
+    }
+
+    // Follow the output, the channel is created for the first subscriber
+    pub fn subscribe(self: &Arc<Self>) -> LogSubscription {
+        let receiver = self
+            .channel
+            .lock()
+            .get_or_insert_with(|| broadcast::channel(LOG_CHANNEL_CAPACITY).0)
+            .subscribe();
+
+        LogSubscription {
+            logs: self.clone(),
+            receiver: Some(receiver),
+        }
+    }
+
+    // Drop the channel, subscribers see the end of the output
+    pub fn close(&self) {
+        self.channel.lock().take();
+    }
+}
+
+/// Live output of a service, the channel is dropped when the last subscription is
+pub struct LogSubscription {
+    logs: Arc<ServiceLogs>,
+    receiver: Option<broadcast::Receiver<String>>,
+}
+
+impl LogSubscription {
+    // Wait for the next line, None when the service stopped
+    pub async fn recv(&mut self) -> Option<Result<String, u64>> {
+        match self.receiver.as_mut()?.recv().await {
+            Ok(line) => Some(Ok(line)),
+            Err(RecvError::Lagged(skipped)) => Some(Err(skipped)),
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+impl Drop for LogSubscription {
+    fn drop(&mut self) {
+        drop(self.receiver.take());
+
+        let mut channel = self.logs.channel.lock();
+
+        if channel
+            .as_ref()
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            channel.take();
+        }
+    }
+}
+
+// Keep the output of a service, and pass it on to our own output
+pub async fn capture_output(output: impl AsyncRead + Unpin, logs: Arc<ServiceLogs>, stderr: bool) {
+    let mut lines = BufReader::new(output).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if stderr {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+
+        logs.push(line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::ServiceLogs;
+
+    #[tokio::test]
+    async fn test_log_channel_lifetime() {
+        let logs = Arc::new(ServiceLogs::default());
+
+        logs.push("before".to_string());
+        assert!(logs.channel.lock().is_none());
+
+        let mut first = logs.subscribe();
+        let second = logs.subscribe();
+
+        logs.push("during".to_string());
+        assert_eq!(first.recv().await, Some(Ok("during".to_string())));
+
+        drop(second);
+        assert!(logs.channel.lock().is_some());
+
+        drop(first);
+        assert!(logs.channel.lock().is_none());
+
+        let mut third = logs.subscribe();
+        logs.close();
+        assert_eq!(third.recv().await, None);
+
+        assert_eq!(logs.recent(), vec!["before", "during"]);
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use auth::GithubOauthService;
 use axum::{
     Router,
@@ -8,48 +8,90 @@ use axum::{
     middleware::{self, Next},
     routing::{any, get, put},
 };
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
 use cookie::Key;
+use futures::future::join_all;
 use github::GitHubStateManager;
+use hyper_rustls::HttpsConnector;
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
+use parking_lot::RwLock;
 use std::{ops::Deref, sync::Arc};
-use tracing::{error, info};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use ws::ws_handler;
 
 use crate::{
-    config::Config, data::data_handler, events::EventManager, monitor::SystemMonitor,
-    services::ServiceManager, upload::upload_handler,
+    config::{Config, ConfigArgs, Favicon},
+    data::data_handler,
+    events::{EventManager, ServerEvent},
+    health::WorkerHealth,
+    listener::{ClientAddr, Listener},
+    monitor::SystemMonitor,
+    presence::PresenceTracker,
+    services::ServiceManager,
+    sinks::{FileSink, WebhookSink},
+    tls::Certificates,
+    upload::upload_handler,
+    version::VersionInfo,
 };
 
 pub const GITHUB_BASE_URL: &str = "https://github.com";
 
+mod alerts;
+mod api;
 mod auth;
+mod cgroup;
+mod check;
 mod config;
+mod cors;
 mod data;
+mod download;
 mod error;
 mod events;
 mod executable;
 mod github;
+mod health;
+mod listener;
+mod logs;
+mod metrics;
 mod monitor;
+mod notify;
+mod openapi;
+mod presence;
 mod proxy;
+mod resume;
 mod service;
 mod services;
+mod sinks;
+mod tls;
 mod upload;
 mod user;
 mod util;
+mod version;
 mod ws;
 
-type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
+type Client = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Body>;
 
 // Global application state
 struct AppStateContainer {
-    config: &'static Config,
+    // replaced when the configuration is reloaded, see `reload_config`
+    config: RwLock<Arc<Config>>,
+    // command line flags, applied again when the configuration is reloaded
+    config_args: ConfigArgs,
     client: Client,
     oauth: GithubOauthService,
     github: GitHubStateManager,
     services: ServiceManager,
     channel: EventManager,
     monitor: SystemMonitor,
+    // heartbeats of the background workers, checked by the monitor
+    health: WorkerHealth,
+    presence: PresenceTracker,
+    version: VersionInfo,
+    shutdown: CancellationToken,
 }
 
 #[derive(Clone)]
@@ -82,28 +124,64 @@ impl From<AppStateContainer> for AppState {
 }
 
 impl AppStateContainer {
-    fn new() -> Result<Self> {
-        let config = Config::from_env()?;
+    fn new(config_args: ConfigArgs) -> Result<Self> {
+        let config = Config::from_env(&config_args)?;
+
+        // Services are plain HTTP on localhost, external services may use HTTPS
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_provider_and_webpki_roots(rustls::crypto::ring::default_provider())?
+            .https_or_http()
+            .enable_http1()
+            .build();
 
         let client: Client =
             hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-                .build(HttpConnector::new());
+                .build(connector);
 
-        let oauth = GithubOauthService::new(config)?;
+        let oauth = GithubOauthService::new(&config)?;
+        let services = ServiceManager::new(config.bin_dir.clone());
+        let channel = EventManager::new(config.event_channel_capacity);
 
         Ok(Self {
-            config,
+            config: RwLock::new(Arc::new(config)),
+            config_args,
             oauth,
             client,
             github: GitHubStateManager::new(),
-            services: ServiceManager::new(),
-            channel: EventManager::new(),
+            services,
+            channel,
             monitor: SystemMonitor::new(),
+            health: WorkerHealth::default(),
+            presence: PresenceTracker::new(),
+            version: VersionInfo::new(),
+            shutdown: CancellationToken::new(),
         })
     }
 
+    // Current configuration, hold on to it for settings that must be consistent within a request
+    fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
     async fn init(state: AppState) {
-        if let Err(e) = state.github.update(state.config).await {
+        info!(
+            "Services are started from executables built for {}",
+            executable::host_platform()
+        );
+        info!(
+            "Event bus capacity is {} events",
+            state.config().event_channel_capacity
+        );
+
+        if let Some(path) = &state.config().event_log_file {
+            state.channel.add_sink(FileSink::new(path.into()));
+        }
+
+        for webhook in &state.config().event_webhooks {
+            state.channel.add_sink(WebhookSink::new(webhook.clone()));
+        }
+
+        if let Err(e) = state.github.update(&state.config()).await {
             error!("Failed to fetch GitHub data: {e:?}");
         }
 
@@ -114,12 +192,26 @@ impl AppStateContainer {
 
     async fn spawn_workers(state: AppState) {
         tokio::spawn(monitor::send_updates(state.clone()));
+        tokio::spawn(presence::send_updates(state.clone()));
         tokio::spawn(github::refresh_github_data(state.clone()));
         tokio::spawn(services::start_and_stop_services(state.clone()));
+        tokio::spawn(executable::cleanup_periodically(state.clone()));
+        tokio::spawn(health::watch_workers(state.clone()));
+
+        tokio::spawn(notify::send_notifications(state.clone()));
     }
 }
 
-fn build_csp_header() -> String {
+fn build_csp_header(favicon: &Favicon) -> String {
+    // a favicon URL is allowed next to the GitHub avatars
+    let img_src = match favicon {
+        Favicon::Url(url) => format!(
+            "img-src 'self' https://avatars.githubusercontent.com {}",
+            url.origin().ascii_serialization()
+        ),
+        _ => "img-src 'self' https://avatars.githubusercontent.com".to_string(),
+    };
+
     [
         "default-src 'none'",
         "base-uri 'none'",
@@ -127,7 +219,7 @@ fn build_csp_header() -> String {
         "object-src 'none'",
         "script-src 'self'",
         "style-src 'self' 'unsafe-inline'",
-        "img-src 'self' https://avatars.githubusercontent.com",
+        &img_src,
         "font-src 'self'",
         "connect-src 'self'",
         "manifest-src 'none'",
@@ -136,38 +228,89 @@ fn build_csp_header() -> String {
     .join("; ")
 }
 
-async fn app(with_frontend: bool) -> Result<(AppState, Router)> {
-    let state: AppState = AppStateContainer::new()?.into();
+async fn app(with_frontend: bool, config_args: ConfigArgs) -> Result<(AppState, Router)> {
+    let state: AppState = AppStateContainer::new(config_args)?.into();
 
-    let mut app = Router::new()
-        .route("/favicon.svg", get(favicon_svg))
-        .route("/etes/login", get(auth::login))
-        .route("/etes/logout", get(auth::logout))
-        .route("/etes/authorize", get(auth::authorize))
+    let mut api = Router::new()
         .route("/etes/api/v1/ws/{caller}", get(ws_handler))
         .route(
             "/etes/api/v1/executable/{trigger_hash}/{build_hash}",
-            put(upload_handler),
+            put(upload_handler).get(download::download_handler),
         )
         .route("/etes/api/v1/data/{caller}", get(data_handler))
-        .with_state(state.clone());
+        .route("/etes/api/v1/version", get(version::version_handler))
+        .route(
+            "/etes/api/v1/services",
+            get(api::list_services_handler).post(api::start_service_handler),
+        )
+        .route(
+            "/etes/api/v1/services/{name}",
+            get(api::get_service_handler).delete(api::stop_service_handler),
+        )
+        .route(
+            "/etes/api/v1/admin/connections",
+            get(presence::connections_handler),
+        )
+        .route("/etes/api/v1/openapi.json", get(openapi::openapi_handler))
+        .route("/etes/api/docs", get(openapi::swagger_ui_handler));
+
+    // other sites only get access to the API
+    if let Some(cors) = cors::cors_layer(&state.config()) {
+        api = api.layer(cors);
+    }
+
+    let mut app = Router::new()
+        .route("/favicon.svg", get(favicon_svg))
+        .route("/etes/healthz", get(health::healthz_handler))
+        .route("/etes/readyz", get(health::readyz_handler))
+        .route("/etes/login", get(auth::login))
+        .route("/etes/logout", get(auth::logout))
+        .route("/etes/authorize", get(auth::authorize))
+        .merge(api)
+        .with_state(state.clone())
+        .layer(middleware::from_fn(error::negotiate_errors));
+
+    let favicon = state.config().favicon();
+
+    // read the image once, the path is checked when the configuration is loaded
+    if let Favicon::File(path) = &favicon {
+        let image = Bytes::from(
+            std::fs::read(path)
+                .with_context(|| format!("Failed to read favicon {}", path.display()))?,
+        );
+        let content_type = Favicon::content_type(path).unwrap_or("image/x-icon");
+
+        app = app.route(
+            "/etes/favicon.ico",
+            get(move || async move {
+                (
+                    [
+                        (header::CONTENT_TYPE, content_type),
+                        (header::CACHE_CONTROL, "public, max-age=86400"),
+                    ],
+                    image,
+                )
+            }),
+        );
+    }
 
     if with_frontend {
-        let index =
-            include_str!("../frontend/index.html").replace("%FAVICON%", &state.config.favicon);
-        let frontend = spaxum::load!(&state.config.title).set_html_template(index);
+        let index = include_str!("../frontend/index.html").replace("%FAVICON%", &favicon.link());
+        let frontend = spaxum::load!(&state.config().title).set_html_template(index);
         app = app.merge(frontend.router());
 
         // add CSP for release builds
         if !cfg!(debug_assertions) {
-            let csp = build_csp_header();
+            let csp = build_csp_header(&favicon);
             let csp_header = HeaderValue::from_str(&csp).expect("CSP header value must be ASCII");
             app = app.layer(middleware::from_fn(move |req, next: Next| {
                 let csp_header = csp_header.clone();
                 async move {
                     let mut res = next.run(req).await;
+                    // the API documentation sets its own policy
                     res.headers_mut()
-                        .insert(CONTENT_SECURITY_POLICY, csp_header);
+                        .entry(CONTENT_SECURITY_POLICY)
+                        .or_insert(csp_header);
                     res
                 }
             }));
@@ -180,7 +323,7 @@ async fn app(with_frontend: bool) -> Result<(AppState, Router)> {
 async fn favicon_svg(State(state): State<AppState>) -> impl axum::response::IntoResponse {
     let svg = format!(
         "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 100 100\"><text y=\".9em\" font-size=\"90\">{}</text></svg>",
-        state.config.favicon
+        state.config().favicon
     );
 
     (
@@ -192,14 +335,101 @@ async fn favicon_svg(State(state): State<AppState>) -> impl axum::response::Into
     )
 }
 
+// Wait for ctrl-c or SIGTERM, then tell sockets and servers to shut down
+async fn shutdown_signal(state: AppState) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {e:?}");
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+
+    info!("Shutting down");
+    state.shutdown.cancel();
+}
+
+// Reload the configuration and TLS certificates on SIGHUP, settings that are only used at startup need a restart
+async fn reload_config(state: AppState, certificates: Certificates) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {e:?}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Err(e) = certificates.reload().await {
+            error!("Failed to reload TLS certificates, keeping the current ones: {e:?}");
+        }
+
+        let config = match Config::from_env(&state.config_args) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to reload configuration, keeping the current one: {e:?}");
+                continue;
+            }
+        };
+
+        let restart_required = state.config().restart_required(&config);
+        if !restart_required.is_empty() {
+            warn!(
+                "Changes to {} take effect after a restart",
+                restart_required.join(", ")
+            );
+        }
+
+        *state.config.write() = Arc::new(config);
+
+        info!("Configuration reloaded");
+        state.channel.send(ServerEvent::ConfigReloaded);
+    }
+}
+
+/// Ephemeral test environments for the pull requests and releases of a GitHub repository
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Log filter like etes=debug, instead of RUST_LOG
+    #[arg(long, global = true)]
+    log: Option<String>,
+    /// Print the effective configuration with secrets redacted, and exit
+    #[arg(long, global = true)]
+    print_config: bool,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the dashboard and the proxy, the default
+    Serve,
+    /// Check the configuration, bin directory, GitHub token, OAuth app and listeners, and exit
+    Check,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let filter = match &cli.log {
+        Some(log) => tracing_subscriber::EnvFilter::try_new(log)
+            .with_context(|| format!("Invalid log filter {log:?}"))?,
+        None => tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
+        }),
+    };
+
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
-            }),
-        )
+        .with(filter)
         .with(
             tracing_subscriber::fmt::layer()
                 .with_ansi(false)
@@ -207,29 +437,101 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let (state, app) = app(true).await?;
+    if cli.print_config {
+        print!("{}", Config::from_env(&cli.config)?.redacted()?);
+
+        return Ok(());
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(cli.config).await,
+        Command::Check => check::check(&cli.config).await,
+    }
+}
+
+async fn serve(config_args: ConfigArgs) -> Result<()> {
+    let (state, app) = app(true, config_args).await?;
+
+    executable::prepare_bin_dir(state.services.bin_dir()).await?;
+
+    if let Err(e) = executable::migrate_bin_dir(state.services.bin_dir()).await {
+        error!("Failed to move executables into the directories of their trigger hash: {e:?}");
+    }
+
+    if let Err(e) = state.services.load_protected().await {
+        error!("Failed to load the protected executables: {e:?}");
+    }
+
+    // the listeners are bound below, failures of the other checks show up in the log
+    let config = state.config();
+    if config.startup_checks {
+        tokio::spawn(async move { check::log_checks(&config).await });
+    }
 
     AppStateContainer::init(state.clone()).await;
     AppStateContainer::spawn_workers(state.clone()).await;
 
+    let certificates = Certificates::load(&state.config()).await?;
+
     let proxy_app: Router = Router::new()
         .fallback(any(proxy::handler))
         .with_state(state.clone());
 
-    let listener_addr = format!("127.0.0.1:{}", state.config.server_port);
-    let proxy_listener_addr = format!("127.0.0.1:{}", state.config.proxy_port);
-    let listener = tokio::net::TcpListener::bind(&listener_addr).await?;
-    let proxy_listener = tokio::net::TcpListener::bind(&proxy_listener_addr).await?;
+    let config = state.config();
+    let socket_mode = config.unix_socket_mode()?;
+    let listeners = Listener::bind_all(&config.server_addrs()?, socket_mode).await?;
+    let proxy_listeners = Listener::bind_all(&config.proxy_addrs()?, socket_mode)
+        .await
+        .context("Failed to start the proxy")?;
+
+    let urls = |listeners: &[Listener], certificate: &Option<tls::Certificate>| {
+        let scheme = match certificate {
+            Some(_) => "https",
+            None => "http",
+        };
+
+        listeners
+            .iter()
+            .map(|listener| format!("{scheme}://{listener}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
 
     info!(
-        "Starting server on {} and {}",
-        listener.local_addr()?,
-        proxy_listener.local_addr()?
+        "Starting {} on {} and proxy on {}",
+        state.version,
+        urls(&listeners, &certificates.server),
+        urls(&proxy_listeners, &certificates.proxy)
     );
 
-    tokio::select! {
-        _ = axum::serve(listener, app) => {}
-        _ = axum::serve(proxy_listener, proxy_app) => {}
+    tokio::spawn(shutdown_signal(state.clone()));
+    tokio::spawn(reload_config(state.clone(), certificates.clone()));
+
+    // every listener of a server serves the same router
+    let server = listeners.into_iter().map(|listener| {
+        listener::serve(
+            listener,
+            certificates.server.clone(),
+            app.clone()
+                .into_make_service_with_connect_info::<ClientAddr>(),
+            state.shutdown.clone(),
+        )
+    });
+    let proxy = proxy_listeners.into_iter().map(|listener| {
+        listener::serve(
+            listener,
+            certificates.proxy.clone(),
+            proxy_app
+                .clone()
+                .into_make_service_with_connect_info::<ClientAddr>(),
+            state.shutdown.clone(),
+        )
+    });
+
+    let (server, proxy) = tokio::join!(join_all(server), join_all(proxy));
+
+    for result in server.into_iter().chain(proxy) {
+        result?;
     }
 
     Ok(())
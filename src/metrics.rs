@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+// Upper bounds of the latency histogram buckets in milliseconds, the last bucket is unbounded
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [5, 10, 25, 50, 100, 250, 1000, 5000];
+
+/// Proxy metrics of a single service, updated without locking
+#[derive(Debug, Default)]
+pub struct ServiceMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    latency_ms_total: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+/// Snapshot of the proxy metrics of a service for the client
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsData {
+    pub requests: u64,
+    pub errors: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub average_latency_ms: Option<u64>,
+    // request counts per bucket of LATENCY_BUCKETS_MS, followed by the overflow bucket
+    pub latency: Vec<u64>,
+}
+
+/// Health of the internal event bus, for admins
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BusMetrics {
+    pub capacity: usize,
+    pub receivers: usize,
+    pub sent: u64,
+    pub send_errors: u64,
+    pub lagged: u64,
+    // most events queued for the slowest receiver at once
+    pub high_water_mark: usize,
+    // number of events sent per event type
+    pub events: BTreeMap<String, u64>,
+    // number of events not delivered per event sink
+    pub sink_errors: BTreeMap<String, u64>,
+}
+
+impl ServiceMetrics {
+    // Count a request forwarded to the service, the latency is the time until the response headers
+    pub fn record_request(&self, error: bool, latency: Duration) {
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.latency_ms_total
+            .fetch_add(latency_ms, Ordering::Relaxed);
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsData {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let latency_ms_total = self.latency_ms_total.load(Ordering::Relaxed);
+
+        MetricsData {
+            requests,
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            average_latency_ms: latency_ms_total.checked_div(requests),
+            latency: self
+                .latency
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ServiceMetrics;
+
+    #[test]
+    fn test_record_request() {
+        let metrics = ServiceMetrics::default();
+
+        metrics.record_request(false, Duration::from_millis(3));
+        metrics.record_request(false, Duration::from_millis(10));
+        metrics.record_request(true, Duration::from_millis(7000));
+        metrics.add_bytes_in(12);
+        metrics.add_bytes_out(100);
+        metrics.add_bytes_out(20);
+
+        let data = metrics.snapshot();
+
+        assert_eq!(data.requests, 3);
+        assert_eq!(data.errors, 1);
+        assert_eq!(data.bytes_in, 12);
+        assert_eq!(data.bytes_out, 120);
+        assert_eq!(data.average_latency_ms, Some(2337));
+        assert_eq!(data.latency, vec![1, 1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+}
@@ -1,68 +1,603 @@
+use chrono::{DateTime, Utc};
 use parking_lot::RwLock;
 use serde::Serialize;
-use std::sync::Arc;
-use sysinfo::System;
-use tracing::error;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use sysinfo::{
+    Disks, MINIMUM_CPU_UPDATE_INTERVAL, Pid, ProcessRefreshKind, ProcessesToUpdate, System,
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
 
-use crate::{AppState, events::Event, executable};
+use crate::{
+    AppState,
+    alerts::Alerts,
+    cgroup::{self, MemorySource},
+    events::{ServerEvent, ServiceCounts},
+    health::Worker,
+};
 
-#[derive(Clone, Serialize)]
-pub struct MemoryState {
-    used: u64,
-    total: u64,
+/// Memory, CPU and disk usage of the machine, the memory fields are those of the former memory state
+#[derive(Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct SystemState {
+    pub used: u64,
+    pub total: u64,
+    // Whether used and total are those of the host or the limit of the container
+    pub memory_source: MemorySource,
+    pub swap_used: u64,
+    pub swap_total: u64,
+    // CPU utilization of all cores together, as a percentage
+    pub cpu: f32,
+    pub cores: usize,
+    // Total size of the uploaded binaries
+    pub bin_size: u64,
+    // Free space on the filesystem of the binaries, if it could be determined
+    pub disk_free: Option<u64>,
+    // 1, 5 and 15 minute load averages, not available on Windows
+    pub load_average: Option<[f64; 3]>,
+    // Seconds since the host booted
+    pub uptime: u64,
+    // Kept up to date by the service manager, not by the measurements
+    pub services: ServiceCounts,
+    // Open file descriptors of etes and the soft limit, only measured on Linux
+    pub fds: Option<u64>,
+    pub fd_limit: Option<u64>,
+    // Running processes of the services, including their child processes
+    pub processes: usize,
 }
 
+// Sent as memory_state, the CPU fields were added to the existing event
+impl From<SystemState> for ServerEvent {
+    fn from(state: SystemState) -> Self {
+        ServerEvent::MemoryState {
+            used: state.used,
+            total: state.total,
+            memory_source: state.memory_source,
+            swap_used: state.swap_used,
+            swap_total: state.swap_total,
+            cpu: state.cpu,
+            cores: state.cores,
+            bin_size: state.bin_size,
+            disk_free: state.disk_free,
+            load_average: state.load_average,
+            uptime: state.uptime,
+            services: state.services,
+            fds: state.fds,
+            fd_limit: state.fd_limit,
+            processes: state.processes,
+        }
+    }
+}
+
+/// Measurement of the monitor at one moment, for the sparklines on the dashboard
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct MonitorSample {
+    pub time: DateTime<Utc>,
+    pub used: u64,
+    pub total: u64,
+    pub cpu: f32,
+    pub services: ServiceCounts,
+}
+
+impl From<&SystemState> for MonitorSample {
+    fn from(state: &SystemState) -> Self {
+        Self {
+            time: Utc::now(),
+            used: state.used,
+            total: state.total,
+            cpu: state.cpu,
+            services: state.services,
+        }
+    }
+}
+
+// Samples older than this are dropped from the history
+const HISTORY_DURATION: Duration = Duration::from_secs(30 * 60);
+
+// Most samples kept, one per second at the shortest monitor interval, which bounds
+// the memory used by the history regardless of the interval
+const MAX_HISTORY_SAMPLES: usize = 1800;
+
+// Number of samples of the history sent to clients
+pub const HISTORY_POINTS: usize = 90;
+
 pub struct SystemMonitor {
-    state: Arc<RwLock<MemoryState>>,
+    state: Arc<RwLock<SystemState>>,
+    // Recent samples, oldest first, kept across config reloads but not restarts
+    history: RwLock<VecDeque<MonitorSample>>,
 }
 
 impl SystemMonitor {
     pub fn new() -> Self {
         Self {
-            state: Arc::new(RwLock::new(MemoryState { used: 0, total: 0 })),
+            state: Arc::new(RwLock::new(SystemState::default())),
+            history: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    // Evenly spread samples of the last half hour, at most the given number of them
+    pub fn get_history(&self, points: usize) -> Vec<MonitorSample> {
+        let history = self.history.read();
+        let len = history.len();
+
+        if len <= points {
+            return history.iter().cloned().collect();
         }
+
+        // the latest sample is always included
+        (1..=points)
+            .map(|point| history[point * len / points - 1].clone())
+            .collect()
     }
 
-    // Get the current memory state
-    pub fn get_state(&self) -> MemoryState {
+    // Get the current system state
+    pub fn get_state(&self) -> SystemState {
         self.state.read().clone()
     }
 
-    // Update the memory state
-    pub fn update(&self, used: u64, total: u64) {
-        let mut state = self.state.write();
-        state.used = used;
-        state.total = total;
+    // Update the measured system state, returns it with the current service counts
+    pub fn update(&self, mut state: SystemState) -> SystemState {
+        let mut current = self.state.write();
+        state.services = current.services;
+        *current = state.clone();
+
+        let mut history = self.history.write();
+        history.push_back((&state).into());
+
+        while history.len() > MAX_HISTORY_SAMPLES
+            || history.front().is_some_and(|sample| {
+                (Utc::now() - sample.time).to_std().unwrap_or_default() > HISTORY_DURATION
+            })
+        {
+            history.pop_front();
+        }
+
+        state
+    }
+
+    // Update the service counts, returns the new state if they changed
+    pub fn update_service_counts(&self, counts: ServiceCounts) -> Option<SystemState> {
+        let mut current = self.state.write();
+
+        if current.services == counts {
+            return None;
+        }
+
+        current.services = counts;
+
+        Some(current.clone())
+    }
+}
+
+// Changes in memory usage below 1/100th of the total are not sent
+const MEMORY_DELTA_DIVISOR: u64 = 100;
+
+// Changes in CPU usage below this many percentage points are not sent
+const CPU_DELTA: f32 = 5.0;
+
+// Changes in the 1 minute load average below this are not sent, the uptime alone never is
+const LOAD_DELTA: f64 = 0.5;
+
+// Changes in open file descriptors below 1/100th of the limit are not sent
+const FD_DELTA_DIVISOR: u64 = 100;
+
+// The bin dir can hold many large files, so it is measured less often
+const DISK_SCAN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Listing all processes is expensive on busy hosts, in between only the processes
+// of the services are refreshed, so new child processes can take this long to show up
+const PROCESS_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const MB: u64 = 1024 * 1024;
+
+impl SystemState {
+    fn read(system: &System, disk: (u64, Option<u64>), processes: usize) -> Self {
+        let (used, total, memory_source) = match cgroup::memory(system.total_memory()) {
+            Some(memory) => (memory.used, memory.limit, memory.source),
+            None => (
+                system.used_memory(),
+                system.total_memory(),
+                MemorySource::Host,
+            ),
+        };
+
+        Self {
+            used,
+            total,
+            memory_source,
+            swap_used: system.used_swap(),
+            swap_total: system.total_swap(),
+            cpu: system.global_cpu_usage(),
+            cores: system.cpus().len(),
+            bin_size: disk.0,
+            disk_free: disk.1,
+            load_average: load_average(),
+            uptime: System::uptime(),
+            services: ServiceCounts::default(),
+            fds: open_fds(),
+            fd_limit: fd_limit(),
+            processes,
+        }
+    }
+
+    // Whether the change is worth sending to the clients, changed service counts
+    // are sent by the service manager right away
+    fn differs_from(&self, other: &SystemState) -> bool {
+        self.total != other.total
+            || self.memory_source != other.memory_source
+            || self.cores != other.cores
+            || self.used.abs_diff(other.used) > self.total / MEMORY_DELTA_DIVISOR
+            || self.swap_total != other.swap_total
+            || self.swap_used.abs_diff(other.swap_used) > self.swap_total / MEMORY_DELTA_DIVISOR
+            || (self.cpu - other.cpu).abs() >= CPU_DELTA
+            || self.bin_size != other.bin_size
+            || self.disk_free != other.disk_free
+            || self.fd_limit != other.fd_limit
+            || self.processes != other.processes
+            || match (self.fds, other.fds) {
+                (Some(fds), Some(other)) => {
+                    fds.abs_diff(other) > self.fd_limit.unwrap_or_default() / FD_DELTA_DIVISOR
+                }
+                (fds, other) => fds.is_some() != other.is_some(),
+            }
+            || match (self.load_average, other.load_average) {
+                (Some(load), Some(other)) => (load[0] - other[0]).abs() >= LOAD_DELTA,
+                (load, other) => load.is_some() != other.is_some(),
+            }
+    }
+}
+
+// Number of open file descriptors of etes
+#[cfg(target_os = "linux")]
+fn open_fds() -> Option<u64> {
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+// Soft limit on the number of open files of etes
+#[cfg(target_os = "linux")]
+fn fd_limit() -> Option<u64> {
+    parse_fd_limit(&std::fs::read_to_string("/proc/self/limits").ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fd_limit() -> Option<u64> {
+    None
+}
+
+// Soft limit of "Max open files" in the format of /proc/self/limits, None when unlimited
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_fd_limit(limits: &str) -> Option<u64> {
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+// Windows reports zeros instead of a load average
+fn load_average() -> Option<[f64; 3]> {
+    if cfg!(windows) {
+        return None;
     }
+
+    let load = System::load_average();
+
+    Some([load.one, load.five, load.fifteen])
+}
+
+// Child processes of every process, threads are skipped because they share the memory of their process
+fn child_processes(system: &System) -> HashMap<Pid, Vec<Pid>> {
+    let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+
+    for (pid, process) in system.processes() {
+        if process.thread_kind().is_some() {
+            continue;
+        }
+
+        if let Some(parent) = process.parent() {
+            children.entry(parent).or_default().push(*pid);
+        }
+    }
+
+    children
+}
+
+// The process and all its descendants
+fn process_tree(children: &HashMap<Pid, Vec<Pid>>, root: Pid) -> Vec<Pid> {
+    let mut tree = vec![];
+    let mut pids = vec![root];
+
+    while let Some(pid) = pids.pop() {
+        tree.push(pid);
+        pids.extend(children.get(&pid).into_iter().flatten());
+    }
+
+    tree
+}
+
+// Processes of the services, including their child processes
+fn service_processes(system: &System, pids: &[(String, u32)]) -> Vec<Pid> {
+    let children = child_processes(system);
+
+    pids.iter()
+        .flat_map(|(_, pid)| process_tree(&children, Pid::from_u32(*pid)))
+        .collect()
+}
+
+// Memory and CPU usage of the processes of the services, including their child processes
+fn service_usage(system: &System, pids: &[(String, u32)]) -> Vec<(String, u64, f32)> {
+    let children = child_processes(system);
+
+    pids.iter()
+        .filter_map(|(name, pid)| {
+            let root = Pid::from_u32(*pid);
+            system.process(root)?;
+
+            let (memory, cpu) = process_tree(&children, root)
+                .into_iter()
+                .filter_map(|pid| system.process(pid))
+                .fold((0, 0.0), |(memory, cpu), process| {
+                    (memory + process.memory(), cpu + process.cpu_usage())
+                });
+
+            Some((name.clone(), memory, cpu))
+        })
+        .collect()
+}
+
+// Total size of the files in the directory and its subdirectories
+async fn dir_size(dir: &Path) -> u64 {
+    let mut size = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match entry.metadata().await {
+                Ok(metadata) if metadata.is_dir() => dirs.push(entry.path()),
+                Ok(metadata) => size += metadata.len(),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    size
+}
+
+// Free space on the disk mounted closest to the directory
+fn free_space(dir: &Path) -> Option<u64> {
+    let dir: PathBuf = std::fs::canonicalize(dir).ok()?;
+    let disks = Disks::new_with_refreshed_list();
+
+    disks
+        .iter()
+        .filter(|disk| dir.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
 }
 
 // Send regular updates to the event manager and thereby the connected clients
 pub async fn send_updates(state: AppState) {
-    let mut system = System::new_all();
-    let last_cleanup = std::time::Instant::now();
+    let mut system = System::new();
+    let mut last_sent = SystemState::default();
+    let mut disk = (0, None);
+    let mut last_disk_scan: Option<Instant> = None;
+    let mut low_disk_space = false;
+    let mut alerts = Alerts::default();
+    let mut services_measured = false;
+    let processes = ProcessRefreshKind::nothing().with_memory().with_cpu();
+    let mut tracked: Vec<Pid> = vec![];
+    let mut last_process_scan: Option<Instant> = None;
+
+    // CPU usage is measured between two refreshes, every refresh in the loop
+    // measures the usage since the previous one
+    system.refresh_cpu_usage();
+    system.refresh_memory();
+    tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL).await;
+
+    if let Some(memory) = cgroup::memory(system.total_memory()) {
+        info!(
+            "Memory is limited to {} MB by the {:?} control group",
+            memory.limit / MB,
+            memory.source
+        );
+    }
 
     loop {
-        // if the last cleanup was more than a day ago, run cleanup
-        if last_cleanup.elapsed().as_secs() > 24 * 60 * 60
-            && let Err(e) = executable::remove_unused_executables(state.clone()).await
-        {
-            error!("Failed to remove unused executables: {e:?}");
+        state.health.beat(Worker::Monitor);
+
+        if last_disk_scan.is_none_or(|scan| scan.elapsed() >= DISK_SCAN_INTERVAL) {
+            let bin_dir = state.services.bin_dir();
+            disk = (dir_size(bin_dir).await, free_space(bin_dir));
+            last_disk_scan = Some(Instant::now());
+
+            // warn once when the free space drops below the threshold
+            let min_free = state.config().min_free_disk_mb * MB;
+            let low = disk.1.is_some_and(|free| free < min_free);
+
+            if low && !low_disk_space {
+                warn!(
+                    "Only {} MB free for uploads in {}",
+                    disk.1.unwrap_or_default() / MB,
+                    bin_dir.display()
+                );
+            } else if !low && low_disk_space {
+                info!("Free disk space for uploads is above the threshold again");
+            }
+
+            low_disk_space = low;
         }
 
-        system.refresh_all();
+        system.refresh_memory();
+        system.refresh_cpu_usage();
 
-        // Store memory state
-        state
+        // Only the processes of the services are measured, all processes are listed
+        // when a service started since the last scan, to find its children
+        let pids = state.services.pids();
+
+        if pids.is_empty() {
+            tracked.clear();
+        } else if last_process_scan.is_none_or(|scan| scan.elapsed() >= PROCESS_SCAN_INTERVAL)
+            || pids
+                .iter()
+                .any(|(_, pid)| !tracked.contains(&Pid::from_u32(*pid)))
+        {
+            system.refresh_processes_specifics(ProcessesToUpdate::All, true, processes);
+            tracked = service_processes(&system, &pids);
+            last_process_scan = Some(Instant::now());
+        } else {
+            system.refresh_processes_specifics(ProcessesToUpdate::Some(&tracked), true, processes);
+        }
+
+        let service_processes = tracked
+            .iter()
+            .filter(|pid| system.process(**pid).is_some())
+            .count();
+        let current = state
             .monitor
-            .update(system.used_memory(), system.total_memory());
+            .update(SystemState::read(&system, disk, service_processes));
+
+        // Alerts are checked even without clients, they also go to the notifier
+        for event in alerts.check(&current, &state.config().alerts) {
+            state.channel.send(event);
+        }
+
+        let usage = service_usage(&system, &pids);
+        state.services.update_resource_usage(&usage);
+
+        // Nobody to send to, clients get the current state when they connect
+        if state.presence.get_state().connected > 0 {
+            // Send update, unless the usage barely changed
+            if current.differs_from(&last_sent) {
+                state.channel.send(ServerEvent::from(current.clone()));
+                last_sent = current;
+            }
+
+            // Usage of the services, sent once more after the last service stopped
+            if !usage.is_empty() || services_measured {
+                services_measured = !usage.is_empty();
+                state.channel.send(ServerEvent::ServiceResources { usage });
+            }
+        }
+
+        // Sleep before next update, read every time so a reload takes effect,
+        // the CPU usage is the average over this interval
+        tokio::time::sleep(state.config().monitor_interval()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        MAX_HISTORY_SAMPLES, SystemMonitor, SystemState, dir_size, free_space, parse_fd_limit,
+        service_processes, service_usage,
+    };
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let dir = std::env::temp_dir().join(format!("etes-bin-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.bin"), [0; 100]).unwrap();
+        std::fs::write(dir.join("nested/b.bin"), [0; 23]).unwrap();
+
+        assert_eq!(dir_size(&dir).await, 123);
+        assert_eq!(dir_size(&dir.join("missing")).await, 0);
+        assert!(free_space(&dir.join("missing")).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let monitor = SystemMonitor::new();
+
+        for used in 0..MAX_HISTORY_SAMPLES as u64 + 10 {
+            monitor.update(SystemState {
+                used,
+                ..Default::default()
+            });
+        }
+
+        assert_eq!(monitor.history.read().len(), MAX_HISTORY_SAMPLES);
+
+        let history = monitor.get_history(90);
+        assert_eq!(history.len(), 90);
+        assert_eq!(history[0].used, 29);
+        assert_eq!(history[89].used, MAX_HISTORY_SAMPLES as u64 + 9);
+        assert!(history.windows(2).all(|pair| pair[0].used < pair[1].used));
+    }
+
+    #[test]
+    fn test_parse_fd_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             24002                24002                processes \n\
+                      Max open files            1024                 524288               files     \n";
+
+        assert_eq!(parse_fd_limit(limits), Some(1024));
+        assert_eq!(
+            parse_fd_limit(
+                "Max open files            unlimited            unlimited            files"
+            ),
+            None
+        );
+        assert_eq!(parse_fd_limit(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_service_usage() {
+        let mut child = tokio::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+
+        let mut system = System::new();
+        let processes = ProcessRefreshKind::nothing().with_memory().with_cpu();
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, processes);
+
+        // etes is the parent of the child, so its usage includes the child
+        let pids = [
+            ("etes".to_string(), std::process::id()),
+            ("sleep".to_string(), pid),
+            ("gone".to_string(), u32::MAX),
+        ];
+        let usage = service_usage(&system, &pids);
+
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[1].0, "sleep");
+        assert!(usage[1].1 > 0);
+        assert!(usage[0].1 >= usage[1].1);
+
+        // refreshing only the processes of the services keeps them up to date
+        let tracked = service_processes(&system, &pids[1..]);
+        assert_eq!(tracked, [Pid::from_u32(pid), Pid::from_u32(u32::MAX)]);
+
+        system.refresh_processes_specifics(ProcessesToUpdate::Some(&tracked), true, processes);
+        let usage = service_usage(&system, &pids[1..]);
+        assert_eq!(usage.len(), 1);
+        assert!(usage[0].1 > 0);
 
-        // Send update
-        state.channel.send(Event::MemoryState {
-            used: system.used_memory(),
-            total: system.total_memory(),
-        });
+        child.kill().await.unwrap();
+        child.wait().await.unwrap();
 
-        // Sleep before next update
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        system.refresh_processes_specifics(ProcessesToUpdate::Some(&tracked), true, processes);
+        assert!(service_usage(&system, &pids[1..]).is_empty());
     }
 }
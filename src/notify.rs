@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{
+    AppState,
+    alerts::alert_message,
+    events::{ClientEvent, ErrorCode, Event, Origin, SequencedEvent, ServerEvent, ServiceState},
+    github::CommitHash,
+    service::ServiceData,
+    services::ServiceQuery,
+    sinks::{post_json, webhook_name},
+    user::User,
+};
+
+// Kinds of events the notification webhooks can receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyKind {
+    ServiceStarted,
+    ServiceCrashed,
+    ServiceStopped,
+    UploadCompleted,
+    GithubRefreshFailed,
+    Alert,
+}
+
+/// Derives notifications from the events on the bus, which only carry the current state
+struct Notifier {
+    services: HashMap<String, ServiceState>,
+    executables: HashSet<CommitHash>,
+    // who last asked to stop or restart a service
+    requested_by: HashMap<String, User>,
+}
+
+impl Notifier {
+    fn new(services: Vec<ServiceData>, executables: HashSet<CommitHash>) -> Self {
+        Self {
+            services: services
+                .into_iter()
+                .map(|service| (service.name, service.state))
+                .collect(),
+            executables,
+            requested_by: HashMap::new(),
+        }
+    }
+
+    fn notifications(&mut self, event: &SequencedEvent) -> Vec<(NotifyKind, String)> {
+        match &event.event {
+            Event::Client(
+                ClientEvent::StopService { name, user, .. }
+                | ClientEvent::RestartService { name, user },
+            ) => {
+                self.requested_by.insert(name.clone(), user.clone());
+                Vec::new()
+            }
+            Event::Server(
+                ServerEvent::ServiceAdded { service } | ServerEvent::ServiceUpdated { service },
+            ) => {
+                let previous = self
+                    .services
+                    .insert(service.name.clone(), service.state.clone());
+
+                if previous.as_ref() == Some(&service.state) {
+                    return Vec::new();
+                }
+
+                match service.state {
+                    ServiceState::Running => {
+                        let actor = self
+                            .requested_by
+                            .remove(&service.name)
+                            .unwrap_or_else(|| service.creator.clone());
+
+                        vec![(
+                            NotifyKind::ServiceStarted,
+                            format!(
+                                "Service {} was started by {}: {}",
+                                service.name,
+                                display_user(&actor),
+                                service.url
+                            ),
+                        )]
+                    }
+                    ServiceState::Error => {
+                        let reason = service
+                            .error
+                            .clone()
+                            .or_else(|| service.exit_status.clone())
+                            .unwrap_or_else(|| "unknown error".to_string());
+
+                        vec![(
+                            NotifyKind::ServiceCrashed,
+                            format!("Service {} crashed: {reason}", service.name),
+                        )]
+                    }
+                    ServiceState::Pending => Vec::new(),
+                }
+            }
+            Event::Server(ServerEvent::ServiceRemoved { name }) => {
+                self.services.remove(name);
+
+                let message = match self.requested_by.remove(name) {
+                    Some(actor) => {
+                        format!("Service {name} was stopped by {}", display_user(&actor))
+                    }
+                    // evicted to stay within the service limit
+                    None => format!("Service {name} was stopped"),
+                };
+
+                vec![(NotifyKind::ServiceStopped, message)]
+            }
+            Event::Server(ServerEvent::ExecutablesState { executables, .. })
+                if event.origin == Origin::Upload =>
+            {
+                executables
+                    .iter()
+                    .filter(|executable| self.executables.insert(executable.hash().clone()))
+                    .map(|executable| {
+                        (
+                            NotifyKind::UploadCompleted,
+                            format!(
+                                "Executable for commit {} was uploaded",
+                                short_hash(executable.hash())
+                            ),
+                        )
+                    })
+                    .collect()
+            }
+            Event::Server(ServerEvent::Error {
+                code: ErrorCode::GithubFetchFailed,
+                message,
+                ..
+            }) => vec![(NotifyKind::GithubRefreshFailed, message.clone())],
+            Event::Server(ServerEvent::Alert {
+                severity,
+                metric,
+                value,
+                threshold,
+            }) => vec![(
+                NotifyKind::Alert,
+                alert_message(*severity, *metric, *value, *threshold),
+            )],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Name of a user for a message in a team channel, anonymous ids are not shown
+fn display_user(user: &User) -> String {
+    match user {
+        User::GitHub(user) => user.login.clone(),
+        User::Anonymous(_) => "an anonymous user".to_string(),
+    }
+}
+
+fn short_hash(hash: &str) -> &str {
+    hash.get(..7).unwrap_or(hash)
+}
+
+// Post notifications of important events to the configured webhooks,
+// identical notifications are sent at most once per repeat interval
+pub async fn send_notifications(state: AppState) {
+    let client = reqwest::Client::new();
+    let mut receiver = state.channel.get_receiver();
+    let mut sent: HashMap<String, Instant> = HashMap::new();
+
+    let executables = state
+        .services
+        .get_executables(&state.config())
+        .iter()
+        .map(|executable| executable.hash().clone())
+        .collect();
+    let mut notifier = Notifier::new(
+        state
+            .services
+            .get_state(&state.config(), &ServiceQuery::default()),
+        executables,
+    );
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Notifier missed {skipped} events");
+                state.channel.record_lag(skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        // inert without webhooks, but keep track of the services in case they are configured later
+        let config = state.config();
+        let notifications = notifier.notifications(&event);
+
+        if config.notify_webhooks.is_empty() {
+            continue;
+        }
+
+        let repeat_interval = Duration::from_secs(config.notify_repeat_interval);
+
+        for (kind, message) in notifications {
+            if !config.notify_events.contains(&kind) {
+                continue;
+            }
+
+            let now = Instant::now();
+            sent.retain(|_, at| now.duration_since(*at) < repeat_interval);
+
+            if sent.contains_key(&message) {
+                info!("Skipping repeated notification: {message}");
+                continue;
+            }
+
+            sent.insert(message.clone(), now);
+
+            let body = json!({ "text": message }).to_string();
+
+            for webhook in &config.notify_webhooks {
+                let client = client.clone();
+                let webhook = webhook.clone();
+                let body = body.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = post_json(&client, &webhook, body).await {
+                        warn!(
+                            "Failed to send notification to {}: {e:?}",
+                            webhook_name(&webhook.url)
+                        );
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use std::collections::HashSet;
+
+    use super::{Notifier, NotifyKind};
+    use crate::{
+        config::{Config, ConfigArgs},
+        events::{ClientEvent, Event, Origin, SequencedEvent, ServerEvent, ServiceState},
+        service::{Service, ServiceData, Upstream},
+        user::User,
+    };
+
+    fn sequenced(event: impl Into<Event>) -> SequencedEvent {
+        SequencedEvent {
+            seq: 1,
+            emitted_at: Utc::now(),
+            origin: Origin::Worker,
+            event: event.into(),
+        }
+    }
+
+    #[test]
+    fn test_notifications() {
+        let mut notifier = Notifier::new(Vec::new(), HashSet::new());
+        let user = User::Anonymous("frank".to_string());
+
+        let config = Config::from_env(&ConfigArgs::default()).unwrap();
+        let mut service = ServiceData::new(
+            &Service::external("foobar", Upstream::local(8000), user.clone()),
+            &config,
+        );
+        service.url = "https://foobar.example.com".to_string();
+
+        let added = ServerEvent::ServiceAdded {
+            service: Box::new(service.clone()),
+        };
+        assert_eq!(notifier.notifications(&sequenced(added)), vec![]);
+
+        service.state = ServiceState::Running;
+        let updated = ServerEvent::ServiceUpdated {
+            service: Box::new(service.clone()),
+        };
+        assert_eq!(
+            notifier.notifications(&sequenced(updated.clone())),
+            vec![(
+                NotifyKind::ServiceStarted,
+                "Service foobar was started by an anonymous user: https://foobar.example.com"
+                    .to_string()
+            )]
+        );
+
+        // only state changes are notified
+        assert_eq!(notifier.notifications(&sequenced(updated)), vec![]);
+
+        let stop = ClientEvent::StopService {
+            name: "foobar".to_string(),
+            user,
+            request_id: None,
+        };
+        assert_eq!(notifier.notifications(&sequenced(stop)), vec![]);
+
+        let removed = ServerEvent::ServiceRemoved {
+            name: "foobar".to_string(),
+        };
+        assert_eq!(
+            notifier.notifications(&sequenced(removed)),
+            vec![(
+                NotifyKind::ServiceStopped,
+                "Service foobar was stopped by an anonymous user".to_string()
+            )]
+        );
+    }
+}
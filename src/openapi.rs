@@ -0,0 +1,197 @@
+use anyhow::anyhow;
+use axum::{
+    Json,
+    extract::State,
+    http::header::CONTENT_SECURITY_POLICY,
+    response::{Html, IntoResponse},
+};
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+use crate::{
+    AppState, api,
+    data::{self, InitialState},
+    download,
+    error::{AppError, ErrorResponse},
+    executable::ExecutableData,
+    github::GitHubState,
+    health, presence,
+    service::ServiceData,
+    upload,
+    user::{GitHubUser, User},
+    util::random_string,
+    version,
+};
+
+// Swagger UI is not bundled, the documentation page loads it from unpkg
+const SWAGGER_UI_URL: &str = "https://unpkg.com/swagger-ui-dist@5.17.14";
+
+const SWAGGER_UI_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>etes API</title>
+  <link rel="stylesheet" href="%SWAGGER_UI_URL%/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="%SWAGGER_UI_URL%/swagger-ui-bundle.js"></script>
+  <script nonce="%NONCE%">
+    window.ui = SwaggerUIBundle({ url: '/etes/api/v1/openapi.json', dom_id: '#swagger-ui' });
+  </script>
+</body>
+</html>
+"#;
+
+/// OpenAPI description of the HTTP API, the websocket protocol is not part of it
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "etes",
+        description = "Start and stop services and upload executables"
+    ),
+    paths(
+        data::data_handler,
+        upload::upload_handler,
+        download::download_handler,
+        api::list_services_handler,
+        api::start_service_handler,
+        api::get_service_handler,
+        api::stop_service_handler,
+        health::healthz_handler,
+        health::readyz_handler,
+        presence::connections_handler,
+        version::version_handler,
+    ),
+    components(schemas(InitialState, ServiceData, ExecutableData, GitHubState, ErrorResponse)),
+    modifiers(&ApiKey),
+)]
+pub struct ApiDoc;
+
+// Security scheme of the requests with the API key, the others use the session cookie of the login
+struct ApiKey;
+
+impl Modify for ApiKey {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("The api_key of the configuration"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Swagger UI for the OpenAPI description, only for admins
+pub async fn swagger_ui_handler(
+    State(state): State<AppState>,
+    github_user: GitHubUser,
+) -> Result<impl IntoResponse, AppError> {
+    if !User::GitHub(github_user).is_admin(&state.config()) {
+        return Err(AppError::Forbidden(anyhow!(
+            "Only admins can view the API documentation"
+        )));
+    }
+
+    // the inline script is allowed by its nonce, the assets by their origin
+    let nonce = random_string();
+    let csp = [
+        "default-src 'none'",
+        "base-uri 'none'",
+        "frame-ancestors 'none'",
+        "object-src 'none'",
+        &format!("script-src https://unpkg.com 'nonce-{nonce}'"),
+        "style-src https://unpkg.com",
+        "img-src 'self' data:",
+        "connect-src 'self'",
+        "form-action 'none'",
+    ]
+    .join("; ");
+
+    let html = SWAGGER_UI_HTML
+        .replace("%SWAGGER_UI_URL%", SWAGGER_UI_URL)
+        .replace("%NONCE%", &nonce);
+
+    Ok(([(CONTENT_SECURITY_POLICY, csp)], Html(html)))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::{app, config::ConfigArgs};
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let (_, app) = app(false, ConfigArgs::default()).await.unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/etes/api/v1/openapi.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+
+        for path in [
+            "/etes/api/v1/data/{caller}",
+            "/etes/api/v1/executable/{trigger_hash}/{build_hash}",
+            "/etes/api/v1/services",
+            "/etes/api/v1/services/{name}",
+            "/etes/readyz",
+            "/etes/api/v1/version",
+            "/etes/api/v1/admin/connections",
+        ] {
+            assert!(spec["paths"][path].is_object(), "missing {path}");
+        }
+
+        for schema in [
+            "InitialState",
+            "ServiceData",
+            "ExecutableData",
+            "GitHubState",
+            "ErrorResponse",
+        ] {
+            assert!(
+                spec["components"]["schemas"][schema].is_object(),
+                "missing {schema}"
+            );
+        }
+
+        // the documentation page needs a login
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/etes/api/docs")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 307);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::anyhow;
+use axum::{Json, extract::State};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+
+use crate::{
+    AppState,
+    error::{AppError, ErrorResponse},
+    events::{ServerEvent, Topic},
+    user::{GitHubUser, User},
+};
+
+#[derive(Clone, Debug, Serialize, PartialEq, ToSchema)]
+pub struct PresenceState {
+    pub connected: usize,
+    pub authenticated: usize,
+    pub anonymous: usize,
+}
+
+/// Open websocket connection, as listed for admins
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub id: u64,
+    // identities of anonymous users are hashed, like everywhere they are shown
+    pub user: User,
+    pub connected_at: DateTime<Utc>,
+    pub topics: Vec<Topic>,
+    // address of the client, as reported by the reverse proxy if there is one
+    pub remote_addr: String,
+}
+
+// Number of open websocket connections
+pub struct PresenceTracker {
+    authenticated: AtomicUsize,
+    anonymous: AtomicUsize,
+    changed: Notify,
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, ConnectionInfo>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self {
+            authenticated: AtomicUsize::new(0),
+            anonymous: AtomicUsize::new(0),
+            changed: Notify::new(),
+            next_id: AtomicU64::new(1),
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Get the open connections, oldest first
+    pub fn get_connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().values().cloned().collect()
+    }
+
+    // Get the current connection counts
+    pub fn get_state(&self) -> PresenceState {
+        let authenticated = self.authenticated.load(Ordering::Relaxed);
+        let anonymous = self.anonymous.load(Ordering::Relaxed);
+
+        PresenceState {
+            connected: authenticated + anonymous,
+            authenticated,
+            anonymous,
+        }
+    }
+
+    fn counter(&self, user: &User) -> &AtomicUsize {
+        match user {
+            User::GitHub(_) => &self.authenticated,
+            User::Anonymous(_) => &self.anonymous,
+        }
+    }
+}
+
+/// Counts a websocket connection for as long as it is alive, also when the
+/// socket task panics or is cancelled
+pub struct PresenceGuard {
+    state: AppState,
+    user: User,
+    id: u64,
+}
+
+impl PresenceGuard {
+    pub fn new(state: AppState, user: User, topics: &HashSet<Topic>, remote_addr: String) -> Self {
+        let presence = &state.presence;
+        let id = presence.next_id.fetch_add(1, Ordering::Relaxed);
+
+        presence.connections.lock().insert(
+            id,
+            ConnectionInfo {
+                id,
+                user: user.hash_anonymous(),
+                connected_at: Utc::now(),
+                topics: sorted(topics),
+                remote_addr,
+            },
+        );
+
+        presence.counter(&user).fetch_add(1, Ordering::Relaxed);
+        presence.changed.notify_one();
+
+        Self { state, user, id }
+    }
+
+    // Record the topics the connection subscribed to
+    pub fn set_topics(&self, topics: &HashSet<Topic>) {
+        if let Some(connection) = self.state.presence.connections.lock().get_mut(&self.id) {
+            connection.topics = sorted(topics);
+        }
+    }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let presence = &self.state.presence;
+        presence.connections.lock().remove(&self.id);
+        presence.counter(&self.user).fetch_sub(1, Ordering::Relaxed);
+        presence.changed.notify_one();
+    }
+}
+
+fn sorted(topics: &HashSet<Topic>) -> Vec<Topic> {
+    let mut topics: Vec<Topic> = topics.iter().copied().collect();
+    topics.sort();
+
+    topics
+}
+
+// Open websocket connections, so admins can tell whether a restart would disrupt anyone
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/admin/connections",
+    responses(
+        (status = 200, description = "Open websocket connections, oldest first", body = Vec<ConnectionInfo>),
+        (status = 403, description = "The user is not an admin", body = ErrorResponse),
+    ),
+)]
+pub async fn connections_handler(
+    State(state): State<AppState>,
+    github_user: GitHubUser,
+) -> Result<Json<Vec<ConnectionInfo>>, AppError> {
+    if !User::GitHub(github_user).is_admin(&state.config()) {
+        return Err(AppError::Forbidden(anyhow!(
+            "Only admins can list the connections"
+        )));
+    }
+
+    Ok(Json(state.presence.get_connections()))
+}
+
+// Broadcast the connection counts when they change, at most once per second
+pub async fn send_updates(state: AppState) {
+    let mut last = state.presence.get_state();
+
+    loop {
+        state.presence.changed.notified().await;
+
+        // Wait for connections opened or closed in quick succession, like a page reload
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let current = state.presence.get_state();
+
+        if current != last {
+            state.channel.send(ServerEvent::Presence {
+                connected: current.connected,
+                authenticated: current.authenticated,
+                anonymous: current.anonymous,
+            });
+            last = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{app, config::ConfigArgs, events::Topic, user::User, util::sha256};
+
+    use super::PresenceGuard;
+
+    #[tokio::test]
+    async fn test_connections() {
+        let (state, _) = app(false, ConfigArgs::default()).await.unwrap();
+
+        let first = PresenceGuard::new(
+            state.clone(),
+            User::Anonymous("caller".to_string()),
+            &Topic::defaults(),
+            "192.0.2.1".to_string(),
+        );
+        let second = PresenceGuard::new(
+            state.clone(),
+            User::Anonymous("other".to_string()),
+            &Topic::defaults(),
+            "192.0.2.2".to_string(),
+        );
+
+        second.set_topics(&[Topic::Memory].into());
+
+        let connections = state.presence.get_connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].user, User::Anonymous(sha256("caller")));
+        assert_eq!(connections[0].remote_addr, "192.0.2.1");
+        assert_eq!(connections[1].topics, vec![Topic::Memory]);
+
+        drop(first);
+
+        let connections = state.presence.get_connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].remote_addr, "192.0.2.2");
+        assert_eq!(state.presence.get_state().connected, 1);
+
+        drop(second);
+        assert!(state.presence.get_connections().is_empty());
+    }
+}
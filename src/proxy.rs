@@ -1,79 +1,595 @@
 use anyhow::{Context, anyhow};
 use axum::{
-    extract::{Request, State},
-    http::header::HOST,
+    body::Body,
+    extract::{ConnectInfo, FromRef, Request, State},
+    http::{
+        Extensions, HeaderMap, HeaderValue,
+        header::{CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, RETRY_AFTER},
+    },
     response::{Html, IntoResponse, Redirect, Response},
 };
+use axum_extra::extract::PrivateCookieJar;
+use futures::StreamExt;
 use hyper::{StatusCode, Uri};
+use std::{
+    error::Error as StdError,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Once,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tracing::{Instrument, Span, error, field, info, info_span, warn};
 
 use crate::{
     AppState,
+    auth::GithubOauthService,
+    config::{Config, HostHeader},
     error::AppError,
+    events::{ErrorCode, Origin, ServerEvent, ServiceState},
+    github::CommitHash,
+    listener::ClientAddr,
+    service::ServiceData,
+    services::ProxyTarget,
     user::{GitHubUser, User},
-    util::{get_random_name, is_valid_hash, random_string},
+    util::{
+        get_pull_number, get_random_name, get_subdomain, is_valid_hash, is_valid_hash_prefix,
+        random_string,
+    },
 };
 
-fn not_found(domain: &str) -> Response {
+fn not_found(config: &Config) -> Response {
+    let (url, domain) = (config.dashboard_url(), &config.base_domain);
+
+    (
+        StatusCode::NOT_FOUND,
+        Html(format!("<h1>No service found on this domain.</h1><h2>Visit <a href=\"{url}\">{domain}</a> to view a list of running instances.</h2>")),
+    )
+        .into_response()
+}
+
+fn still_starting(name: &str) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Html(format!("<html><head><meta http-equiv=\"refresh\" content=\"2\"></head><body><h1>Service {name} is still starting.</h1><h2>This page will reload automatically.</h2></body></html>")),
+    )
+        .into_response()
+}
+
+fn autostart_disabled(commit_hash: &str, config: &Config) -> Response {
+    let (url, domain) = (config.dashboard_url(), &config.base_domain);
+
+    (
+        StatusCode::NOT_FOUND,
+        Html(format!("<h1>No service is running for commit {commit_hash}.</h1><h2>Sign in at <a href=\"{url}\">{domain}</a> and start the preview from the dashboard.</h2>")),
+    )
+        .into_response()
+}
+
+fn build_not_available(number: i64, config: &Config) -> Response {
+    let (url, domain) = (config.dashboard_url(), &config.base_domain);
+
     (
         StatusCode::NOT_FOUND,
-        Html(format!("<h1>No service found on this domain.</h1><h2>Visit <a href=\"https://{domain}\">{domain}</a> to view a list of running instances.</h2>")),
+        Html(format!("<h1>No build is available yet for pull request #{number}.</h1><h2>Visit <a href=\"{url}\">{domain}</a> to view a list of available builds.</h2>")),
+    )
+        .into_response()
+}
+
+fn ambiguous_hash(prefix: &str, candidates: &[CommitHash], config: &Config) -> Response {
+    let links = candidates
+        .iter()
+        .map(|hash| {
+            let url = config.service_url(hash);
+
+            format!("<li><a href=\"{url}\">{hash}</a></li>")
+        })
+        .collect::<String>();
+
+    (
+        StatusCode::NOT_FOUND,
+        Html(format!("<h1>Commit hash {prefix} is ambiguous.</h1><h2>Choose one of the matching commits:</h2><ul>{links}</ul>")),
+    )
+        .into_response()
+}
+
+fn payload_too_large(limit: u64) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Html(format!("<h1>Request body too large.</h1><h2>Requests to services are limited to {} bytes ({:.1} MiB).</h2>", limit, limit as f64 / (1024.0 * 1024.0))),
     )
         .into_response()
 }
 
+fn bad_gateway(detail: Option<String>) -> Response {
+    let detail = detail.unwrap_or_else(|| "The service could not be reached.".to_string());
+
+    (
+        StatusCode::BAD_GATEWAY,
+        Html(format!("<h1>Upstream error</h1><h2>{detail}</h2>")),
+    )
+        .into_response()
+}
+
+fn maintenance(service: &ServiceData) -> Response {
+    let initiator = match &service.restarted_by {
+        Some(User::GitHub(user)) => user.login.clone(),
+        _ => "an anonymous user".to_string(),
+    };
+
+    let expected = match service.startup_duration_ms {
+        Some(ms) => format!("Startup usually takes about {} seconds.", ms.div_ceil(1000)),
+        None => "This should only take a moment.".to_string(),
+    };
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, HeaderValue::from_static("5"))],
+        Html(format!("<html><head><meta http-equiv=\"refresh\" content=\"5\"><style>body {{ font-family: sans-serif; max-width: 40rem; margin: 4rem auto; color: #333; }} h1 {{ color: #1c7ed6; }}</style></head><body><h1>{} is being restarted</h1><p>The restart was initiated by {initiator}. {expected}</p><p>This page will reload automatically.</p></body></html>", service.name)),
+    )
+        .into_response()
+}
+
+fn failed_to_start(name: &str, error: Option<String>) -> Response {
+    let error = error.unwrap_or_else(|| "The service could not be started.".to_string());
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Html(format!(
+            "<h1>Service {name} is not available.</h1><h2>{error}</h2>"
+        )),
+    )
+        .into_response()
+}
+
+fn too_many_requests(name: &str, retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, HeaderValue::from(seconds))],
+        Html(format!(
+            "<h1>Too many requests for service {name}.</h1><h2>Try again in {seconds} seconds.</h2>"
+        )),
+    )
+        .into_response()
+}
+
+fn misdirected(host: &str) -> Response {
+    (
+        StatusCode::MISDIRECTED_REQUEST,
+        format!("Host {host} is not served by this proxy"),
+    )
+        .into_response()
+}
+
+static USER_LOGIN_HEADER: &str = "x-etes-user-login";
+static USER_NAME_HEADER: &str = "x-etes-user-name";
+
+// Replace user headers sent by the client with the acting user
+fn set_user_headers(headers: &mut HeaderMap, user: &User, config: &Config) {
+    headers.remove(USER_LOGIN_HEADER);
+    headers.remove(USER_NAME_HEADER);
+
+    if !config.inject_user_headers {
+        return;
+    }
+
+    let (login, name) = match user.hash_anonymous() {
+        User::GitHub(user) => (user.login, Some(user.name)),
+        User::Anonymous(id) if config.inject_anonymous_user_headers => (id, None),
+        User::Anonymous(_) => return,
+    };
+
+    if let Ok(login) = HeaderValue::from_bytes(login.as_bytes()) {
+        headers.insert(USER_LOGIN_HEADER, login);
+    }
+
+    if let Some(Ok(name)) = name.map(|name| HeaderValue::from_bytes(name.as_bytes())) {
+        headers.insert(USER_NAME_HEADER, name);
+    }
+}
+
+// Remove the cookies of etes from a request to a service, services run untrusted code that
+// could replay the session of the user against the dashboard
+fn strip_etes_cookies(headers: &mut HeaderMap, oauth: &GithubOauthService) {
+    let names = [
+        oauth.session_cookie_name(),
+        oauth.csrf_cookie_name(),
+        oauth.visitor_cookie_name(),
+    ];
+
+    let cookies: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| {
+            let name = cookie.split_once('=').map_or(*cookie, |(name, _)| name);
+            !cookie.is_empty() && !names.iter().any(|etes| etes == name.trim())
+        })
+        .map(str::to_string)
+        .collect();
+
+    headers.remove(COOKIE);
+
+    if let Ok(value) = HeaderValue::from_str(&cookies.join("; "))
+        && !cookies.is_empty()
+    {
+        headers.insert(COOKIE, value);
+    }
+}
+
+// Id of an anonymous visitor from the visitor cookie, a first visit gets a new id and the jar
+// with the cookie to set. The hashed id passed to the services stays the same per visitor.
+fn visitor_id(state: &AppState, headers: &HeaderMap) -> (String, Option<PrivateCookieJar>) {
+    let oauth = GithubOauthService::from_ref(state);
+    let jar = PrivateCookieJar::from_headers(headers, oauth.session_key());
+
+    if let Some(cookie) = jar.get(&oauth.visitor_cookie_name()) {
+        return (cookie.value().to_string(), None);
+    }
+
+    let id = random_string();
+    let jar = jar.add(oauth.visitor_cookie(id.clone()));
+
+    (id, Some(jar))
+}
+
+// Wrap a body in a stream that fails once more than `limit` bytes have passed,
+// the returned flag is set when the limit was exceeded
+fn limit_body(body: Body, limit: u64) -> (Body, Arc<AtomicBool>) {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let flag = exceeded.clone();
+    let mut total: u64 = 0;
+
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk?;
+        total += chunk.len() as u64;
+
+        if total > limit {
+            flag.store(true, Ordering::Relaxed);
+
+            return Err(axum::Error::new(anyhow!("Body exceeds {limit} bytes")));
+        }
+
+        Ok(chunk)
+    });
+
+    (Body::from_stream(stream), exceeded)
+}
+
+// Report the size of every chunk of a body while it is streamed
+fn count_body(body: Body, count: impl Fn(usize) + Send + 'static) -> Body {
+    let stream = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            count(chunk.len());
+        }
+    });
+
+    Body::from_stream(stream)
+}
+
+// Describe why a request to a service failed, based on the underlying IO error
+fn describe_upstream_error(error: &hyper_util::client::legacy::Error) -> String {
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        if let Some(io_error) = e.downcast_ref::<io::Error>() {
+            return match io_error.kind() {
+                io::ErrorKind::ConnectionRefused => "connection refused".to_string(),
+                io::ErrorKind::TimedOut => "connection timed out".to_string(),
+                io::ErrorKind::ConnectionReset => "connection reset".to_string(),
+                _ => format!("connection error: {io_error}"),
+            };
+        }
+
+        source = e.source();
+    }
+
+    match error.source() {
+        Some(source) => format!("{error}: {source}"),
+        None => error.to_string(),
+    }
+}
+
+// Report a failed request to a service, with details for the owner of the service
+fn upstream_error(
+    state: &AppState,
+    name: &str,
+    user: &User,
+    error: &hyper_util::client::legacy::Error,
+) -> Response {
+    let cause = describe_upstream_error(error);
+    error!("Upstream error for service {name}: {cause}");
+
+    // Notify the owner the first time a running service refuses connections
+    if error.is_connect()
+        && let Some(creator) = state.services.set_reachable(name, false)
+    {
+        state.channel.send_from(
+            Origin::Proxy,
+            ServerEvent::Error {
+                message: format!("Service {name} is not accepting connections: {cause}"),
+                code: ErrorCode::ServiceUnreachable,
+                user: creator,
+                request_id: None,
+            },
+        );
+    }
+
+    if !state.services.is_owner(name, user, &state.config()) {
+        return bad_gateway(None);
+    }
+
+    let mut detail = format!("Service {name} could not be reached: {cause}.");
+
+    if let Some(service) = state.services.get_service_data(name, &state.config()) {
+        if let Some(error) = service.error {
+            detail.push_str(&format!(" Last error: {error}."));
+        }
+
+        if let Some(exit_status) = service.exit_status {
+            detail.push_str(&format!(" The process has exited ({exit_status})."));
+        }
+    }
+
+    bad_gateway(Some(detail))
+}
+
+// Get the client IP, the address reported by a trusted reverse proxy or the address of the connection.
+// Every proxy appends the address it got the request from to X-Forwarded-For, so the last address
+// that is not a trusted proxy is the client, the entries before it could be forged by the client.
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions, config: &Config) -> String {
+    // connections over a unix domain socket have no address, the reverse proxy should set the header
+    let peer = extensions
+        .get::<ConnectInfo<ClientAddr>>()
+        .map(|ConnectInfo(ClientAddr(addr))| addr.map(|addr| addr.ip().to_canonical()));
+
+    // every listener provides the address, without it the router is served incorrectly
+    // and X-Forwarded-For is ignored
+    if peer.is_none() {
+        static MISSING_CONNECT_INFO: Once = Once::new();
+
+        MISSING_CONNECT_INFO
+            .call_once(|| warn!("Address of the connection is unknown, ignoring X-Forwarded-For"));
+    }
+
+    let trusted = match peer {
+        Some(Some(ip)) => config.is_trusted_proxy(ip),
+        Some(None) => true,
+        None => false,
+    };
+
+    if trusted {
+        let forwarded_for: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+
+        for value in forwarded_for.into_iter().rev() {
+            let Some(ip) = parse_forwarded_ip(value.trim()) else {
+                break;
+            };
+
+            if !config.is_trusted_proxy(ip) {
+                return ip.to_string();
+            }
+        }
+    }
+
+    peer.flatten()
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Some reverse proxies add the port, like 192.0.2.1:1234 or [2001:db8::1]:1234,
+// and IPv4 clients of a dual-stack socket have a mapped address like ::ffff:192.0.2.1
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let ip = value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()?;
+
+    Some(ip.to_canonical())
+}
+
+// Wait for a pending service to start, returns false when the grace period has passed
+async fn wait_while_pending(state: &AppState, name: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(state.config().startup_grace_period);
+
+    while state.services.get_service_state(name) == Some(ServiceState::Pending) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    true
+}
+
+// Find all known commit hashes (executables and GitHub) starting with a prefix
+fn resolve_commit_prefix(state: &AppState, prefix: &str) -> Vec<CommitHash> {
+    let mut candidates = state
+        .services
+        .get_executables_by_commit_prefix(prefix)
+        .iter()
+        .flat_map(|executable| [executable.hash().clone(), executable.trigger_hash().clone()])
+        .chain(state.github.get_commit_hashes_by_prefix(prefix))
+        .filter(|hash| hash.starts_with(prefix))
+        .collect::<Vec<CommitHash>>();
+
+    candidates.sort();
+    candidates.dedup();
+
+    candidates
+}
+
+// Redirect to a service once it is running, or explain why it is not available
+async fn redirect_when_running(state: &AppState, name: &str) -> Response {
+    if !wait_while_pending(state, name).await {
+        return still_starting(name);
+    }
+
+    match state.services.get_service_data(name, &state.config()) {
+        Some(service) if service.state == ServiceState::Running => {
+            Redirect::temporary(&service.url).into_response()
+        }
+        Some(service) => failed_to_start(name, service.error),
+        None => failed_to_start(name, None),
+    }
+}
+
 async fn redirect_to_service(
     state: AppState,
-    domain: &str,
     user: User,
     commit_hash: &str,
 ) -> Result<Response, AppError> {
     // find exsisting service
     if let Some(name) = state.services.get_name_by_commit(commit_hash) {
-        // redirect to service
-        return Ok(Redirect::temporary(&format!("https://{name}.{domain}")).into_response());
+        return Ok(redirect_when_running(&state, &name).await);
     }
 
-    // start up new service
-    let name = get_random_name(&state.config.words);
+    let config = state.config();
+    if !config.proxy_autostart {
+        return Ok(autostart_disabled(commit_hash, &config));
+    }
+
+    // start up new service, this waits for the startup to finish
+    let name = get_random_name(&config.words);
     state
         .services
-        .start_service(&name, &commit_hash.into(), user, state.clone())
+        .start_service(&name, &commit_hash.into(), user, None, false, state.clone())
         .await;
 
-    // redirect to service
-    Ok(Redirect::temporary(&format!("https://{name}.{domain}")).into_response())
+    Ok(redirect_when_running(&state, &name).await)
 }
 
 pub async fn handler(
     State(state): State<AppState>,
     user: Option<GitHubUser>,
-    mut req: Request,
+    req: Request,
 ) -> Result<Response, AppError> {
     let host = req
         .headers()
         .get(HOST)
         .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
         .context("No request host found")?;
 
-    let subdomain = host
-        .split('.')
-        .next()
-        .context("Could not determine subdomain")?;
+    // Settings stay the same for the whole request, also when the configuration is reloaded
+    let config = state.config();
+    let domain = &config.base_domain;
+
+    // Only accept hosts under the configured base domain
+    let Some(subdomain) = get_subdomain(&host, domain) else {
+        let host_name = host.split(':').next().unwrap_or(&host);
+
+        if host_name.eq_ignore_ascii_case(domain) {
+            return Ok(not_found(&config));
+        }
 
-    let domain = host.split('.').skip(1).collect::<Vec<&str>>().join(".");
+        return Ok(misdirected(&host));
+    };
+
+    // Host names are case insensitive, browsers send them in lowercase
+    let subdomain = &subdomain.to_ascii_lowercase();
 
     if is_valid_hash(subdomain) {
         let user = User::from_request(random_string(), user)?;
 
-        return redirect_to_service(state, &domain, user, subdomain).await;
+        return redirect_to_service(state, user, subdomain).await;
     }
 
-    // Check if the subdomain is a valid service
-    let Some(port) = state.services.get_port(subdomain) else {
+    if let Some(number) = get_pull_number(subdomain) {
+        let Some(commit_hash) = state.github.get_pull_commit_hash(number) else {
+            return Ok(not_found(&config));
+        };
+
+        if state
+            .services
+            .get_executable_by_commit(&commit_hash)
+            .is_none()
+        {
+            return Ok(build_not_available(number, &config));
+        }
+
+        let user = User::from_request(random_string(), user)?;
+
+        return redirect_to_service(state, user, &commit_hash).await;
+    }
+
+    if is_valid_hash_prefix(subdomain) {
+        match resolve_commit_prefix(&state, subdomain).as_slice() {
+            [] => return Ok(not_found(&config)),
+            [commit_hash] => {
+                let user = User::from_request(random_string(), user)?;
+
+                return redirect_to_service(state, user, commit_hash).await;
+            }
+            candidates => return Ok(ambiguous_hash(subdomain, candidates, &config)),
+        }
+    }
+
+    // Check if the subdomain is a valid service or alias
+    let Some(name) = state.services.resolve_name(subdomain) else {
         // Return a 404 response, with a link to the homepage
-        return Ok(not_found(&domain));
+        return Ok(not_found(&config));
+    };
+
+    let Some(ProxyTarget {
+        upstream,
+        metrics,
+        rate_limiter,
+    }) = state.services.get_proxy_target(&name)
+    else {
+        return Ok(not_found(&config));
     };
 
+    // Show a maintenance page while the service is being restarted
+    if state.services.is_restarting(&name)
+        && let Some(service) = state.services.get_service_data(&name, &config)
+    {
+        return Ok(maintenance(&service));
+    }
+
+    // Hold the request while the service is starting
+    if !wait_while_pending(&state, &name).await {
+        return Ok(still_starting(&name));
+    }
+
+    // Throttle requests above the rate limit of the service
+    if let Some(retry_after) = rate_limiter.check(config.rate_limit().as_ref()) {
+        return Ok(too_many_requests(&name, retry_after));
+    }
+
+    // Reject request bodies that are too large, also while streaming
+    let max_body_bytes = config.proxy_max_body_bytes;
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if content_length.is_some_and(|length| length > max_body_bytes) {
+        return Ok(payload_too_large(max_body_bytes));
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = count_body(body, {
+        let metrics = metrics.clone();
+        move |bytes| metrics.add_bytes_in(bytes)
+    });
+    let (body, body_exceeded) = limit_body(body, max_body_bytes);
+    let mut req = Request::from_parts(parts, body);
+
     // Update the request URI to point to the service
     let path = req.uri().path();
     let path_query = req
@@ -82,14 +598,462 @@ pub async fn handler(
         .map(|v| v.as_str())
         .unwrap_or(path);
 
-    let uri = format!("http://127.0.0.1:{port}{path_query}");
+    let uri = upstream.url(path_query);
+
+    let span = if config.proxy_access_log {
+        info_span!(
+            "proxy",
+            service = name,
+            method = %req.method(),
+            path = req.uri().path(),
+            client_ip = client_ip(req.headers(), req.extensions(), &config),
+            status = field::Empty,
+            duration_ms = field::Empty,
+        )
+    } else {
+        Span::none()
+    };
+
     *req.uri_mut() = Uri::try_from(uri).unwrap();
 
-    // Forward the request to the service
-    Ok(state
-        .client
-        .request(req)
-        .await
-        .map_err(|_| anyhow!("Upstream error"))?
-        .into_response())
+    // Set the Host header passed to the service
+    let upstream_host = match &config.proxy_host_header {
+        HostHeader::Preserve => None,
+        HostHeader::Rewrite => Some(upstream.authority()),
+        HostHeader::Override(value) => Some(value.clone()),
+    };
+
+    if let Some(upstream_host) = upstream_host {
+        let headers = req.headers_mut();
+        headers.insert(HOST, HeaderValue::from_str(&upstream_host)?);
+        headers.insert("x-forwarded-host", HeaderValue::from_str(&host)?);
+    }
+
+    // Pass the acting user to the service, anonymous visitors are only told apart when their id is passed
+    let (user, visitor_jar) = match user {
+        Some(user) => (User::GitHub(user), None),
+        None if config.inject_user_headers && config.inject_anonymous_user_headers => {
+            let (id, jar) = visitor_id(&state, req.headers());
+
+            (User::Anonymous(id), jar)
+        }
+        None => (User::Anonymous(random_string()), None),
+    };
+    set_user_headers(req.headers_mut(), &user, &config);
+    strip_etes_cookies(req.headers_mut(), &GithubOauthService::from_ref(&state));
+
+    // Forward the request to the service, Accept-Encoding is passed through as is
+    let start = Instant::now();
+    let result = state.client.request(req).instrument(span.clone()).await;
+
+    if body_exceeded.load(Ordering::Relaxed) {
+        metrics.record_request(false, start.elapsed());
+
+        return Ok(payload_too_large(max_body_bytes));
+    }
+
+    let status = result.as_ref().ok().map(|response| response.status());
+    let error = status.is_none_or(|status| status.is_server_error());
+    metrics.record_request(error, start.elapsed());
+
+    if config.proxy_access_log {
+        span.record("duration_ms", start.elapsed().as_millis());
+        if let Some(status) = status {
+            span.record("status", status.as_u16());
+        }
+        span.in_scope(|| info!("Forwarded request"));
+    }
+
+    let response = match result {
+        Ok(response) => {
+            state.services.set_reachable(&name, true);
+            response
+        }
+        Err(e) => return Ok(upstream_error(&state, &name, &user, &e)),
+    };
+
+    // Count and limit the size of the response body, the body is streamed either way.
+    // Bodies are never decoded or re-encoded, so Content-Encoding and
+    // Content-Length from the service remain valid.
+    let mut response = response.map(|body| {
+        let body = count_body(Body::new(body), move |bytes| metrics.add_bytes_out(bytes));
+
+        match config.proxy_max_response_bytes {
+            Some(limit) => limit_body(body, limit).0,
+            None => body,
+        }
+    });
+
+    // Ask reverse proxies in front of etes not to buffer event streams
+    let is_event_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+
+    if is_event_stream {
+        response
+            .headers_mut()
+            .insert("x-accel-buffering", HeaderValue::from_static("no"));
+    }
+
+    // the cookie of a new visitor
+    match visitor_jar {
+        Some(jar) => Ok((jar, response).into_response()),
+        None => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        Router,
+        extract::ConnectInfo,
+        http::{
+            Extensions, HeaderMap,
+            header::{
+                ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, COOKIE, RETRY_AFTER, SET_COOKIE,
+            },
+        },
+        response::sse::{Event, Sse},
+        routing::{any, get},
+    };
+    use futures::{StreamExt, stream};
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    use super::{client_ip, parse_forwarded_ip};
+    use crate::{
+        AppState, AppStateContainer,
+        config::{Config, ConfigArgs, RateLimit},
+        events::ServiceState,
+        executable::Executable,
+        listener::{self, ClientAddr, Listener},
+        service::Service,
+        user::User,
+    };
+
+    // "Hello from a gzip encoded service!" compressed with gzip
+    const GZIP_BODY: [u8; 54] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xf3, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0x48, 0x2b, 0xca, 0xcf, 0x55, 0x48, 0x54, 0x48, 0xaf, 0xca, 0x2c, 0x50, 0x48, 0xcd,
+        0x4b, 0xce, 0x4f, 0x49, 0x4d, 0x51, 0x28, 0x4e, 0x2d, 0x2a, 0xcb, 0x4c, 0x4e, 0x55, 0x04,
+        0x00, 0xf5, 0xa4, 0xb7, 0xe7, 0x22, 0x00, 0x00, 0x00,
+    ];
+
+    // Serve a router as a running service and the proxy in front of it, returns the proxy port
+    async fn serve_service(state: &AppState, name: &str, upstream: Router) -> u16 {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream_listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(upstream_listener, upstream).await });
+
+        let hash = "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee".to_string();
+        let executable =
+            Executable::from_commit(state.services.bin_dir(), hash.clone(), hash, None);
+        let mut service = Service::new(name, &executable, User::Anonymous("frank".into()))
+            .await
+            .unwrap();
+        service.set_port(upstream_port);
+        service.set_state(ServiceState::Running, None);
+        state.services.insert_service(name, service);
+
+        let proxy = Router::new()
+            .fallback(any(super::handler))
+            .with_state(state.clone());
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = proxy_listener.local_addr().unwrap().port();
+        tokio::spawn(listener::serve(
+            Listener::Tcp(proxy_listener),
+            None,
+            proxy.into_make_service_with_connect_info::<ClientAddr>(),
+            state.shutdown.clone(),
+        ));
+
+        proxy_port
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_is_not_buffered() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        // Upstream service sending one event, then waiting until the client received it
+        let (done, wait) = oneshot::channel::<()>();
+        let wait = Arc::new(Mutex::new(Some(wait)));
+        let upstream = Router::new().route(
+            "/events",
+            get(move || {
+                let wait = wait.lock().unwrap().take();
+                let first =
+                    stream::once(async { Ok::<_, Infallible>(Event::default().data("first")) });
+                let second = stream::once(async move {
+                    if let Some(wait) = wait {
+                        let _ = wait.await;
+                    }
+
+                    Ok::<_, Infallible>(Event::default().data("second"))
+                });
+
+                async move { Sse::new(first.chain(second)) }
+            }),
+        );
+
+        let proxy_port = serve_service(&state, "streaming", upstream).await;
+
+        let mut response = reqwest::Client::new()
+            .get(format!("http://127.0.0.1:{proxy_port}/events"))
+            .header("Host", format!("streaming.{}", state.config().base_domain))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-accel-buffering"], "no");
+
+        // The first event must arrive while the upstream stream is still open
+        let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+            .await
+            .expect("first event was buffered")
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&chunk), "data: first\n\n");
+
+        done.send(()).unwrap();
+
+        let chunk = response.chunk().await.unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&chunk), "data: second\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_compressed_response_passthrough() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        // Upstream service echoing the received Accept-Encoding with a gzip body
+        let upstream = Router::new().route(
+            "/compressed",
+            get(|headers: HeaderMap| async move {
+                let accept_encoding = headers
+                    .get(ACCEPT_ENCODING)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default();
+
+                (
+                    [
+                        (CONTENT_ENCODING, "gzip".to_string()),
+                        (ACCEPT_ENCODING, accept_encoding),
+                    ],
+                    GZIP_BODY.to_vec(),
+                )
+            }),
+        );
+
+        let proxy_port = serve_service(&state, "compressed", upstream).await;
+        let client = reqwest::Client::new();
+        let host = format!("compressed.{}", state.config().base_domain);
+
+        let response = client
+            .get(format!("http://127.0.0.1:{proxy_port}/compressed"))
+            .header("Host", &host)
+            .header(ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "54");
+        assert_eq!(response.headers()[ACCEPT_ENCODING], "gzip");
+        assert_eq!(response.bytes().await.unwrap().as_ref(), GZIP_BODY);
+
+        // The proxy must not advertise encodings the client did not ask for
+        let response = client
+            .get(format!("http://127.0.0.1:{proxy_port}/compressed"))
+            .header("Host", &host)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()[ACCEPT_ENCODING], "");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_user_header() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+        config.inject_user_headers = true;
+        config.inject_anonymous_user_headers = true;
+        *state.config.write() = Arc::new(config);
+
+        // Upstream service echoing the user header it received
+        let upstream = Router::new().route(
+            "/user",
+            get(|headers: HeaderMap| async move {
+                headers["x-etes-user-login"].to_str().unwrap().to_string()
+            }),
+        );
+
+        let proxy_port = serve_service(&state, "visitors", upstream).await;
+        let client = reqwest::Client::new();
+        let host = format!("visitors.{}", state.config().base_domain);
+        let url = format!("http://127.0.0.1:{proxy_port}/user");
+
+        let response = client.get(&url).header("Host", &host).send().await.unwrap();
+        let cookie = response.headers()[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        let login = response.text().await.unwrap();
+
+        // the same visitor gets the same id, without a new cookie
+        let response = client
+            .get(&url)
+            .header("Host", &host)
+            .header(COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(SET_COOKIE));
+        assert_eq!(response.text().await.unwrap(), login);
+
+        let response = client.get(&url).header("Host", &host).send().await.unwrap();
+        assert_ne!(response.text().await.unwrap(), login);
+    }
+
+    #[tokio::test]
+    async fn test_etes_cookies_are_not_forwarded() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        // the session cookie reaches the services with a cookie_domain
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+        config.cookie_domain = Some(config.base_domain.clone());
+        *state.config.write() = Arc::new(config);
+
+        // Upstream service echoing the cookies it received
+        let upstream = Router::new().route(
+            "/cookies",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get(COOKIE)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            }),
+        );
+
+        let proxy_port = serve_service(&state, "cookies", upstream).await;
+        let client = reqwest::Client::new();
+        let host = format!("cookies.{}", state.config().base_domain);
+
+        let cookies = |cookie: &'static str| {
+            client
+                .get(format!("http://127.0.0.1:{proxy_port}/cookies"))
+                .header("Host", &host)
+                .header(COOKIE, cookie)
+                .send()
+        };
+
+        let response = cookies("SESSION=secret; theme=dark; CSRF=token; VISITOR=id")
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "theme=dark");
+
+        let response = cookies("SESSION=secret").await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+
+        let upstream = Router::new().route("/", get(|| async { "Hello" }));
+        let proxy_port = serve_service(&state, "limited", upstream).await;
+
+        state
+            .services
+            .get_proxy_target("limited")
+            .unwrap()
+            .rate_limiter
+            .set_rate_limit(Some(RateLimit {
+                rate: 0.1,
+                burst: 1,
+            }));
+
+        let request = || {
+            reqwest::Client::new()
+                .get(format!("http://127.0.0.1:{proxy_port}/"))
+                .header("Host", format!("limited.{}", state.config().base_domain))
+                .send()
+        };
+
+        let response = request().await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = request().await.unwrap();
+        assert_eq!(response.status(), 429);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        let service = state
+            .services
+            .get_service_data("limited", &state.config())
+            .unwrap();
+        assert!(service.throttled);
+        assert_eq!(service.throttled_requests, 1);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let config = Config::from_env(&ConfigArgs::default()).unwrap();
+        let ip = |peer: Option<&str>, forwarded_for: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+
+            let mut extensions = Extensions::new();
+            extensions.insert(ConnectInfo(ClientAddr(
+                peer.map(|peer| peer.parse().unwrap()),
+            )));
+
+            client_ip(&headers, &extensions, &config)
+        };
+
+        // only a trusted reverse proxy reports the client
+        assert_eq!(ip(Some("127.0.0.1:4000"), "192.0.2.1"), "192.0.2.1");
+        assert_eq!(ip(Some("198.51.100.7:4000"), "192.0.2.1"), "198.51.100.7");
+        assert_eq!(ip(None, "192.0.2.1"), "192.0.2.1");
+
+        // entries the client added itself are skipped, as is text that is not an address
+        assert_eq!(
+            ip(Some("127.0.0.1:4000"), "203.0.113.9, 192.0.2.1, ::1"),
+            "192.0.2.1"
+        );
+        assert_eq!(ip(Some("127.0.0.1:4000"), "<script>"), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_parse_forwarded_ip() {
+        let ip = |value| parse_forwarded_ip(value).map(|ip| ip.to_string());
+
+        assert_eq!(ip("192.0.2.1"), Some("192.0.2.1".to_string()));
+        assert_eq!(ip("192.0.2.1:1234"), Some("192.0.2.1".to_string()));
+        assert_eq!(ip("2001:db8::1"), Some("2001:db8::1".to_string()));
+        assert_eq!(ip("[2001:db8::1]"), Some("2001:db8::1".to_string()));
+        assert_eq!(ip("[2001:db8::1]:1234"), Some("2001:db8::1".to_string()));
+        assert_eq!(ip("::ffff:192.0.2.1"), Some("192.0.2.1".to_string()));
+        assert_eq!(ip("unknown"), None);
+    }
 }
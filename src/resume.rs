@@ -0,0 +1,74 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use cookie::{Cookie, CookieJar, Key};
+use serde::{Deserialize, Serialize};
+
+use crate::user::User;
+
+// Encrypted under its own name, so a session cookie can't be used as a token or vice versa
+const TOKEN_NAME: &str = "etes-resume";
+
+/// Identity and event stream position of a websocket connection, encrypted
+/// with the session key so clients can neither read nor forge it
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ResumeToken {
+    pub user: User,
+    // last sequence number sent to the connection
+    pub seq: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ResumeToken {
+    pub fn new(user: User, seq: u64, ttl: u64) -> Self {
+        Self {
+            user,
+            seq,
+            expires_at: Utc::now() + TimeDelta::seconds(ttl as i64),
+        }
+    }
+
+    // Encrypt the token to hand it to the client
+    pub fn seal(&self, key: &Key) -> Option<String> {
+        let value = serde_json::to_string(self).ok()?;
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(key).add(Cookie::new(TOKEN_NAME, value));
+
+        jar.get(TOKEN_NAME).map(|cookie| cookie.value().to_string())
+    }
+
+    // Decrypt a token sent by a client, expired tokens are rejected
+    pub fn open(token: &str, key: &Key) -> Option<Self> {
+        let cookie = CookieJar::new()
+            .private(key)
+            .decrypt(Cookie::new(TOKEN_NAME, token.to_string()))?;
+
+        let token: Self = serde_json::from_str(cookie.value()).ok()?;
+
+        (token.expires_at > Utc::now()).then_some(token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cookie::Key;
+
+    use super::ResumeToken;
+    use crate::user::User;
+
+    #[test]
+    fn test_resume_token() {
+        let key = Key::from(&[1; 64]);
+        let token = ResumeToken::new(User::Anonymous("frank".to_string()), 42, 60);
+
+        let sealed = token.seal(&key).unwrap();
+        assert_eq!(ResumeToken::open(&sealed, &key), Some(token));
+
+        // tokens can't be opened with another key, or after tampering
+        assert_eq!(ResumeToken::open(&sealed, &Key::from(&[2; 64])), None);
+        assert_eq!(ResumeToken::open(&format!("A{sealed}"), &key), None);
+
+        let expired = ResumeToken::new(User::Anonymous("frank".to_string()), 42, 0);
+        let sealed = expired.seal(&key).unwrap();
+        assert_eq!(ResumeToken::open(&sealed, &key), None);
+    }
+}
@@ -1,138 +1,504 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{process::Command, sync::oneshot, task::JoinHandle};
 use tracing::{error, info};
+use utoipa::ToSchema;
 
 use crate::{
-    config::Config,
+    config::{Config, RateLimit},
     events::ServiceState,
     executable::{Executable, ExecutableData},
+    logs::{ServiceLogs, capture_output},
+    metrics::{MetricsData, ServiceMetrics},
     user::User,
     util::get_free_port,
 };
 
+/// Address a service is reached on by the proxy
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Upstream {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Upstream {
+    // Upstream for a service started by etes
+    pub fn local(port: u16) -> Self {
+        Self {
+            scheme: "http".to_string(),
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        matches!(self.scheme.as_str(), "http" | "https")
+            && !self.host.is_empty()
+            && self
+                .host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+    }
+
+    // Host and port, as used in the Host header
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // URL of a path (and query) on the upstream
+    pub fn url(&self, path_query: &str) -> String {
+        format!("{}://{}{path_query}", self.scheme, self.authority())
+    }
+}
+
 /// Service data structure for the client
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceData {
     pub name: String,
+    // public URL of the service, and of its aliases
+    pub url: String,
+    pub alias_urls: Vec<String>,
     pub port: u16,
-    pub executable: ExecutableData,
+    pub upstream: Upstream,
+    pub executable: Option<ExecutableData>,
     pub state: ServiceState,
     pub creator: User,
     pub error: Option<String>,
     pub created_at: DateTime<Utc>,
+    pub metrics: MetricsData,
+    pub aliases: Vec<String>,
+    pub exit_status: Option<String>,
+    pub restarted_by: Option<User>,
+    pub startup_duration_ms: Option<u64>,
+    pub rate_limit: Option<RateLimit>,
+    pub throttled: bool,
+    pub throttled_requests: u64,
+    // memory in bytes and CPU percentage of the process and its children
+    pub memory: Option<u64>,
+    pub cpu: Option<f32>,
 }
 
-impl From<&Service> for ServiceData {
-    fn from(service: &Service) -> Self {
+impl ServiceData {
+    pub fn new(service: &Service, config: &Config) -> Self {
+        let (rate_limit, throttled, throttled_requests) = service.rate_limiter.state();
+
         Self {
             name: service.name.to_string(),
+            url: config.service_url(&service.name),
+            alias_urls: Vec::new(),
             port: service.port,
-            executable: (&service.executable).into(),
+            upstream: service.upstream.clone(),
+            executable: service
+                .executable
+                .as_ref()
+                .map(|executable| executable.into()),
             created_at: service.created_at,
             creator: service.creator.hash_anonymous(),
             error: service.error.clone(),
             state: service.state.clone(),
+            metrics: service.metrics.snapshot(),
+            aliases: Vec::new(),
+            exit_status: service.exit_status(),
+            restarted_by: service.restarted_by.as_ref().map(User::hash_anonymous),
+            startup_duration_ms: service
+                .startup_duration
+                .map(|duration| duration.as_millis() as u64),
+            rate_limit,
+            throttled,
+            throttled_requests,
+            memory: service.resources.map(|(memory, _)| memory),
+            cpu: service.resources.map(|(_, cpu)| cpu),
+        }
+    }
+}
+
+// Number of state changes kept per service
+const HISTORY_LEN: usize = 50;
+
+/// Change of the state of a service, for its lifecycle history
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StateChange {
+    pub state: ServiceState,
+    pub error: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+/// Token bucket state for rate limiting requests
+#[derive(Debug)]
+struct TokenBucket {
+    // override of the default rate limit
+    rate_limit: Option<RateLimit>,
+    tokens: f64,
+    updated: Instant,
+    throttled: bool,
+    throttled_requests: u64,
+}
+
+/// Rate limit of a service, shared with the proxy so requests don't lock the services
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            // a full bucket, capped to the burst size on the first request
+            bucket: Mutex::new(TokenBucket {
+                rate_limit: None,
+                tokens: f64::INFINITY,
+                updated: Instant::now(),
+                throttled: false,
+                throttled_requests: 0,
+            }),
         }
     }
 }
 
+impl RateLimiter {
+    // Override the default rate limit
+    pub fn set_rate_limit(&self, rate_limit: Option<RateLimit>) {
+        self.bucket.lock().rate_limit = rate_limit;
+    }
+
+    // Take a token from the bucket, returns the time to wait when the request is throttled
+    pub fn check(&self, default: Option<&RateLimit>) -> Option<Duration> {
+        let mut bucket = self.bucket.lock();
+        let limit = bucket.rate_limit.clone().or_else(|| default.cloned())?;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+
+            return None;
+        }
+
+        bucket.throttled = true;
+        bucket.throttled_requests += 1;
+
+        Some(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+    }
+
+    // The rate limit override, whether the last request was throttled and the throttled requests
+    fn state(&self) -> (Option<RateLimit>, bool, u64) {
+        let bucket = self.bucket.lock();
+
+        (
+            bucket.rate_limit.clone(),
+            bucket.throttled,
+            bucket.throttled_requests,
+        )
+    }
+}
+
 /// Internal service data structure
 #[derive(Debug)]
 pub struct Service {
     name: String,
-    executable: Executable,
+    executable: Option<Executable>,
     port: u16,
+    upstream: Upstream,
     creator: User,
     created_at: DateTime<Utc>,
     state: ServiceState,
     error: Option<String>,
+    metrics: Arc<ServiceMetrics>,
+    reachable: bool,
+    exit_status: Arc<RwLock<Option<String>>>,
+    restarted_by: Option<User>,
+    restarts: u32,
+    history: VecDeque<StateChange>,
+    logs: Arc<ServiceLogs>,
+    startup_duration: Option<Duration>,
+    rate_limiter: Arc<RateLimiter>,
     kill: Option<oneshot::Sender<()>>,
     child: Option<JoinHandle<()>>,
+    pid: Option<u32>,
+    // memory and CPU usage, updated by the monitor
+    resources: Option<(u64, f32)>,
 }
 
 impl Service {
     pub async fn new(name: &str, executable: &Executable, creator: User) -> Option<Self> {
         let port = get_free_port().await?;
 
-        Some(Self {
+        Some(Self::with_upstream(
+            name,
+            Some(executable.clone()),
+            Upstream::local(port),
+            creator,
+        ))
+    }
+
+    // Service that is not started by etes, only proxied to
+    pub fn external(name: &str, upstream: Upstream, creator: User) -> Self {
+        Self::with_upstream(name, None, upstream, creator)
+    }
+
+    fn with_upstream(
+        name: &str,
+        executable: Option<Executable>,
+        upstream: Upstream,
+        creator: User,
+    ) -> Self {
+        let created_at = Utc::now();
+
+        Self {
             name: name.to_string(),
-            port,
-            executable: executable.clone(),
-            creator: creator.clone(),
-            created_at: Utc::now(),
+            port: upstream.port,
+            upstream,
+            executable,
+            creator,
+            created_at,
             state: ServiceState::Pending,
             error: None,
+            metrics: Arc::default(),
+            reachable: true,
+            exit_status: Arc::new(RwLock::new(None)),
+            restarted_by: None,
+            restarts: 0,
+            history: VecDeque::from([StateChange {
+                state: ServiceState::Pending,
+                error: None,
+                at: created_at,
+            }]),
+            logs: Arc::default(),
+            startup_duration: None,
+            rate_limiter: Arc::default(),
             kill: None,
             child: None,
-        })
+            pid: None,
+            resources: None,
+        }
     }
 
     pub fn set_state(&mut self, state: ServiceState, error: Option<String>) {
+        if state != ServiceState::Pending {
+            self.restarted_by = None;
+        }
+
+        self.change_state(state, error);
+    }
+
+    // Set the state and record it in the history, repeated states are recorded once
+    fn change_state(&mut self, state: ServiceState, error: Option<String>) {
+        let unchanged = self
+            .history
+            .back()
+            .is_some_and(|last| last.state == state && last.error == error);
+
+        if !unchanged {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+
+            self.history.push_back(StateChange {
+                state: state.clone(),
+                error: error.clone(),
+                at: Utc::now(),
+            });
+        }
+
         self.state = state;
         self.error = error;
     }
 
+    // Store how long the last startup took
+    pub fn set_startup_duration(&mut self, duration: Duration) {
+        self.startup_duration = Some(duration);
+    }
+
+    // Override the default rate limit for this service
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limiter.set_rate_limit(rate_limit);
+    }
+
+    #[cfg(test)]
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+        self.upstream.port = port;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Process id while the process runs
+    pub fn pid(&self) -> Option<u32> {
+        self.pid.filter(|_| self.exit_status.read().is_none())
+    }
+
+    pub fn set_resource_usage(&mut self, memory: u64, cpu: f32) {
+        self.resources = Some((memory, cpu));
+    }
+
     pub fn user(&self) -> &User {
         &self.creator
     }
 
-    pub fn port(&self) -> u16 {
-        self.port
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
     }
 
-    pub fn hash(&self) -> &str {
-        self.executable.hash()
+    pub fn metrics(&self) -> Arc<ServiceMetrics> {
+        self.metrics.clone()
     }
 
-    pub fn trigger_hash(&self) -> &str {
-        self.executable.trigger_hash()
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.executable
+            .as_ref()
+            .map(|executable| executable.hash().as_str())
+    }
+
+    pub fn trigger_hash(&self) -> Option<&str> {
+        self.executable
+            .as_ref()
+            .map(|executable| executable.trigger_hash().as_str())
+    }
+
+    pub fn state(&self) -> &ServiceState {
+        &self.state
     }
 
     pub fn error(&self) -> Option<String> {
         self.error.clone()
     }
 
+    // Exit status of the process, if it has exited by itself
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    // State changes of the service, oldest first
+    pub fn history(&self) -> Vec<StateChange> {
+        self.history.iter().cloned().collect()
+    }
+
+    pub fn logs(&self) -> Arc<ServiceLogs> {
+        self.logs.clone()
+    }
+
+    pub fn exit_status(&self) -> Option<String> {
+        self.exit_status.read().clone()
+    }
+
+    // Update whether the service accepts connections,
+    // returns true when a running service just became unreachable
+    pub fn set_reachable(&mut self, reachable: bool) -> bool {
+        let became_unreachable =
+            self.reachable && !reachable && self.state == ServiceState::Running;
+        self.reachable = reachable;
+
+        became_unreachable
+    }
+
     pub fn start(&mut self, config: &Config) {
-        // collect command args and replace port number
-        let args = config
-            .command_args
-            .iter()
-            .map(|arg| arg.replace("{port}", &self.port.to_string()))
-            .collect::<Vec<_>>();
+        *self.exit_status.write() = None;
+
+        // external services are not started by etes
+        let Some(executable) = &self.executable else {
+            return;
+        };
 
-        // collect environment variables
-        let env = config.command_env.clone();
+        // collect command args and environment variables with the placeholders replaced
+        let command = match config.service_command(
+            &self.name,
+            self.port,
+            executable.hash(),
+            executable.trigger_hash(),
+        ) {
+            Ok(command) => command,
+            Err(e) => {
+                self.change_state(ServiceState::Error, Some(format!("{e:#}")));
+                return;
+            }
+        };
+
+        // relative paths are ambiguous when the working directory changes
+        let program = std::path::absolute(executable.path())
+            .unwrap_or_else(|_| executable.path().to_path_buf());
+
+        let mut process = Command::new(program);
+        process
+            .args(command.args)
+            .envs(command.env)
+            .stderr(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped());
+
+        if let Some(workdir) = command.workdir {
+            if let Err(e) = std::fs::create_dir_all(&workdir) {
+                self.change_state(
+                    ServiceState::Error,
+                    Some(format!(
+                        "Failed to create working directory {}: {e:?}",
+                        workdir.display()
+                    )),
+                );
+                return;
+            }
+
+            process.current_dir(workdir);
+        }
 
         // start the service / run the command
-        let mut child = match Command::new(self.executable.path())
-            .args(args)
-            .envs(env)
-            .stderr(std::process::Stdio::inherit())
-            .stdout(std::process::Stdio::inherit())
-            .spawn()
-        {
+        let mut child = match process.spawn() {
             Ok(child) => child,
             Err(e) => {
-                self.state = ServiceState::Error;
-                self.error = Some(format!("Failed to start service: {e:?}"));
+                self.change_state(
+                    ServiceState::Error,
+                    Some(format!("Failed to start service: {e:?}")),
+                );
                 return;
             }
         };
 
+        self.pid = child.id();
+        self.resources = None;
+
+        if let Some(stdout) = child.stdout.take() {
+            tokio::task::spawn(capture_output(stdout, self.logs.clone(), false));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            tokio::task::spawn(capture_output(stderr, self.logs.clone(), true));
+        }
+
         // Create a oneshot channel to kill the child process
         let (kill, recv_kill) = oneshot::channel::<()>();
 
         // Store the child process and the kill channel
         let port = self.port;
+        let exit_status = self.exit_status.clone();
         self.kill = Some(kill);
         self.child = Some(tokio::task::spawn(async move {
             tokio::select! {
                 result = child.wait() => {
-                    if let Err(e) = result {
-                        error!("Child error: {:?}", e);
+                    match result {
+                        Ok(status) => {
+                            info!("Child on port {} exited: {}", port, status);
+                            *exit_status.write() = Some(status.to_string());
+                        }
+                        Err(e) => error!("Child error: {:?}", e),
                     }
                 }
                 _ = recv_kill => {
@@ -147,8 +513,32 @@ impl Service {
         }));
     }
 
+    // Pending again after a restart by a user
+    pub fn is_restarting(&self) -> bool {
+        self.state == ServiceState::Pending && self.restarted_by.is_some()
+    }
+
+    // Stop the process for a restart, returns the task to await before starting again
+    pub fn begin_restart(&mut self, initiator: User) -> Option<JoinHandle<()>> {
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.send(());
+        }
+
+        self.change_state(ServiceState::Pending, None);
+        self.restarted_by = Some(initiator);
+        self.restarts += 1;
+
+        self.child.take()
+    }
+
     // Stop the service by sending a signal to the kill channel
     pub fn stop(self) -> Result<()> {
+        self.logs.close();
+
+        if self.executable.is_none() {
+            return Ok(());
+        }
+
         match (self.kill, self.child) {
             (Some(kill), Some(_)) => {
                 let _ = kill.send(());
@@ -1,74 +1,305 @@
 use anyhow::{Result, anyhow};
-use parking_lot::RwLock;
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use tracing::{error, info};
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
 
 use crate::{
     AppState, Config,
-    events::{Event, ServiceState},
-    executable::{Executable, ExecutableData, get_executables},
+    config::RateLimit,
+    data::ServiceDetail,
+    events::{ClientEvent, ErrorCode, Event, ServerEvent, ServiceCounts, ServiceState},
+    executable::{self, Executable, ExecutableData, get_executables},
     github::CommitHash,
-    service::{Service, ServiceData},
+    health::{self, Worker},
+    logs::ServiceLogs,
+    metrics::ServiceMetrics,
+    monitor::{MB, SystemState},
+    service::{RateLimiter, Service, ServiceData, Upstream},
     user::User,
-    util::is_valid_name,
+    util::{get_pull_number, is_valid_hash_prefix, is_valid_name},
 };
 
+// File in the bin_dir with the commit hashes admins protected from the cleanup
+const PROTECTED_FILE: &str = "protected.json";
+
+/// Failed service operation, with the error code reported to the client
+#[derive(Debug)]
+pub struct ServiceError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ServiceError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Order of a service listing
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceSort {
+    // newest first
+    #[default]
+    CreatedAt,
+    Name,
+    // most memory first
+    Memory,
+}
+
+/// Selection and order of the services in a listing, everything by default
+#[derive(Debug, Clone, Default)]
+pub struct ServiceQuery {
+    pub creator: Option<User>,
+    pub state: Option<ServiceState>,
+    pub sort: ServiceSort,
+}
+
+/// What the proxy needs of a service, taken from the list once per request
+pub struct ProxyTarget {
+    pub upstream: Upstream,
+    pub metrics: Arc<ServiceMetrics>,
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
 pub struct ServiceManager {
     services: Arc<RwLock<HashMap<String, Service>>>,
+    aliases: Arc<RwLock<HashMap<String, String>>>,
     executables: Arc<RwLock<Vec<Executable>>>,
+    // time the bin directory was last scanned for executables
+    executables_scanned_at: RwLock<Option<DateTime<Utc>>>,
+    // Directory with the uploaded executables, fixed at startup
+    bin_dir: PathBuf,
+    // Service state as last broadcast, to derive the granular service events
+    broadcast: Mutex<HashMap<String, ServiceData>>,
+    // New services are refused for lack of memory, the admins have been warned
+    memory_exhausted: AtomicBool,
+    // Held while executables are removed, so cleanups don't run concurrently
+    cleanup: tokio::sync::Mutex<()>,
+    // Commit hashes protected by admins, next to the protected_hashes of the configuration
+    protected: RwLock<HashSet<CommitHash>>,
 }
 
 impl ServiceManager {
     // Construct initial state, list exsisting executables
-    pub fn new() -> Self {
+    pub fn new(bin_dir: PathBuf) -> Self {
         Self {
+            bin_dir,
             services: Arc::new(RwLock::new(HashMap::new())),
+            aliases: Arc::new(RwLock::new(HashMap::new())),
             executables: Arc::new(RwLock::new(Vec::new())),
+            executables_scanned_at: RwLock::new(None),
+            broadcast: Mutex::new(HashMap::new()),
+            memory_exhausted: AtomicBool::new(false),
+            cleanup: tokio::sync::Mutex::new(()),
+            protected: RwLock::new(HashSet::new()),
         }
     }
 
-    // Get the state of all services
-    pub fn get_state(&self) -> Vec<ServiceData> {
+    // Get the state of the services selected by the query
+    pub fn get_state(&self, config: &Config, query: &ServiceQuery) -> Vec<ServiceData> {
         let services = self.services.read();
+        let aliases = self.aliases.read();
+
+        // the creator of the service data is hashed like anonymous identities everywhere
+        let creator = query.creator.as_ref().map(User::hash_anonymous);
 
         let mut services = services
             .values()
-            .map(|service| service.into())
+            .map(|service| ServiceData::new(service, config))
+            .filter(|service| {
+                creator
+                    .as_ref()
+                    .is_none_or(|creator| service.creator == *creator)
+            })
+            .filter(|service| {
+                query
+                    .state
+                    .as_ref()
+                    .is_none_or(|state| service.state == *state)
+            })
             .collect::<Vec<ServiceData>>();
 
-        services.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        for service in services.iter_mut() {
+            service.aliases = aliases
+                .iter()
+                .filter(|(_, name)| **name == service.name)
+                .map(|(alias, _)| alias.clone())
+                .collect();
+            service.aliases.sort();
+            service.alias_urls = service
+                .aliases
+                .iter()
+                .map(|alias| config.service_url(alias))
+                .collect();
+        }
+
+        match query.sort {
+            ServiceSort::CreatedAt => services.sort_by_key(|service| Reverse(service.created_at)),
+            ServiceSort::Name => services.sort_by(|a, b| a.name.cmp(&b.name)),
+            ServiceSort::Memory => services.sort_by_key(|service| Reverse(service.memory)),
+        }
 
         services
     }
 
+    // Broadcast the services that were added, changed or removed since the last broadcast,
+    // followed by the full state if enabled
+    fn send_state(&self, state: &AppState) {
+        let services = self.get_state(&state.config(), &ServiceQuery::default());
+
+        // Holding the lock keeps the events of concurrent broadcasts in order
+        let mut broadcast = self.broadcast.lock();
+        let mut previous = std::mem::take(&mut *broadcast);
+
+        // Oldest first, so clients can insert new services at the front
+        for service in services.iter().rev() {
+            match previous.remove(&service.name) {
+                None => state.channel.send(ServerEvent::ServiceAdded {
+                    service: Box::new(service.clone()),
+                }),
+                Some(old) if old != *service => state.channel.send(ServerEvent::ServiceUpdated {
+                    service: Box::new(service.clone()),
+                }),
+                Some(_) => {}
+            }
+
+            broadcast.insert(service.name.clone(), service.clone());
+        }
+
+        for name in previous.into_keys() {
+            state.channel.send(ServerEvent::ServiceRemoved { name });
+        }
+
+        if let Some(system) = state
+            .monitor
+            .update_service_counts(ServiceCounts::count(&services))
+        {
+            state.channel.send(ServerEvent::from(system));
+        }
+
+        if state.config().service_state_snapshots {
+            state.channel.send(ServerEvent::ServiceState { services });
+        }
+    }
+
+    // Check whether the host has enough free memory for another service, and isn't
+    // swapping heavily, returns the warning for the admins the first time memory runs low
+    fn check_free_memory(
+        &self,
+        system: &SystemState,
+        config: &Config,
+    ) -> Result<(), (ServiceError, Option<String>)> {
+        // nothing measured yet
+        if system.total == 0 {
+            return Ok(());
+        }
+
+        let free = system.total.saturating_sub(system.used) / MB;
+        let swap_used = system.swap_used / MB;
+
+        let problem = match (config.min_free_memory_mb, config.max_swap_used_mb) {
+            (Some(min_free), _) if free < min_free => format!(
+                "Only {free} MB of memory is free, new services are refused below {min_free} MB"
+            ),
+            (_, Some(max_swap)) if swap_used > max_swap => format!(
+                "{swap_used} MB of swap is used, new services are refused above {max_swap} MB"
+            ),
+            _ => {
+                self.memory_exhausted.store(false, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
+
+        let warning = (!self.memory_exhausted.swap(true, Ordering::Relaxed)).then_some(problem);
+
+        Err((
+            ServiceError::new(
+                ErrorCode::MemoryExhausted,
+                "Host memory exhausted, stop an existing service first",
+            ),
+            warning,
+        ))
+    }
+
     // Add a new service, check if the service already exists, get the executable for the commit
     async fn add_service(
         &self,
         name: &str,
         executable: &Executable,
         creator: User,
-        config: &Config,
-    ) -> anyhow::Result<String> {
-        if self.services.read().contains_key(name) {
-            return Err(anyhow::anyhow!("Service {} already exists!", name));
+        force: bool,
+        state: &AppState,
+    ) -> Result<String, ServiceError> {
+        let config = state.config();
+
+        if self.services.read().contains_key(name) || self.aliases.read().contains_key(name) {
+            return Err(ServiceError::new(
+                ErrorCode::NameTaken,
+                format!("Service {} already exists!", name),
+            ));
+        }
+
+        // admins can start a service anyway
+        if let Err((error, warning)) = self.check_free_memory(&state.monitor.get_state(), &config) {
+            if let Some(message) = warning {
+                warn!("{message}");
+                state.channel.send(ServerEvent::Warning { message });
+            }
+
+            if !(force && creator.is_admin(&config)) {
+                return Err(error);
+            }
+
+            warn!("Starting service {name} despite low memory, forced by an admin");
         }
 
         info!("Starting service {name}");
         let Some(mut service) = Service::new(name, executable, creator).await else {
-            return Err(anyhow::anyhow!("Failed to start service: no free port"));
+            return Err(ServiceError::new(
+                ErrorCode::NoFreePort,
+                "Failed to start service: no free port",
+            ));
         };
 
         // Start and add the service
-        service.start(config);
+        service.start(&config);
         let error = service.error();
         self.services.write().insert(name.to_string(), service);
 
         // Stop the oldest services if the service limit is reached
-        for (index, s) in self.get_state().iter().enumerate() {
+        for (index, s) in self
+            .get_state(&config, &ServiceQuery::default())
+            .iter()
+            .enumerate()
+        {
             info!("Service {index}/{}: {}", config.max_services, s.name);
             if index >= config.max_services {
                 info!("Stopping service {}", s.name);
-                if let Some(removed_service) = self.services.write().remove(&s.name)
+                if let Some(removed_service) = self.remove_service(&s.name)
                     && let Err(e) = removed_service.stop()
                 {
                     error!("Failed to stop service {}: {:?}", name, e);
@@ -77,15 +308,21 @@ impl ServiceManager {
         }
 
         match error {
-            Some(e) => Err(anyhow::anyhow!(e)),
+            Some(e) => Err(ServiceError::new(ErrorCode::StartFailed, e)),
             None => Ok(name.to_string()),
         }
     }
 
+    // Add a service without starting it
+    #[cfg(test)]
+    pub fn insert_service(&self, name: &str, service: Service) {
+        self.services.write().insert(name.to_string(), service);
+    }
+
     // Wait for the service to start, check if the service is running
     pub async fn wait_for_startup(&self, name: &str) -> Result<()> {
-        let port = match self.services.read().get(name) {
-            Some(service) => service.port(),
+        let upstream = match self.services.read().get(name) {
+            Some(service) => service.upstream().clone(),
             None => return Err(anyhow::anyhow!("Service {} not found", name)),
         };
 
@@ -93,12 +330,18 @@ impl ServiceManager {
             .timeout(Duration::from_secs(1))
             .build()?;
 
+        let start = Instant::now();
+
         for i in 0..10 {
-            info!("Checking ({i}) service on port {}", port);
+            info!("Checking ({i}) service on {}", upstream.authority());
 
-            if let Ok(response) = client.get(format!("http://127.0.0.1:{port}/")).send().await
+            if let Ok(response) = client.get(upstream.url("/")).send().await
                 && response.status().is_success()
             {
+                if let Some(service) = self.services.write().get_mut(name) {
+                    service.set_startup_duration(start.elapsed());
+                }
+
                 self.set_service_state(name, ServiceState::Running, None);
 
                 return Ok(());
@@ -123,34 +366,245 @@ impl ServiceManager {
         }
     }
 
-    // Remove a service from the list
+    // Mark a service as (un)reachable, returns the creator when a running service just became unreachable
+    pub fn set_reachable(&self, name: &str, reachable: bool) -> Option<User> {
+        let mut services = self.services.write();
+        let service = services.get_mut(name)?;
+
+        service
+            .set_reachable(reachable)
+            .then(|| service.user().clone())
+    }
+
+    // Process ids of the services with a running process
+    pub fn pids(&self) -> Vec<(String, u32)> {
+        self.services
+            .read()
+            .values()
+            .filter_map(|service| Some((service.name().to_string(), service.pid()?)))
+            .collect()
+    }
+
+    // Store the memory and CPU usage of the services, as measured by the monitor
+    pub fn update_resource_usage(&self, usage: &[(String, u64, f32)]) {
+        let mut services = self.services.write();
+
+        for (name, memory, cpu) in usage {
+            if let Some(service) = services.get_mut(name) {
+                service.set_resource_usage(*memory, *cpu);
+            }
+        }
+    }
+
+    // Remove a service from the list, release its aliases. Both locks are held, in the order
+    // of add_alias, so no alias is added for the service while it is removed.
     fn remove_service(&self, name: &str) -> Option<Service> {
-        self.services.write().remove(name)
+        let mut services = self.services.write();
+        let mut aliases = self.aliases.write();
+
+        aliases.retain(|_, service_name| service_name != name);
+
+        services.remove(name)
+    }
+
+    // Resolve a name or an alias to the name of a service
+    pub fn resolve_name(&self, name: &str) -> Option<String> {
+        if self.services.read().contains_key(name) {
+            return Some(name.to_string());
+        }
+
+        self.aliases.read().get(name).cloned()
+    }
+
+    // Add an alias for a service, check if the alias is valid and not in use
+    fn add_alias(&self, name: &str, alias: &str) -> Result<(), ServiceError> {
+        if !is_valid_name(alias) {
+            return Err(ServiceError::new(
+                ErrorCode::InvalidName,
+                "Alias must be alphanumeric",
+            ));
+        }
+
+        // the proxy routes these subdomains to commits and pull requests, not to aliases
+        if is_valid_hash_prefix(alias) || get_pull_number(alias).is_some() {
+            return Err(ServiceError::new(
+                ErrorCode::InvalidName,
+                format!("Alias {alias} looks like a commit hash or pull request"),
+            ));
+        }
+
+        let services = self.services.read();
+        let mut aliases = self.aliases.write();
+
+        if !services.contains_key(name) {
+            return Err(ServiceError::new(
+                ErrorCode::ServiceNotFound,
+                format!("Service {name} not found"),
+            ));
+        }
+
+        if services.contains_key(alias) || aliases.contains_key(alias) {
+            return Err(ServiceError::new(
+                ErrorCode::NameTaken,
+                format!("Alias {alias} is already in use"),
+            ));
+        }
+
+        aliases.insert(alias.to_string(), name.to_string());
+
+        Ok(())
+    }
+
+    // Commit hashes of the executables the services were started from, whatever their state,
+    // a stopped or failed service can be restarted from the same executable
+    pub fn hashes_in_use(&self) -> HashSet<CommitHash> {
+        self.services
+            .read()
+            .values()
+            .flat_map(|service| [service.hash(), service.trigger_hash()])
+            .flatten()
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Commit hashes exempt from the cleanup, of the configuration and protected by admins
+    pub fn protected_hashes(&self, config: &Config) -> HashSet<CommitHash> {
+        let mut protected = self.protected.read().clone();
+        protected.extend(config.protected_hashes.iter().cloned());
+
+        protected
+    }
+
+    // Load the commit hashes admins protected before a restart
+    pub async fn load_protected(&self) -> Result<()> {
+        let contents = match tokio::fs::read(self.bin_dir.join(PROTECTED_FILE)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        *self.protected.write() = serde_json::from_slice(&contents)?;
+
+        Ok(())
     }
 
-    // Get the list of executable commit hashes
-    pub fn get_executables(&self) -> Vec<ExecutableData> {
+    // Protect a commit hash from the cleanup or lift that, and store the protected hashes
+    pub async fn set_protected(&self, hash: &str, protected: bool) -> Result<()> {
+        let mut hashes: Vec<CommitHash> = {
+            let mut hashes = self.protected.write();
+
+            if protected {
+                hashes.insert(hash.to_string());
+            } else {
+                hashes.remove(hash);
+            }
+
+            hashes.iter().cloned().collect()
+        };
+        hashes.sort();
+
+        tokio::fs::write(
+            self.bin_dir.join(PROTECTED_FILE),
+            serde_json::to_vec_pretty(&hashes)?,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // Get the list of executable commit hashes, newest uploads first
+    pub fn get_executables(&self, config: &Config) -> Vec<ExecutableData> {
+        let protected = self.protected_hashes(config);
+
         self.executables
             .read()
             .iter()
-            .map(|executable| executable.into())
+            .map(|executable| {
+                ExecutableData::from(executable).with_protected(executable.is_protected(&protected))
+            })
             .collect()
     }
 
+    pub fn bin_dir(&self) -> &Path {
+        &self.bin_dir
+    }
+
     // Update the list of executables
     pub async fn update_executables(&self) {
-        let executables = get_executables().await;
+        let executables = get_executables(&self.bin_dir).await;
 
         *self.executables.write() = executables;
+        *self.executables_scanned_at.write() = Some(Utc::now());
     }
 
-    // Get the port of a service by a name
-    pub fn get_port(&self, name: &str) -> Option<u16> {
-        if let Some(service) = self.services.read().get(name) {
-            return Some(service.port());
-        }
+    pub fn last_executable_scan(&self) -> Option<DateTime<Utc>> {
+        *self.executables_scanned_at.read()
+    }
 
-        None
+    // Get the upstream of a service by a name, with the metrics and rate limit used while proxying
+    pub fn get_proxy_target(&self, name: &str) -> Option<ProxyTarget> {
+        self.services.read().get(name).map(|service| ProxyTarget {
+            upstream: service.upstream().clone(),
+            metrics: service.metrics(),
+            rate_limiter: service.rate_limiter(),
+        })
+    }
+
+    // Check if a service is being restarted, the proxy shows a maintenance page meanwhile
+    pub fn is_restarting(&self, name: &str) -> bool {
+        self.services
+            .read()
+            .get(name)
+            .is_some_and(|service| service.is_restarting())
+    }
+
+    // Get the client data of a service by a name
+    pub fn get_service_data(&self, name: &str, config: &Config) -> Option<ServiceData> {
+        self.services
+            .read()
+            .get(name)
+            .map(|service| ServiceData::new(service, config))
+    }
+
+    // Get a service by a name or alias with the details that are left out of the state
+    // broadcasts, the logs only for its owner and admins
+    pub fn get_service_detail(
+        &self,
+        name: &str,
+        user: &User,
+        config: &Config,
+    ) -> Option<ServiceDetail> {
+        let name = self.resolve_name(name)?;
+        let service = self
+            .get_state(config, &ServiceQuery::default())
+            .into_iter()
+            .find(|service| service.name == name)?;
+        let is_owner = self.is_owner(&name, user, config);
+
+        let services = self.services.read();
+        let details = services.get(&name)?;
+
+        Some(ServiceDetail {
+            service,
+            restarts: details.restarts(),
+            history: details.history(),
+            logs: is_owner.then(|| details.logs().recent()),
+        })
+    }
+
+    // Get the output of a service by a name or alias
+    pub fn get_logs(&self, name: &str) -> Option<Arc<ServiceLogs>> {
+        let name = self.resolve_name(name)?;
+
+        self.services.read().get(&name).map(Service::logs)
+    }
+
+    // Get the state of a service by a name
+    pub fn get_service_state(&self, name: &str) -> Option<ServiceState> {
+        self.services
+            .read()
+            .get(name)
+            .map(|service| service.state().clone())
     }
 
     // Check if the caller is the owner of the service, or is the admin
@@ -162,13 +616,50 @@ impl ServiceManager {
         false
     }
 
-    // Get the executable by the commit hash
+    // Get the executable by the commit hash, the build for the platform of the host when there are several
     pub fn get_executable_by_commit(&self, commit: &CommitHash) -> Option<Executable> {
         self.executables
             .read()
             .iter()
-            .find(|executable| executable.hash() == commit || executable.trigger_hash() == commit)
+            .filter(|executable| executable.hash() == commit || executable.trigger_hash() == commit)
+            .min_by_key(|executable| !executable.runs_on_host())
+            .cloned()
+    }
+
+    // Wait for a running cleanup of the executables to finish
+    pub async fn lock_cleanup(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.cleanup.lock().await
+    }
+
+    // Get the executable of a build for a platform
+    pub fn get_executable(
+        &self,
+        hash: &str,
+        trigger_hash: &str,
+        platform: Option<&str>,
+    ) -> Option<Executable> {
+        self.executables
+            .read()
+            .iter()
+            .find(|executable| {
+                executable.hash() == hash
+                    && executable.trigger_hash() == trigger_hash
+                    && executable.platform() == platform
+            })
+            .cloned()
+    }
+
+    // Get all executables with a commit hash starting with the given prefix
+    pub fn get_executables_by_commit_prefix(&self, prefix: &str) -> Vec<Executable> {
+        self.executables
+            .read()
+            .iter()
+            .filter(|executable| {
+                executable.hash().starts_with(prefix)
+                    || executable.trigger_hash().starts_with(prefix)
+            })
             .cloned()
+            .collect()
     }
 
     // Get the name of a service by the commit hash
@@ -176,7 +667,7 @@ impl ServiceManager {
         let services = self.services.read();
 
         for (name, service) in services.iter() {
-            if service.hash() == commit || service.trigger_hash() == commit {
+            if service.hash() == Some(commit) || service.trigger_hash() == Some(commit) {
                 return Some(name.clone());
             }
         }
@@ -184,15 +675,44 @@ impl ServiceManager {
         None
     }
 
-    // Stop a service, check if the caller is the owner
-    async fn stop_service(&self, name: &str, user: User, state: AppState) {
-        if !self.is_owner(name, &user, state.config) {
-            state.channel.send(Event::Error {
-                message: "You are not the owner of this service".to_owned(),
+    // Send the details of a single service to the caller
+    fn get_service(&self, name: &str, user: User, state: &AppState) {
+        let Some(service) = self.get_service_detail(name, &user, &state.config()) else {
+            state.channel.send(ServerEvent::Error {
+                message: format!("Service {name} does not exist"),
+                code: ErrorCode::ServiceNotFound,
                 user,
+                request_id: None,
             });
 
             return;
+        };
+
+        state.channel.send(ServerEvent::ServiceDetail {
+            service: Box::new(service),
+            user,
+        });
+    }
+
+    // Stop a service, check if the caller is the owner
+    pub fn try_stop_service(
+        &self,
+        name: &str,
+        user: &User,
+        state: &AppState,
+    ) -> Result<(), ServiceError> {
+        if !self.services.read().contains_key(name) {
+            return Err(ServiceError::new(
+                ErrorCode::ServiceNotFound,
+                format!("Service {name} does not exist"),
+            ));
+        }
+
+        if !self.is_owner(name, user, &state.config()) {
+            return Err(ServiceError::new(
+                ErrorCode::NotOwner,
+                "You are not the owner of this service",
+            ));
         }
 
         if let Some(service) = self.remove_service(name)
@@ -201,75 +721,339 @@ impl ServiceManager {
             error!("Failed to stop service {}: {:?}", name, e);
         }
 
-        state.channel.send(Event::ServiceState {
-            services: self.get_state(),
-        });
+        self.send_state(state);
+
+        Ok(())
     }
 
-    // Start a service, check if the commit exists, check if the name is alphanumeric
-    pub async fn start_service(
+    // Stop a service on request of a websocket client
+    async fn stop_service(
         &self,
         name: &str,
-        commit_hash: &CommitHash,
+        user: User,
+        request_id: Option<String>,
+        state: AppState,
+    ) {
+        match self.try_stop_service(name, &user, &state) {
+            Ok(()) => {
+                if let Some(request_id) = request_id {
+                    state.channel.send(ServerEvent::Done { request_id, user });
+                }
+            }
+            Err(e) => state.channel.send(ServerEvent::Error {
+                message: e.message,
+                code: e.code,
+                user,
+                request_id,
+            }),
+        }
+    }
+
+    // Restart the process of a service, check if the caller is the owner
+    async fn restart_service(&self, name: &str, user: User, state: AppState) {
+        if !self.is_owner(name, &user, &state.config()) {
+            state.channel.send(ServerEvent::Error {
+                message: "You are not the owner of this service".to_owned(),
+                code: ErrorCode::NotOwner,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        info!("Restarting service {name}");
+
+        // Stop the running process and wait until it has exited
+        let child = match self.services.write().get_mut(name) {
+            Some(service) => service.begin_restart(user.clone()),
+            None => return,
+        };
+
+        if let Some(child) = child {
+            let _ = child.await;
+        }
+
+        let error = match self.services.write().get_mut(name) {
+            Some(service) => {
+                service.start(&state.config());
+                service.error()
+            }
+            None => return,
+        };
+
+        if let Some(error) = error {
+            error!("Failed to restart service {}: {}", name, error);
+            self.set_service_state(name, ServiceState::Error, Some(error.clone()));
+
+            state.channel.send(ServerEvent::Error {
+                message: format!("Failed to restart service: {error}"),
+                code: ErrorCode::StartFailed,
+                user,
+                request_id: None,
+            });
+            self.send_state(&state);
+
+            return;
+        }
+
+        self.send_state(&state);
+
+        if let Err(e) = self.wait_for_startup(name).await {
+            error!("Failed to restart service {}: {:?}", name, e);
+            state.channel.send(ServerEvent::Error {
+                message: format!("Failed to restart service: {e}"),
+                code: ErrorCode::StartFailed,
+                user,
+                request_id: None,
+            });
+        } else {
+            info!("Restarted service {}", name);
+        }
+
+        self.send_state(&state);
+    }
+
+    // Override the rate limit of a service, only allowed for admins
+    fn set_rate_limit(
+        &self,
+        name: &str,
+        rate_limit: Option<RateLimit>,
         user: User,
         state: AppState,
     ) {
-        // Check if the commit exists
-        let executable = match self.get_executable_by_commit(commit_hash) {
-            Some(executable) => executable,
-            None => {
-                state.channel.send(Event::Error {
-                    message: "Executable not found".to_owned(),
+        if !user.is_admin(&state.config()) {
+            state.channel.send(ServerEvent::Error {
+                message: "Only admins can change rate limits".to_owned(),
+                code: ErrorCode::NotAdmin,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        if rate_limit
+            .as_ref()
+            .is_some_and(|limit| !limit.rate.is_finite() || limit.rate <= 0.0 || limit.burst == 0)
+        {
+            state.channel.send(ServerEvent::Error {
+                message: "Rate limit and burst must be positive".to_owned(),
+                code: ErrorCode::InvalidRequest,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        match self.services.write().get_mut(name) {
+            Some(service) => service.set_rate_limit(rate_limit),
+            None => return,
+        }
+
+        self.send_state(&state);
+    }
+
+    // Register a service running elsewhere, only allowed for admins
+    async fn add_external_service(
+        &self,
+        name: &str,
+        upstream: Upstream,
+        user: User,
+        state: AppState,
+    ) {
+        if !user.is_admin(&state.config()) {
+            state.channel.send(ServerEvent::Error {
+                message: "Only admins can add external services".to_owned(),
+                code: ErrorCode::NotAdmin,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        if !is_valid_name(name) || !upstream.is_valid() {
+            state.channel.send(ServerEvent::Error {
+                message: "Invalid external service name or upstream".to_owned(),
+                code: ErrorCode::InvalidRequest,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        {
+            let mut services = self.services.write();
+
+            if services.contains_key(name) || self.aliases.read().contains_key(name) {
+                drop(services);
+                state.channel.send(ServerEvent::Error {
+                    message: format!("Service {name} already exists!"),
+                    code: ErrorCode::NameTaken,
                     user,
+                    request_id: None,
                 });
 
                 return;
             }
-        };
 
-        // check name is alphanumeric
-        if !is_valid_name(name) {
-            state.channel.send(Event::Error {
-                message: "Service name must be alphanumeric".to_owned(),
+            info!("Adding external service {name} on {}", upstream.url("/"));
+            services.insert(
+                name.to_string(),
+                Service::external(name, upstream, user.clone()),
+            );
+        }
+
+        self.send_state(&state);
+
+        if let Err(e) = self.wait_for_startup(name).await {
+            state.channel.send(ServerEvent::Error {
+                message: format!("External service is not reachable: {e}"),
+                code: ErrorCode::ServiceUnreachable,
+                user,
+                request_id: None,
+            });
+        }
+
+        self.send_state(&state);
+    }
+
+    // Set an alias for a service, check if the caller is the owner
+    async fn set_alias(&self, name: &str, alias: &str, user: User, state: AppState) {
+        if !self.is_owner(name, &user, &state.config()) {
+            state.channel.send(ServerEvent::Error {
+                message: "You are not the owner of this service".to_owned(),
+                code: ErrorCode::NotOwner,
+                user,
+                request_id: None,
+            });
+
+            return;
+        }
+
+        if let Err(e) = self.add_alias(name, alias) {
+            state.channel.send(ServerEvent::Error {
+                message: format!("Failed to set alias: {e}"),
+                code: e.code,
                 user,
+                request_id: None,
             });
+
             return;
         }
 
+        info!("Added alias {alias} for service {name}");
+
+        self.send_state(&state);
+    }
+
+    // Start a service for the executable of a commit and wait until it is up,
+    // the dashboards follow along through the broadcasts of the service state
+    pub async fn try_start_service(
+        &self,
+        name: &str,
+        commit_hash: &CommitHash,
+        user: &User,
+        force: bool,
+        state: &AppState,
+    ) -> Result<ServiceData, ServiceError> {
+        let executable = self.get_executable_by_commit(commit_hash).ok_or_else(|| {
+            ServiceError::new(ErrorCode::ExecutableNotFound, "Executable not found")
+        })?;
+
+        if !executable.runs_on_host() {
+            return Err(ServiceError::new(
+                ErrorCode::WrongPlatform,
+                format!(
+                    "Executable is built for {}, services run on {}",
+                    executable.platform().unwrap_or_default(),
+                    executable::host_platform()
+                ),
+            ));
+        }
+
+        if executable.is_corrupt() {
+            return Err(ServiceError::new(
+                ErrorCode::ExecutableCorrupt,
+                "Executable does not match the checksum of its upload",
+            ));
+        }
+
+        // check name is alphanumeric
+        if !is_valid_name(name) {
+            return Err(ServiceError::new(
+                ErrorCode::InvalidName,
+                "Service name must be alphanumeric",
+            ));
+        }
+
         // Add and start the service
+        if let Err(e) = self
+            .add_service(name, &executable, user.clone(), force, state)
+            .await
+        {
+            error!("Failed to start service: {}", e);
+            self.send_state(state);
+
+            return Err(ServiceError::new(
+                e.code,
+                format!("Failed to start service: {e}"),
+            ));
+        }
+
+        self.send_state(state);
+
+        if let Err(e) = self.wait_for_startup(name).await {
+            error!("Failed to start service {}: {:?}", name, e);
+
+            return Err(ServiceError::new(
+                ErrorCode::StartFailed,
+                format!("Failed to start service: {e}"),
+            ));
+        }
+
+        info!("Started service {}", name);
+        self.send_state(state);
+
+        self.get_state(&state.config(), &ServiceQuery::default())
+            .into_iter()
+            .find(|service| service.name == name)
+            .ok_or_else(|| {
+                ServiceError::new(
+                    ErrorCode::ServiceNotFound,
+                    format!("Service {name} was stopped while starting"),
+                )
+            })
+    }
+
+    // Start a service on request of a websocket client or the proxy
+    pub async fn start_service(
+        &self,
+        name: &str,
+        commit_hash: &CommitHash,
+        user: User,
+        request_id: Option<String>,
+        force: bool,
+        state: AppState,
+    ) {
         match self
-            .add_service(name, &executable, user.clone(), state.config)
+            .try_start_service(name, commit_hash, &user, force, &state)
             .await
         {
             Ok(_) => {
-                state.channel.send(Event::ServiceState {
-                    services: self.get_state(),
-                });
-
-                if let Err(e) = self.wait_for_startup(name).await {
-                    error!("Failed to start service {}: {:?}", name, e);
-                    state.channel.send(Event::Error {
-                        message: format!("Failed to start service: {e}"),
-                        user,
-                    });
-                } else {
-                    info!("Started service {}", name);
-                    state.channel.send(Event::ServiceState {
-                        services: self.get_state(),
-                    });
+                if let Some(request_id) = request_id {
+                    state.channel.send(ServerEvent::Done { request_id, user });
                 }
             }
-            Err(e) => {
-                error!("Failed to start service: {}", e);
-                state.channel.send(Event::Error {
-                    message: format!("Failed to start service: {e}"),
-                    user,
-                });
-
-                state.channel.send(Event::ServiceState {
-                    services: self.get_state(),
-                });
-            }
+            Err(e) => state.channel.send(ServerEvent::Error {
+                message: e.message,
+                code: e.code,
+                user,
+                request_id,
+            }),
         }
     }
 }
@@ -277,39 +1061,147 @@ impl ServiceManager {
 pub async fn start_and_stop_services(state: AppState) -> Result<()> {
     let mut receiver = state.channel.get_receiver();
 
-    while let Ok(event) = receiver.recv().await {
+    loop {
+        let received =
+            match health::next_event(&mut receiver, &state.health, Worker::Services).await {
+                Ok(received) => received,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Service worker missed {skipped} events");
+                    state.channel.record_lag(skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+        // Debug print log all incoming events
+        let event = received.event;
+        if !event.is_usage_update() {
+            match event.caller() {
+                Some(caller) => info!(
+                    "Received event {} from {} ({} at {})",
+                    event.name(),
+                    caller,
+                    received.origin,
+                    received.emitted_at
+                ),
+                None => info!(
+                    "Received event: {} ({} at {})",
+                    event.name(),
+                    received.origin,
+                    received.emitted_at
+                ),
+            }
+        }
+
         match event {
-            Event::StopService { name, user } => {
+            Event::Client(ClientEvent::StopService {
+                name,
+                user,
+                request_id,
+            }) => {
                 let state = state.clone();
                 tokio::task::spawn(async move {
                     state
                         .services
-                        .stop_service(&name, user, state.clone())
+                        .stop_service(&name, user, request_id, state.clone())
                         .await;
                 });
             }
-            Event::StartService {
-                executable,
+            Event::Client(ClientEvent::RestartService { name, user }) => {
+                let state = state.clone();
+                tokio::task::spawn(async move {
+                    state
+                        .services
+                        .restart_service(&name, user, state.clone())
+                        .await;
+                });
+            }
+            Event::Client(ClientEvent::SetRateLimit {
                 name,
+                rate_limit,
                 user,
-            } => {
+            }) => {
+                state
+                    .services
+                    .set_rate_limit(&name, rate_limit, user, state.clone());
+            }
+            Event::Client(ClientEvent::AddExternalService {
+                name,
+                upstream,
+                user,
+            }) => {
                 let state = state.clone();
                 tokio::task::spawn(async move {
                     state
                         .services
-                        .start_service(&name, executable.hash(), user, state.clone())
+                        .add_external_service(&name, upstream, user, state.clone())
                         .await;
                 });
             }
-            _ => {
-                // Debug print log all incoming events
-                if !event.is_memory_state() {
-                    match event.caller() {
-                        Some(caller) => info!("Received event {} from {}", event.name(), caller),
-                        None => info!("Received event: {}", event.name()),
-                    }
-                }
+            Event::Client(ClientEvent::GetService { name, user }) => {
+                state.services.get_service(&name, user, &state);
+            }
+            Event::Client(ClientEvent::CleanupExecutables { user }) => {
+                tokio::task::spawn(executable::cleanup_executables(state.clone(), user));
+            }
+            Event::Client(ClientEvent::DeleteExecutable {
+                hash,
+                trigger_hash,
+                platform,
+                user,
+            }) => {
+                tokio::task::spawn(executable::delete_executable(
+                    state.clone(),
+                    hash,
+                    trigger_hash,
+                    platform,
+                    user,
+                ));
+            }
+            Event::Client(ClientEvent::ProtectExecutable {
+                hash,
+                protected,
+                user,
+            }) => {
+                tokio::task::spawn(executable::protect_executable(
+                    state.clone(),
+                    hash,
+                    protected,
+                    user,
+                ));
             }
+            Event::Client(ClientEvent::SetAlias { name, alias, user }) => {
+                let state = state.clone();
+                tokio::task::spawn(async move {
+                    state
+                        .services
+                        .set_alias(&name, &alias, user, state.clone())
+                        .await;
+                });
+            }
+            Event::Client(ClientEvent::StartService {
+                executable,
+                name,
+                user,
+                request_id,
+                force,
+            }) => {
+                let state = state.clone();
+                tokio::task::spawn(async move {
+                    state
+                        .services
+                        .start_service(
+                            &name,
+                            executable.hash(),
+                            user,
+                            request_id,
+                            force,
+                            state.clone(),
+                        )
+                        .await;
+                });
+            }
+            _ => {}
         }
     }
 
@@ -318,23 +1210,124 @@ pub async fn start_and_stop_services(state: AppState) -> Result<()> {
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use crate::{
         AppState, AppStateContainer,
-        events::{Event, ServiceState},
-        executable::Executable,
-        services::start_and_stop_services,
-        user::User,
+        config::{Config, ConfigArgs},
+        events::{ClientEvent, ErrorCode, Event, SequencedEvent, ServerEvent, ServiceState},
+        executable::{self, Executable, prepare_bin_dir},
+        service::{Service, Upstream},
+        services::{ServiceManager, ServiceQuery, ServiceSort, start_and_stop_services},
+        user::{GitHubUser, User},
     };
+    use tokio::sync::broadcast::Receiver;
+
+    // Next event, skipping the usage updates that follow every change of the service counts
+    async fn next_event(receiver: &mut Receiver<SequencedEvent>) -> Event {
+        loop {
+            let event = receiver.recv().await.unwrap().event;
+
+            if !event.is_usage_update() {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_state_query() {
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
+        let config = state.config();
+
+        let octocat = User::GitHub(GitHubUser {
+            login: "octocat".to_string(),
+            name: "The Octocat".to_string(),
+            avatar_url: String::new(),
+        });
+        let anonymous = User::Anonymous("frank".to_string());
+
+        for (name, user, service_state) in [
+            ("alpha", &octocat, ServiceState::Running),
+            ("bravo", &anonymous, ServiceState::Running),
+            ("charlie", &anonymous, ServiceState::Error),
+        ] {
+            let mut service = Service::external(name, Upstream::local(1), user.clone());
+            service.set_state(service_state, None);
+            state
+                .services
+                .services
+                .write()
+                .insert(name.to_string(), service);
+        }
+
+        let names = |query: ServiceQuery| {
+            state
+                .services
+                .get_state(&config, &query)
+                .into_iter()
+                .map(|service| service.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(ServiceQuery {
+                sort: ServiceSort::Name,
+                ..Default::default()
+            }),
+            ["alpha", "bravo", "charlie"]
+        );
+        assert_eq!(
+            names(ServiceQuery {
+                creator: Some(anonymous),
+                state: Some(ServiceState::Running),
+                sort: ServiceSort::Name,
+            }),
+            ["bravo"]
+        );
+        assert_eq!(
+            names(ServiceQuery {
+                creator: Some(octocat),
+                ..Default::default()
+            }),
+            ["alpha"]
+        );
+    }
+
+    #[test]
+    fn test_add_alias() {
+        let services = ServiceManager::new("bin".into());
+        let service =
+            Service::external("alpha", Upstream::local(1), User::Anonymous("frank".into()));
+        services
+            .services
+            .write()
+            .insert("alpha".to_string(), service);
+
+        assert!(services.add_alias("alpha", "staging").is_ok());
+        assert_eq!(services.resolve_name("staging").as_deref(), Some("alpha"));
+
+        for alias in ["deadbeef", "pr-12", &"a".repeat(40)] {
+            let error = services.add_alias("alpha", alias).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidName);
+        }
+    }
 
     #[tokio::test]
     async fn test_service_manager() {
-        let state: AppState = AppStateContainer::new().unwrap().into();
+        let state: AppState = AppStateContainer::new(ConfigArgs::default())
+            .unwrap()
+            .into();
 
-        assert_eq!(state.config.command_args[0], "{port}");
+        assert_eq!(state.config().command_args[0], "{port}");
 
         let hash = "ffffffffffffffffffffffffffffffffffffffff".to_string();
-        let executable = Executable::from_commit(hash.clone(), hash.clone());
+        let executable =
+            Executable::from_commit(state.services.bin_dir(), hash.clone(), hash.clone(), None);
 
+        prepare_bin_dir(state.services.bin_dir()).await.unwrap();
+        executable.create_dir().await.unwrap();
         let _ = tokio::fs::remove_file(executable.path()).await;
         tokio::fs::copy("test/hello-world", executable.path())
             .await
@@ -348,21 +1341,37 @@ mod test {
 
         tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
 
-        state.channel.send(Event::StartService {
+        state.channel.send(ClientEvent::StartService {
             executable: (&executable).into(),
             name: "foobar".to_string(),
             user: User::Anonymous("frank".to_string()),
+            request_id: None,
+            force: false,
         });
 
-        let event = receiver.recv().await.unwrap();
+        let event = next_event(&mut receiver).await;
 
-        let Event::StartService { .. } = event else {
+        let Event::Client(ClientEvent::StartService { .. }) = event else {
             panic!("Expected StartService event, got {event:?}");
         };
 
-        let event = receiver.recv().await.unwrap();
+        let event = next_event(&mut receiver).await;
+
+        let Event::Server(ServerEvent::ServiceAdded { service }) = event else {
+            panic!("Expected ServiceAdded event, got {event:?}");
+        };
+
+        assert_eq!(service.name, "foobar");
+        assert_eq!(service.state, ServiceState::Pending);
+
+        // executables copied into the bin_dir get the size of the file system
+        let size = tokio::fs::metadata("test/hello-world").await.unwrap().len();
+        let data = serde_json::to_value(service.executable.as_ref().unwrap()).unwrap();
+        assert_eq!(data["sizeBytes"], size);
 
-        let Event::ServiceState { services } = event else {
+        let event = next_event(&mut receiver).await;
+
+        let Event::Server(ServerEvent::ServiceState { services }) = event else {
             panic!("Expected ServiceData event, got {event:?}");
         };
 
@@ -370,9 +1379,18 @@ mod test {
         assert_eq!(services[0].name, "foobar");
         assert_eq!(services[0].state, ServiceState::Pending);
 
-        let event = receiver.recv().await.unwrap();
+        let event = next_event(&mut receiver).await;
+
+        let Event::Server(ServerEvent::ServiceUpdated { service }) = event else {
+            panic!("Expected ServiceUpdated event, got {event:?}");
+        };
+
+        assert_eq!(service.name, "foobar");
+        assert_eq!(service.state, ServiceState::Running);
+
+        let event = next_event(&mut receiver).await;
 
-        let Event::ServiceState { services } = event else {
+        let Event::Server(ServerEvent::ServiceState { services }) = event else {
             panic!("Expected ServiceData event, got {event:?}");
         };
 
@@ -380,20 +1398,55 @@ mod test {
         assert_eq!(services[0].name, "foobar");
         assert_eq!(services[0].state, ServiceState::Running);
 
-        state.channel.send(Event::StopService {
+        state.channel.send(ClientEvent::GetService {
+            name: "foobar".to_string(),
+            user: User::Anonymous("frank".to_string()),
+        });
+
+        let event = next_event(&mut receiver).await;
+
+        let Event::Client(ClientEvent::GetService { .. }) = event else {
+            panic!("Expected GetService event, got {event:?}");
+        };
+
+        let event = next_event(&mut receiver).await;
+
+        let Event::Server(ServerEvent::ServiceDetail { service, user }) = event else {
+            panic!("Expected ServiceDetail event, got {event:?}");
+        };
+
+        assert_eq!(service.service.name, "foobar");
+        assert_eq!(service.restarts, 0);
+        assert_eq!(
+            service.history.last().map(|change| &change.state),
+            Some(&ServiceState::Running)
+        );
+        assert!(service.logs.is_some());
+        assert_eq!(user, User::Anonymous("frank".to_string()));
+
+        state.channel.send(ClientEvent::StopService {
             name: "foobar".to_string(),
             user: User::Anonymous("frank".to_string()),
+            request_id: None,
         });
 
-        let event = receiver.recv().await.unwrap();
+        let event = next_event(&mut receiver).await;
 
-        let Event::StopService { .. } = event else {
+        let Event::Client(ClientEvent::StopService { .. }) = event else {
             panic!("Expected StopService event, got {event:?}");
         };
 
-        let event = receiver.recv().await.unwrap();
+        let event = next_event(&mut receiver).await;
+
+        let Event::Server(ServerEvent::ServiceRemoved { name }) = event else {
+            panic!("Expected ServiceRemoved event, got {event:?}");
+        };
+
+        assert_eq!(name, "foobar");
+
+        let event = next_event(&mut receiver).await;
 
-        let Event::ServiceState { services } = event else {
+        let Event::Server(ServerEvent::ServiceState { services }) = event else {
             panic!("Expected ServiceData event, got {event:?}");
         };
 
@@ -404,4 +1457,90 @@ mod test {
 
         tokio::fs::remove_file(executable.path()).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_cleanup_keeps_executables_in_use() {
+        // the cleanup removes every unused executable, so it gets a bin_dir of its own
+        let bin_dir = std::env::temp_dir().join(format!("etes-cleanup-{}", std::process::id()));
+        let args = ConfigArgs {
+            bin_dir: Some(bin_dir.to_string_lossy().to_string()),
+            ..ConfigArgs::default()
+        };
+
+        let state: AppState = AppStateContainer::new(args.clone()).unwrap().into();
+
+        // GitHub knows no commits and every executable is expired
+        let mut config = Config::from_env(&args).unwrap();
+        config.retention_days = Some(0);
+        config.release_retention_days = Some(0);
+        *state.config.write() = Arc::new(config);
+
+        let hash = "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee".to_string();
+        let executable =
+            Executable::from_commit(state.services.bin_dir(), hash.clone(), hash.clone(), None);
+
+        prepare_bin_dir(state.services.bin_dir()).await.unwrap();
+        executable.create_dir().await.unwrap();
+        let _ = tokio::fs::remove_file(executable.path()).await;
+        tokio::fs::copy("test/hello-world", executable.path())
+            .await
+            .unwrap();
+
+        let mut receiver = state.channel.get_receiver();
+
+        state.services.update_executables().await;
+
+        let job = tokio::task::spawn(start_and_stop_services(state.clone()));
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        state.channel.send(ClientEvent::StartService {
+            executable: (&executable).into(),
+            name: "cleanup".to_string(),
+            user: User::Anonymous("frank".to_string()),
+            request_id: None,
+            force: false,
+        });
+
+        loop {
+            if let Event::Server(ServerEvent::ServiceUpdated { service }) =
+                next_event(&mut receiver).await
+                && service.state == ServiceState::Running
+            {
+                break;
+            }
+        }
+
+        executable::remove_unused_executables(state.clone())
+            .await
+            .unwrap();
+
+        assert!(executable.path().exists());
+
+        state.channel.send(ClientEvent::StopService {
+            name: "cleanup".to_string(),
+            user: User::Anonymous("frank".to_string()),
+            request_id: None,
+        });
+
+        loop {
+            if let Event::Server(ServerEvent::ServiceRemoved { .. }) =
+                next_event(&mut receiver).await
+            {
+                break;
+            }
+        }
+
+        job.abort();
+        let _ = job.await;
+
+        // without its service the executable is removed
+        executable::remove_unused_executables(state.clone())
+            .await
+            .unwrap();
+
+        assert!(!executable.path().exists());
+
+        tokio::fs::remove_dir_all(&bin_dir).await.unwrap();
+    }
 }
@@ -0,0 +1,272 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
+use tracing::{error, warn};
+use url::Url;
+
+use crate::events::SequencedEvent;
+
+// Events queued per sink, further events are dropped until the sink catches up
+const SINK_QUEUE_SIZE: usize = 1024;
+
+// Attempts to deliver an event to a webhook, waiting twice as long after every failure
+const WEBHOOK_ATTEMPTS: u32 = 5;
+const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+// Webhook that receives every event as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventWebhook {
+    pub url: String,
+    // Sent as a bearer token in the Authorization header
+    pub token: Option<String>,
+}
+
+/// Destination for the events on the bus besides the websocket clients,
+/// like a log file or an external system
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> String;
+
+    // Called while sending on the bus, so it must not block
+    fn send(&self, event: &SequencedEvent);
+
+    // Number of events that were not delivered
+    fn errors(&self) -> u64;
+}
+
+/// Bounded queue to a background writer, so a slow or dead destination can't block the bus
+struct SinkQueue {
+    sender: mpsc::Sender<String>,
+    errors: Arc<AtomicU64>,
+}
+
+impl SinkQueue {
+    fn new() -> (Self, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(SINK_QUEUE_SIZE);
+
+        let queue = Self {
+            sender,
+            errors: Arc::default(),
+        };
+
+        (queue, receiver)
+    }
+
+    fn push(&self, name: &str, event: &SequencedEvent) {
+        let result = serde_json::to_string(event)
+            .map_err(|e| e.to_string())
+            .and_then(|line| self.sender.try_send(line).map_err(|e| e.to_string()));
+
+        if let Err(e) = result {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            warn!("Dropped event {} for sink {name}: {e}", event.seq);
+        }
+    }
+}
+
+/// Appends every event to a file as a line of JSON
+pub struct FileSink {
+    path: PathBuf,
+    queue: SinkQueue,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> Self {
+        let (queue, mut receiver) = SinkQueue::new();
+        let errors = queue.errors.clone();
+        let file_path = path.clone();
+
+        tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                if let Err(e) = append_line(&file_path, &line).await {
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    error!("Failed to write event to {}: {e:?}", file_path.display());
+                }
+            }
+        });
+
+        Self { path, queue }
+    }
+}
+
+// Open the file for every line, so the file can be rotated
+async fn append_line(path: &PathBuf, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    file.write_all(format!("{line}\n").as_bytes()).await?;
+
+    Ok(())
+}
+
+impl EventSink for FileSink {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    fn send(&self, event: &SequencedEvent) {
+        self.queue.push(&self.name(), event);
+    }
+
+    fn errors(&self) -> u64 {
+        self.queue.errors.load(Ordering::Relaxed)
+    }
+}
+
+/// Posts every event as JSON to a webhook, retrying failed deliveries
+pub struct WebhookSink {
+    // host of the webhook, the rest of the URL can contain a secret
+    name: String,
+    queue: SinkQueue,
+}
+
+// Name of a webhook for the logs and metrics, webhooks like Slack's carry their secret in the path
+pub fn webhook_name(url: &str) -> String {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    format!("webhook:{host}")
+}
+
+impl WebhookSink {
+    pub fn new(webhook: EventWebhook) -> Self {
+        let (queue, mut receiver) = SinkQueue::new();
+        let errors = queue.errors.clone();
+        let name = webhook_name(&webhook.url);
+        let task_name = name.clone();
+
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+
+            while let Some(body) = receiver.recv().await {
+                if let Err(e) = post_json(&client, &webhook, body).await {
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    error!("Failed to post event to {task_name}: {e:?}");
+                }
+            }
+        });
+
+        Self { name, queue }
+    }
+}
+
+// Post JSON to a webhook, retrying with an increasing delay when it fails
+pub async fn post_json(
+    client: &reqwest::Client,
+    webhook: &EventWebhook,
+    body: String,
+) -> Result<()> {
+    let name = webhook_name(&webhook.url);
+    let mut backoff = WEBHOOK_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let mut request = client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+
+        if let Some(token) = &webhook.token {
+            request = request.bearer_auth(token);
+        }
+
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt == WEBHOOK_ATTEMPTS {
+            bail!("gave up after {attempt} attempts, last error: {error}");
+        }
+
+        warn!("Failed to post to {name}: {error}, retrying in {backoff:?}");
+
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn send(&self, event: &SequencedEvent) {
+        self.queue.push(&self.name(), event);
+    }
+
+    fn errors(&self) -> u64 {
+        self.queue.errors.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{FileSink, webhook_name};
+    use crate::{
+        events::{ClientEvent, EventManager},
+        user::User,
+    };
+
+    #[test]
+    fn test_webhook_name() {
+        assert_eq!(
+            webhook_name("https://hooks.slack.com/services/T000/B000/secret"),
+            "webhook:hooks.slack.com"
+        );
+        assert_eq!(webhook_name("not a url"), "webhook:unknown");
+    }
+
+    #[tokio::test]
+    async fn test_file_sink() {
+        let path = std::env::temp_dir().join(format!("etes-events-{}.jsonl", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let channel = EventManager::new(16);
+        channel.add_sink(FileSink::new(path.clone()));
+
+        channel.send(ClientEvent::GithubRefresh {
+            user: User::Anonymous("frank".to_string()),
+        });
+
+        // the sink writes in the background, wait for the line
+        let contents = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(contents) = tokio::fs::read_to_string(&path).await
+                    && contents.ends_with('\n')
+                {
+                    return contents;
+                }
+
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The event was not written to the file");
+        let event: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+
+        assert_eq!(event["seq"], 1);
+        assert_eq!(event["origin"], "worker");
+        assert_eq!(event["type"], "github_refresh");
+        // anonymous ids are hashed before they leave etes
+        assert_ne!(event["user"], "frank");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use axum_server::{
+    accept::DefaultAcceptor,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+/// Certificate and key of a listener, the paths are kept to reload them after a renewal
+#[derive(Clone)]
+pub struct Certificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    config: RustlsConfig,
+}
+
+impl Certificate {
+    async fn load(cert_path: &PathBuf, key_path: &PathBuf) -> Result<Self> {
+        let config = RustlsConfig::from_pem_file(cert_path, key_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load TLS certificate {} with key {}",
+                    cert_path.display(),
+                    key_path.display()
+                )
+            })?;
+
+        Ok(Self {
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            config,
+        })
+    }
+
+    pub fn acceptor(&self) -> RustlsAcceptor<DefaultAcceptor> {
+        RustlsAcceptor::new(self.config.clone())
+    }
+
+    // Read the certificate and key again, new connections use the new certificate
+    pub async fn reload(&self) -> Result<()> {
+        self.config
+            .reload_from_pem_file(&self.cert_path, &self.key_path)
+            .await
+            .with_context(|| format!("Failed to reload {}", self.cert_path.display()))
+    }
+}
+
+/// Certificates of the listeners, a listener without certificate serves plain HTTP
+#[derive(Clone)]
+pub struct Certificates {
+    pub server: Option<Certificate>,
+    pub proxy: Option<Certificate>,
+}
+
+impl Certificates {
+    pub async fn load(config: &Config) -> Result<Self> {
+        // use the ring provider, like the client connector
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let server = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                Some(Certificate::load(cert_path, key_path).await?)
+            }
+            _ => None,
+        };
+
+        // the proxy uses its own (wildcard) certificate if configured, or the one of the server
+        let proxy = match (&config.proxy_tls_cert_path, &config.proxy_tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                Some(Certificate::load(cert_path, key_path).await?)
+            }
+            _ => server.clone(),
+        };
+
+        Ok(Self { server, proxy })
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        for certificate in self.server.iter().chain(&self.proxy) {
+            certificate.reload().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -3,21 +3,51 @@ use axum::{
     extract::{Path, Request, State},
     response::IntoResponse,
 };
-use constant_time_eq::constant_time_eq;
+use chrono::Utc;
 use futures::TryStreamExt;
 use hyper::StatusCode;
-use std::{fs::Permissions, io, os::unix::fs::PermissionsExt};
+use sha2::{Digest, Sha256};
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufWriter},
 };
-use tokio_util::io::StreamReader;
 use tracing::{error, info};
 
 use crate::{
-    AppState, error::AppError, events::Event, executable::Executable, util::is_valid_hash,
+    AppState,
+    auth::check_api_key,
+    error::{AppError, ErrorResponse},
+    events::{Origin, ServerEvent},
+    executable::{self, Executable, ExecutableMetadata},
+    util::{hex, is_valid_hash, is_valid_platform},
 };
 
+// Header with the SHA-256 of the executable, hex encoded
+pub const CHECKSUM: &str = "x-etes-sha256";
+
+// Header with the platform the executable was built for, like x86_64 or aarch64
+const PLATFORM: &str = "x-etes-platform";
+
+// Upload the executable of a build, the body is the binary
+#[utoipa::path(
+    put,
+    path = "/etes/api/v1/executable/{trigger_hash}/{build_hash}",
+    params(
+        ("trigger_hash" = String, Path, description = "Commit hash that triggered the build"),
+        ("build_hash" = String, Path, description = "Commit hash that was built"),
+        ("X-Etes-Platform" = Option<String>, Header, description = "Platform the executable was built for, like x86_64 or aarch64"),
+        ("X-Etes-Sha256" = Option<String>, Header, description = "SHA-256 of the executable, hex encoded, required with require_upload_checksum"),
+    ),
+    request_body(description = "The executable", content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "Executable uploaded", body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid commit hash, or a missing or mismatching checksum", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid API key", body = ErrorResponse),
+        (status = 500, description = "Failed to store the executable", body = ErrorResponse),
+    ),
+    security(("api_key" = [])),
+)]
 pub async fn upload_handler(
     State(state): State<AppState>,
     Path((trigger_hash, build_hash)): Path<(String, String)>,
@@ -29,72 +59,140 @@ pub async fn upload_handler(
 
     info!("Incoming upload for {trigger_hash} and {build_hash}");
 
-    // get the authorization header
-    let authorization = request
-        .headers()
-        .get("authorization")
-        .ok_or_else(|| AppError::Client(anyhow!("No authorization header found")))?
-        .to_str()
-        .map_err(|_| AppError::Client(anyhow!("Invalid authorization header value")))?
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| {
-            AppError::Client(anyhow!("Missing 'Bearer' in authorization header value"))
-        })?;
-
-    // secure string compare
-    if !constant_time_eq(authorization.as_bytes(), state.config.api_key.as_bytes()) {
-        error!("Invalid API key for upload of {trigger_hash} and {build_hash}");
-
-        return Err(AppError::Client(anyhow!("Invalid API key")));
+    if let Err(e) = check_api_key(request.headers(), &state.config().api_key) {
+        error!("Unauthorized upload of {trigger_hash} and {build_hash}");
+
+        return Err(e);
     }
 
+    // checksum of the uploader, to detect executables that are damaged on the way
+    let expected_checksum = match request.headers().get(CHECKSUM) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .filter(|checksum| {
+                    checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit())
+                })
+                .ok_or_else(|| {
+                    AppError::Client(anyhow!(
+                        "{CHECKSUM} must be the hex encoded SHA-256 of the executable"
+                    ))
+                })?
+                .to_ascii_lowercase(),
+        ),
+        None if state.config().require_upload_checksum => {
+            return Err(AppError::Client(anyhow!(
+                "Missing {CHECKSUM} header with the SHA-256 of the executable"
+            )));
+        }
+        None => None,
+    };
+
+    let platform = match request.headers().get(PLATFORM) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .filter(|platform| is_valid_platform(platform))
+                .ok_or_else(|| {
+                    AppError::Client(anyhow!(
+                        "{PLATFORM} must be a platform like x86_64 or aarch64"
+                    ))
+                })?
+                .to_string(),
+        ),
+        None => None,
+    };
+
     // init new executable
-    let executable = Executable::from_commit(build_hash.clone(), trigger_hash.clone());
+    let mut executable = Executable::from_commit(
+        state.services.bin_dir(),
+        build_hash.clone(),
+        trigger_hash.clone(),
+        platform,
+    );
 
     // delete the file if it already exists
     if executable.path().exists()
-        && let Err(err) = tokio::fs::remove_file(executable.path()).await
+        && let Err(err) = executable.remove().await
     {
         error!("Failed to remove existing file: {err}");
         return Err(AppError::Server(anyhow!("Failed to remove existing file")));
     }
 
     // get data stream
-    let body_reader = StreamReader::new(
-        request
-            .into_body()
-            .into_data_stream()
-            .map_err(io::Error::other),
-    );
-
-    futures::pin_mut!(body_reader);
+    let mut body = request.into_body().into_data_stream();
 
+    executable.create_dir().await?;
     let mut file = BufWriter::new(File::create(executable.path()).await?);
+    let mut hasher = Sha256::new();
+    let mut size_bytes: u64 = 0;
 
-    // copy the body into the file (streaming)
-    tokio::io::copy(&mut body_reader, &mut file).await?;
+    // copy the body into the file (streaming), computing the checksum on the way
+    while let Some(chunk) = body.try_next().await? {
+        hasher.update(&chunk);
+        size_bytes += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+    }
 
     // close the file
     file.flush().await?;
     drop(file);
 
+    let checksum = hex(&hasher.finalize());
+
+    if expected_checksum.is_some_and(|expected| expected != checksum) {
+        error!("Upload of {trigger_hash} and {build_hash} does not match its checksum");
+        executable.remove().await?;
+
+        return Err(AppError::Client(anyhow!(
+            "Executable does not match the {CHECKSUM} header"
+        )));
+    }
+
     // make file executable
     tokio::fs::set_permissions(executable.path(), Permissions::from_mode(0o755)).await?;
 
+    executable
+        .write_metadata(ExecutableMetadata {
+            uploaded_at: Utc::now(),
+            size_bytes,
+            checksum: Some(checksum),
+            corrupt: false,
+        })
+        .await?;
+
     info!("Uploaded {trigger_hash} and {build_hash}");
 
+    let cleanup = state.services.lock_cleanup().await;
+
+    if let Err(e) = executable::remove_excess_builds(&state).await {
+        error!("Failed to remove older builds of {trigger_hash}: {e:?}");
+    }
+
+    drop(cleanup);
+
     // update state
     state.services.update_executables().await;
 
     // set updated state to all clients
-    state.channel.send(Event::ExecutablesState {
-        executables: state.services.get_executables(),
-    });
-
-    if state.github.update(state.config).await.is_ok() {
-        state.channel.send(Event::GithubState {
-            payload: state.github.get_state(),
-        });
+    state.channel.send_from(
+        Origin::Upload,
+        ServerEvent::ExecutablesState {
+            executables: state.services.get_executables(&state.config()),
+            scanned_at: state.services.last_executable_scan(),
+        },
+    );
+
+    if state.github.update(&state.config()).await.is_ok() {
+        state.channel.send_from(
+            Origin::Upload,
+            ServerEvent::GithubState {
+                payload: state.github.get_dashboard_state(&state.config()),
+                refresh: state.github.get_refresh(),
+            },
+        );
     }
 
     Ok((
@@ -110,21 +208,55 @@ mod test {
     use hyper::Method;
     use tower::ServiceExt;
 
-    use crate::{app, executable::Executable};
+    use crate::{
+        app,
+        config::ConfigArgs,
+        executable::{Executable, ExecutableData},
+        util::sha256,
+    };
 
     #[tokio::test]
     async fn test_upload_handler() {
-        let (state, app) = app(false).await.unwrap();
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
 
         let hash1 = "1111111111111111111111111111111111111111";
         let hash2 = "2222222222222222222222222222222222222222";
 
+        // an executable that was damaged on the way is rejected
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(format!("/etes/api/v1/executable/{hash1}/{hash2}"))
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", state.config().api_key),
+                    )
+                    .header("X-Etes-Sha256", sha256("other"))
+                    .body(Body::new("test".to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 400);
+        assert!(
+            !Executable::from_commit(state.services.bin_dir(), hash2.into(), hash1.into(), None)
+                .path()
+                .exists()
+        );
+
         let response = app
             .oneshot(
                 Request::builder()
                     .method(Method::PUT)
                     .uri(format!("/etes/api/v1/executable/{hash1}/{hash2}"))
-                    .header("Authorization", format!("Bearer {}", state.config.api_key))
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", state.config().api_key),
+                    )
+                    .header("X-Etes-Sha256", sha256("test"))
                     .body(Body::new("test".to_string()))
                     .unwrap(),
             )
@@ -139,7 +271,15 @@ mod test {
             format!("Upload of executable for {hash1} and {hash2} successful")
         );
 
-        let executable = Executable::from_commit(hash2.into(), hash1.into());
-        tokio::fs::remove_file(executable.path()).await.unwrap();
+        let executable = state
+            .services
+            .get_executable_by_commit(&hash2.into())
+            .unwrap();
+        let data = serde_json::to_value(ExecutableData::from(&executable)).unwrap();
+        assert_eq!(data["sizeBytes"], 4);
+        assert_eq!(data["checksum"], sha256("test"));
+        assert!(data["uploadedAt"].is_string());
+
+        executable.remove().await.unwrap();
     }
 }
@@ -9,21 +9,30 @@ use axum::{
 };
 use axum_extra::extract::PrivateCookieJar;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::{
-    auth::{COOKIE_NAME, GithubOauthService},
+    auth::GithubOauthService,
     config::Config,
     error::AppError,
     util::{is_valid_name, sha256},
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
 #[serde(untagged)]
 pub enum User {
     Anonymous(String),
     GitHub(GitHubUser),
 }
 
+// Permission level of a user, resolved against the configured admins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Anonymous,
+    Member,
+    Admin,
+}
+
 impl User {
     pub fn from_request(caller: String, user: Option<GitHubUser>) -> Result<Self, AppError> {
         if let Some(user) = user {
@@ -37,13 +46,18 @@ impl User {
         }
     }
 
-    pub fn is_admin(&self, config: &Config) -> bool {
+    pub fn role(&self, config: &Config) -> Role {
         match self {
-            User::GitHub(user) => config.admins.contains(&user.login),
-            _ => false,
+            User::GitHub(user) if config.admins.contains(&user.login) => Role::Admin,
+            User::GitHub(_) => Role::Member,
+            User::Anonymous(_) => Role::Anonymous,
         }
     }
 
+    pub fn is_admin(&self, config: &Config) -> bool {
+        self.role(config) == Role::Admin
+    }
+
     pub fn hash_anonymous(&self) -> User {
         match self {
             User::Anonymous(id) => User::Anonymous(sha256(id)),
@@ -61,7 +75,7 @@ impl Display for User {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, ToSchema)]
 pub struct GitHubUser {
     pub login: String,
     pub name: String,
@@ -110,7 +124,7 @@ where
         let jar: PrivateCookieJar =
             PrivateCookieJar::from_headers(&parts.headers, service.session_key());
 
-        let Some(session_cookie) = jar.get(COOKIE_NAME) else {
+        let Some(session_cookie) = jar.get(&service.session_cookie_name()) else {
             return Ok(None);
         };
 
@@ -139,11 +153,12 @@ where
             PrivateCookieJar::from_headers(&parts.headers, service.session_key());
 
         let session_cookie = jar
-            .get(COOKIE_NAME)
+            .get(&service.session_cookie_name())
             .ok_or(AuthAction::Redirect("/etes/login".into()))?;
 
-        let user: GitHubUser = serde_json::from_str(session_cookie.value())
-            .map_err(|_| AuthAction::Error(AppError::Client(anyhow!("Invalid user cookie"))))?;
+        let user: GitHubUser = serde_json::from_str(session_cookie.value()).map_err(|_| {
+            AuthAction::Error(AppError::Unauthorized(anyhow!("Invalid user cookie")))
+        })?;
 
         Ok(user)
     }
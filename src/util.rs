@@ -1,5 +1,6 @@
 use rand::{RngExt, distr::Alphanumeric};
 use sha2::Digest;
+use std::collections::HashSet;
 use tokio::net::TcpListener;
 
 // Get a free port number on localhost
@@ -24,6 +25,47 @@ pub fn is_valid_hash(hash: &str) -> bool {
     hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())
 }
 
+// Platforms are part of the file names of executables, like x86_64 or aarch64
+pub fn is_valid_platform(platform: &str) -> bool {
+    (1..=32).contains(&platform.len())
+        && platform
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Get the subdomain part of a host (without port) relative to the base domain
+pub fn get_subdomain<'a>(host: &'a str, base_domain: &str) -> Option<&'a str> {
+    let host = host.split(':').next()?;
+
+    if host.len() <= base_domain.len() + 1 {
+        return None;
+    }
+
+    let (subdomain, domain) = host.split_at_checked(host.len() - base_domain.len())?;
+
+    if !domain.eq_ignore_ascii_case(base_domain) {
+        return None;
+    }
+
+    subdomain.strip_suffix('.').filter(|s| !s.is_empty())
+}
+
+// Check if a string is a valid abbreviated (or full) git commit hash
+pub fn is_valid_hash_prefix(hash: &str) -> bool {
+    (7..=40).contains(&hash.len()) && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Get the pull request number from a pr-{number} name
+pub fn get_pull_number(name: &str) -> Option<i64> {
+    let number = name.strip_prefix("pr-")?;
+
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    number.parse().ok()
+}
+
 // Generate a random string
 pub fn random_string() -> String {
     rand::rng()
@@ -38,12 +80,12 @@ pub fn sha256(input: &str) -> String {
     let mut hasher = sha2::Sha256::new();
     hasher.update(input.as_bytes());
 
-    hasher
-        .finalize()
-        .iter()
-        .map(|b| format!("{b:02x}").to_string())
-        .collect::<Vec<String>>()
-        .join("")
+    hex(&hasher.finalize())
+}
+
+// Lowercase hexadecimal encoding of bytes
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
 pub fn sha512(input: &str) -> [u8; 64] {
@@ -53,6 +95,63 @@ pub fn sha512(input: &str) -> [u8; 64] {
     hasher.finalize().into()
 }
 
+// Curated list of words used when the configuration has none
+pub fn default_words() -> Vec<String> {
+    include_str!("words.txt")
+        .lines()
+        .map(|word| word.trim().to_string())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+// Number of different names get_random_name can generate from a list of words
+pub fn name_combinations(words: &[String]) -> u64 {
+    let unique = words.iter().collect::<HashSet<_>>().len() as u64;
+
+    unique * unique.saturating_sub(1) * unique.saturating_sub(2)
+}
+
+// Replace {placeholders} in a template with their value, unknown placeholders are an error.
+// Use {{ for a literal brace, other braces around anything but a lowercase name are kept as is
+pub fn render_template(template: &str, values: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(escaped) = after.strip_prefix('{') {
+            rendered.push('{');
+            rest = escaped;
+            continue;
+        }
+
+        let placeholder = after.find('}').map(|end| &after[..end]).filter(|name| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+        });
+
+        match placeholder {
+            Some(name) => {
+                let Some((_, value)) = values.iter().find(|(key, _)| *key == name) else {
+                    anyhow::bail!("unknown placeholder {{{name}}} in {template:?}");
+                };
+
+                rendered.push_str(value);
+                rest = &after[name.len() + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
 // Get a random name from a list of words
 pub fn get_random_name(words: &[String]) -> String {
     let mut rng = rand::rng();
@@ -96,6 +195,58 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_is_valid_hash_prefix() {
+        assert!(!super::is_valid_hash_prefix("4f5d3b"));
+        assert!(super::is_valid_hash_prefix("4f5d3be"));
+        assert!(super::is_valid_hash_prefix("4f5d3be66fb5"));
+        assert!(super::is_valid_hash_prefix(
+            "4f5d3be66fb5324eda7c05c9d95b777f057d25f9"
+        ));
+        assert!(!super::is_valid_hash_prefix("4f5d3bx"));
+        assert!(!super::is_valid_hash_prefix(
+            "4f5d3be66fb5324eda7c05c9d95b777f057d25f90"
+        ));
+    }
+
+    #[test]
+    fn test_get_pull_number() {
+        assert_eq!(super::get_pull_number("pr-1"), Some(1));
+        assert_eq!(super::get_pull_number("pr-1234"), Some(1234));
+        assert_eq!(super::get_pull_number("pr-"), None);
+        assert_eq!(super::get_pull_number("pr--1"), None);
+        assert_eq!(super::get_pull_number("pr-12a"), None);
+        assert_eq!(super::get_pull_number("foo-12"), None);
+    }
+
+    #[test]
+    fn test_get_subdomain() {
+        assert_eq!(
+            super::get_subdomain("foo.example.com", "example.com"),
+            Some("foo")
+        );
+        assert_eq!(
+            super::get_subdomain("foo.example.com:3001", "example.com"),
+            Some("foo")
+        );
+        assert_eq!(
+            super::get_subdomain("foo.bar.example.com", "example.com"),
+            Some("foo.bar")
+        );
+        assert_eq!(
+            super::get_subdomain("FOO.Example.COM", "example.com"),
+            Some("FOO")
+        );
+        assert_eq!(super::get_subdomain("example.com", "example.com"), None);
+        assert_eq!(super::get_subdomain(".example.com", "example.com"), None);
+        assert_eq!(super::get_subdomain("fooexample.com", "example.com"), None);
+        assert_eq!(
+            super::get_subdomain("foo.example.com.evil.org", "example.com"),
+            None
+        );
+        assert_eq!(super::get_subdomain("foo.evil.org", "example.com"), None);
+    }
+
     #[test]
     fn test_sha256() {
         assert_eq!(
@@ -104,6 +255,42 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_render_template() {
+        let values = [("name", "foo-bar-baz"), ("port", "8000")];
+
+        assert_eq!(
+            super::render_template("--port={port}", &values).unwrap(),
+            "--port=8000"
+        );
+        assert_eq!(
+            super::render_template("/data/{name}/{name}.db", &values).unwrap(),
+            "/data/foo-bar-baz/foo-bar-baz.db"
+        );
+        assert_eq!(
+            super::render_template("no placeholders", &values).unwrap(),
+            "no placeholders"
+        );
+
+        // braces that aren't placeholders are passed on
+        assert_eq!(
+            super::render_template(r#"{"port": {port}}"#, &values).unwrap(),
+            r#"{"port": 8000}"#
+        );
+        assert_eq!(super::render_template("{}{", &values).unwrap(), "{}{");
+        assert_eq!(
+            super::render_template("{{name} is {name}", &values).unwrap(),
+            "{name} is foo-bar-baz"
+        );
+        assert_eq!(
+            super::render_template("{{{port}", &values).unwrap(),
+            "{8000"
+        );
+
+        let error = super::render_template("{nmae}", &values).unwrap_err();
+        assert!(error.to_string().contains("unknown placeholder {nmae}"));
+    }
+
     #[test]
     fn test_get_random_name() {
         let words = vec![
@@ -0,0 +1,54 @@
+use axum::{Json, extract::State};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::AppState;
+
+/// Version and build of etes, to tell installs apart
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    version: &'static str,
+    // git commit the binary was built from, "unknown" outside a git checkout
+    commit: &'static str,
+    build_time: Option<DateTime<Utc>>,
+    // start of the process, the uptime of etes is derived from it
+    started_at: DateTime<Utc>,
+}
+
+impl VersionInfo {
+    pub fn new() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("ETES_GIT_COMMIT"),
+            build_time: env!("ETES_BUILD_TIME")
+                .parse()
+                .ok()
+                .and_then(|seconds| DateTime::from_timestamp(seconds, 0)),
+            started_at: Utc::now(),
+        }
+    }
+}
+
+impl std::fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "etes {} ({}", self.version, self.commit)?;
+
+        if let Some(build_time) = self.build_time {
+            write!(f, ", built {}", build_time.format("%Y-%m-%d %H:%M:%S UTC"))?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+// Version of etes, the dashboard gets it with the initial state
+#[utoipa::path(
+    get,
+    path = "/etes/api/v1/version",
+    responses((status = 200, description = "Version and build of etes", body = VersionInfo)),
+)]
+pub async fn version_handler(State(state): State<AppState>) -> Json<VersionInfo> {
+    Json(state.version.clone())
+}
@@ -1,56 +1,424 @@
+use anyhow::anyhow;
 use axum::{
     extract::{
-        Path, State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        FromRef, Path, Query, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
+    },
+    http::{
+        Extensions, HeaderMap,
+        header::{HOST, ORIGIN},
     },
     response::IntoResponse,
 };
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use cookie::Key;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::{AbortHandle, JoinSet},
+    time::{self, Instant, MissedTickBehavior},
+};
 use tracing::{error, info, warn};
+use url::Url;
 
 use crate::{
     AppState,
+    config::Config,
     error::AppError,
-    events::Event,
+    events::{ClientEvent, Event, Origin, Replay, SequencedEvent, ServerEvent, Topic},
+    logs::LogSubscription,
+    monitor::{HISTORY_POINTS, MonitorSample},
+    presence::PresenceGuard,
+    proxy::client_ip,
+    resume::ResumeToken,
+    services::ServiceQuery,
     user::{GitHubUser, User},
 };
 
+#[derive(Deserialize)]
+pub struct ConnectParams {
+    // Last sequence number seen by a reconnecting client
+    since: Option<u64>,
+    // Resume token of the previous connection of a reconnecting client
+    resume: Option<String>,
+}
+
+// Check the Origin of a websocket upgrade, to prevent other sites from connecting
+// with the session cookie of the user
+fn is_allowed_origin(origin: Option<&str>, host: Option<&str>, config: &Config) -> bool {
+    let Some(origin) = origin else {
+        return config.ws_allow_missing_origin;
+    };
+
+    if config
+        .ws_allowed_origins
+        .iter()
+        .chain(&config.cors_allowed_origins)
+        .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+
+    let Ok(url) = Url::parse(origin) else {
+        return false;
+    };
+
+    let Some(origin_host) = url.host_str() else {
+        return false;
+    };
+
+    // the base domain itself, or the host Etes is served on
+    let authority = match url.port() {
+        Some(port) => format!("{origin_host}:{port}"),
+        None => origin_host.to_string(),
+    };
+
+    origin_host.eq_ignore_ascii_case(&config.base_domain)
+        || host.is_some_and(|host| host.eq_ignore_ascii_case(&authority))
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Path(caller): Path<String>,
+    Query(params): Query<ConnectParams>,
     State(state): State<AppState>,
+    headers: HeaderMap,
+    extensions: Extensions,
     user: Option<GitHubUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::from_request(caller, user)?;
+    let origin = headers.get(ORIGIN).and_then(|v| v.to_str().ok());
+    let host = headers.get(HOST).and_then(|v| v.to_str().ok());
+
+    if !is_allowed_origin(origin, host, &state.config()) {
+        return Err(AppError::Forbidden(anyhow!(
+            "Websocket origin {origin:?} is not allowed"
+        )));
+    }
+
+    // A resume token re-binds the identity of the previous connection,
+    // logged in users still need a matching session
+    let resumed = params
+        .resume
+        .as_deref()
+        .and_then(|token| ResumeToken::open(token, &Key::from_ref(&state)))
+        .filter(|token| match &token.user {
+            User::Anonymous(_) => user.is_none(),
+            User::GitHub(resumed) => user.as_ref() == Some(resumed),
+        });
+
+    let (user, since) = match resumed {
+        Some(token) => (token.user, params.since.or(Some(token.seq))),
+        None => (User::from_request(caller, user)?, params.since),
+    };
+
+    let remote_addr = client_ip(&headers, &extensions, &state.config());
+
+    // Client events are small, larger messages are rejected before they are buffered
+    let max_message_bytes = state.config().ws_max_message_bytes;
+
+    Ok(ws
+        .max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, user, since, remote_addr, state)))
+}
+
+// Full state for a client that just connected or missed events
+fn snapshot(state: &AppState, seq: u64) -> Vec<SequencedEvent> {
+    let presence = state.presence.get_state();
+
+    let events = vec![
+        ServerEvent::ServiceState {
+            services: state
+                .services
+                .get_state(&state.config(), &ServiceQuery::default()),
+        },
+        ServerEvent::ExecutablesState {
+            executables: state.services.get_executables(&state.config()),
+            scanned_at: state.services.last_executable_scan(),
+        },
+        ServerEvent::GithubState {
+            payload: state.github.get_dashboard_state(&state.config()),
+            refresh: state.github.get_refresh(),
+        },
+        state.monitor.get_state().into(),
+        ServerEvent::Presence {
+            connected: presence.connected,
+            authenticated: presence.authenticated,
+            anonymous: presence.anonymous,
+        },
+        ServerEvent::BusMetrics {
+            metrics: state.channel.get_metrics(),
+        },
+    ];
+
+    events
+        .into_iter()
+        .map(|event| SequencedEvent {
+            seq,
+            emitted_at: Utc::now(),
+            origin: Origin::Ws,
+            event: event.into(),
+        })
+        .collect()
+}
+
+/// Server event as sent to clients, with the metadata of the bus next to the event fields
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingEvent<'a> {
+    seq: u64,
+    emitted_at: DateTime<Utc>,
+    origin: Origin,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+// Number of log lines queued for the socket before the forwarders wait
+const LOG_BUFFER: usize = 64;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "service_log")]
+struct LogMessage {
+    name: String,
+    line: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "monitor_history")]
+struct MonitorHistoryMessage {
+    samples: Vec<MonitorSample>,
+}
+
+// Pass the output of a service on to the socket, until the service stops or the client unsubscribes
+async fn forward_logs(
+    name: String,
+    mut subscription: LogSubscription,
+    sender: mpsc::Sender<LogMessage>,
+) {
+    while let Some(line) = subscription.recv().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(skipped) => format!("[{skipped} lines skipped]"),
+        };
+
+        let message = LogMessage {
+            name: name.clone(),
+            line,
+        };
+
+        if sender.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "resume_token", rename_all = "camelCase")]
+struct ResumeMessage {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Issue a resume token for everything the connection has received up to the sequence number
+fn resume_message(state: &AppState, user: &User, seq: u64) -> Option<Message> {
+    let token = ResumeToken::new(user.clone(), seq, state.config().ws_resume_token_ttl);
+
+    let msg = serde_json::to_string(&ResumeMessage {
+        token: token.seal(&Key::from_ref(state))?,
+        expires_at: token.expires_at,
+    })
+    .ok()?;
+
+    Some(Message::Text(msg.into()))
+}
+
+// Serialize a server event for a client, if it is meant for the user and subscribed to.
+// The role is resolved for every event, admins can change with a reload of the configuration.
+fn to_message(
+    event: &SequencedEvent,
+    user: &User,
+    config: &Config,
+    topics: &HashSet<Topic>,
+) -> Option<Message> {
+    let Event::Server(server_event) = &event.event else {
+        return None;
+    };
 
-    Ok(ws.on_upgrade(|socket| handle_socket(socket, user, state)))
+    let subscribed = server_event
+        .topic()
+        .is_none_or(|topic| topics.contains(&topic));
+
+    if !subscribed || !server_event.should_forward(user, user.role(config)) {
+        return None;
+    }
+
+    let msg = serde_json::to_string(&OutgoingEvent {
+        seq: event.seq,
+        emitted_at: event.emitted_at,
+        origin: event.origin,
+        event: server_event,
+    })
+    .ok()?;
+
+    Some(Message::Text(msg.into()))
 }
 
 // Route messags between the internal bus and the websocket
-async fn handle_socket(mut socket: WebSocket, user: User, state: AppState) {
-    let mut receiver = state.channel.get_receiver();
+async fn handle_socket(
+    mut socket: WebSocket,
+    user: User,
+    since: Option<u64>,
+    remote_addr: String,
+    state: AppState,
+) {
+    let (mut receiver, replay) = state.channel.subscribe_since(since);
+
+    // Ping the client periodically, connections without a timely pong are dead
+    let ping_period = Duration::from_secs(state.config().ws_ping_interval);
+    let mut ping_interval = time::interval_at(Instant::now() + ping_period, ping_period);
+    ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let pong_deadline = time::sleep(Duration::ZERO);
+    tokio::pin!(pong_deadline);
+    let mut awaiting_pong = false;
+
+    // Topics the client subscribed to, memory updates are opt-in
+    let mut topics = Topic::defaults();
+
+    let presence = PresenceGuard::new(state.clone(), user.clone(), &topics, remote_addr);
+
+    // Output of the services the client follows, the tasks are aborted with the connection
+    let (log_sender, mut log_receiver) = mpsc::channel(LOG_BUFFER);
+    let mut log_tasks = JoinSet::new();
+    let mut followed: HashMap<String, AbortHandle> = HashMap::new();
+
+    info!("Connection opened, user {user}");
 
-    loop {
+    // Bootstrap the client with the full state, this is authoritative over the data handler.
+    // Reconnecting clients only get the events they missed, if these are still available.
+    // Events sent in the meantime are already queued on the receiver.
+    let initial = match replay {
+        Replay::Events(events) => {
+            info!("Replaying {} events, user {user}", events.len());
+            events
+        }
+        Replay::Snapshot(seq) => snapshot(&state, seq),
+    };
+
+    // Last sequence number sent to the client, recorded in its resume token
+    let mut seen = initial
+        .last()
+        .map_or(since.unwrap_or_default(), |event| event.seq);
+
+    if let Some(msg) = resume_message(&state, &user, seen)
+        && let Err(e) = socket.send(msg).await
+    {
+        warn!("Socket error {e}, user {user}");
+        return;
+    }
+
+    for event in &initial {
+        if let Some(msg) = to_message(event, &user, &state.config(), &topics)
+            && let Err(e) = socket.send(msg).await
+        {
+            warn!("Socket error {e}, user {user}");
+            return;
+        }
+    }
+
+    'socket: loop {
         tokio::select! {
             Some(msg) = socket.recv() => {
                 match msg {
                     Ok(Message::Text(msg)) => {
-                        let Ok(event) = serde_json::from_str::<Event>(&msg) else {
-                            error!("Invalid event: {msg}");
+                        let Ok(event) = serde_json::from_str::<ClientEvent>(&msg) else {
+                            error!("Invalid client event: {msg}");
                             continue;
                         };
 
-                        if event.is_client_event() {
-                            state.channel.send(event.update_user(user.clone()));
+                        if let ClientEvent::Subscribe { topics: subscribed } = event {
+                            info!("Subscribed to {subscribed:?}, user {user}");
+
+                            // Send the current state of newly subscribed topics
+                            let added: HashSet<Topic> = subscribed.difference(&topics).copied().collect();
+                            topics = subscribed;
+                            presence.set_topics(&topics);
+
+                            for event in snapshot(&state, state.channel.last_sequence()) {
+                                if let Some(msg) = to_message(&event, &user, &state.config(), &added) && let Err(e) = socket.send(msg).await {
+                                    warn!("Socket error {e}, user {user}");
+                                    break 'socket;
+                                }
+                            }
+                        } else if let ClientEvent::SubscribeLogs { services } = event {
+                            info!("Following logs of {services:?}, user {user}");
+
+                            followed.retain(|name, task| {
+                                let keep = services.contains(name) && !task.is_finished();
+
+                                if !keep {
+                                    task.abort();
+                                }
+
+                                keep
+                            });
+                            while log_tasks.try_join_next().is_some() {}
+
+                            for name in services {
+                                if followed.contains_key(&name) {
+                                    continue;
+                                }
+
+                                let Some(logs) = state.services.get_logs(&name) else {
+                                    warn!("No logs for service {name}, user {user}");
+                                    continue;
+                                };
+
+                                let task = log_tasks.spawn(forward_logs(name.clone(), logs.subscribe(), log_sender.clone()));
+                                followed.insert(name, task);
+                            }
+                        } else if let ClientEvent::GetMonitorHistory = event {
+                            let history = MonitorHistoryMessage {
+                                samples: state.monitor.get_history(HISTORY_POINTS),
+                            };
+
+                            if let Ok(msg) = serde_json::to_string(&history) && let Err(e) = socket.send(Message::Text(msg.into())).await {
+                                warn!("Socket error {e}, user {user}");
+                                break;
+                            }
                         } else {
-                            error!("Invalid client event: {msg}");
+                            state.channel.send_from(Origin::Ws, event.update_user(user.clone()));
                         }
                     }
+                    Ok(Message::Ping(_)) => {
+                        // the pong is queued by the websocket implementation and flushed on the next read or write
+                    }
+                    Ok(Message::Pong(_)) => {
+                        awaiting_pong = false;
+                    }
                     Ok(Message::Close(_)) => {
                         info!("Connection closed by client, user {user}");
                         break;
                     }
                     Err(e) => {
-                        warn!("Error, closing connection: {e:?}, user {user}");
+                        let e = e.into_inner();
+
+                        if let Some(tungstenite::Error::Capacity(e)) = e.downcast_ref() {
+                            warn!("Message too large, closing connection: {e}, user {user}");
+
+                            let frame = CloseFrame {
+                                code: close_code::SIZE,
+                                reason: "Message too large".into(),
+                            };
+                            let _ = socket.send(Message::Close(Some(frame))).await;
+                        } else {
+                            warn!("Error, closing connection: {e:?}, user {user}");
+                        }
+
                         break;
                     }
                     _ => {
@@ -58,14 +426,280 @@ async fn handle_socket(mut socket: WebSocket, user: User, state: AppState) {
                     }
                 }
             }
-            Ok(event) = receiver.recv() => {
-                if event.should_forward(&user) && let Ok(msg) = serde_json::to_string(&event) && let Err(e) = socket.send(Message::Text(msg.into())).await {
-                            warn!("Socket error {e}, user {user}");
-                            break;
+            result = receiver.recv() => {
+                let events = match result {
+                    Ok(event) => vec![event],
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Missed {skipped} events, sending a snapshot, user {user}");
+                        state.channel.record_lag(skipped);
+                        snapshot(&state, state.channel.last_sequence())
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                for event in &events {
+                    seen = event.seq;
+
+                    if let Some(msg) = to_message(event, &user, &state.config(), &topics) && let Err(e) = socket.send(msg).await {
+                        warn!("Socket error {e}, user {user}");
+                        break 'socket;
+                    }
+                }
+            }
+            Some(log) = log_receiver.recv() => {
+                if let Ok(msg) = serde_json::to_string(&log) && let Err(e) = socket.send(Message::Text(msg.into())).await {
+                    warn!("Socket error {e}, user {user}");
+                    break;
+                }
+            }
+            _ = ping_interval.tick(), if !awaiting_pong => {
+                if let Err(e) = socket.send(Message::Ping(Bytes::new())).await {
+                    warn!("Socket error {e}, user {user}");
+                    break;
+                }
+
+                // Keep the resume token fresh, it expires shortly after the connection is lost
+                if let Some(msg) = resume_message(&state, &user, seen)
+                    && let Err(e) = socket.send(msg).await
+                {
+                    warn!("Socket error {e}, user {user}");
+                    break;
+                }
+
+                awaiting_pong = true;
+                pong_deadline
+                    .as_mut()
+                    .reset(Instant::now() + Duration::from_secs(state.config().ws_pong_timeout));
+            }
+            _ = state.shutdown.cancelled() => {
+                let frame = CloseFrame {
+                    code: close_code::RESTART,
+                    reason: "Server restarting".into(),
+                };
 
+                if let Err(e) = socket.send(Message::Close(Some(frame))).await {
+                    warn!("Socket error {e}, user {user}");
                 }
+
+                info!("Closing connection for shutdown, user {user}");
+                break;
+            }
+            _ = &mut pong_deadline, if awaiting_pong => {
+                warn!("No pong received, closing connection, user {user}");
+                break;
             }
             else => break,
         }
     }
+
+    info!("Connection closed, user {user}");
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{HeaderValue, header::ORIGIN};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{
+        MaybeTlsStream, WebSocketStream, connect_async,
+        tungstenite::{
+            self, Message, client::IntoClientRequest, protocol::frame::coding::CloseCode,
+        },
+    };
+
+    use super::{is_allowed_origin, to_message};
+    use crate::{
+        AppState, app,
+        config::{Config, ConfigArgs},
+        events::{EventManager, Origin, SequencedEvent, ServerEvent, Topic},
+        listener::{self, ClientAddr, Listener},
+        user::{GitHubUser, User},
+    };
+
+    // Connect a websocket client to the real router, served like the dashboard in main
+    async fn try_connect(
+        origin: Option<&str>,
+    ) -> (
+        Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Error>,
+        AppState,
+    ) {
+        let (state, app) = app(false, ConfigArgs::default()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listener::serve(
+            Listener::Tcp(listener),
+            None,
+            app.into_make_service_with_connect_info::<ClientAddr>(),
+            state.shutdown.clone(),
+        ));
+
+        let mut request = format!("ws://127.0.0.1:{port}/etes/api/v1/ws/test")
+            .into_client_request()
+            .unwrap();
+
+        if let Some(origin) = origin {
+            request
+                .headers_mut()
+                .insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+        }
+
+        let socket = connect_async(request).await.map(|(socket, _)| socket);
+
+        (socket, state)
+    }
+
+    async fn connect() -> (WebSocketStream<MaybeTlsStream<TcpStream>>, AppState) {
+        let (socket, state) = try_connect(Some("https://example.com")).await;
+
+        (socket.unwrap(), state)
+    }
+
+    #[test]
+    fn test_is_allowed_origin() {
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+
+        assert!(is_allowed_origin(
+            Some("https://example.com"),
+            None,
+            &config
+        ));
+        assert!(is_allowed_origin(
+            Some("https://EXAMPLE.com"),
+            None,
+            &config
+        ));
+        assert!(is_allowed_origin(
+            Some("http://localhost:3000"),
+            Some("localhost:3000"),
+            &config
+        ));
+        assert!(!is_allowed_origin(Some("https://evil.com"), None, &config));
+        assert!(!is_allowed_origin(
+            Some("https://example.com.evil.com"),
+            Some("example.com"),
+            &config
+        ));
+        assert!(!is_allowed_origin(
+            Some("http://localhost:3001"),
+            Some("localhost:3000"),
+            &config
+        ));
+        assert!(!is_allowed_origin(Some("null"), None, &config));
+        assert!(!is_allowed_origin(
+            Some("https://portal.example.org"),
+            None,
+            &config
+        ));
+        assert!(!is_allowed_origin(None, Some("example.com"), &config));
+
+        config.cors_allowed_origins = vec!["https://portal.example.org".to_string()];
+        assert!(is_allowed_origin(
+            Some("https://portal.example.org"),
+            None,
+            &config
+        ));
+    }
+
+    #[test]
+    fn test_admin_events_only_reach_admins() {
+        let event = SequencedEvent {
+            seq: 1,
+            emitted_at: chrono::Utc::now(),
+            origin: Origin::Worker,
+            event: ServerEvent::BusMetrics {
+                metrics: EventManager::new(16).get_metrics(),
+            }
+            .into(),
+        };
+        let topics = Topic::defaults();
+        let mut config = Config::from_env(&ConfigArgs::default()).unwrap();
+        config.admins = vec!["octocat".to_string()];
+
+        let anonymous = User::Anonymous("frank".to_string());
+        let admin = User::GitHub(GitHubUser {
+            login: "octocat".to_string(),
+            name: "The Octocat".to_string(),
+            avatar_url: String::new(),
+        });
+
+        assert!(to_message(&event, &anonymous, &config, &topics).is_none());
+        assert!(to_message(&event, &admin, &config, &topics).is_some());
+
+        // an admin removed by a reload of the configuration no longer gets the events
+        config.admins = vec![];
+        assert!(to_message(&event, &admin, &config, &topics).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mismatching_origin_is_rejected() {
+        let (socket, _state) = try_connect(Some("https://evil.com")).await;
+
+        let Err(tungstenite::Error::Http(response)) = socket else {
+            panic!("Expected the upgrade to be rejected");
+        };
+        assert_eq!(response.status(), 403);
+
+        let (socket, _state) = try_connect(None).await;
+
+        let Err(tungstenite::Error::Http(response)) = socket else {
+            panic!("Expected the upgrade to be rejected");
+        };
+        assert_eq!(response.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_client_ping_is_answered() {
+        let (mut socket, _state) = connect().await;
+
+        socket
+            .send(Message::Ping("keepalive".into()))
+            .await
+            .unwrap();
+
+        loop {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Pong(payload) => {
+                    assert_eq!(payload.as_ref(), b"keepalive");
+                    break;
+                }
+                Message::Text(_) => continue,
+                msg => panic!("Unexpected message: {msg:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_has_client_address() {
+        let (mut socket, state) = connect().await;
+
+        // the initial state is sent after the connection is registered
+        assert!(matches!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text(_)
+        ));
+
+        let connections = state.presence.get_connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].remote_addr, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_closes_connection() {
+        let (mut socket, state) = connect().await;
+
+        let message = "x".repeat(state.config().ws_max_message_bytes + 1);
+        socket.send(Message::Text(message.into())).await.unwrap();
+
+        loop {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Close(Some(frame)) => {
+                    assert_eq!(frame.code, CloseCode::Size);
+                    break;
+                }
+                Message::Text(_) => continue,
+                msg => panic!("Unexpected message: {msg:?}"),
+            }
+        }
+    }
 }